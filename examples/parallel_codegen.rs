@@ -0,0 +1,77 @@
+//! Times generating the code of a module with many functions on one thread and on all of
+//! them. Run with `cargo run --release --example parallel_codegen [functions]`.
+
+use std::time::{Duration, Instant};
+
+use fig::ir::{Function, Module, ValueRef};
+
+/// A function adding up the numbers below `n` in a loop and printing the sum.
+fn sum_below(name: String, n: i64) -> Function {
+    let mut func = Function::new(name);
+    let mut entry = func.new_block(".entry".into());
+    let sum = entry.build_alloc(8);
+    let counter = entry.build_alloc(8);
+    let zero = entry.build_constant(0);
+    entry.build_store(zero, sum);
+    let n = entry.build_constant(n);
+    entry.build_store(n, counter);
+
+    let mut body = func.new_block(".loop".into());
+    let value = body.build_load(counter);
+    let total = body.build_load(sum);
+    let total = body.build_add(total, value);
+    body.build_store(total, sum);
+    let value = body.build_load(counter);
+    let value = body.build_subtract(value, ValueRef::Immediate(1));
+    body.build_store(value, counter);
+    let value = body.build_load(counter);
+    body.build_jump_if_not_zero(value, ".loop".into());
+
+    let mut end = func.new_block(".end".into());
+    let total = end.build_load(sum);
+    end.build_put_int(total);
+    end.build_return(None);
+
+    for block in [entry, body, end] {
+        func.append_block(block);
+    }
+    func
+}
+
+/// The shortest of `runs` timings of `generate`.
+fn best_of(runs: usize, mut generate: impl FnMut() -> String) -> (Duration, String) {
+    let mut best = Duration::MAX;
+    let mut code = String::new();
+    for _ in 0..runs {
+        let start = Instant::now();
+        code = generate();
+        best = best.min(start.elapsed());
+    }
+    (best, code)
+}
+
+fn main() {
+    let count = std::env::args()
+        .nth(1)
+        .map_or(1000, |arg| arg.parse().expect("the number of functions"));
+    let mut module = Module::default();
+    for i in 0..count {
+        module.append_func(sum_below(format!("sum_below_{}", i), i as i64 + 1));
+    }
+
+    let (serial, serial_code) = best_of(5, || module.generate_string().unwrap());
+    let (parallel, parallel_code) = best_of(5, || module.generate_code_parallel().unwrap());
+    assert_eq!(parallel_code, serial_code);
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    println!(
+        "{} functions, {} bytes of assembly",
+        count,
+        serial_code.len()
+    );
+    println!("serial:   {:?}", serial);
+    println!("parallel: {:?} on {} threads", parallel, threads);
+    println!(
+        "speedup:  {:.2}x",
+        serial.as_secs_f64() / parallel.as_secs_f64()
+    );
+}
@@ -58,3 +58,88 @@ put_int:
         syscall
         add     rsp, 40
         ret
+
+; void put_hex(uint64_t n)
+; {
+;     char buf[32];
+;     char *p = &buf[sizeof(buf) - 1];
+;     *p = '\n';
+;     do {
+;         *--p = "0123456789abcdef"[n & 15];
+;         n >>= 4;
+;     } while (n != 0);
+;     write(1, p, &buf[sizeof(buf)] - p);
+; }
+global put_hex
+put_hex:
+        sub     rsp, 40
+        mov     BYTE [rsp+31], 10
+        lea     rsi, [rsp+31]
+.L1:
+        mov     eax, edi
+        and     eax, 15
+        add     eax, 48
+        cmp     eax, 57
+        jbe     .L2
+        add     eax, 39
+.L2:
+        sub     rsi, 1
+        mov     BYTE [rsi], al
+        shr     rdi, 4
+        jne     .L1
+        lea     rdx, [rsp+32]
+        sub     rdx, rsi
+        mov     edi, 1
+        mov     eax, 1
+        syscall
+        add     rsp, 40
+        ret
+
+; void put_bin(uint64_t n)
+; {
+;     char buf[72];
+;     char *p = &buf[sizeof(buf) - 1];
+;     *p = '\n';
+;     do {
+;         *--p = '0' + (n & 1);
+;         n >>= 1;
+;     } while (n != 0);
+;     write(1, p, &buf[sizeof(buf)] - p);
+; }
+global put_bin
+put_bin:
+        sub     rsp, 72
+        mov     BYTE [rsp+71], 10
+        lea     rsi, [rsp+71]
+.L1:
+        mov     eax, edi
+        and     eax, 1
+        add     eax, 48
+        sub     rsi, 1
+        mov     BYTE [rsi], al
+        shr     rdi, 1
+        jne     .L1
+        lea     rdx, [rsp+72]
+        sub     rdx, rsi
+        mov     edi, 1
+        mov     eax, 1
+        syscall
+        add     rsp, 72
+        ret
+
+; void put_char(int64_t c)
+; {
+;     char buf = c;
+;     write(1, &buf, 1);
+; }
+global put_char
+put_char:
+        sub     rsp, 8
+        mov     BYTE [rsp], dil
+        mov     rsi, rsp
+        mov     edx, 1
+        mov     edi, 1
+        mov     eax, 1
+        syscall
+        add     rsp, 8
+        ret
@@ -0,0 +1,476 @@
+//! Turning a module into an executable, shared library or object file with the external
+//! assembler and linker.
+
+use std::{
+    fs,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::ir::{att, AsmSyntax, Module, OutputKind, Target};
+
+/// Source of the runtime library shipped with the crate, providing `put_int`, `put_hex`,
+/// `put_bin` and `put_char`.
+pub const EMBEDDED_RUNTIME: &str = include_str!("../lib/lib.s");
+
+/// Where the runtime library linked into executables and shared libraries comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Runtime {
+    /// The runtime embedded in the crate, assembled in a temporary directory of its own
+    /// for every build which is deleted afterwards.
+    Embedded,
+    /// A runtime assembly source file on disk. Its object file is placed next to it.
+    Source(PathBuf),
+}
+
+/// Object file format NASM assembles into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ObjectFormat {
+    /// ELF64 as used by Linux.
+    #[default]
+    Elf64,
+    /// 64-bit Mach-O as used by macOS.
+    Macho64,
+    /// 64-bit COFF as used by Windows.
+    Win64,
+}
+
+impl ObjectFormat {
+    /// The name of the format as passed to `nasm -f`.
+    pub fn nasm_name(self) -> &'static str {
+        match self {
+            ObjectFormat::Elf64 => "elf64",
+            ObjectFormat::Macho64 => "macho64",
+            ObjectFormat::Win64 => "win64",
+        }
+    }
+}
+
+impl std::str::FromStr for ObjectFormat {
+    type Err = String;
+
+    /// Parse the name of a format as passed to `nasm -f`.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "elf64" => Ok(ObjectFormat::Elf64),
+            "macho64" => Ok(ObjectFormat::Macho64),
+            "win64" => Ok(ObjectFormat::Win64),
+            _ => Err(format!(
+                "unknown object format `{}`, expected elf64, macho64 or win64",
+                name
+            )),
+        }
+    }
+}
+
+/// Configuration for building a module.
+#[derive(Debug, Clone)]
+pub struct BuildConfig {
+    /// Directory for the intermediate assembly and object files.
+    pub build_dir: PathBuf,
+    /// Path of the resulting executable, shared library or object file.
+    pub output: PathBuf,
+    /// The runtime library to link against.
+    pub runtime: Runtime,
+    /// Skip assembling a runtime source file if its object file is newer than the source.
+    pub reuse_runtime_object: bool,
+    /// Additional object files or static archives to link against, e.g. a library
+    /// providing functions the module calls.
+    pub link_libraries: Vec<PathBuf>,
+    /// Additional arguments passed to the linker as is.
+    pub extra_link_args: Vec<String>,
+    /// The object file format NASM assembles into. Code in AT&T syntax is always assembled
+    /// into the native format of the GNU assembler.
+    pub object_format: ObjectFormat,
+    /// The linker command.
+    pub linker: String,
+}
+
+impl Default for BuildConfig {
+    fn default() -> Self {
+        Self {
+            build_dir: PathBuf::from("."),
+            output: PathBuf::from("output"),
+            runtime: Runtime::Embedded,
+            reuse_runtime_object: true,
+            link_libraries: vec![],
+            extra_link_args: vec![],
+            object_format: ObjectFormat::default(),
+            linker: "ld".to_string(),
+        }
+    }
+}
+
+/// Generate the code of the module, assemble it and link it according to its output kind.
+/// Only x86-64 modules can be built since the runtime library is written for it.
+pub fn build(module: &Module, config: &BuildConfig) -> io::Result<()> {
+    if module.target() != Target::X86_64 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "only x86-64 modules can be built, assemble the code of other targets yourself",
+        ));
+    }
+    fs::create_dir_all(&config.build_dir)?;
+    let source = config.build_dir.join("output.s");
+    let mut writer = BufWriter::new(fs::File::create(&source)?);
+    module.generate_code(&mut writer)?;
+    writer.flush()?;
+
+    let syntax = module.syntax();
+    if module.output_kind() == OutputKind::Object {
+        return assemble(&source, &config.output, syntax, config.object_format);
+    }
+    let object = config.build_dir.join("output.o");
+    assemble(&source, &object, syntax, config.object_format)?;
+
+    // The temporary directory of an embedded runtime lives until the linker is done.
+    let (runtime, _runtime_dir) = build_runtime(config, syntax)?;
+    let mut ld = Command::new(&config.linker);
+    if module.output_kind() == OutputKind::SharedLibrary {
+        ld.arg("-shared");
+    }
+    ld.arg("-o").arg(&config.output).arg(&object).arg(&runtime);
+    run_command(
+        ld.args(&config.link_libraries)
+            .args(&config.extra_link_args),
+    )
+}
+
+/// A path in the temporary directory starting with `prefix` which no other call in any
+/// process returns, so concurrent builds don't overwrite each other's files.
+pub(crate) fn unique_temp_dir(prefix: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    std::env::temp_dir().join(format!(
+        "{}-{}-{}",
+        prefix,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    ))
+}
+
+/// A temporary directory which is deleted together with its contents when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(prefix: &str) -> io::Result<Self> {
+        let path = unique_temp_dir(prefix);
+        fs::create_dir_all(&path)?;
+        Ok(TempDir(path))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// Assemble the configured runtime library and return the path of its object file,
+/// together with the temporary directory holding it if it has to be deleted after linking.
+/// The runtime is written in NASM syntax and translated first if the module uses AT&T
+/// syntax.
+fn build_runtime(
+    config: &BuildConfig,
+    syntax: AsmSyntax,
+) -> io::Result<(PathBuf, Option<TempDir>)> {
+    match config.runtime {
+        Runtime::Embedded => {
+            let dir = TempDir::new("fig-runtime")?;
+            let source = dir.0.join("lib.s");
+            let object = dir.0.join("lib.o");
+            write_runtime_source(&source, EMBEDDED_RUNTIME, syntax)?;
+            assemble(&source, &object, syntax, config.object_format)?;
+            Ok((object, Some(dir)))
+        }
+        Runtime::Source(ref source) => {
+            let object = source.with_extension("o");
+            if !(config.reuse_runtime_object && is_newer(&object, source)?) {
+                match syntax {
+                    AsmSyntax::Nasm => assemble(source, &object, syntax, config.object_format)?,
+                    AsmSyntax::Att => {
+                        let translated = source.with_extension("att.s");
+                        write_runtime_source(&translated, &fs::read_to_string(source)?, syntax)?;
+                        assemble(&translated, &object, syntax, config.object_format)?;
+                    }
+                }
+            }
+            Ok((object, None))
+        }
+    }
+}
+
+/// Write the NASM runtime source `nasm` to `path` in the given syntax.
+fn write_runtime_source(path: &Path, nasm: &str, syntax: AsmSyntax) -> io::Result<()> {
+    match syntax {
+        AsmSyntax::Nasm => fs::write(path, nasm),
+        AsmSyntax::Att => {
+            let mut writer = BufWriter::new(fs::File::create(path)?);
+            att::translate(nasm, &mut writer)?;
+            writer.flush()
+        }
+    }
+}
+
+/// Whether `path` exists and was modified after `than`.
+fn is_newer(path: &Path, than: &Path) -> io::Result<bool> {
+    let modified = match fs::metadata(path) {
+        Ok(metadata) => metadata.modified()?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    Ok(modified > fs::metadata(than)?.modified()?)
+}
+
+/// Assemble a source file into an object file, with NASM or the GNU assembler depending on
+/// its syntax.
+fn assemble(
+    source: &Path,
+    object: &Path,
+    syntax: AsmSyntax,
+    format: ObjectFormat,
+) -> io::Result<()> {
+    let mut cmd = match syntax {
+        AsmSyntax::Nasm => {
+            let mut nasm = Command::new("nasm");
+            nasm.args(["-f", format.nasm_name()]);
+            nasm
+        }
+        AsmSyntax::Att => {
+            let mut gas = Command::new("as");
+            gas.arg("--64");
+            gas
+        }
+    };
+    run_command(cmd.arg("-o").arg(object).arg(source))
+}
+
+/// Run a command, turning a non-zero exit status into an error carrying its output.
+fn run_command(cmd: &mut Command) -> io::Result<()> {
+    let output = cmd.output().map_err(|err| {
+        if err.kind() == io::ErrorKind::NotFound {
+            io::Error::new(
+                err.kind(),
+                format!(
+                    "{} not found, make sure it is installed and in PATH",
+                    cmd.get_program().to_string_lossy()
+                ),
+            )
+        } else {
+            err
+        }
+    })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "{:?} failed: {}",
+            cmd,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ir::{Block, Function},
+        testing::{has_tool, report_skipped},
+    };
+
+    #[test]
+    fn temp_dirs_are_unique_and_removed() {
+        let first = TempDir::new("fig-build-test").unwrap();
+        let second = TempDir::new("fig-build-test").unwrap();
+        assert_ne!(first.0, second.0);
+        let path = first.0.clone();
+        fs::write(path.join("lib.o"), b"").unwrap();
+        drop(first);
+        assert!(!path.exists());
+        assert!(second.0.exists());
+    }
+
+    #[test]
+    fn builds_with_embedded_runtime_in_att_syntax() {
+        for tool in ["as", "ld"] {
+            if !has_tool(tool) {
+                return report_skipped("builds_with_embedded_runtime_in_att_syntax", tool);
+            }
+        }
+        let mut entry = Block::new(".entry".into());
+        let value = entry.build_constant(7);
+        entry.build_put_int(value);
+        let exit_code = entry.build_constant(3);
+        entry.build_exit(exit_code);
+        let mut func = Function::new("_start".into());
+        func.append_block(entry);
+        let mut module = Module::default();
+        module.append_func(func);
+        module.set_syntax(AsmSyntax::Att);
+
+        let dir = TempDir::new("fig-build-test").unwrap();
+        let config = BuildConfig {
+            build_dir: dir.0.clone(),
+            output: dir.0.join("output"),
+            ..BuildConfig::default()
+        };
+        build(&module, &config).unwrap();
+        let output = Command::new(&config.output).output().unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"7\n");
+    }
+
+    /// A module in AT&T syntax with the function `counter`, which increments a global
+    /// variable initialized to 20 and returns its new value, the given output kind and
+    /// symbol information.
+    fn counter_module(output_kind: OutputKind) -> Module {
+        let mut module = Module::default().with_symbol_info(true);
+        module.set_output_kind(output_kind);
+        module.set_syntax(AsmSyntax::Att);
+        let count = module.add_global("count".into(), 20).unwrap();
+        let mut block = Block::new(".entry".into());
+        let value = block.build_load(count);
+        let value = block.build_add(value, crate::ir::ValueRef::Immediate(1));
+        block.build_move(value, count);
+        block.build_return(Some(value));
+        let mut func = Function::new("counter".into());
+        func.append_block(block);
+        module.append_func(func);
+        module
+    }
+
+    #[test]
+    fn shared_library_is_callable_through_dlopen() {
+        for tool in ["as", "ld", "gcc"] {
+            if !has_tool(tool) {
+                return report_skipped("shared_library_is_callable_through_dlopen", tool);
+            }
+        }
+        let dir = TempDir::new("fig-build-test").unwrap();
+        let library = dir.0.join("libcounter.so");
+        let config = BuildConfig {
+            build_dir: dir.0.clone(),
+            output: library.clone(),
+            ..BuildConfig::default()
+        };
+        build(&counter_module(OutputKind::SharedLibrary), &config).unwrap();
+
+        let c = "#include <dlfcn.h>
+                 int main(int argc, char **argv) {
+                     void *library = dlopen(argv[1], RTLD_NOW);
+                     if (!library) return 1;
+                     long (*counter)(void) = (long (*)(void))dlsym(library, \"counter\");
+                     if (!counter) return 2;
+                     return counter() + counter();
+                 }";
+        fs::write(dir.0.join("main.c"), c).unwrap();
+        let program = dir.0.join("main");
+        run_command(
+            Command::new("gcc")
+                .arg("-o")
+                .arg(&program)
+                .arg(dir.0.join("main.c"))
+                .arg("-ldl"),
+        )
+        .unwrap();
+        let status = Command::new(&program).arg(&library).status().unwrap();
+        assert_eq!(status.code(), Some(21 + 22));
+    }
+
+    #[test]
+    fn symbol_info_gives_functions_type_and_size() {
+        for tool in ["as", "ld", "readelf"] {
+            if !has_tool(tool) {
+                return report_skipped("symbol_info_gives_functions_type_and_size", tool);
+            }
+        }
+        for output_kind in [OutputKind::Object, OutputKind::SharedLibrary] {
+            let dir = TempDir::new("fig-build-test").unwrap();
+            let config = BuildConfig {
+                build_dir: dir.0.clone(),
+                output: dir.0.join("output"),
+                ..BuildConfig::default()
+            };
+            build(&counter_module(output_kind), &config).unwrap();
+            let output = Command::new("readelf")
+                .arg("-sW")
+                .arg(&config.output)
+                .output()
+                .unwrap();
+            let symbols = String::from_utf8(output.stdout).unwrap();
+            // Columns: number, value, size, type, binding, visibility, section and name.
+            let counter: Vec<&str> = symbols
+                .lines()
+                .map(|line| line.split_whitespace().collect::<Vec<_>>())
+                .find(|columns| columns.len() == 8 && columns[7] == "counter")
+                .unwrap_or_else(|| panic!("no symbol counter in\n{}", symbols));
+            assert_eq!(counter[3], "FUNC", "{}", symbols);
+            assert_ne!(counter[2], "0", "{}", symbols);
+        }
+    }
+
+    #[test]
+    fn object_format_names_parse_back() {
+        for format in [
+            ObjectFormat::Elf64,
+            ObjectFormat::Macho64,
+            ObjectFormat::Win64,
+        ] {
+            assert_eq!(format.nasm_name().parse(), Ok(format));
+        }
+        assert_eq!(
+            "coff".parse::<ObjectFormat>(),
+            Err("unknown object format `coff`, expected elf64, macho64 or win64".to_string())
+        );
+    }
+
+    #[test]
+    fn links_with_configured_linker_libraries_and_arguments() {
+        for tool in ["as", "ld"] {
+            if !has_tool(tool) {
+                return report_skipped(
+                    "links_with_configured_linker_libraries_and_arguments",
+                    tool,
+                );
+            }
+        }
+        let dir = TempDir::new("fig-build-test").unwrap();
+        let library = dir.0.join("counter.o");
+        let config = BuildConfig {
+            build_dir: dir.0.clone(),
+            output: library.clone(),
+            ..BuildConfig::default()
+        };
+        build(&counter_module(OutputKind::Object), &config).unwrap();
+
+        // The entry point isn't `_start`, so the linker has to be told with `-e`.
+        let mut module = Module::default();
+        module.set_syntax(AsmSyntax::Att);
+        let mut block = Block::new(".entry".into());
+        let value = block.build_call_with_result("counter".into(), &[]).unwrap();
+        block.build_exit(value);
+        let mut func = Function::new("begin".into());
+        func.append_block(block);
+        module.append_func(func);
+        let mut config = BuildConfig {
+            build_dir: dir.0.clone(),
+            output: dir.0.join("output"),
+            link_libraries: vec![library],
+            extra_link_args: vec!["-e".into(), "begin".into()],
+            ..BuildConfig::default()
+        };
+        build(&module, &config).unwrap();
+        let status = Command::new(&config.output).status().unwrap();
+        assert_eq!(status.code(), Some(21));
+
+        config.linker = "fig-missing-linker".into();
+        let err = build(&module, &config).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(
+            err.to_string(),
+            "fig-missing-linker not found, make sure it is installed and in PATH"
+        );
+    }
+}
@@ -0,0 +1,583 @@
+//! An interpreter executing the IR directly, to cross-check the generated native code.
+
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    io::{self, Write},
+};
+
+use crate::ir::{
+    float_args, int_args, Function, Instruction, Module, Register, ShiftCount, Symbol, ValueRef,
+    Width, XmmRegister, ARG_REGS, FLOAT_ARG_REGS,
+};
+
+/// Size of the simulated stack in bytes.
+const STACK_SIZE: usize = 1 << 20;
+
+/// A fault that kills the program natively, which `Module::interpret_to` reports as an
+/// error wrapping it instead of exiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    /// A division by zero, or of the smallest value by -1 whose quotient doesn't fit. The
+    /// native program is killed by SIGFPE.
+    DivideError,
+}
+
+impl std::fmt::Display for Trap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Trap::DivideError => write!(f, "divide error (SIGFPE)"),
+        }
+    }
+}
+
+impl std::error::Error for Trap {}
+
+impl From<Trap> for io::Error {
+    fn from(trap: Trap) -> Self {
+        io::Error::other(trap)
+    }
+}
+
+impl Module {
+    /// Execute this module starting at its `_start` function, printing the output of the
+    /// runtime routines to stdout. Returns the exit code the program exits with.
+    ///
+    /// Panics if the program does something the interpreter can't model, like calling an
+    /// unknown external function or jumping to a computed address, or if it traps. Use
+    /// `interpret_to` to handle traps.
+    pub fn interpret(&self) -> i64 {
+        self.interpret_to(&mut io::stdout())
+            .expect("failed to interpret the program")
+    }
+
+    /// Execute this module like `interpret`, writing the output of the runtime routines to
+    /// the given Writer. A program that traps, like by dividing by zero, fails with an
+    /// error wrapping a `Trap`.
+    ///
+    /// ```
+    /// use fig::{interp::Trap, ir::{Block, Function, Module}};
+    ///
+    /// let mut block = Block::new(".entry".into());
+    /// let left = block.build_constant(1);
+    /// let right = block.build_constant(0);
+    /// let quotient = block.build_divide(left, right);
+    /// block.build_exit(quotient);
+    /// let mut func = Function::new("_start".into());
+    /// func.append_block(block);
+    /// let mut module = Module::default();
+    /// module.append_func(func);
+    ///
+    /// let error = module.interpret_to(&mut std::io::sink()).unwrap_err();
+    /// assert_eq!(error.get_ref().unwrap().downcast_ref(), Some(&Trap::DivideError));
+    /// ```
+    pub fn interpret_to(&self, out: &mut impl Write) -> io::Result<i64> {
+        Interpreter::new(self).run(out)
+    }
+}
+
+/// Position of the next instruction to be executed.
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    func: usize,
+    block: usize,
+    instruction: usize,
+}
+
+/// Everything needed to return from a call to a function of the module.
+struct CallFrame {
+    /// Where execution continues after the call.
+    ret: Position,
+    /// The caller's frame pointer.
+    rbp: usize,
+    /// Registers the caller preserves around the call, with their values.
+    preserved: Vec<(Register, i64)>,
+    /// SSE registers the caller preserves around the call, with their values.
+    preserved_xmm: Vec<(XmmRegister, i64)>,
+    /// Callee-saved registers the called function saves in its prologue, with their values.
+    saved: Vec<(Register, i64)>,
+    /// Where the caller stores the returned value.
+    result: Option<ValueRef>,
+    /// Whether the value is returned in XMM0 instead of RAX.
+    float_result: bool,
+}
+
+/// State of an executing program.
+struct Interpreter<'m> {
+    module: &'m Module,
+    registers: HashMap<Register, i64>,
+    /// The bits of the floating-point values in SSE registers.
+    xmm: HashMap<XmmRegister, i64>,
+    /// The simulated stack, growing down from its end like the native one.
+    stack: Vec<u8>,
+    rsp: usize,
+    rbp: usize,
+    /// The active calls, innermost last.
+    calls: Vec<CallFrame>,
+    /// The values of the global variables of the module. The program runs in a single
+    /// thread, so its thread-local variables are kept here too.
+    globals: HashMap<Symbol, i64>,
+}
+
+impl<'m> Interpreter<'m> {
+    fn new(module: &'m Module) -> Self {
+        Self {
+            module,
+            registers: HashMap::new(),
+            xmm: HashMap::new(),
+            stack: vec![0; STACK_SIZE],
+            rsp: STACK_SIZE,
+            rbp: STACK_SIZE,
+            calls: vec![],
+            // A string constant is stored after its length, which is what reading it yields.
+            globals: module
+                .globals
+                .iter()
+                .chain(&module.thread_locals)
+                .copied()
+                .chain(
+                    module
+                        .strings
+                        .iter()
+                        .map(|(label, string)| (*label, string.len() as i64)),
+                )
+                .collect(),
+        }
+    }
+
+    /// Run the program until it exits and return its exit code.
+    fn run(&mut self, out: &mut impl Write) -> io::Result<i64> {
+        use Instruction::*;
+
+        let mut pos = Position {
+            func: self.function_index("_start").expect("no _start function"),
+            block: 0,
+            instruction: 0,
+        };
+        self.enter(pos.func);
+        loop {
+            let func = &self.module.funcs[pos.func];
+            let block = match func.blocks.get(pos.block) {
+                Some(block) => block,
+                None => {
+                    // Falling off the end of a function returns to its caller.
+                    pos = self.ret();
+                    continue;
+                }
+            };
+            let instruction = match block.instructions.get(pos.instruction) {
+                Some(instruction) => instruction,
+                None => {
+                    pos.block += 1;
+                    pos.instruction = 0;
+                    continue;
+                }
+            };
+            pos.instruction += 1;
+
+            match *instruction {
+                Constant {
+                    storage,
+                    value,
+                    width,
+                } => self.write_sized(storage, value, width),
+                Alloc { .. } => {}
+                Store {
+                    value,
+                    storage,
+                    width,
+                    ..
+                } => {
+                    let value = self.read_sized(value, width);
+                    self.write_sized(storage, value, width);
+                }
+                Load {
+                    storage, source, ..
+                }
+                | Move { storage, source } => {
+                    let value = self.read(source);
+                    self.write(storage, value);
+                }
+                // Instructions are executed one at a time, in order.
+                Fence { .. } => {}
+                Add { left, right, width } => {
+                    let result = self
+                        .read_sized(left, width)
+                        .wrapping_add(self.read_sized(right, width));
+                    self.write_sized(left, result, width);
+                }
+                Subtract { left, right, width } => {
+                    let result = self
+                        .read_sized(left, width)
+                        .wrapping_sub(self.read_sized(right, width));
+                    self.write_sized(left, result, width);
+                }
+                Multiply { left, right } => {
+                    let result = self.read(left).wrapping_mul(self.read(right));
+                    self.write(left, result);
+                }
+                FloatConstant { storage, value } => self.write(storage, value.to_bits() as i64),
+                FloatAdd { left, right } => self.float_op(left, right, |l, r| l + r),
+                FloatSubtract { left, right } => self.float_op(left, right, |l, r| l - r),
+                FloatMultiply { left, right } => self.float_op(left, right, |l, r| l * r),
+                FloatDivide { left, right } => self.float_op(left, right, |l, r| l / r),
+                // `idiv` and `div` fault where the checked operations fail.
+                Divide { left, right } => {
+                    let result = self.read(left).checked_div(self.read(right));
+                    self.write(left, result.ok_or(Trap::DivideError)?);
+                }
+                Modulo { left, right } => {
+                    let result = self.read(left).checked_rem(self.read(right));
+                    self.write(left, result.ok_or(Trap::DivideError)?);
+                }
+                UnsignedDivide { left, right } => {
+                    let result = (self.read(left) as u64).checked_div(self.read(right) as u64);
+                    self.write(left, result.ok_or(Trap::DivideError)? as i64);
+                }
+                UnsignedModulo { left, right } => {
+                    let result = (self.read(left) as u64).checked_rem(self.read(right) as u64);
+                    self.write(left, result.ok_or(Trap::DivideError)? as i64);
+                }
+                And { left, right } => {
+                    let result = self.read(left) & self.read(right);
+                    self.write(left, result);
+                }
+                Or { left, right } => {
+                    let result = self.read(left) | self.read(right);
+                    self.write(left, result);
+                }
+                Xor { left, right } => {
+                    let result = self.read(left) ^ self.read(right);
+                    self.write(left, result);
+                }
+                SelectIndex {
+                    storage,
+                    index,
+                    ref values,
+                    ..
+                } => {
+                    let result = self.read(values[self.read(index) as usize]);
+                    self.write(storage, result);
+                }
+                Select {
+                    storage,
+                    cond,
+                    if_true,
+                    if_false,
+                } => {
+                    let result = if self.read(cond) != 0 {
+                        self.read(if_true)
+                    } else {
+                        self.read(if_false)
+                    };
+                    self.write(storage, result);
+                }
+                Equal { left, right } => {
+                    let result = (self.read(left) == self.read(right)) as i64;
+                    self.write(left, result);
+                }
+                NotEqual { left, right } => {
+                    let result = (self.read(left) != self.read(right)) as i64;
+                    self.write(left, result);
+                }
+                LessThan { left, right } => {
+                    let result = (self.read(left) < self.read(right)) as i64;
+                    self.write(left, result);
+                }
+                GreaterThan { left, right } => {
+                    let result = (self.read(left) > self.read(right)) as i64;
+                    self.write(left, result);
+                }
+                LessEqual { left, right } => {
+                    let result = (self.read(left) <= self.read(right)) as i64;
+                    self.write(left, result);
+                }
+                GreaterEqual { left, right } => {
+                    let result = (self.read(left) >= self.read(right)) as i64;
+                    self.write(left, result);
+                }
+                Minimum { left, right } => {
+                    let result = self.read(left).min(self.read(right));
+                    self.write(left, result);
+                }
+                Maximum { left, right } => {
+                    let result = self.read(left).max(self.read(right));
+                    self.write(left, result);
+                }
+                Negate { value } => {
+                    let result = self.read(value).wrapping_neg();
+                    self.write(value, result);
+                }
+                ShiftLeft { left, count } => {
+                    let result = self.read(left).wrapping_shl(self.shift_count(count));
+                    self.write(left, result);
+                }
+                ShiftRight { left, count } => {
+                    let result = (self.read(left) as u64).wrapping_shr(self.shift_count(count));
+                    self.write(left, result as i64);
+                }
+                Popcount { storage, value } => {
+                    let result = self.read(value).count_ones() as i64;
+                    self.write(storage, result);
+                }
+                Jump { ref dest } => pos = self.jump(func, pos, dest),
+                JumpIfZero { value, ref dest } => {
+                    if self.read(value) == 0 {
+                        pos = self.jump(func, pos, dest);
+                    }
+                }
+                JumpIfNotZero { value, ref dest } => {
+                    if self.read(value) != 0 {
+                        pos = self.jump(func, pos, dest);
+                    }
+                }
+                Switch {
+                    value,
+                    ref cases,
+                    ref default,
+                    ..
+                } => {
+                    let value = self.read(value);
+                    let dest = cases
+                        .iter()
+                        .find(|&&(case, _)| case == value)
+                        .map_or(default, |(_, dest)| dest);
+                    pos = self.jump(func, pos, dest);
+                }
+                Call {
+                    ref func,
+                    ref args,
+                    ref preserve,
+                    ref preserve_xmm,
+                    result,
+                    float_result,
+                } => {
+                    let preserved = preserve
+                        .iter()
+                        .map(|&reg| (reg, self.register(reg)))
+                        .collect();
+                    let preserved_xmm = preserve_xmm
+                        .iter()
+                        .map(|&reg| (reg, self.read(ValueRef::Xmm(reg))))
+                        .collect();
+                    let int_args: Vec<i64> = int_args(args).map(|arg| self.read(arg)).collect();
+                    let float_args: Vec<i64> = float_args(args)
+                        .map(|reg| self.read(ValueRef::Xmm(reg)))
+                        .collect();
+                    self.registers
+                        .extend(ARG_REGS.iter().copied().zip(int_args));
+                    // The callee may overwrite every SSE register, so nothing the caller
+                    // doesn't preserve survives the call.
+                    self.xmm.clear();
+                    self.xmm
+                        .extend(FLOAT_ARG_REGS.iter().copied().zip(float_args));
+                    match self.function_index(func) {
+                        Some(callee) => {
+                            let saved = self.module.funcs[callee]
+                                .saved_registers()
+                                .into_iter()
+                                .map(|reg| (reg, self.register(reg)))
+                                .collect();
+                            self.calls.push(CallFrame {
+                                ret: pos,
+                                rbp: self.rbp,
+                                preserved,
+                                preserved_xmm,
+                                saved,
+                                result,
+                                float_result,
+                            });
+                            self.enter(callee);
+                            pos = Position {
+                                func: callee,
+                                block: 0,
+                                instruction: 0,
+                            };
+                        }
+                        None => {
+                            self.call_runtime(func, out)?;
+                            if let Some(result) = result {
+                                self.write(result, self.returned(float_result));
+                            }
+                            self.registers.extend(preserved);
+                            self.xmm.extend(preserved_xmm);
+                        }
+                    }
+                }
+                Return { value } => {
+                    if let Some(value) = value {
+                        let storage = match value {
+                            ValueRef::Xmm(_) => ValueRef::Xmm(XmmRegister::Xmm0),
+                            _ => ValueRef::Register(Register::Rax),
+                        };
+                        let value = self.read(value);
+                        self.write(storage, value);
+                    }
+                    pos = self.ret();
+                }
+                PrintString { string, .. } => {
+                    let (_, string) = self
+                        .module
+                        .strings
+                        .iter()
+                        .find(|&&(label, _)| ValueRef::Global(label) == string)
+                        .expect("print_string needs a string constant");
+                    out.write_all(string.as_bytes())?;
+                }
+                Exit { exit_code } => return Ok(self.read(exit_code)),
+                TableLoad { .. }
+                | JumpIndirect { .. }
+                | TailCallIndirect { .. }
+                | SaveContext { .. }
+                | RestoreContext { .. } => {
+                    panic!("{:?} is not supported by the interpreter", instruction)
+                }
+            }
+        }
+    }
+
+    /// Set up a new frame like the prologue of a called function.
+    fn enter(&mut self, func: usize) {
+        // Account for the return address and the saved RBP.
+        self.rsp = self.rsp.checked_sub(16).expect("stack overflow");
+        self.rbp = self.rsp;
+        let frame_size = self.module.funcs[func].frame_size();
+        self.rsp = self.rsp.checked_sub(frame_size).expect("stack overflow");
+    }
+
+    /// Tear down the current frame like the epilogue of a function and return the
+    /// position to continue at in the caller.
+    fn ret(&mut self) -> Position {
+        let frame = self.calls.pop().expect("returned from _start");
+        self.rsp = self.rbp + 16;
+        self.rbp = frame.rbp;
+        // The callee restores these before returning, so before the result is stored.
+        self.registers.extend(frame.saved);
+        if let Some(result) = frame.result {
+            self.write(result, self.returned(frame.float_result));
+        }
+        self.registers.extend(frame.preserved);
+        self.xmm.extend(frame.preserved_xmm);
+        frame.ret
+    }
+
+    fn function_index(&self, name: &str) -> Option<usize> {
+        self.module.funcs.iter().position(|func| func.name == name)
+    }
+
+    fn jump(&self, func: &Function, pos: Position, dest: &str) -> Position {
+        let block = func
+            .blocks
+            .iter()
+            .position(|block| block.name == dest)
+            .unwrap_or_else(|| panic!("jump to unknown block {}", dest));
+        Position {
+            block,
+            instruction: 0,
+            ..pos
+        }
+    }
+
+    /// Execute a routine of the runtime library.
+    fn call_runtime(&mut self, func: &str, out: &mut impl Write) -> io::Result<()> {
+        let arg = self.register(Register::Rdi);
+        match func {
+            "put_int" => writeln!(out, "{}", arg),
+            "put_hex" => writeln!(out, "{:x}", arg),
+            "put_bin" => writeln!(out, "{:b}", arg),
+            "put_char" => out.write_all(&[arg as u8]),
+            _ => panic!("call to unknown external function {}", func),
+        }
+    }
+
+    /// The value returned by a call, in XMM0 for floating-point values or else in RAX.
+    fn returned(&self, float: bool) -> i64 {
+        if float {
+            self.read(ValueRef::Xmm(XmmRegister::Xmm0))
+        } else {
+            self.register(Register::Rax)
+        }
+    }
+
+    fn register(&self, reg: Register) -> i64 {
+        self.registers.get(&reg).copied().unwrap_or(0)
+    }
+
+    fn read(&self, value: ValueRef) -> i64 {
+        match value {
+            ValueRef::Register(reg) => self.register(reg),
+            ValueRef::Memory(off) => {
+                let addr = self.rbp - off;
+                i64::from_le_bytes(self.stack[addr..addr + 8].try_into().unwrap())
+            }
+            ValueRef::Global(name) | ValueRef::ThreadLocal(name) => self.globals[&name],
+            ValueRef::Xmm(reg) => self.xmm.get(&reg).copied().unwrap_or(0),
+            ValueRef::Immediate(value) => i64::from(value),
+        }
+    }
+
+    /// Compute `op` of two floating-point values and write the result to `left`.
+    fn float_op(&mut self, left: ValueRef, right: ValueRef, op: impl Fn(f64, f64) -> f64) {
+        let result = op(
+            f64::from_bits(self.read(left) as u64),
+            f64::from_bits(self.read(right) as u64),
+        );
+        self.write(left, result.to_bits() as i64);
+    }
+
+    /// Read the lower `width` bits of a value, zero-extended.
+    fn read_sized(&self, value: ValueRef, width: Width) -> i64 {
+        match (value, width) {
+            (ValueRef::Memory(off), Width::Dword) => {
+                let addr = self.rbp - off;
+                i64::from(u32::from_le_bytes(
+                    self.stack[addr..addr + 4].try_into().unwrap(),
+                ))
+            }
+            _ => width.truncate(self.read(value)),
+        }
+    }
+
+    /// The number of bits to shift by, masked like x86 does.
+    fn shift_count(&self, count: ShiftCount) -> u32 {
+        match count {
+            ShiftCount::Immediate(count) => u32::from(count),
+            ShiftCount::Value { count, .. } => self.read(count) as u32 & 63,
+        }
+    }
+
+    fn write(&mut self, storage: ValueRef, value: i64) {
+        match storage {
+            ValueRef::Register(reg) => {
+                self.registers.insert(reg, value);
+            }
+            ValueRef::Memory(off) => {
+                let addr = self.rbp - off;
+                self.stack[addr..addr + 8].copy_from_slice(&value.to_le_bytes());
+            }
+            ValueRef::Global(name) | ValueRef::ThreadLocal(name) => {
+                self.globals.insert(name, value);
+            }
+            ValueRef::Xmm(reg) => {
+                self.xmm.insert(reg, value);
+            }
+            ValueRef::Immediate(_) => panic!("can't write to an immediate"),
+        }
+    }
+
+    /// Write the lower `width` bits of `value`. Like on x86, a 32-bit write clears the upper
+    /// half of a register but leaves the rest of memory untouched.
+    fn write_sized(&mut self, storage: ValueRef, value: i64, width: Width) {
+        match (storage, width) {
+            (ValueRef::Memory(off), Width::Dword) => {
+                let addr = self.rbp - off;
+                self.stack[addr..addr + 4].copy_from_slice(&(value as u32).to_le_bytes());
+            }
+            (ValueRef::Global(name) | ValueRef::ThreadLocal(name), Width::Dword) => {
+                let upper = self.globals[&name] & !0xffff_ffff;
+                self.globals.insert(name, upper | width.truncate(value));
+            }
+            _ => self.write(storage, width.truncate(value)),
+        }
+    }
+}
@@ -1,13 +1,40 @@
-use std::{borrow::Cow, io::Write};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    convert::TryFrom,
+    io::Write,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+mod aarch64;
+pub(crate) mod att;
+pub mod binary;
+#[cfg(test)]
+mod tests;
 
 /// A value.
 type Value = i64;
 
 /// Reference to a value created by an instruction.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ValueRef {
     Register(Register),
     Memory(usize),
+    /// A global variable declared with `Module::add_global`, named by its symbol.
+    Global(Symbol),
+    /// A thread-local variable declared with `Module::add_tls`, named by its symbol. Each
+    /// thread accesses its own copy of it.
+    ThreadLocal(Symbol),
+    /// A floating-point value in an SSE register. Building an integer instruction like
+    /// `Add` on it panics, as does building a floating-point one on an integer register or
+    /// immediate; values in memory can be used by both.
+    Xmm(XmmRegister),
+    /// An integer encoded in the instruction itself, sign-extended to 64 bits. It can only
+    /// be read, and only as the right operand of `Add`, `Subtract`, `And`, `Or` and `Xor`
+    /// or as the value of a `Store`. `Block::optimize_peephole` folds constants into these,
+    /// and `Block::build_immediate` creates them from values that may not fit.
+    Immediate(i32),
 }
 
 impl ValueRef {
@@ -16,39 +43,833 @@ impl ValueRef {
         match self {
             Register(reg) => Cow::Borrowed(reg.name()),
             Memory(off) => Cow::Owned(format!("[rbp-{}]", off)),
+            Global(name) => Cow::Owned(format!("[rel {}]", name)),
+            ThreadLocal(name) => Cow::Owned(format!("[fs:{} wrt ..tpoff]", name)),
+            Xmm(reg) => Cow::Borrowed(reg.name()),
+            Immediate(value) => Cow::Owned(value.to_string()),
+        }
+    }
+
+    /// Reference to the global variable with the given name, which must fit in a `Symbol`.
+    #[cfg(test)]
+    pub(crate) fn global(name: &str) -> Result<Self, BuildError> {
+        Symbol::new(name).map(ValueRef::Global)
+    }
+
+    /// An immediate operand with the given value. Instructions encode immediates in 32 bits,
+    /// so larger values are rejected, see `Block::build_immediate` for materializing them in
+    /// a register instead.
+    ///
+    /// ```
+    /// use fig::ir::{BuildError, ValueRef};
+    ///
+    /// assert_eq!(ValueRef::immediate(-5), Ok(ValueRef::Immediate(-5)));
+    /// assert_eq!(
+    ///     ValueRef::immediate(1 << 40),
+    ///     Err(BuildError::ImmediateOutOfRange { value: 1 << 40, bits: 32 })
+    /// );
+    /// ```
+    pub fn immediate(value: Value) -> Result<Self, BuildError> {
+        i32::try_from(value)
+            .map(ValueRef::Immediate)
+            .map_err(|_| BuildError::ImmediateOutOfRange { value, bits: 32 })
+    }
+
+    /// Whether the value is in memory, on the stack or in a global or thread-local variable.
+    fn in_memory(self) -> bool {
+        !matches!(
+            self,
+            ValueRef::Register(_) | ValueRef::Xmm(_) | ValueRef::Immediate(_)
+        )
+    }
+
+    /// Like `code` but for an operand of the given width: registers are named by the part
+    /// of them holding it, and memory gets an explicit `DWORD` size for 32-bit operands.
+    pub fn code_width(self, width: Width) -> Cow<'static, str> {
+        match (self, width) {
+            (ValueRef::Register(reg), Width::Dword) => Cow::Borrowed(reg.name32()),
+            (ValueRef::Xmm(_), _) | (ValueRef::Immediate(_), _) => self.code(),
+            (_, Width::Dword) => Cow::Owned(format!("DWORD {}", self.code())),
+            (_, Width::Qword) => self.code(),
+        }
+    }
+
+    /// Like `code` but with an explicit operand size for memory, for instructions whose
+    /// other operands don't determine it.
+    fn sized_code(self) -> Cow<'static, str> {
+        match self {
+            ValueRef::Register(_) | ValueRef::Xmm(_) | ValueRef::Immediate(_) => self.code(),
+            _ => Cow::Owned(format!("QWORD {}", self.code())),
+        }
+    }
+}
+
+/// Formats the value for the textual form of the IR: values in registers are named after
+/// their register, like `%r15`, values in memory are shown as their address, global and
+/// thread-local variables by their name, like `@counter`, and immediates as the number.
+impl std::fmt::Display for ValueRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ValueRef::Register(reg) => write!(f, "%{}", reg.name()),
+            ValueRef::Memory(offset) => write!(f, "[rbp-{}]", offset),
+            ValueRef::Global(name) | ValueRef::ThreadLocal(name) => write!(f, "@{}", name),
+            ValueRef::Xmm(reg) => write!(f, "%{}", reg.name()),
+            ValueRef::Immediate(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// The symbol of a global variable or string constant. The name is stored in the symbol
+/// itself, so a `ValueRef` can refer to a global without being tied to a module and stays
+/// `Copy`, at the cost of limiting names to `Symbol::CAPACITY` bytes.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol {
+    len: u8,
+    bytes: [u8; Symbol::CAPACITY],
+}
+
+impl Symbol {
+    /// The maximum length of a symbol in bytes.
+    pub const CAPACITY: usize = 31;
+
+    /// The symbol with the given name, or `BuildError::SymbolTooLong` if it doesn't fit.
+    ///
+    /// ```
+    /// use fig::ir::{BuildError, Symbol};
+    ///
+    /// assert_eq!(Symbol::new("counter").unwrap().as_str(), "counter");
+    /// let name = "x".repeat(Symbol::CAPACITY + 1);
+    /// assert_eq!(Symbol::new(&name), Err(BuildError::SymbolTooLong { name }));
+    /// ```
+    pub fn new(name: &str) -> Result<Self, BuildError> {
+        if name.len() > Self::CAPACITY {
+            return Err(BuildError::SymbolTooLong {
+                name: name.to_string(),
+            });
+        }
+        let mut bytes = [0; Self::CAPACITY];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(Symbol {
+            len: name.len() as u8,
+            bytes,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // The bytes were copied from a `str` in `new`.
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap()
+    }
+}
+
+impl std::ops::Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl std::fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl std::fmt::Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Width of the integer operated on by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    /// 32 bits. Writing a 32-bit value to a register clears its upper half, like x86 does.
+    Dword,
+    /// 64 bits, the width of all values unless stated otherwise.
+    Qword,
+}
+
+impl Width {
+    /// Cut `value` down to this width, zero-extending it back to a `Value`.
+    pub(crate) fn truncate(self, value: Value) -> Value {
+        match self {
+            Width::Dword => Value::from(value as u32),
+            Width::Qword => value,
+        }
+    }
+
+    /// Suffix of the mnemonics of instructions of this width in the textual IR.
+    fn suffix(self) -> &'static str {
+        match self {
+            Width::Dword => "32",
+            Width::Qword => "",
+        }
+    }
+}
+
+/// How a `Fence` orders the memory accesses around it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryOrdering {
+    /// Memory accesses after the fence are not moved before it.
+    Acquire,
+    /// Memory accesses before the fence are not moved after it.
+    Release,
+    /// No memory access is moved across the fence in either direction, by the compiler or
+    /// the processor.
+    SeqCst,
+}
+
+impl MemoryOrdering {
+    /// The name of the ordering in the textual IR.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            MemoryOrdering::Acquire => "acquire",
+            MemoryOrdering::Release => "release",
+            MemoryOrdering::SeqCst => "seq_cst",
         }
     }
 }
 
 /// Instructions of the IR to be compiled into native code.
 #[derive(Debug)]
-enum Instruction {
+pub(crate) enum Instruction {
     /// Introduce a new value to the code to be used by other instructions.
-    Constant { storage: ValueRef, value: Value },
-    /// Allocate memory on the stack.
-    Alloc { size: usize },
-    /// Store a value in memory.
-    Store { value: ValueRef, storage: ValueRef },
+    Constant {
+        storage: ValueRef,
+        value: Value,
+        width: Width,
+    },
+    /// Allocate memory on the stack. `offset` is where the memory starts below RBP. The
+    /// memory is reserved once in the prologue of the function, so this emits no code.
+    Alloc { size: usize, offset: usize },
+    /// Store a value in memory. Volatile stores must never be removed or reordered by
+    /// optimizations.
+    Store {
+        value: ValueRef,
+        storage: ValueRef,
+        volatile: bool,
+        width: Width,
+    },
+    /// Load a value from memory. Volatile loads must never be removed or reordered by
+    /// optimizations, even if the loaded value is never used.
+    Load {
+        storage: ValueRef,
+        source: ValueRef,
+        volatile: bool,
+    },
+    /// Keep optimizations from moving memory accesses across this point as the ordering
+    /// requires. Only sequentially consistent fences emit code on x86, whose loads and
+    /// stores already have acquire and release semantics.
+    Fence { ordering: MemoryOrdering },
+    /// Copy a value into the storage of another one, leaving the source untouched.
+    Move { storage: ValueRef, source: ValueRef },
     /// Add two values.
-    Add { left: ValueRef, right: ValueRef },
+    Add {
+        left: ValueRef,
+        right: ValueRef,
+        width: Width,
+    },
     /// Subtract two values.
-    Subtract { left: ValueRef, right: ValueRef },
+    Subtract {
+        left: ValueRef,
+        right: ValueRef,
+        width: Width,
+    },
     /// Multiply two values.
     Multiply { left: ValueRef, right: ValueRef },
+    /// Introduce a new floating-point value, loaded from read-only memory.
+    FloatConstant { storage: ValueRef, value: f64 },
+    /// Add two floating-point values.
+    FloatAdd { left: ValueRef, right: ValueRef },
+    /// Subtract two floating-point values.
+    FloatSubtract { left: ValueRef, right: ValueRef },
+    /// Multiply two floating-point values.
+    FloatMultiply { left: ValueRef, right: ValueRef },
+    /// Divide two floating-point values.
+    FloatDivide { left: ValueRef, right: ValueRef },
     /// Divide two values.
     Divide { left: ValueRef, right: ValueRef },
+    /// Compute the remainder of dividing two values.
+    Modulo { left: ValueRef, right: ValueRef },
+    /// Divide two values interpreted as unsigned integers.
+    UnsignedDivide { left: ValueRef, right: ValueRef },
+    /// Compute the remainder of dividing two values interpreted as unsigned integers.
+    UnsignedModulo { left: ValueRef, right: ValueRef },
+    /// Bitwise and of two values.
+    And { left: ValueRef, right: ValueRef },
+    /// Bitwise or of two values.
+    Or { left: ValueRef, right: ValueRef },
+    /// Bitwise exclusive or of two values.
+    Xor { left: ValueRef, right: ValueRef },
+    /// Compare two values, 1 if are equal, 0 otherwise.
+    Equal { left: ValueRef, right: ValueRef },
+    /// Compare two values, 1 if are not equal, 0 otherwise.
+    NotEqual { left: ValueRef, right: ValueRef },
+    /// Compare two values, 1 if the left one is less than the right one, 0 otherwise.
+    LessThan { left: ValueRef, right: ValueRef },
+    /// Compare two values, 1 if the left one is greater than the right one, 0 otherwise.
+    GreaterThan { left: ValueRef, right: ValueRef },
+    /// Compare two values, 1 if the left one is less than or equal to the right one, 0 otherwise.
+    LessEqual { left: ValueRef, right: ValueRef },
+    /// Compare two values, 1 if the left one is greater than or equal to the right one, 0 otherwise.
+    GreaterEqual { left: ValueRef, right: ValueRef },
+    /// The smaller of two values, compared as signed integers.
+    Minimum { left: ValueRef, right: ValueRef },
+    /// The larger of two values, compared as signed integers.
+    Maximum { left: ValueRef, right: ValueRef },
+    /// Negate a value.
+    Negate { value: ValueRef },
+    /// Shift a value to the left.
+    ShiftLeft { left: ValueRef, count: ShiftCount },
+    /// Shift a value to the right, filling in zeros.
+    ShiftRight { left: ValueRef, count: ShiftCount },
+    /// Load an entry of a table in static memory indexed by a runtime value.
+    TableLoad {
+        storage: ValueRef,
+        table: String,
+        index: ValueRef,
+        elem_size: usize,
+    },
+    /// Select the value at a runtime index from a list of values. `table` is the stack
+    /// memory used to index larger lists.
+    SelectIndex {
+        storage: ValueRef,
+        index: ValueRef,
+        values: Vec<ValueRef>,
+        table: Option<ValueRef>,
+    },
+    /// Select `if_true` if `cond` is non-zero and `if_false` otherwise.
+    Select {
+        storage: ValueRef,
+        cond: ValueRef,
+        if_true: ValueRef,
+        if_false: ValueRef,
+    },
+    /// Count the bits set in a value.
+    Popcount { storage: ValueRef, value: ValueRef },
     /// Jump to the given block.
     Jump { dest: String },
+    /// Jump to the address held by the given value.
+    JumpIndirect { target: ValueRef },
+    /// Call the function at the address held by `target` with a single argument, replacing
+    /// the current function's frame instead of returning to it.
+    TailCallIndirect {
+        target: ValueRef,
+        arg: Option<ValueRef>,
+    },
     /// Jump to the given block if the value is 0.
     JumpIfZero { value: ValueRef, dest: String },
-    /// Call a function by its name with a single argument.
-    Call { func: String, arg: Option<ValueRef> },
-    /// Exit the process with the given exit code.
+    /// Jump to the given block if the value is not 0.
+    JumpIfNotZero { value: ValueRef, dest: String },
+    /// Jump to the block of the case matching the value, or to `default` if none does.
+    /// `table` is the register the index into a jump table is computed in, the cases are
+    /// compared one by one if it is `None`.
+    Switch {
+        value: ValueRef,
+        cases: Vec<(Value, String)>,
+        default: String,
+        table: Option<Register>,
+    },
+    /// Call a function by its name with arguments passed in registers like the System V
+    /// ABI does, saving the given registers and SSE registers around the call: values in
+    /// SSE registers are passed in XMM0 to XMM7, up to six others in `ARG_REGS`. The value
+    /// returned in RAX, or in XMM0 if `float_result` is set, is moved to `result` if given.
+    Call {
+        func: String,
+        args: Vec<ValueRef>,
+        preserve: Vec<Register>,
+        preserve_xmm: Vec<XmmRegister>,
+        result: Option<ValueRef>,
+        float_result: bool,
+    },
+    /// Save the callee-saved registers, the stack pointer and a resume address into the
+    /// given context buffer.
+    SaveContext { context: ValueRef },
+    /// Restore the registers saved in the given context buffer and resume execution right
+    /// after the corresponding `SaveContext`.
+    RestoreContext { context: ValueRef },
+    /// Return from the function, passing the given value to the caller in RAX, or in XMM0
+    /// if it is in an SSE register.
+    Return { value: Option<ValueRef> },
+    /// Exit the process with the given integer exit code.
     Exit { exit_code: ValueRef },
+    /// Write a string added with `Module::add_string` to standard output with the `write`
+    /// system call, saving the given registers around it.
+    PrintString {
+        string: ValueRef,
+        preserve: Vec<Register>,
+    },
+}
+
+/// The number of bits to shift a value by.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ShiftCount {
+    /// A count known at compile time.
+    Immediate(u8),
+    /// A count computed at runtime. x86 takes it in CL, `save_rcx` tells whether RCX holds
+    /// a live value that has to be preserved while loading the count into it.
+    Value { count: ValueRef, save_rcx: bool },
+}
+
+impl Instruction {
+    /// Compute the result of a binary operation whose operands are the constants `left`
+    /// and `right` at compile time. Returns `None` for other instructions and operations
+    /// that would fault at runtime, like a division by zero.
+    fn fold(&self, left: Value, right: Value) -> Option<Value> {
+        use Instruction::*;
+        match self {
+            Add { width, .. } => Some(width.truncate(left.wrapping_add(right))),
+            Subtract { width, .. } => Some(width.truncate(left.wrapping_sub(right))),
+            Multiply { .. } => Some(left.wrapping_mul(right)),
+            Divide { .. } => left.checked_div(right),
+            Modulo { .. } => left.checked_rem(right),
+            UnsignedDivide { .. } => (left as u64).checked_div(right as u64).map(|v| v as Value),
+            UnsignedModulo { .. } => (left as u64).checked_rem(right as u64).map(|v| v as Value),
+            And { .. } => Some(left & right),
+            Or { .. } => Some(left | right),
+            Xor { .. } => Some(left ^ right),
+            Equal { .. } => Some((left == right) as Value),
+            NotEqual { .. } => Some((left != right) as Value),
+            LessThan { .. } => Some((left < right) as Value),
+            GreaterThan { .. } => Some((left > right) as Value),
+            LessEqual { .. } => Some((left <= right) as Value),
+            GreaterEqual { .. } => Some((left >= right) as Value),
+            Minimum { .. } => Some(left.min(right)),
+            Maximum { .. } => Some(left.max(right)),
+            _ => None,
+        }
+    }
+
+    /// The width of the values this instruction operates on.
+    fn width(&self) -> Width {
+        use Instruction::*;
+        match *self {
+            Constant { width, .. }
+            | Store { width, .. }
+            | Add { width, .. }
+            | Subtract { width, .. } => width,
+            _ => Width::Qword,
+        }
+    }
+
+    /// The value written by this instruction if writing it is all it does, so it can be
+    /// dropped if the value is never used, together with whether it also reads the value.
+    /// Divisions are not included because they fault when dividing by zero.
+    fn pure_output(&self) -> Option<(ValueRef, bool)> {
+        use Instruction::*;
+        match *self {
+            Constant { storage, .. }
+            | TableLoad { storage, .. }
+            | SelectIndex { storage, .. }
+            | Select { storage, .. }
+            | Popcount { storage, .. }
+            | Load {
+                storage,
+                volatile: false,
+                ..
+            }
+            | Move { storage, .. }
+            | FloatConstant { storage, .. } => Some((storage, false)),
+            Add { left, .. }
+            | Subtract { left, .. }
+            | Multiply { left, .. }
+            | And { left, .. }
+            | Or { left, .. }
+            | Xor { left, .. }
+            | Equal { left, .. }
+            | NotEqual { left, .. }
+            | LessThan { left, .. }
+            | GreaterThan { left, .. }
+            | LessEqual { left, .. }
+            | GreaterEqual { left, .. }
+            | Minimum { left, .. }
+            | Maximum { left, .. }
+            | ShiftLeft { left, .. }
+            | ShiftRight { left, .. }
+            | FloatAdd { left, .. }
+            | FloatSubtract { left, .. }
+            | FloatMultiply { left, .. }
+            | FloatDivide { left, .. } => Some((left, true)),
+            Negate { value } => Some((value, true)),
+            _ => None,
+        }
+    }
+
+    /// The destination and source of this instruction if it copies a whole value without
+    /// any other effect.
+    fn copy(&self) -> Option<(ValueRef, ValueRef)> {
+        use Instruction::*;
+        match *self {
+            Store {
+                value,
+                storage,
+                volatile: false,
+                width: Width::Qword,
+            } => Some((storage, value)),
+            Load {
+                storage,
+                source,
+                volatile: false,
+            }
+            | Move { storage, source } => Some((storage, source)),
+            _ => None,
+        }
+    }
+
+    /// All values read or written by this instruction.
+    fn operands(&self) -> Vec<ValueRef> {
+        use Instruction::*;
+        match *self {
+            Constant { storage, .. } | FloatConstant { storage, .. } => vec![storage],
+            Alloc { .. } | Fence { .. } | Jump { .. } => vec![],
+            Store { value, storage, .. } => vec![value, storage],
+            Load {
+                storage, source, ..
+            }
+            | Move { storage, source } => vec![storage, source],
+            Add { left, right, .. }
+            | Subtract { left, right, .. }
+            | Multiply { left, right }
+            | FloatAdd { left, right }
+            | FloatSubtract { left, right }
+            | FloatMultiply { left, right }
+            | FloatDivide { left, right }
+            | Divide { left, right }
+            | Modulo { left, right }
+            | UnsignedDivide { left, right }
+            | UnsignedModulo { left, right }
+            | And { left, right }
+            | Or { left, right }
+            | Xor { left, right }
+            | Equal { left, right }
+            | NotEqual { left, right }
+            | LessThan { left, right }
+            | GreaterThan { left, right }
+            | LessEqual { left, right }
+            | GreaterEqual { left, right }
+            | Minimum { left, right }
+            | Maximum { left, right } => vec![left, right],
+            Negate { value } => vec![value],
+            ShiftLeft { left, count } | ShiftRight { left, count } => match count {
+                ShiftCount::Immediate(_) => vec![left],
+                ShiftCount::Value { count, .. } => vec![left, count],
+            },
+            TableLoad { storage, index, .. } => vec![storage, index],
+            Select {
+                storage,
+                cond,
+                if_true,
+                if_false,
+            } => vec![storage, cond, if_true, if_false],
+            SelectIndex {
+                storage,
+                index,
+                ref values,
+                table,
+            } => [storage, index]
+                .iter()
+                .chain(values)
+                .chain(&table)
+                .copied()
+                .collect(),
+            Popcount { storage, value } => vec![storage, value],
+            JumpIndirect { target } => vec![target],
+            TailCallIndirect { target, arg } => std::iter::once(target).chain(arg).collect(),
+            JumpIfZero { value, .. } | JumpIfNotZero { value, .. } => vec![value],
+            Switch { value, table, .. } => std::iter::once(value)
+                .chain(table.map(ValueRef::Register))
+                .collect(),
+            Call {
+                ref args, result, ..
+            } => args.iter().copied().chain(result).collect(),
+            SaveContext { context } | RestoreContext { context } => vec![context],
+            Return { value } => value.into_iter().collect(),
+            Exit { exit_code } => vec![exit_code],
+            PrintString { string, .. } => vec![string],
+        }
+    }
+
+    /// Replace every value read or written by this instruction with the result of `f`.
+    fn map_operands(&mut self, mut f: impl FnMut(ValueRef) -> ValueRef) {
+        use Instruction::*;
+        let mut operands: Vec<&mut ValueRef> = match self {
+            Constant { storage, .. } | FloatConstant { storage, .. } => vec![storage],
+            Alloc { .. } | Fence { .. } | Jump { .. } => vec![],
+            Store { value, storage, .. } => vec![value, storage],
+            Load {
+                storage, source, ..
+            }
+            | Move { storage, source } => vec![storage, source],
+            Add { left, right, .. }
+            | Subtract { left, right, .. }
+            | Multiply { left, right }
+            | FloatAdd { left, right }
+            | FloatSubtract { left, right }
+            | FloatMultiply { left, right }
+            | FloatDivide { left, right }
+            | Divide { left, right }
+            | Modulo { left, right }
+            | UnsignedDivide { left, right }
+            | UnsignedModulo { left, right }
+            | And { left, right }
+            | Or { left, right }
+            | Xor { left, right }
+            | Equal { left, right }
+            | NotEqual { left, right }
+            | LessThan { left, right }
+            | GreaterThan { left, right }
+            | LessEqual { left, right }
+            | GreaterEqual { left, right }
+            | Minimum { left, right }
+            | Maximum { left, right } => vec![left, right],
+            Negate { value } => vec![value],
+            ShiftLeft { left, count } | ShiftRight { left, count } => match count {
+                ShiftCount::Immediate(_) => vec![left],
+                ShiftCount::Value { count, .. } => vec![left, count],
+            },
+            TableLoad { storage, index, .. } => vec![storage, index],
+            Select {
+                storage,
+                cond,
+                if_true,
+                if_false,
+            } => vec![storage, cond, if_true, if_false],
+            SelectIndex {
+                storage,
+                index,
+                values,
+                table,
+            } => vec![storage, index]
+                .into_iter()
+                .chain(values)
+                .chain(table)
+                .collect(),
+            Popcount { storage, value } => vec![storage, value],
+            JumpIndirect { target } => vec![target],
+            TailCallIndirect { target, arg } => std::iter::once(target).chain(arg).collect(),
+            JumpIfZero { value, .. } | JumpIfNotZero { value, .. } => vec![value],
+            // The jump table register is scratch space, not a value.
+            Switch { value, .. } => vec![value],
+            Call { args, result, .. } => args.iter_mut().chain(result).collect(),
+            SaveContext { context } | RestoreContext { context } => vec![context],
+            Return { value } => value.iter_mut().collect(),
+            Exit { exit_code } => vec![exit_code],
+            PrintString { string, .. } => vec![string],
+        };
+        for operand in &mut operands {
+            **operand = f(**operand);
+        }
+    }
+}
+
+/// Formats the instruction in the textual form of the IR read by `Module::parse`, with
+/// values shown as in the `Display` implementation of `ValueRef`, which the parser takes
+/// as value names. The jump table register of a `Switch` and the registers saved around
+/// calls aren't shown since building the parsed instructions picks the same ones again.
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use Instruction::*;
+        match self {
+            Constant {
+                storage,
+                value,
+                width,
+            } => write!(f, "{} = const{} {}", storage, width.suffix(), value),
+            Alloc { size, offset } => write!(f, "{} = alloc {}", ValueRef::Memory(*offset), size),
+            Store {
+                value,
+                storage,
+                volatile,
+                width,
+            } => {
+                let op = if *volatile { "store_volatile" } else { "store" };
+                write!(f, "{}{} {}, {}", op, width.suffix(), value, storage)
+            }
+            Load {
+                storage,
+                source,
+                volatile,
+            } => {
+                let op = if *volatile { "load_volatile" } else { "load" };
+                write!(f, "{} = {} {}", storage, op, source)
+            }
+            Fence { ordering } => write!(f, "fence {}", ordering.name()),
+            Move { storage, source } => write!(f, "move {}, {}", source, storage),
+            Add { left, right, width } => write!(f, "add{} {}, {}", width.suffix(), left, right),
+            Subtract { left, right, width } => {
+                write!(f, "sub{} {}, {}", width.suffix(), left, right)
+            }
+            Multiply { left, right } => write!(f, "mul {}, {}", left, right),
+            FloatConstant { storage, value } => write!(f, "{} = fconst {:?}", storage, value),
+            FloatAdd { left, right } => write!(f, "fadd {}, {}", left, right),
+            FloatSubtract { left, right } => write!(f, "fsub {}, {}", left, right),
+            FloatMultiply { left, right } => write!(f, "fmul {}, {}", left, right),
+            FloatDivide { left, right } => write!(f, "fdiv {}, {}", left, right),
+            Divide { left, right } => write!(f, "div {}, {}", left, right),
+            Modulo { left, right } => write!(f, "mod {}, {}", left, right),
+            UnsignedDivide { left, right } => write!(f, "udiv {}, {}", left, right),
+            UnsignedModulo { left, right } => write!(f, "umod {}, {}", left, right),
+            And { left, right } => write!(f, "and {}, {}", left, right),
+            Or { left, right } => write!(f, "or {}, {}", left, right),
+            Xor { left, right } => write!(f, "xor {}, {}", left, right),
+            Equal { left, right } => write!(f, "eq {}, {}", left, right),
+            NotEqual { left, right } => write!(f, "ne {}, {}", left, right),
+            LessThan { left, right } => write!(f, "lt {}, {}", left, right),
+            GreaterThan { left, right } => write!(f, "gt {}, {}", left, right),
+            LessEqual { left, right } => write!(f, "le {}, {}", left, right),
+            GreaterEqual { left, right } => write!(f, "ge {}, {}", left, right),
+            Minimum { left, right } => write!(f, "min {}, {}", left, right),
+            Maximum { left, right } => write!(f, "max {}, {}", left, right),
+            Negate { value } => write!(f, "neg {}", value),
+            ShiftLeft { left, count } => write!(f, "shl {}, {}", left, count),
+            ShiftRight { left, count } => write!(f, "shr {}, {}", left, count),
+            TableLoad {
+                storage,
+                table,
+                index,
+                elem_size,
+            } => write!(
+                f,
+                "{} = table_load {}, {}, {}",
+                storage, table, index, elem_size
+            ),
+            SelectIndex {
+                storage,
+                index,
+                values,
+                table,
+            } => {
+                write!(f, "{} = select {}", storage, index)?;
+                for value in values {
+                    write!(f, ", {}", value)?;
+                }
+                if let Some(table) = table {
+                    write!(f, ", table {}", table)?;
+                }
+                Ok(())
+            }
+            Select {
+                storage,
+                cond,
+                if_true,
+                if_false,
+            } => write!(
+                f,
+                "{} = select_if {}, {}, {}",
+                storage, cond, if_true, if_false
+            ),
+            Popcount { storage, value } => write!(f, "{} = popcount {}", storage, value),
+            Jump { dest } => write!(f, "jmp {}", dest),
+            JumpIndirect { target } => write!(f, "jmp_indirect {}", target),
+            TailCallIndirect { target, arg } => {
+                write!(f, "tail_call_indirect {}", target)?;
+                if let Some(arg) = arg {
+                    write!(f, ", {}", arg)?;
+                }
+                Ok(())
+            }
+            JumpIfZero { value, dest } => write!(f, "jz {}, {}", value, dest),
+            JumpIfNotZero { value, dest } => write!(f, "jnz {}, {}", value, dest),
+            Switch {
+                value,
+                cases,
+                default,
+                ..
+            } => {
+                write!(f, "switch {}, {}", value, default)?;
+                for (case, dest) in cases {
+                    write!(f, ", {}: {}", case, dest)?;
+                }
+                Ok(())
+            }
+            Call {
+                func,
+                args,
+                result,
+                float_result,
+                ..
+            } => {
+                if let Some(result) = result {
+                    write!(f, "{} = ", result)?;
+                }
+                let op = if *float_result { "call_float" } else { "call" };
+                write!(f, "{} {}", op, func)?;
+                for (i, arg) in args.iter().enumerate() {
+                    let separator = if i == 0 { " " } else { ", " };
+                    write!(f, "{}{}", separator, arg)?;
+                }
+                Ok(())
+            }
+            SaveContext { context } => write!(f, "save_context {}", context),
+            RestoreContext { context } => write!(f, "restore_context {}", context),
+            Return { value: None } => write!(f, "ret"),
+            Return { value: Some(value) } => write!(f, "ret {}", value),
+            Exit { exit_code } => write!(f, "exit {}", exit_code),
+            PrintString { string, .. } => write!(f, "print_string {}", string),
+        }
+    }
+}
+
+impl std::fmt::Display for ShiftCount {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ShiftCount::Immediate(count) => write!(f, "{}", count),
+            ShiftCount::Value { count, .. } => write!(f, "{}", count),
+        }
+    }
+}
+
+/// Registers saved by `SaveContext` in the order they are laid out in the context buffer.
+/// The saved stack pointer and the resume address follow them.
+const CONTEXT_REGS: [&str; 6] = ["rbx", "rbp", "r12", "r13", "r14", "r15"];
+
+/// Check that the arguments of a call fit in the argument registers.
+fn check_arg_count(args: &[ValueRef]) -> Result<(), BuildError> {
+    let ints = int_args(args).count();
+    if ints > ARG_REGS.len() {
+        return Err(BuildError::TooManyArguments {
+            count: ints,
+            max: ARG_REGS.len(),
+        });
+    }
+    let floats = float_args(args).count();
+    if floats > FLOAT_ARG_REGS.len() {
+        return Err(BuildError::TooManyArguments {
+            count: floats,
+            max: FLOAT_ARG_REGS.len(),
+        });
+    }
+    Ok(())
 }
 
+/// Maximum number of values selected with `cmove` by `SelectIndex`.
+const MAX_CMOV_SELECT: usize = 4;
+
+/// Minimum number of cases of a `Switch` for a jump table to be used.
+const MIN_SWITCH_TABLE_CASES: usize = 3;
+
+/// The smallest case value and the number of entries of a jump table for the cases of a
+/// `Switch`, if they are dense enough for one: at least half of the entries must jump to a
+/// case, the others jump to the default block.
+fn switch_table_range(cases: &[(Value, String)]) -> Option<(Value, usize)> {
+    let min = cases.iter().map(|&(case, _)| case).min()?;
+    let max = cases.iter().map(|&(case, _)| case).max()?;
+    let len = max as i128 - min as i128 + 1;
+    if cases.len() < MIN_SWITCH_TABLE_CASES
+        || len > cases.len() as i128 * 2
+        || i32::try_from(min).is_err()
+    {
+        return None;
+    }
+    Some((min, len as usize))
+}
+
+/// Size of a context buffer used by `SaveContext` and `RestoreContext`.
+const CONTEXT_SIZE: usize = (CONTEXT_REGS.len() + 2) * 8;
+
 /// Enumeration of general-purpose registers.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Register {
     Rax,
     Rbx,
@@ -67,6 +888,14 @@ pub enum Register {
 }
 
 impl Register {
+    /// All general-purpose registers values can be allocated in.
+    const ALL: [Register; 14] = {
+        use Register::*;
+        [
+            Rax, Rbx, Rcx, Rdx, Rsi, Rdi, R8, R9, R10, R11, R12, R13, R14, R15,
+        ]
+    };
+
     fn name(self) -> &'static str {
         use Register::*;
         match self {
@@ -86,293 +915,4756 @@ impl Register {
             R15 => "r15",
         }
     }
+
+    /// The name of the lower 32 bits of this register.
+    pub fn name32(self) -> &'static str {
+        use Register::*;
+        match self {
+            Rax => "eax",
+            Rbx => "ebx",
+            Rcx => "ecx",
+            Rdx => "edx",
+            Rsi => "esi",
+            Rdi => "edi",
+            R8 => "r8d",
+            R9 => "r9d",
+            R10 => "r10d",
+            R11 => "r11d",
+            R12 => "r12d",
+            R13 => "r13d",
+            R14 => "r14d",
+            R15 => "r15d",
+        }
+    }
+
+    /// Whether a called function may overwrite this register without restoring it.
+    fn is_caller_saved(self) -> bool {
+        use Register::*;
+        matches!(self, Rax | Rcx | Rdx | Rsi | Rdi | R8 | R9 | R10 | R11)
+    }
+
+    /// The name of the lowest byte of this register.
+    fn byte_name(self) -> &'static str {
+        use Register::*;
+        match self {
+            Rax => "al",
+            Rbx => "bl",
+            Rcx => "cl",
+            Rdx => "dl",
+            Rsi => "sil",
+            Rdi => "dil",
+            R8 => "r8b",
+            R9 => "r9b",
+            R10 => "r10b",
+            R11 => "r11b",
+            R12 => "r12b",
+            R13 => "r13b",
+            R14 => "r14b",
+            R15 => "r15b",
+        }
+    }
 }
 
-/// Register allocator for code generation.
-#[derive(Debug)]
+/// Enumeration of the SSE registers holding floating-point values. Like in the System V
+/// ABI they are all caller-saved, and calls don't preserve them: floating-point values
+/// needed after a call have to be stored in memory. XMM15 is never allocated, it holds
+/// operands in memory while an instruction is computed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum XmmRegister {
+    Xmm0,
+    Xmm1,
+    Xmm2,
+    Xmm3,
+    Xmm4,
+    Xmm5,
+    Xmm6,
+    Xmm7,
+    Xmm8,
+    Xmm9,
+    Xmm10,
+    Xmm11,
+    Xmm12,
+    Xmm13,
+    Xmm14,
+    Xmm15,
+}
+
+impl XmmRegister {
+    pub fn name(self) -> &'static str {
+        use XmmRegister::*;
+        match self {
+            Xmm0 => "xmm0",
+            Xmm1 => "xmm1",
+            Xmm2 => "xmm2",
+            Xmm3 => "xmm3",
+            Xmm4 => "xmm4",
+            Xmm5 => "xmm5",
+            Xmm6 => "xmm6",
+            Xmm7 => "xmm7",
+            Xmm8 => "xmm8",
+            Xmm9 => "xmm9",
+            Xmm10 => "xmm10",
+            Xmm11 => "xmm11",
+            Xmm12 => "xmm12",
+            Xmm13 => "xmm13",
+            Xmm14 => "xmm14",
+            Xmm15 => "xmm15",
+        }
+    }
+
+    /// The number of this register, e.g. 3 for XMM3.
+    pub(crate) fn index(self) -> u8 {
+        self as u8
+    }
+}
+
+/// Register allocator for code generation. Clones share their state, which lets all the
+/// blocks of a function allocate from the same set of registers.
+#[derive(Debug, Clone)]
 struct RegisterAlloc {
-    /// List of free registers left for this block.
+    state: Arc<Mutex<RegisterState>>,
+}
+
+#[derive(Debug)]
+struct RegisterState {
+    /// List of free registers left.
     free_regs: Vec<Register>,
-    /// List of used registers in this block.
+    /// List of used registers.
     used_regs: Vec<Register>,
+    /// List of free SSE registers left.
+    free_xmm: Vec<XmmRegister>,
+    /// List of used SSE registers.
+    used_xmm: Vec<XmmRegister>,
 }
 
 impl RegisterAlloc {
     /// Create a new, clean register allocator.
     pub fn new() -> Self {
-        use Register::*;
-        Self {
+        use {Register::*, XmmRegister::*};
+        let state = RegisterState {
             free_regs: vec![
                 Rdi, Rsi, Rdx, Rcx, Rbx, Rax, R8, R9, R10, R11, R12, R13, R14, R15,
             ],
             used_regs: vec![],
+            free_xmm: vec![
+                Xmm14, Xmm13, Xmm12, Xmm11, Xmm10, Xmm9, Xmm8, Xmm7, Xmm6, Xmm5, Xmm4, Xmm3, Xmm2,
+                Xmm1, Xmm0,
+            ],
+            used_xmm: vec![],
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, RegisterState> {
+        self.state.lock().expect("register allocator poisoned")
+    }
+
+    /// Allocate a new register and return its identifier, or fail with
+    /// `CodegenError::OutOfRegisters` if all registers are in use.
+    pub fn alloc(&self) -> Result<Register, CodegenError> {
+        let mut state = self.state();
+        let reg = state.free_regs.pop().ok_or(CodegenError::OutOfRegisters)?;
+        state.used_regs.push(reg);
+        Ok(reg)
+    }
+
+    /// Allocate the given register, or fail with `CodegenError::RegisterInUse` if it is
+    /// already allocated.
+    pub fn alloc_specific(&self, reg: Register) -> Result<(), CodegenError> {
+        let mut state = self.state();
+        if state.used_regs.contains(&reg) {
+            return Err(CodegenError::RegisterInUse(reg));
         }
+        state.free_regs.retain(|&free| free != reg);
+        state.used_regs.push(reg);
+        Ok(())
+    }
+
+    /// Whether the given register is currently allocated.
+    pub fn is_allocated(&self, reg: Register) -> bool {
+        self.state().used_regs.contains(&reg)
+    }
+
+    /// Whether other allocators share the state of this one.
+    pub fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.state) > 1
+    }
+
+    /// All currently allocated registers.
+    pub fn allocated(&self) -> Vec<Register> {
+        self.state().used_regs.clone()
     }
 
-    /// Allocate a new register and return its identifier.
-    pub fn alloc(&mut self) -> Register {
-        self.free_regs.pop().expect("no free register")
+    /// The allocated registers a called function may overwrite.
+    pub fn live_caller_saved(&self) -> Vec<Register> {
+        self.state()
+            .used_regs
+            .iter()
+            .copied()
+            .filter(|reg| reg.is_caller_saved())
+            .collect()
     }
 
     /// Free an allocated register so it can be allocated for something else again later.
-    pub fn free(&mut self, reg: Register) {
-        self.free_regs.push(reg);
+    pub fn free(&self, reg: Register) {
+        let mut state = self.state();
+        state.used_regs.retain(|&used| used != reg);
+        state.free_regs.push(reg);
+    }
+
+    /// All currently allocated SSE registers. The System V ABI lets called functions
+    /// overwrite every one of them.
+    pub fn allocated_xmm(&self) -> Vec<XmmRegister> {
+        self.state().used_xmm.clone()
+    }
+
+    /// Allocate a new SSE register like `alloc`.
+    pub fn alloc_xmm(&self) -> Result<XmmRegister, CodegenError> {
+        let mut state = self.state();
+        let reg = state.free_xmm.pop().ok_or(CodegenError::OutOfRegisters)?;
+        state.used_xmm.push(reg);
+        Ok(reg)
+    }
+
+    /// Whether the given SSE register is currently allocated.
+    pub fn is_xmm_allocated(&self, reg: XmmRegister) -> bool {
+        self.state().used_xmm.contains(&reg)
+    }
+
+    /// Free an allocated SSE register like `free`.
+    pub fn free_xmm(&self, reg: XmmRegister) {
+        let mut state = self.state();
+        state.used_xmm.retain(|&used| used != reg);
+        state.free_xmm.push(reg);
     }
 }
 
-/// Stack memory allocator for code generation.
-#[derive(Debug, Default)]
+/// Stack memory allocator for code generation. Clones share their state like those of
+/// `RegisterAlloc`.
+#[derive(Debug, Default, Clone)]
 struct StackAlloc {
-    /// The current size of the stack allocated memory.
+    state: Arc<Mutex<StackState>>,
+}
+
+#[derive(Debug, Default)]
+struct StackState {
+    /// The size of the stack memory allocated so far. Freed memory doesn't reduce it, so
+    /// it is the high-water mark the frame has to reserve.
     current_size: usize,
+    /// Memory that was freed and can be allocated again, as (offset, size).
+    free_slots: Vec<(usize, usize)>,
+    /// Offsets of the memory holding values spilled because all registers were in use.
+    spill_slots: Vec<usize>,
 }
 
 impl StackAlloc {
-    /// Allocate memory on the stack with the given size.
-    pub fn alloc(&mut self, size: usize) -> usize {
-        self.current_size += size;
-        self.current_size
+    /// Allocate memory on the stack with the given size, reusing freed memory of the same
+    /// size if there is any. Returns the offset of the memory.
+    pub fn alloc(&self, size: usize) -> usize {
+        let mut state = self.state();
+        if let Some(i) = state.free_slots.iter().position(|&(_, free)| free == size) {
+            return state.free_slots.swap_remove(i).0;
+        }
+        state.current_size += size;
+        state.current_size
     }
-}
 
-/// A module is a collection of functions.
-#[derive(Debug, Default)]
-pub struct Module<'a> {
-    funcs: Vec<&'a Function<'a>>,
-}
+    /// Free memory allocated with the given offset and size so it can be allocated for
+    /// something else again later.
+    pub fn free(&self, offset: usize, size: usize) {
+        let mut state = self.state();
+        debug_assert!(
+            offset <= state.current_size && !state.free_slots.contains(&(offset, size)),
+            "stack memory at [rbp-{}] is not allocated",
+            offset
+        );
+        state.free_slots.push((offset, size));
+    }
 
-impl<'a> Module<'a> {
-    pub fn append_func(&mut self, func: &'a Function) {
-        self.funcs.push(func);
+    /// Allocate memory for a value spilled because all registers are in use like `alloc`.
+    /// It is freed again by `free_spill` once the value is consumed.
+    pub fn alloc_spill(&self, size: usize) -> usize {
+        let offset = self.alloc(size);
+        self.state().spill_slots.push(offset);
+        offset
     }
 
-    pub fn generate_code(&self, w: &mut impl Write) -> std::io::Result<()> {
-        writeln!(w, "segment .text")?;
-        writeln!(w, "extern put_int")?;
-        for func in &self.funcs {
-            func.generate_code(w)?;
+    /// Free the memory at the given offset if it holds a spilled value, see `alloc_spill`.
+    pub fn free_spill(&self, offset: usize, size: usize) {
+        let mut state = self.state();
+        if let Some(i) = state.spill_slots.iter().position(|&slot| slot == offset) {
+            state.spill_slots.swap_remove(i);
+            drop(state);
+            self.free(offset, size);
         }
-        Ok(())
     }
-}
 
-#[derive(Debug)]
-pub struct Function<'a> {
-    /// The name of the function which will be used as a label in native code.
-    name: String,
-    /// The blocks that belong to this function.
-    blocks: Vec<&'a Block>,
-}
+    /// The size of the stack memory allocated so far, see `StackState::current_size`.
+    pub fn size(&self) -> usize {
+        self.state().current_size
+    }
 
-impl<'a> Function<'a> {
-    /// Create a new function with the given name.
-    pub fn new(name: String) -> Self {
-        Self {
-            name,
-            blocks: vec![],
-        }
+    /// Whether this allocator shares its state with `other`.
+    pub fn is_shared_with(&self, other: &StackAlloc) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
     }
 
-    /// Append a block to this function.
-    pub fn append_block(&mut self, block: &'a Block) {
-        self.blocks.push(block);
+    fn state(&self) -> MutexGuard<'_, StackState> {
+        self.state.lock().expect("stack allocator poisoned")
     }
+}
 
-    /// Generate native code for this function.
-    pub fn generate_code(&self, w: &mut impl Write) -> std::io::Result<()> {
-        writeln!(w, "global {}", self.name)?;
-        writeln!(w, "{}:", self.name)?;
-        writeln!(w, "\tpush rbp")?;
-        writeln!(w, "\tmov rbp, rsp")?;
-        for block in &self.blocks {
-            block.generate_code(w)?;
-        }
-        writeln!(w, "\tleave")?;
-        Ok(())
+/// The kind of artifact a module is compiled into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputKind {
+    /// A statically linked executable with a `_start` entry point.
+    #[default]
+    Executable,
+    /// A shared library exporting every function of the module.
+    SharedLibrary,
+    /// A relocatable object file that is not linked at all.
+    Object,
+}
+
+/// The architecture code is generated for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Target {
+    /// x86-64 assembly for NASM.
+    #[default]
+    X86_64,
+    /// AArch64 (ARM64) assembly for the GNU assembler. CET marks and the `popcnt` feature
+    /// only apply to x86-64 and are ignored.
+    Aarch64,
+}
+
+/// The assembler syntax x86-64 code is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AsmSyntax {
+    /// Intel syntax for NASM.
+    #[default]
+    Nasm,
+    /// AT&T syntax for the GNU assembler, e.g. `movq $10, %rax`.
+    Att,
+}
+
+/// Optional CPU features beyond the x86-64 baseline the generated code may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TargetFeatures {
+    /// The `popcnt` instruction.
+    pub popcnt: bool,
+}
+
+/// Module wide settings that influence how instructions are lowered.
+#[derive(Debug, Clone, Default)]
+struct CodegenOptions<'a> {
+    /// The architecture to generate code for.
+    target: Target,
+    /// Emit position-independent code, e.g. calls through the PLT.
+    pic: bool,
+    /// CPU features the generated code may use.
+    features: TargetFeatures,
+    /// Mark indirect branch targets with `endbr64` for Intel CET.
+    cet: bool,
+    /// Names of the functions that never return.
+    noreturn: Vec<&'a str>,
+    /// The registers declared to be overwritten by calls to the given functions.
+    clobbers: &'a [(String, Vec<Register>)],
+    /// Give function symbols a type and size in the symbol table.
+    symbol_info: bool,
+}
+
+impl CodegenOptions<'_> {
+    /// The registers of `preserve` which have to be saved around a call to `func` with the
+    /// given arguments: those the function may overwrite, all caller-saved registers unless
+    /// declared otherwise with `Module::declare_function_clobbers`, together with those
+    /// the call itself overwrites, the argument registers and `returned` if the returned
+    /// value is used.
+    fn call_saved(
+        &self,
+        func: &str,
+        args: &[ValueRef],
+        preserve: &[Register],
+        returned: Option<Register>,
+    ) -> Vec<Register> {
+        let clobbered = match self.clobbers.iter().find(|(name, _)| name == func) {
+            Some((_, clobbered)) => clobbered,
+            None => return preserve.to_vec(),
+        };
+        preserve
+            .iter()
+            .copied()
+            .filter(|reg| {
+                clobbered.contains(reg)
+                    || ARG_REGS[..int_args(args).count()].contains(reg)
+                    || returned == Some(*reg)
+            })
+            .collect()
     }
 }
 
-/// A block is a set of named set of instructions.
-#[derive(Debug)]
-pub struct Block {
-    /// The name will be used as a label in the resulting native code.
-    pub name: String,
-    /// List of instructions belonging to this block.
-    instructions: Vec<Instruction>,
-    /// Register allocator for code generation.
-    registers: RegisterAlloc,
-    /// Stack memory allocator for code generation.
-    stack: StackAlloc,
+/// Pick a register which is not used by any of the given operands to temporarily hold an
+/// intermediate value. The caller has to save and restore it.
+fn scratch_register(operands: &[ValueRef]) -> Result<Register, CodegenError> {
+    use Register::*;
+    [
+        Rax, Rcx, Rdx, Rsi, Rdi, R8, R9, R10, R11, Rbx, R12, R13, R14, R15,
+    ]
+    .iter()
+    .copied()
+    .find(|&reg| !operands.contains(&ValueRef::Register(reg)))
+    .ok_or(CodegenError::OutOfRegisters)
 }
 
-impl Block {
-    /// Create a new empty block with the given name.
-    pub fn new(name: String) -> Self {
-        Self {
-            name,
-            instructions: vec![],
-            registers: RegisterAlloc::new(),
-            stack: StackAlloc::default(),
+/// Write code computing a value into `storage` with `write`, for instructions which can
+/// only write their result to a register. If `storage` is a value spilled to memory, a
+/// scratch register not used by `operands` stands in for it and is stored afterwards.
+fn write_to_register<W: Write>(
+    w: &mut W,
+    storage: ValueRef,
+    operands: &[ValueRef],
+    write: impl FnOnce(&mut W, ValueRef) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    match storage {
+        ValueRef::Register(_) => write(w, storage),
+        _ => {
+            let scratch = scratch_register(operands)?;
+            writeln!(w, "\tpush {}", scratch.name())?;
+            write(w, ValueRef::Register(scratch))?;
+            writeln!(w, "\tmov {}, {}", storage.code(), scratch.name())?;
+            writeln!(w, "\tpop {}", scratch.name())?;
+            Ok(())
         }
     }
+}
 
-    /// Generate the native code for this block and write it to the given Writer.
-    fn generate_code(&self, w: &mut impl Write) -> std::io::Result<()> {
-        use Instruction::*;
+/// Write a move of an immediate value into a register or memory. x86 can only store
+/// immediates that fit into 32 bits (sign-extended) to memory directly, larger ones are
+/// materialized in a scratch register first.
+fn write_mov_immediate(
+    w: &mut impl Write,
+    storage: ValueRef,
+    value: Value,
+    width: Width,
+) -> std::io::Result<()> {
+    if width == Width::Dword {
+        // Every 32-bit immediate can be moved to a register or memory directly.
+        return writeln!(w, "\tmov {}, {}", storage.code_width(width), value as i32);
+    }
+    match storage {
+        ValueRef::Register(_) => writeln!(w, "\tmov {}, {}", storage.code(), value),
+        _ if i32::try_from(value).is_ok() => {
+            writeln!(w, "\tmov QWORD {}, {}", storage.code(), value)
+        }
+        _ => {
+            let scratch = scratch_register(&[])?.name();
+            writeln!(w, "\tpush {}", scratch)?;
+            writeln!(w, "\tmov {}, {}", scratch, value)?;
+            writeln!(w, "\tmov {}, {}", storage.code(), scratch)?;
+            writeln!(w, "\tpop {}", scratch)
+        }
+    }
+}
 
-        writeln!(w, "{}:", self.name)?;
-        for instruction in &self.instructions {
-            match *instruction {
-                Constant { storage, value } => {
-                    writeln!(w, "\tmov {}, {}", storage.code(), value)?;
-                }
-                Alloc { size } => {
-                    writeln!(w, "\tsub rsp, {}", size)?;
-                }
-                Store { value, storage } => {
-                    writeln!(w, "\tmov {}, {}", storage.code(), value.code())?;
+/// Write a division of `left` by `right`, storing the part of the result held in `result`
+/// into `left`: RAX for the quotient or RDX for the remainder. Signed divisions sign-extend
+/// the dividend into RDX with `cqo` and use `idiv`, unsigned ones clear RDX and use `div`.
+/// RAX and RDX are saved around the division unless `left` is one of them, then the result
+/// replaces its value. A divisor in RAX or RDX would be overwritten by the dividend, so it
+/// is moved to a scratch register first, and so is an immediate, which `idiv` can't take.
+fn write_division(
+    w: &mut impl Write,
+    left: ValueRef,
+    right: ValueRef,
+    result: Register,
+    signed: bool,
+) -> std::io::Result<()> {
+    let rax = ValueRef::Register(Register::Rax);
+    let rdx = ValueRef::Register(Register::Rdx);
+    if left != rdx {
+        writeln!(w, "\tpush rdx")?;
+    }
+    if left != rax {
+        writeln!(w, "\tpush rax")?;
+    }
+    let divisor = match right {
+        ValueRef::Immediate(_) => Some(scratch_register(&[left, rax, rdx])?),
+        _ if right == rax || right == rdx => Some(scratch_register(&[left, rax, rdx])?),
+        _ => None,
+    };
+    if let Some(divisor) = divisor {
+        writeln!(w, "\tpush {}", divisor.name())?;
+        writeln!(w, "\tmov {}, {}", divisor.name(), right.code())?;
+    }
+    if left != rax {
+        writeln!(w, "\tmov rax, {}", left.code())?;
+    }
+    let right = divisor.map_or(right, ValueRef::Register);
+    if signed {
+        writeln!(w, "\tcqo")?;
+        writeln!(w, "\tidiv {}", right.sized_code())?;
+    } else {
+        writeln!(w, "\txor edx, edx")?;
+        writeln!(w, "\tdiv {}", right.sized_code())?;
+    }
+    if left != ValueRef::Register(result) {
+        writeln!(w, "\tmov {}, {}", left.code(), result.name())?;
+    }
+    if let Some(divisor) = divisor {
+        writeln!(w, "\tpop {}", divisor.name())?;
+    }
+    if left != rax {
+        writeln!(w, "\tpop rax")?;
+    }
+    if left != rdx {
+        writeln!(w, "\tpop rdx")?;
+    }
+    Ok(())
+}
+
+/// Write a two-address instruction like `add left, right`. x86 can't encode instructions
+/// with two memory operands, so in that case the right operand is loaded into a scratch
+/// register first.
+fn write_binary_op(
+    w: &mut impl Write,
+    op: &str,
+    left: ValueRef,
+    right: ValueRef,
+) -> std::io::Result<()> {
+    write_sized_binary_op(w, op, left, right, Width::Qword)
+}
+
+/// Like `write_binary_op` but for operands of the given width.
+fn write_sized_binary_op(
+    w: &mut impl Write,
+    op: &str,
+    left: ValueRef,
+    right: ValueRef,
+    width: Width,
+) -> std::io::Result<()> {
+    // Moves between SSE registers and other values copy the bits.
+    let op = match (op, left, right) {
+        ("mov", ValueRef::Xmm(_), _) | ("mov", _, ValueRef::Xmm(_)) => match width {
+            Width::Dword => "movd",
+            Width::Qword => "movq",
+        },
+        _ => op,
+    };
+    if left.in_memory() && right.in_memory() {
+        let scratch = scratch_register(&[])?;
+        writeln!(w, "\tpush {}", scratch.name())?;
+        writeln!(
+            w,
+            "\tmov {}, {}",
+            ValueRef::Register(scratch).code_width(width),
+            right.code_width(width)
+        )?;
+        writeln!(
+            w,
+            "\t{} {}, {}",
+            op,
+            left.code_width(width),
+            ValueRef::Register(scratch).code_width(width)
+        )?;
+        writeln!(w, "\tpop {}", scratch.name())?;
+    } else {
+        // Nothing else gives the size of memory combined with an immediate.
+        let left_code = match (left, right, width) {
+            (_, ValueRef::Immediate(_), Width::Qword) => left.sized_code(),
+            _ => left.code_width(width),
+        };
+        writeln!(w, "\t{} {}, {}", op, left_code, right.code_width(width))?;
+    }
+    Ok(())
+}
+
+/// Write a two-address floating-point instruction like `addsd left, right`. Its result can
+/// only be written to an SSE register, so a value in memory is computed in XMM15.
+fn write_float_op(
+    w: &mut impl Write,
+    op: &str,
+    left: ValueRef,
+    right: ValueRef,
+) -> std::io::Result<()> {
+    match left {
+        ValueRef::Xmm(_) => writeln!(w, "\t{} {}, {}", op, left.code(), right.code()),
+        _ => {
+            writeln!(w, "\tmovq xmm15, {}", left.code())?;
+            writeln!(w, "\t{} xmm15, {}", op, right.code())?;
+            writeln!(w, "\tmovq {}, xmm15", left.code())
+        }
+    }
+}
+
+/// Alignment of the stack pointer at calls required by the System V ABI.
+const STACK_ALIGN: usize = 16;
+
+/// Registers the arguments of a call are passed in, in order.
+pub(crate) const ARG_REGS: [Register; 6] = [
+    Register::Rdi,
+    Register::Rsi,
+    Register::Rdx,
+    Register::Rcx,
+    Register::R8,
+    Register::R9,
+];
+
+/// SSE registers for passing floating-point arguments, in order.
+pub(crate) const FLOAT_ARG_REGS: [XmmRegister; 8] = [
+    XmmRegister::Xmm0,
+    XmmRegister::Xmm1,
+    XmmRegister::Xmm2,
+    XmmRegister::Xmm3,
+    XmmRegister::Xmm4,
+    XmmRegister::Xmm5,
+    XmmRegister::Xmm6,
+    XmmRegister::Xmm7,
+];
+
+/// The arguments of a call passed in `ARG_REGS`, those not in SSE registers.
+pub(crate) fn int_args(args: &[ValueRef]) -> impl Iterator<Item = ValueRef> + '_ {
+    args.iter()
+        .copied()
+        .filter(|arg| !matches!(arg, ValueRef::Xmm(_)))
+}
+
+/// The SSE registers holding the arguments of a call passed in `FLOAT_ARG_REGS`.
+pub(crate) fn float_args(args: &[ValueRef]) -> impl Iterator<Item = XmmRegister> + '_ {
+    args.iter().filter_map(|&arg| match arg {
+        ValueRef::Xmm(reg) => Some(reg),
+        _ => None,
+    })
+}
+
+/// Move the arguments of a call into the argument registers. If an argument is held in
+/// the argument register of an earlier one, moving them one after another would overwrite
+/// it, so in that case they are shuffled through the stack instead.
+fn write_call_args(w: &mut impl Write, args: &[ValueRef]) -> std::io::Result<()> {
+    let moves: Vec<(XmmRegister, XmmRegister)> = FLOAT_ARG_REGS
+        .iter()
+        .copied()
+        .zip(float_args(args))
+        .filter(|&(reg, arg)| arg != reg)
+        .collect();
+    let clobbers = moves
+        .iter()
+        .enumerate()
+        .any(|(i, &(_, arg))| moves[..i].iter().any(|&(reg, _)| arg == reg));
+    if clobbers {
+        writeln!(w, "\tsub rsp, {}", moves.len() * 8)?;
+        for (i, (_, arg)) in moves.iter().enumerate() {
+            writeln!(w, "\tmovsd [rsp+{}], {}", i * 8, arg.name())?;
+        }
+        for (i, (reg, _)) in moves.iter().enumerate() {
+            writeln!(w, "\tmovsd {}, [rsp+{}]", reg.name(), i * 8)?;
+        }
+        writeln!(w, "\tadd rsp, {}", moves.len() * 8)?;
+    } else {
+        for (reg, arg) in moves {
+            writeln!(w, "\tmovsd {}, {}", reg.name(), arg.name())?;
+        }
+    }
+    let moves: Vec<(Register, ValueRef)> = ARG_REGS
+        .iter()
+        .copied()
+        .zip(int_args(args))
+        .filter(|&(reg, arg)| arg != ValueRef::Register(reg))
+        .collect();
+    let clobbers = moves.iter().enumerate().any(|(i, &(_, arg))| {
+        moves[..i]
+            .iter()
+            .any(|&(reg, _)| arg == ValueRef::Register(reg))
+    });
+    if clobbers {
+        for (_, arg) in &moves {
+            writeln!(w, "\tpush {}", arg.sized_code())?;
+        }
+        for (reg, _) in moves.iter().rev() {
+            writeln!(w, "\tpop {}", reg.name())?;
+        }
+    } else {
+        for (reg, arg) in moves {
+            writeln!(w, "\tmov {}, {}", reg.name(), arg.code())?;
+        }
+    }
+    Ok(())
+}
+
+/// Write a comparison of `left` and `right` followed by a move of `right` into `left` if
+/// the condition `cc` of `cmovcc` holds. `cmovcc` can only write to a register, so `left`
+/// is loaded into a scratch register and stored back if it is in memory.
+fn write_conditional_move(
+    w: &mut impl Write,
+    cc: &str,
+    left: ValueRef,
+    right: ValueRef,
+) -> std::io::Result<()> {
+    write_to_register(w, left, &[left, right], |w, dest| {
+        if dest != left {
+            writeln!(w, "\tmov {}, {}", dest.code(), left.code())?;
+        }
+        writeln!(w, "\tcmp {}, {}", dest.code(), right.code())?;
+        writeln!(w, "\tcmov{} {}, {}", cc, dest.code(), right.code())
+    })
+}
+
+/// Write a comparison of `left` and `right` storing 1 in `left` if the condition `cc` of
+/// `setcc` holds and 0 otherwise.
+fn write_comparison(
+    w: &mut impl Write,
+    cc: &str,
+    left: ValueRef,
+    right: ValueRef,
+) -> std::io::Result<()> {
+    write_binary_op(w, "cmp", left, right)?;
+    match left {
+        ValueRef::Register(reg) => {
+            writeln!(w, "\tset{} {}", cc, reg.byte_name())?;
+            writeln!(w, "\tmovzx {}, {}", reg.name(), reg.byte_name())?;
+        }
+        _ => {
+            let scratch = scratch_register(&[left, right])?;
+            writeln!(w, "\tpush {}", scratch.name())?;
+            writeln!(w, "\tset{} {}", cc, scratch.byte_name())?;
+            writeln!(w, "\tmovzx {}, {}", scratch.name(), scratch.byte_name())?;
+            writeln!(w, "\tmov {}, {}", left.code(), scratch.name())?;
+            writeln!(w, "\tpop {}", scratch.name())?;
+        }
+    }
+    Ok(())
+}
+
+/// Write a shift like `shl left, count`. Shifts by a runtime count take it in CL, so it is
+/// moved into RCX first. If `left` is RCX itself the shift is done in a scratch register.
+fn write_shift(
+    w: &mut impl Write,
+    op: &str,
+    left: ValueRef,
+    count: ShiftCount,
+) -> std::io::Result<()> {
+    let (count, save_rcx) = match count {
+        ShiftCount::Immediate(count) => {
+            writeln!(w, "\t{} {}, {}", op, left.sized_code(), count)?;
+            return Ok(());
+        }
+        ShiftCount::Value { count, save_rcx } => (count, save_rcx),
+    };
+    if count == ValueRef::Register(Register::Rcx) {
+        writeln!(w, "\t{} {}, cl", op, left.sized_code())?;
+    } else if left == ValueRef::Register(Register::Rcx) {
+        let scratch = scratch_register(&[left, count])?.name();
+        writeln!(w, "\tpush {}", scratch)?;
+        writeln!(w, "\tmov {}, rcx", scratch)?;
+        writeln!(w, "\tmov rcx, {}", count.code())?;
+        writeln!(w, "\t{} {}, cl", op, scratch)?;
+        writeln!(w, "\tmov rcx, {}", scratch)?;
+        writeln!(w, "\tpop {}", scratch)?;
+    } else {
+        if save_rcx {
+            writeln!(w, "\tpush rcx")?;
+        }
+        writeln!(w, "\tmov rcx, {}", count.code())?;
+        writeln!(w, "\t{} {}, cl", op, left.sized_code())?;
+        if save_rcx {
+            writeln!(w, "\tpop rcx")?;
+        }
+    }
+    Ok(())
+}
+
+/// The label of the block named `block` in the function `func`. Block names are prefixed
+/// with the function name so that functions can use the same block names, like NASM's
+/// local labels starting with a dot.
+fn block_label(func: &str, block: &str) -> String {
+    if block.starts_with('.') || func.is_empty() {
+        format!("{}{}", func, block)
+    } else {
+        format!("{}.{}", func, block)
+    }
+}
+
+/// The bytes of a string constant as a comma-separated list of numbers, which needs no
+/// escaping in either assembler syntax.
+fn string_bytes(string: &str) -> String {
+    let bytes: Vec<String> = string.bytes().map(|byte| byte.to_string()).collect();
+    bytes.join(", ")
+}
+
+/// Collect the code written by `generate` into a string.
+fn generate_string(
+    generate: impl FnOnce(&mut Vec<u8>) -> std::io::Result<()>,
+) -> std::io::Result<String> {
+    let mut code = vec![];
+    generate(&mut code)?;
+    String::from_utf8(code).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
+
+/// Write a minimal `_start` entry point which calls the function `main_name` and exits the
+/// process with the value it returns in RAX.
+fn write_crt0(w: &mut impl Write, main_name: &str) -> std::io::Result<()> {
+    writeln!(w, "global _start")?;
+    writeln!(w, "_start:")?;
+    writeln!(w, "\tcall {}", main_name)?;
+    writeln!(w, "\tmov rdi, rax")?;
+    writeln!(w, "\tmov rax, 60")?;
+    writeln!(w, "\tsyscall")?;
+    Ok(())
+}
+
+/// The libc function called when a stack protector detects an overwritten canary.
+const STACK_CHK_FAIL: &str = "__stack_chk_fail";
+
+/// The stack frame a function sets up.
+#[derive(Debug)]
+struct Frame {
+    kind: FrameKind,
+    /// Callee-saved registers pushed before setting up the frame, which have to be
+    /// restored before returning.
+    saved: Vec<Register>,
+}
+
+/// The kind of stack frame a function sets up.
+#[derive(Debug)]
+enum FrameKind {
+    /// No frame because the function doesn't use the stack.
+    None,
+    /// RBP is saved and points to the stack memory of the function.
+    Standard,
+    /// Like `Standard` but with a canary right below the saved RBP which is checked when
+    /// tearing down the frame, jumping to `fail_label` if it was overwritten.
+    Protected { fail_label: String },
+}
+
+impl Frame {
+    /// Write the code tearing down the frame and restoring the saved registers, leaving
+    /// RSP at the return address.
+    fn write_teardown(&self, w: &mut impl Write) -> std::io::Result<()> {
+        match &self.kind {
+            FrameKind::None => {}
+            FrameKind::Standard => writeln!(w, "\tleave")?,
+            FrameKind::Protected { fail_label } => {
+                // R10 is the only register free here: RAX holds the returned value, and
+                // RDI and R11 the argument and target of a `TailCallIndirect`.
+                writeln!(w, "\tmov r10, [rbp]")?;
+                writeln!(w, "\txor r10, [fs:0x28]")?;
+                writeln!(w, "\tjne {}", fail_label)?;
+                writeln!(w, "\tmov rsp, rbp")?;
+                writeln!(w, "\tadd rsp, 16")?;
+                writeln!(w, "\tpop rbp")?;
+            }
+        }
+        for reg in self.saved.iter().rev() {
+            writeln!(w, "\tpop {}", reg.name())?;
+        }
+        Ok(())
+    }
+}
+
+/// The offset of the context buffer operand of `instruction`, which has to be in stack
+/// memory.
+pub(crate) fn context_offset(
+    instruction: &'static str,
+    context: ValueRef,
+) -> Result<usize, CodegenError> {
+    match context {
+        ValueRef::Memory(off) => Ok(off),
+        _ => Err(CodegenError::InvalidOperand {
+            instruction,
+            operand: context,
+        }),
+    }
+}
+
+/// Address of the `index`-th 8 byte slot of the context buffer at offset `context`.
+fn context_slot(context: usize, index: usize) -> String {
+    format!("QWORD [rbp-{}]", context - index * 8)
+}
+
+/// Time spent generating the code of a module.
+///
+/// Registers and stack memory are allocated while the IR is built, so this only covers
+/// lowering the instructions and writing the assembly text.
+#[derive(Debug, Clone, Default)]
+pub struct CodegenTimings {
+    /// Time spent generating the whole module.
+    pub total: Duration,
+    /// Time spent generating each function, in module order.
+    pub functions: Vec<(String, Duration)>,
+}
+
+/// An error found by `Function::validate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// Stack memory at the given offset is used without being allocated first.
+    UnallocatedSlot { block: String, offset: usize },
+    /// The block doesn't end with an instruction transferring control elsewhere.
+    MissingTerminator { block: String },
+    /// The block `from` jumps to `dest`, which is not a block of the function.
+    UnknownBlock { from: String, dest: String },
+    /// The block calls `func`, which is neither a function of the module nor declared with
+    /// `Module::declare_extern`. Only reported if `Module::set_check_externs` is enabled.
+    UndeclaredFunction { block: String, func: String },
+    /// The block uses a register which isn't allocated at that point, because the value in
+    /// it was consumed by an earlier instruction or comes from a block not created by the
+    /// same function.
+    UseAfterFree { block: String, register: String },
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ValidationError::UnallocatedSlot { block, offset } => write!(
+                f,
+                "block {} uses stack memory at [rbp-{}] which is not allocated",
+                block, offset
+            ),
+            ValidationError::MissingTerminator { block } => {
+                write!(f, "block {} doesn't end with a jump, return or exit", block)
+            }
+            ValidationError::UnknownBlock { from, dest } => write!(
+                f,
+                "block {} jumps to {} which is not a block of the function",
+                from, dest
+            ),
+            ValidationError::UndeclaredFunction { block, func } => write!(
+                f,
+                "block {} calls {} which is neither defined nor declared extern",
+                block, func
+            ),
+            ValidationError::UseAfterFree { block, register } => write!(
+                f,
+                "block {} uses register {} after the value in it was freed",
+                block, register
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// An error building an instruction from invalid operands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    /// The value doesn't fit in an immediate operand of the given number of bits, which
+    /// is sign-extended to 64 bits.
+    ImmediateOutOfRange { value: Value, bits: u32 },
+    /// The name is longer than `Symbol::CAPACITY` bytes.
+    SymbolTooLong { name: String },
+    /// A global or thread-local variable with the name was already declared with
+    /// `Module::add_global` or `Module::add_tls`.
+    DuplicateGlobal { name: String },
+    /// A call passes more arguments than there are argument registers.
+    TooManyArguments { count: usize, max: usize },
+    /// A switch has more than one case for the value.
+    DuplicateCase { value: Value },
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BuildError::ImmediateOutOfRange { value, bits } => write!(
+                f,
+                "{} doesn't fit in a {}-bit immediate operand",
+                value, bits
+            ),
+            BuildError::SymbolTooLong { name } => write!(
+                f,
+                "symbol {} is longer than {} bytes",
+                name,
+                Symbol::CAPACITY
+            ),
+            BuildError::DuplicateGlobal { name } => {
+                write!(f, "global {} is declared twice", name)
+            }
+            BuildError::TooManyArguments { count, max } => write!(
+                f,
+                "calls with {} arguments are not supported, at most {} are",
+                count, max
+            ),
+            BuildError::DuplicateCase { value } => write!(f, "duplicate switch case {}", value),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// An error allocating registers for values or generating code for instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodegenError {
+    /// All registers are in use, so none is left for a value or an intermediate result.
+    OutOfRegisters,
+    /// The register was requested for a value while another value occupies it.
+    RegisterInUse(Register),
+    /// The value can't be an operand of the instruction, like a context buffer outside of
+    /// stack memory.
+    InvalidOperand {
+        instruction: &'static str,
+        operand: ValueRef,
+    },
+    /// A `SelectIndex` is built without values to select from.
+    NothingToSelect,
+}
+
+impl std::fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CodegenError::OutOfRegisters => write!(f, "all registers are in use"),
+            CodegenError::RegisterInUse(reg) => {
+                write!(f, "register {} is already in use", reg.name())
+            }
+            CodegenError::InvalidOperand {
+                instruction,
+                operand,
+            } => write!(f, "{} can't be an operand of {}", operand, instruction),
+            CodegenError::NothingToSelect => write!(f, "there are no values to select from"),
+        }
+    }
+}
+
+impl std::error::Error for CodegenError {}
+
+/// Lets code generation, which writes through `std::io`, fail with a `CodegenError`.
+impl From<CodegenError> for std::io::Error {
+    fn from(err: CodegenError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// A module is a collection of functions.
+#[derive(Debug, Default)]
+pub struct Module {
+    pub(crate) funcs: Vec<Function>,
+    /// The kind of artifact this module is compiled into.
+    output_kind: OutputKind,
+    /// The architecture code is generated for.
+    target: Target,
+    /// The assembler syntax of x86-64 code.
+    syntax: AsmSyntax,
+    /// CPU features the generated code may use.
+    features: TargetFeatures,
+    /// Whether to mark indirect branch targets for Intel CET.
+    cet: bool,
+    /// Whether to give function symbols a type and size in the symbol table.
+    symbol_info: bool,
+    /// The global variables of this module with their initial values.
+    pub(crate) globals: Vec<(Symbol, Value)>,
+    /// The thread-local variables of this module with their initial values.
+    pub(crate) thread_locals: Vec<(Symbol, Value)>,
+    /// The string constants of this module with their labels.
+    pub(crate) strings: Vec<(Symbol, String)>,
+    /// External symbols declared with `declare_extern`.
+    externs: Vec<String>,
+    /// Whether validation reports calls to functions which aren't declared extern.
+    check_externs: bool,
+    /// The registers calls to the given functions may overwrite, declared with
+    /// `declare_function_clobbers`.
+    clobbers: Vec<(String, Vec<Register>)>,
+}
+
+impl Module {
+    /// Append a function to this module.
+    pub fn append_func(&mut self, func: Function) {
+        self.funcs.push(func);
+    }
+
+    /// Add a string constant to the read-only data of this module, or find an equal one
+    /// added before. It is stored under a generated label as its length in bytes followed
+    /// by its bytes, so loading the returned global yields the length.
+    /// Returns a reference to the string to be used with `Block::build_print_string`.
+    pub fn add_string(&mut self, bytes: &str) -> ValueRef {
+        if let Some(&(label, _)) = self.strings.iter().find(|(_, string)| string == bytes) {
+            return ValueRef::Global(label);
+        }
+        // The generated labels are far shorter than a symbol can be.
+        let label = Symbol::new(&format!("__fig_str{}", self.strings.len())).unwrap();
+        self.strings.push((label, bytes.to_string()));
+        ValueRef::Global(label)
+    }
+
+    /// Declare a global variable initialized to `init`, which keeps its value across calls.
+    /// It is placed in the data section under the symbol `name`, which isn't exported.
+    /// Returns a reference to the variable to be used in instructions of any function, or
+    /// an error if the name is too long for a `Symbol` or already declared.
+    ///
+    /// ```
+    /// use fig::ir::{BuildError, Module};
+    ///
+    /// let mut module = Module::default();
+    /// module.add_global("counter".into(), 0).unwrap();
+    /// assert_eq!(
+    ///     module.add_global("counter".into(), 1),
+    ///     Err(BuildError::DuplicateGlobal { name: "counter".into() })
+    /// );
+    /// ```
+    pub fn add_global(&mut self, name: String, init: Value) -> Result<ValueRef, BuildError> {
+        let symbol = self.declare_variable(name)?;
+        self.globals.push((symbol, init));
+        Ok(ValueRef::Global(symbol))
+    }
+
+    /// Declare a thread-local variable initialized to `init`, of which each thread has its
+    /// own copy. It is placed in the `.tdata` section under the symbol `name`, or in `.tbss`
+    /// if `init` is zero, and accessed at its offset from the thread pointer in FS, which
+    /// the linker resolves as the variable is defined in the executable. Setting up the
+    /// thread pointer is up to the runtime, so this only works in programs linked against
+    /// libc. Names are shared with global variables, and errors are reported like by
+    /// `add_global`.
+    ///
+    /// ```
+    /// use fig::ir::{Function, Module};
+    ///
+    /// let mut module = Module::default();
+    /// let errno = module.add_tls("errno".into(), 0).unwrap();
+    /// let mut func = Function::new("get_errno".into());
+    /// let mut block = func.new_block(".entry".into());
+    /// let value = block.build_load(errno);
+    /// block.build_return(Some(value));
+    /// func.append_block(block);
+    /// module.append_func(func);
+    ///
+    /// let code = module.generate_string().unwrap();
+    /// assert!(code.contains("section .tbss\n"));
+    /// assert!(code.contains("mov r15, [fs:errno wrt ..tpoff]\n"));
+    /// ```
+    pub fn add_tls(&mut self, name: String, init: Value) -> Result<ValueRef, BuildError> {
+        let symbol = self.declare_variable(name)?;
+        self.thread_locals.push((symbol, init));
+        Ok(ValueRef::ThreadLocal(symbol))
+    }
+
+    /// The symbol for a new global or thread-local variable named `name`.
+    fn declare_variable(&self, name: String) -> Result<Symbol, BuildError> {
+        let declared = (self.globals.iter())
+            .chain(&self.thread_locals)
+            .any(|(variable, _)| **variable == name);
+        if declared {
+            return Err(BuildError::DuplicateGlobal { name });
+        }
+        Symbol::new(&name)
+    }
+
+    /// Declare an external symbol provided by the runtime or another library, like
+    /// `put_int`. An `extern` line is emitted for it even if nothing calls it. Functions
+    /// called but not defined in the module are declared automatically, unless
+    /// `set_check_externs` makes calling them an error.
+    pub fn declare_extern(&mut self, name: String) {
+        if !self.externs.contains(&name) {
+            self.externs.push(name);
+        }
+    }
+
+    /// Declare that calls to the function `name` overwrite no registers but `clobbered`,
+    /// besides those the call passes arguments or returns a value in. Only live registers
+    /// among these are saved around calls to it, instead of all live caller-saved ones,
+    /// e.g. for runtime helpers known to preserve every register. Declaring a function
+    /// again replaces its clobbers.
+    ///
+    /// ```
+    /// use fig::ir::{Function, Module};
+    ///
+    /// let mut func = Function::new("f".into());
+    /// let mut block = func.new_block(".entry".into());
+    /// let value = block.build_constant(1);
+    /// for _ in 0..4 {
+    ///     block.build_constant(2);
+    /// }
+    /// block.build_call("keep".into(), &[]).unwrap();
+    /// block.build_return(Some(value));
+    /// func.append_block(block);
+    /// let mut module = Module::default();
+    /// module.append_func(func);
+    /// assert!(module.generate_string().unwrap().contains("push r11"));
+    ///
+    /// module.declare_function_clobbers("keep".into(), &[]);
+    /// assert!(!module.generate_string().unwrap().contains("push r11"));
+    /// ```
+    pub fn declare_function_clobbers(&mut self, name: String, clobbered: &[Register]) {
+        self.clobbers.retain(|(other, _)| *other != name);
+        self.clobbers.push((name, clobbered.to_vec()));
+    }
+
+    /// Enable or disable reporting calls to functions which are neither defined in the
+    /// module nor declared with `declare_extern` in `validate_all`, to catch misspelled
+    /// function names.
+    pub fn set_check_externs(&mut self, check_externs: bool) {
+        self.check_externs = check_externs;
+    }
+
+    /// Whether calls to undeclared functions are reported by `validate_all`.
+    pub fn check_externs(&self) -> bool {
+        self.check_externs
+    }
+
+    /// Validate every function of this module, collecting the errors of all functions
+    /// instead of stopping at the first one. Each error is paired with the name of its
+    /// function.
+    pub fn validate_all(&self) -> Result<(), Vec<(String, ValidationError)>> {
+        let noreturn = self.codegen_options().noreturn;
+        let errors: Vec<_> = self
+            .funcs
+            .iter()
+            .filter_map(|func| {
+                func.validate_with(&noreturn)
+                    .and_then(|()| self.check_calls(func))
+                    .err()
+                    .map(|err| (func.name.clone(), err))
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Set the kind of artifact this module is compiled into.
+    pub fn set_output_kind(&mut self, output_kind: OutputKind) {
+        self.output_kind = output_kind;
+    }
+
+    /// The kind of artifact this module is compiled into.
+    pub fn output_kind(&self) -> OutputKind {
+        self.output_kind
+    }
+
+    /// Set the architecture code is generated for.
+    pub fn set_target(&mut self, target: Target) {
+        self.target = target;
+    }
+
+    /// The architecture code is generated for.
+    pub fn target(&self) -> Target {
+        self.target
+    }
+
+    /// Set the assembler syntax x86-64 code is written in. Code for other targets has a
+    /// syntax of its own.
+    pub fn set_syntax(&mut self, syntax: AsmSyntax) {
+        self.syntax = syntax;
+    }
+
+    /// The assembler syntax x86-64 code is written in.
+    pub fn syntax(&self) -> AsmSyntax {
+        self.syntax
+    }
+
+    /// Set the CPU features the generated code may use.
+    pub fn set_target_features(&mut self, features: TargetFeatures) {
+        self.features = features;
+    }
+
+    /// The CPU features the generated code may use.
+    pub fn target_features(&self) -> TargetFeatures {
+        self.features
+    }
+
+    /// Enable or disable marking indirect branch targets with `endbr64`, which is required
+    /// to run under Intel CET (control-flow enforcement). When enabled every function and
+    /// every block of a function containing an indirect jump starts with `endbr64`.
+    pub fn with_cet(mut self, cet: bool) -> Self {
+        self.cet = cet;
+        self
+    }
+
+    /// Enable or disable giving every function symbol the `FUNC` type and its size in the
+    /// ELF symbol table, the equivalent of `.type name, @function` and `.size name, .-name`.
+    /// This lets profilers like `perf` attribute samples to the right function.
+    pub fn with_symbol_info(mut self, symbol_info: bool) -> Self {
+        self.symbol_info = symbol_info;
+        self
+    }
+
+    pub fn generate_code(&self, w: &mut impl Write) -> std::io::Result<()> {
+        self.generate_code_with_timings(w, None)
+    }
+
+    /// Generate native code like `generate_code` and return it as a string.
+    pub fn generate_string(&self) -> std::io::Result<String> {
+        generate_string(|w| self.generate_code(w))
+    }
+
+    /// Generate native code like `generate_code` followed by a minimal `_start` entry point
+    /// which calls the function `main_name` and exits the process with the value it
+    /// returns in RAX, like C's `int main()`. The module must not define `_start` itself.
+    pub fn generate_with_crt0(&self, w: &mut impl Write, main_name: &str) -> std::io::Result<()> {
+        if self.target == Target::Aarch64 {
+            self.generate_code(w)?;
+            return aarch64::write_crt0(w, main_name);
+        }
+        if self.syntax == AsmSyntax::Att {
+            let code = generate_string(|code| {
+                self.generate_nasm(code, None)?;
+                write_crt0(code, main_name)
+            })?;
+            return att::translate(&code, w);
+        }
+        self.generate_nasm(w, None)?;
+        write_crt0(w, main_name)
+    }
+
+    /// Generate native code like `generate_code` while measuring how long it takes.
+    /// This is meant for diagnosing slow code generation of large modules.
+    pub fn generate_code_timed(&self, w: &mut impl Write) -> (std::io::Result<()>, CodegenTimings) {
+        let mut timings = CodegenTimings::default();
+        let start = Instant::now();
+        let result = self.generate_code_with_timings(w, Some(&mut timings));
+        timings.total = start.elapsed();
+        (result, timings)
+    }
+
+    /// Check that `func` only calls functions of this module or declared externs, if
+    /// enabled with `set_check_externs`.
+    fn check_calls(&self, func: &Function) -> Result<(), ValidationError> {
+        if !self.check_externs {
+            return Ok(());
+        }
+        for block in &func.blocks {
+            for callee in block.callees() {
+                let defined = self.funcs.iter().any(|func| func.name == callee);
+                if !defined && !self.externs.iter().any(|name| name == callee) {
+                    return Err(ValidationError::UndeclaredFunction {
+                        block: block.name.clone(),
+                        func: callee.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Names of the external symbols of this module: those declared with `declare_extern`
+    /// followed by the functions called but not defined in it, in the order they are first
+    /// called. These have to be provided by the runtime or another library.
+    fn external_functions(&self) -> Vec<&str> {
+        let mut external: Vec<&str> = self.externs.iter().map(String::as_str).collect();
+        let callees = self
+            .funcs
+            .iter()
+            .flat_map(|func| &func.blocks)
+            .flat_map(|block| block.callees());
+        for callee in callees {
+            if !external.contains(&callee) && !self.funcs.iter().any(|func| func.name == callee) {
+                external.push(callee);
+            }
+        }
+        external
+    }
+
+    /// Generate native code like `generate_code`, generating the functions on multiple
+    /// threads. Each function is generated into its own buffer and the buffers are
+    /// concatenated in order, so the result is the same as that of `generate_string`.
+    pub fn generate_code_parallel(&self) -> std::io::Result<String> {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        self.generate_code_on(threads)
+    }
+
+    /// Generate native code like `generate_code_parallel` on the given number of threads.
+    pub(crate) fn generate_code_on(&self, threads: usize) -> std::io::Result<String> {
+        let options = self.codegen_options();
+        let mut code = vec![];
+        self.write_header(&mut code, &options)?;
+        let chunk_size = self.funcs.len().div_ceil(threads).max(1);
+        let chunks: Vec<std::io::Result<Vec<u8>>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .funcs
+                .chunks(chunk_size)
+                .map(|funcs| {
+                    let options = &options;
+                    scope.spawn(move || {
+                        let mut code = vec![];
+                        for func in funcs {
+                            func.generate_code_with(&mut code, options)?;
+                        }
+                        Ok(code)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("code generation panicked"))
+                .collect()
+        });
+        for chunk in chunks {
+            code.extend(chunk?);
+        }
+        let code = String::from_utf8(code)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        if self.target == Target::X86_64 && self.syntax == AsmSyntax::Att {
+            return generate_string(|w| att::translate(&code, w));
+        }
+        Ok(code)
+    }
+
+    /// The options the functions of this module are generated with.
+    fn codegen_options(&self) -> CodegenOptions<'_> {
+        CodegenOptions {
+            target: self.target,
+            pic: self.output_kind == OutputKind::SharedLibrary,
+            features: self.features,
+            cet: self.cet,
+            noreturn: self
+                .funcs
+                .iter()
+                .filter(|func| func.noreturn)
+                .map(|func| func.name.as_str())
+                .collect(),
+            clobbers: &self.clobbers,
+            symbol_info: self.symbol_info,
+        }
+    }
+
+    /// Write the directives preceding the code of the functions.
+    fn write_header(&self, w: &mut impl Write, options: &CodegenOptions) -> std::io::Result<()> {
+        if options.target == Target::Aarch64 {
+            return aarch64::write_header(w, &self.globals, &self.thread_locals, &self.strings);
+        }
+        if options.pic {
+            writeln!(w, "default rel")?;
+        }
+        if !self.globals.is_empty() {
+            writeln!(w, "section .data")?;
+            writeln!(w, "align 8")?;
+            for (name, init) in &self.globals {
+                writeln!(w, "{}:", name)?;
+                writeln!(w, "\tdq {}", init)?;
+            }
+        }
+        let (tbss, tdata): (Vec<_>, Vec<_>) =
+            self.thread_locals.iter().partition(|&&(_, init)| init == 0);
+        if !tdata.is_empty() {
+            writeln!(w, "section .tdata")?;
+            writeln!(w, "align 8")?;
+            for (name, init) in tdata {
+                writeln!(w, "{}:", name)?;
+                writeln!(w, "\tdq {}", init)?;
+            }
+        }
+        if !tbss.is_empty() {
+            writeln!(w, "section .tbss")?;
+            writeln!(w, "align 8")?;
+            for (name, _) in tbss {
+                writeln!(w, "{}:", name)?;
+                writeln!(w, "\tresq 1")?;
+            }
+        }
+        if !self.strings.is_empty() {
+            writeln!(w, "section .rodata")?;
+            for (label, string) in &self.strings {
+                writeln!(w, "align 8")?;
+                writeln!(w, "{}:", label)?;
+                writeln!(w, "\tdq {}", string.len())?;
+                if !string.is_empty() {
+                    writeln!(w, "\tdb {}", string_bytes(string))?;
+                }
+            }
+        }
+        writeln!(w, "segment .text")?;
+        for func in self.external_functions() {
+            writeln!(w, "extern {}", func)?;
+        }
+        if self.funcs.iter().any(|func| func.stack_protector) {
+            writeln!(w, "extern {}", STACK_CHK_FAIL)?;
+        }
+        Ok(())
+    }
+
+    /// Generate native code, recording the time spent on each function if `timings` is given.
+    fn generate_code_with_timings(
+        &self,
+        w: &mut impl Write,
+        timings: Option<&mut CodegenTimings>,
+    ) -> std::io::Result<()> {
+        if self.target == Target::X86_64 && self.syntax == AsmSyntax::Att {
+            let code = generate_string(|code| self.generate_nasm(code, timings))?;
+            return att::translate(&code, w);
+        }
+        self.generate_nasm(w, timings)
+    }
+
+    /// Generate native code like `generate_code_with_timings`, always in NASM syntax for
+    /// x86-64.
+    fn generate_nasm(
+        &self,
+        w: &mut impl Write,
+        mut timings: Option<&mut CodegenTimings>,
+    ) -> std::io::Result<()> {
+        let options = self.codegen_options();
+        self.write_header(w, &options)?;
+        for func in &self.funcs {
+            let start = Instant::now();
+            func.generate_code_with(w, &options)?;
+            if let Some(timings) = timings.as_mut() {
+                timings.functions.push((func.name.clone(), start.elapsed()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Function {
+    /// The name of the function which will be used as a label in native code.
+    pub(crate) name: String,
+    /// The blocks that belong to this function.
+    pub(crate) blocks: Vec<Block>,
+    /// Whether this function never returns to its caller.
+    noreturn: bool,
+    /// Whether to protect the stack memory of this function with a canary.
+    stack_protector: bool,
+    /// Register allocator shared by the blocks created by this function.
+    registers: RegisterAlloc,
+    /// Stack memory allocator shared by the blocks created by this function.
+    stack: StackAlloc,
+}
+
+impl Function {
+    /// Create a new function with the given name.
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            blocks: vec![],
+            noreturn: false,
+            stack_protector: false,
+            registers: RegisterAlloc::new(),
+            stack: StackAlloc::default(),
+        }
+    }
+
+    /// Create a new empty block with the given name which allocates registers and stack
+    /// memory together with all other blocks created by this function. Unlike with
+    /// `Block::new`, values in registers can be used across these blocks.
+    pub fn new_block(&self, name: String) -> Block {
+        Block {
+            name,
+            instructions: vec![],
+            comments: vec![],
+            stale_operands: vec![],
+            registers: self.registers.clone(),
+            stack: self.stack.clone(),
+        }
+    }
+
+    /// Mark this function as never returning to its caller, e.g. because it always exits
+    /// the process. No epilogue is generated for it and code following a call to it within
+    /// the same module is dropped.
+    pub fn set_noreturn(&mut self, noreturn: bool) {
+        self.noreturn = noreturn;
+    }
+
+    /// Check this function for errors that would make the generated code misbehave.
+    ///
+    /// Every stack memory operand has to lie within memory allocated by an `Alloc` that
+    /// comes before it, either earlier in the same block or in a block emitted before it.
+    /// Every block has to end with a jump, return or exit instead of running into the
+    /// next one, and jumps have to target blocks of this function. Register operands have
+    /// to hold a value that hasn't been consumed yet, see `ValidationError::UseAfterFree`.
+    /// A call to a function marked with `set_noreturn` ends a block too, but only
+    /// `Module::validate_all` knows which functions those are.
+    ///
+    /// ```
+    /// use fig::ir::{Function, ValidationError};
+    ///
+    /// let mut func = Function::new("_start".into());
+    /// let mut entry = func.new_block(".entry".into());
+    /// let one = entry.build_constant(1);
+    /// let two = entry.build_constant(2);
+    /// let sum = entry.build_add(one, two);
+    /// // `two` was consumed by the addition, its register now holds `three`.
+    /// let three = entry.build_constant(3);
+    /// let wrong = entry.build_add(sum, two);
+    /// let result = entry.build_add(wrong, three);
+    /// entry.build_exit(result);
+    /// func.append_block(entry);
+    ///
+    /// assert!(matches!(
+    ///     func.validate(),
+    ///     Err(ValidationError::UseAfterFree { .. })
+    /// ));
+    /// ```
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        self.validate_with(&[])
+    }
+
+    /// Validate this function like `validate`, where calls to the functions named in
+    /// `noreturn` end a block.
+    fn validate_with(&self, noreturn: &[&str]) -> Result<(), ValidationError> {
+        self.check_register_uses()?;
+        // Allocated slots as (offset, size).
+        let mut slots = vec![];
+        for block in &self.blocks {
+            for instruction in &block.instructions {
+                if let Instruction::Alloc { size, offset } = *instruction {
+                    slots.push((offset, size));
+                }
+                for operand in instruction.operands() {
+                    if let ValueRef::Memory(offset) = operand {
+                        let allocated = slots
+                            .iter()
+                            .any(|&(start, size)| start - size < offset && offset <= start);
+                        if !allocated {
+                            return Err(ValidationError::UnallocatedSlot {
+                                block: block.name.clone(),
+                                offset,
+                            });
+                        }
+                    }
+                }
+            }
+            if !block.is_terminated(noreturn) {
+                return Err(ValidationError::MissingTerminator {
+                    block: block.name.clone(),
+                });
+            }
+        }
+        self.check_jump_targets()
+    }
+
+    /// Check that no block used a register which wasn't allocated when the instruction
+    /// using it was built.
+    fn check_register_uses(&self) -> Result<(), ValidationError> {
+        for block in &self.blocks {
+            if let Some(&(_, value)) = block.stale_operands.first() {
+                let register = match value {
+                    ValueRef::Register(reg) => reg.name(),
+                    ValueRef::Xmm(reg) => reg.name(),
+                    _ => unreachable!("only registers are freed"),
+                };
+                return Err(ValidationError::UseAfterFree {
+                    block: block.name.clone(),
+                    register: register.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that all jumps target blocks of this function.
+    fn check_jump_targets(&self) -> Result<(), ValidationError> {
+        for block in &self.blocks {
+            for dest in block.jump_targets() {
+                if !self.blocks.iter().any(|block| block.name == dest) {
+                    return Err(ValidationError::UnknownBlock {
+                        from: block.name.clone(),
+                        dest: dest.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Offsets of stack memory allocated by this function which is never used by any
+    /// instruction, e.g. because a pass removed all uses. Such memory only wastes frame
+    /// space.
+    pub fn unused_slots(&self) -> Vec<usize> {
+        let instructions = || self.blocks.iter().flat_map(|block| &block.instructions);
+        let used: Vec<usize> = instructions()
+            .flat_map(|instruction| instruction.operands())
+            .filter_map(|operand| match operand {
+                ValueRef::Memory(offset) => Some(offset),
+                _ => None,
+            })
+            .collect();
+        instructions()
+            .filter_map(|instruction| match *instruction {
+                Instruction::Alloc { size, offset } => Some((offset, size)),
+                _ => None,
+            })
+            .filter(|&(start, size)| {
+                !used
+                    .iter()
+                    .any(|&offset| start - size < offset && offset <= start)
+            })
+            .map(|(start, _)| start)
+            .collect()
+    }
+
+    /// Remove the stack memory reported by `unused_slots` and move the remaining memory
+    /// up to close the gaps it leaves, shrinking the frame. Memory operands are adjusted
+    /// accordingly. This should only run once all blocks of the function are built.
+    ///
+    /// ```
+    /// use fig::ir::Function;
+    ///
+    /// let mut func = Function::new("f".into());
+    /// let mut block = func.new_block(".entry".into());
+    /// block.build_alloc(16);
+    /// let used = block.build_alloc(16);
+    /// let value = block.build_load(used);
+    /// block.build_return(Some(value));
+    /// func.append_block(block);
+    ///
+    /// func.remove_unused_slots();
+    /// assert!(func.unused_slots().is_empty());
+    /// assert!(func.to_string().contains("[rbp-16] = alloc 16"));
+    /// ```
+    pub fn remove_unused_slots(&mut self) {
+        let unused = self.unused_slots();
+        if unused.is_empty() {
+            return;
+        }
+        for block in &mut self.blocks {
+            let removed: Vec<usize> = (block.instructions.iter().enumerate())
+                .filter(|(_, instruction)| {
+                    matches!(instruction, Instruction::Alloc { offset, .. } if unused.contains(offset))
+                })
+                .map(|(i, _)| i)
+                .collect();
+            block.remove_instructions(&removed);
+        }
+
+        // The ranges of the memory kept, as (start, end) below RBP, merged where they overlap.
+        let mut kept: Vec<(usize, usize)> = (self.blocks.iter())
+            .flat_map(|block| &block.instructions)
+            .filter_map(|instruction| match *instruction {
+                Instruction::Alloc { size, offset } => Some((offset - size, offset)),
+                _ => None,
+            })
+            .collect();
+        kept.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = vec![];
+        for (start, end) in kept {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        // Each offset moves up by the size of the gaps above it.
+        let relocate = |offset: usize| {
+            let gaps = merged
+                .iter()
+                .take_while(|&&(start, _)| start < offset)
+                .fold((0, 0), |(gaps, previous_end), &(start, end)| {
+                    (gaps + start - previous_end, end)
+                })
+                .0;
+            offset - gaps
+        };
+        for block in &mut self.blocks {
+            for instruction in &mut block.instructions {
+                if let Instruction::Alloc { offset, .. } = instruction {
+                    *offset = relocate(*offset);
+                }
+                instruction.map_operands(|operand| match operand {
+                    ValueRef::Memory(offset) => ValueRef::Memory(relocate(offset)),
+                    operand => operand,
+                });
+            }
+            for (_, operand) in &mut block.stale_operands {
+                if let ValueRef::Memory(offset) = *operand {
+                    *operand = ValueRef::Memory(relocate(offset));
+                }
+            }
+        }
+    }
+
+    /// Keep stack memory in a register instead if it is only accessed by plain 64-bit
+    /// stores and loads within the block allocating it, which also means its address is
+    /// never taken. Each promoted slot takes one of the callee-saved registers this
+    /// function doesn't use otherwise, so its value survives calls, and slots stay in
+    /// memory once those run out. Blocks saving or restoring a context are skipped since
+    /// resuming a context restores registers but not memory. The promoted slots are
+    /// removed with `remove_unused_slots`, so this should only run once all blocks of the
+    /// function are built.
+    ///
+    /// ```
+    /// use fig::ir::Function;
+    ///
+    /// let mut func = Function::new("f".into());
+    /// let mut block = func.new_block(".entry".into());
+    /// let local = block.build_alloc(8);
+    /// let five = block.build_constant(5);
+    /// block.build_store(five, local);
+    /// let value = block.build_load(local);
+    /// block.build_return(Some(value));
+    /// func.append_block(block);
+    ///
+    /// func.promote_locals();
+    /// assert_eq!(
+    ///     func.to_string(),
+    ///     "func f
+    /// .entry:
+    ///     %r15 = const 5
+    ///     move %r15, %rbx
+    ///     move %rbx, %r15
+    ///     ret %r15
+    /// "
+    /// );
+    /// ```
+    pub fn promote_locals(&mut self) {
+        use Register::*;
+        let used: Vec<ValueRef> = (self.blocks.iter())
+            .flat_map(|block| &block.instructions)
+            .flat_map(|instruction| instruction.operands())
+            .collect();
+        let mut free = [Rbx, R12, R13, R14, R15]
+            .iter()
+            .copied()
+            .filter(|&reg| !used.contains(&ValueRef::Register(reg)));
+        let mut promoted = false;
+        for i in 0..self.blocks.len() {
+            let slots: Vec<(usize, usize)> = (self.blocks[i].instructions.iter())
+                .filter_map(|instruction| match *instruction {
+                    Instruction::Alloc { size, offset } => Some((offset, size)),
+                    _ => None,
+                })
+                .collect();
+            for (offset, size) in slots {
+                if !self.is_promotable(i, offset, size) {
+                    continue;
+                }
+                let reg = match free.next() {
+                    Some(reg) => ValueRef::Register(reg),
+                    None => break,
+                };
+                let slot = ValueRef::Memory(offset);
+                for instruction in &mut self.blocks[i].instructions {
+                    match *instruction {
+                        Instruction::Store { value, storage, .. } if storage == slot => {
+                            *instruction = Instruction::Move {
+                                storage: reg,
+                                source: value,
+                            };
+                        }
+                        Instruction::Load {
+                            storage, source, ..
+                        } if source == slot => {
+                            *instruction = Instruction::Move {
+                                storage,
+                                source: reg,
+                            };
+                        }
+                        _ => {}
+                    }
+                }
+                promoted = true;
+            }
+        }
+        if promoted {
+            self.remove_unused_slots();
+        }
+    }
+
+    /// Whether the memory allocated at `offset` by the block at index `block` can be kept
+    /// in a register, see `promote_locals`.
+    fn is_promotable(&self, block: usize, offset: usize, size: usize) -> bool {
+        let in_slot = |operand| matches!(operand, ValueRef::Memory(other) if offset - size < other && other <= offset);
+        let slot = ValueRef::Memory(offset);
+        let mut accessed = false;
+        for (i, other) in self.blocks.iter().enumerate() {
+            for instruction in &other.instructions {
+                if let Instruction::SaveContext { .. } | Instruction::RestoreContext { .. } =
+                    instruction
+                {
+                    if i == block {
+                        return false;
+                    }
+                }
+                if !instruction.operands().into_iter().any(in_slot) {
+                    continue;
+                }
+                let plain = match *instruction {
+                    Instruction::Store {
+                        value: ValueRef::Register(_) | ValueRef::Immediate(_),
+                        storage,
+                        volatile: false,
+                        width: Width::Qword,
+                    } => storage == slot,
+                    Instruction::Load {
+                        storage: storage @ (ValueRef::Register(_) | ValueRef::Memory(_)),
+                        source,
+                        volatile: false,
+                    } => source == slot && !in_slot(storage),
+                    _ => false,
+                };
+                if i != block || !plain {
+                    return false;
+                }
+                accessed = true;
+            }
+        }
+        accessed
+    }
+
+    /// The callee-saved registers used by this function, which it has to restore before
+    /// returning to its caller as the System V ABI requires. Functions that never return,
+    /// like the entry point, have nothing to restore them for.
+    pub(crate) fn saved_registers(&self) -> Vec<Register> {
+        use Register::*;
+        if self.noreturn || self.name == "_start" {
+            return vec![];
+        }
+        let used: Vec<ValueRef> = self
+            .blocks
+            .iter()
+            .flat_map(|block| &block.instructions)
+            .flat_map(|instruction| instruction.operands())
+            .collect();
+        [Rbx, R12, R13, R14, R15]
+            .iter()
+            .copied()
+            .filter(|&reg| used.contains(&ValueRef::Register(reg)))
+            .collect()
+    }
+
+    /// The size of the stack memory reserved for this function in its prologue. Offsets
+    /// of stack memory are relative to the frame, so the frame has to reach down to the
+    /// end of the memory allocated at the largest offset by any of the blocks.
+    pub(crate) fn frame_size(&self) -> usize {
+        self.blocks
+            .iter()
+            .flat_map(|block| &block.instructions)
+            .filter_map(|instruction| match *instruction {
+                Instruction::Alloc { offset, .. } => Some(offset),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Enable or disable protecting the stack memory of this function against buffer
+    /// overflows, like `-fstack-protector`. If the function allocates stack memory, a
+    /// canary read from `fs:0x28` is placed between that memory and the saved frame pointer
+    /// and checked before returning; `__stack_chk_fail` is called if it was overwritten.
+    ///
+    /// The canary and `__stack_chk_fail` are provided by libc, so this only works in
+    /// programs linked against it.
+    pub fn with_stack_protector(mut self, stack_protector: bool) -> Self {
+        self.stack_protector = stack_protector;
+        self
+    }
+
+    /// Split the block named `block_name` in two at the given instruction index with
+    /// `Block::split_off`, inserting the new block with the given name directly after it.
+    /// If the block fell through into the next one, the new block does so instead.
+    ///
+    /// Panics if this function has no block with the given name.
+    ///
+    /// ```
+    /// use fig::ir::Function;
+    ///
+    /// let mut func = Function::new("_start".into());
+    /// let mut entry = func.new_block(".entry".into());
+    /// let mut exit = func.new_block(".exit".into());
+    /// let value = entry.build_constant(40);
+    /// let two = entry.build_constant(2);
+    /// let value = entry.build_add(value, two);
+    /// let cond = entry.build_constant(0);
+    /// entry.build_jump_if_zero(cond, ".exit".into());
+    /// exit.build_exit(value);
+    /// func.append_block(entry);
+    /// func.append_block(exit);
+    ///
+    /// func.split_block(".entry", 2, ".add".into());
+    /// assert_eq!(func.successors(".entry"), [".add"]);
+    /// assert_eq!(func.successors(".add"), [".exit"]);
+    /// assert_eq!(func.validate(), Ok(()));
+    /// ```
+    pub fn split_block(&mut self, block_name: &str, at: usize, name: String) {
+        let index = self
+            .blocks
+            .iter()
+            .position(|block| block.name == block_name)
+            .unwrap_or_else(|| panic!("no block named {} in {}", block_name, self.name));
+        let tail = self.blocks[index].split_off(at, name);
+        self.blocks.insert(index + 1, tail);
+    }
+
+    /// Append a block to this function.
+    ///
+    /// A block not created by `new_block` allocated its stack memory on its own, at the
+    /// same offsets as the memory of other blocks. That memory is moved below the memory
+    /// allocated by this function so far, keeping the blocks from overwriting each other's
+    /// values. Operands referring to memory beyond what the block allocated itself are
+    /// left alone, they can only be memory of other blocks.
+    ///
+    /// ```
+    /// use fig::ir::{Block, Function, ValueRef};
+    ///
+    /// let mut func = Function::new("_start".into());
+    /// let mut entry = func.new_block(".entry".into());
+    /// let slot = entry.build_alloc(8);
+    /// let mut other = Block::new(".other".into());
+    /// assert_eq!(other.build_alloc(8), slot);
+    /// func.append_block(entry);
+    /// func.append_block(other);
+    /// assert!(func.to_string().contains("[rbp-32] = alloc 16"));
+    /// ```
+    pub fn append_block(&mut self, mut block: Block) {
+        if !block.stack.is_shared_with(&self.stack) {
+            block.rebase_stack(&self.stack);
+        }
+        self.blocks.push(block);
+    }
+
+    /// The names of the blocks control can pass to after the block with the given name, in
+    /// the order their jumps appear and each named once. A block that doesn't end with an
+    /// unconditional transfer of control also falls through into the block after it, like
+    /// after a final `JumpIfZero`. Blocks ending with `Exit` or `Return` have none, and so
+    /// do jumps to computed addresses, whose targets aren't known.
+    ///
+    /// Panics if this function has no block with the given name.
+    ///
+    /// ```
+    /// use fig::ir::Function;
+    ///
+    /// let mut func = Function::new("_start".into());
+    /// let mut r#loop = func.new_block(".loop".into());
+    /// let mut end = func.new_block(".end".into());
+    /// let counter = r#loop.build_constant(3);
+    /// r#loop.builder().jump_if_zero(counter, ".end".into()).jump(".loop".into());
+    /// let exit_code = end.build_constant(0);
+    /// end.build_exit(exit_code);
+    /// func.append_block(r#loop);
+    /// func.append_block(end);
+    ///
+    /// assert_eq!(func.successors(".loop"), [".end", ".loop"]);
+    /// assert_eq!(func.predecessors(".end"), [".loop"]);
+    /// assert!(func.successors(".end").is_empty());
+    /// assert!(func
+    ///     .to_dot()
+    ///     .contains("\".loop\" -> \".end\" [style=dashed];"));
+    /// ```
+    pub fn successors(&self, block_name: &str) -> Vec<&str> {
+        let index = self
+            .blocks
+            .iter()
+            .position(|block| block.name == block_name)
+            .unwrap_or_else(|| panic!("no block named {} in {}", block_name, self.name));
+        let block = &self.blocks[index];
+        let fall_through = self
+            .blocks
+            .get(index + 1)
+            .filter(|_| block.falls_through())
+            .map(|next| next.name.as_str());
+        let mut successors: Vec<&str> = vec![];
+        for dest in block.jump_targets().into_iter().chain(fall_through) {
+            if !successors.contains(&dest) {
+                successors.push(dest);
+            }
+        }
+        successors
+    }
+
+    /// The names of the blocks control can pass from to the block with the given name, in
+    /// the order of the blocks. See `successors`.
+    pub fn predecessors(&self, block_name: &str) -> Vec<&str> {
+        self.blocks
+            .iter()
+            .map(|block| block.name.as_str())
+            .filter(|&name| self.successors(name).contains(&block_name))
+            .collect()
+    }
+
+    /// Render the control-flow graph of this function in the Graphviz DOT format, with a
+    /// node labeled with its name for every block and an edge for every jump and
+    /// fall-through between them. Edges taken only if a condition holds, those of
+    /// conditional jumps and `Switch` cases, are dashed.
+    pub fn to_dot(&self) -> String {
+        let quote = |name: &str| format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""));
+        let mut dot = format!("digraph {} {{\n", quote(&self.name));
+        for block in &self.blocks {
+            dot += &format!("    {0} [label={0}];\n", quote(&block.name));
+        }
+        for (i, block) in self.blocks.iter().enumerate() {
+            // Each target once, with the kind of the first jump to it.
+            let mut edges: Vec<(&str, bool)> = vec![];
+            for instruction in &block.instructions {
+                let (targets, conditional) = match instruction {
+                    Instruction::Jump { dest } => (vec![dest.as_str()], false),
+                    Instruction::JumpIfZero { dest, .. }
+                    | Instruction::JumpIfNotZero { dest, .. } => (vec![dest.as_str()], true),
+                    Instruction::Switch { cases, default, .. } => (
+                        cases
+                            .iter()
+                            .map(|(_, dest)| dest.as_str())
+                            .chain(Some(default.as_str()))
+                            .collect(),
+                        true,
+                    ),
+                    _ => continue,
+                };
+                for dest in targets {
+                    if edges.iter().all(|&(edge, _)| edge != dest) {
+                        edges.push((dest, conditional));
+                    }
+                }
+            }
+            if let Some(next) = self.blocks.get(i + 1).filter(|_| block.falls_through()) {
+                if edges.iter().all(|&(edge, _)| edge != next.name) {
+                    edges.push((&next.name, false));
+                }
+            }
+            for (dest, conditional) in edges {
+                let style = if conditional { " [style=dashed]" } else { "" };
+                dot += &format!("    {} -> {}{};\n", quote(&block.name), quote(dest), style);
+            }
+        }
+        dot += "}\n";
+        dot
+    }
+
+    /// Split every critical edge, one from a block with several successors to a block with
+    /// several predecessors, by inserting an empty block on it that only jumps on to the
+    /// original successor. Code which has to run on that edge alone, like the moves of an
+    /// out-of-SSA translation, can then be placed in the new block. The new block directly
+    /// follows the source of the edge, which jumps explicitly where it fell through before.
+    ///
+    /// ```
+    /// use fig::ir::Function;
+    ///
+    /// let mut func = Function::new("f".into());
+    /// let mut entry = func.new_block(".entry".into());
+    /// let mut then = func.new_block(".then".into());
+    /// let join = func.new_block(".join".into());
+    /// let cond = entry.build_constant(0);
+    /// entry.build_jump_if_zero(cond, ".join".into());
+    /// then.build_jump(".join".into());
+    /// func.append_block(entry);
+    /// func.append_block(then);
+    /// func.append_block(join);
+    ///
+    /// func.split_critical_edges();
+    /// assert_eq!(func.successors(".entry"), [".entry.join", ".then"]);
+    /// assert_eq!(func.predecessors(".join"), [".entry.join", ".then"]);
+    /// ```
+    pub fn split_critical_edges(&mut self) {
+        let mut edges: Vec<(String, String)> = vec![];
+        for block in &self.blocks {
+            let successors = self.successors(&block.name);
+            if successors.len() < 2 {
+                continue;
+            }
+            for dest in successors {
+                if self.predecessors(dest).len() > 1 {
+                    edges.push((block.name.clone(), dest.to_string()));
+                }
+            }
+        }
+        for (from, to) in edges {
+            let mut name = format!("{}.{}", from, to.trim_start_matches('.'));
+            while self.blocks.iter().any(|block| block.name == name) {
+                name.push('_');
+            }
+            let index = self
+                .blocks
+                .iter()
+                .position(|block| block.name == from)
+                .unwrap();
+            let fall_through = self
+                .blocks
+                .get(index + 1)
+                .filter(|_| self.blocks[index].falls_through())
+                .map(|next| {
+                    if next.name == to {
+                        name.clone()
+                    } else {
+                        next.name.clone()
+                    }
+                });
+            let source = &mut self.blocks[index];
+            source.retarget(&to, &name);
+            if let Some(next) = fall_through {
+                source.build_jump(next);
+            }
+            let mut edge = self.new_block(name);
+            edge.build_jump(to);
+            self.blocks.insert(index + 1, edge);
+        }
+    }
+
+    /// Reorder the blocks so that the target of a block's final `Jump` directly follows it
+    /// where possible, turning the jump into a fall-through that is omitted from the
+    /// generated code. The first block stays first and blocks that fall through into their
+    /// successor are kept together.
+    pub fn layout_blocks(&mut self) {
+        let chains = self.fall_through_chains();
+        let mut placed = vec![false; chains.len()];
+        let mut order = Vec::with_capacity(self.blocks.len());
+        let mut next = Some(0);
+        while let Some(current) = next {
+            placed[current] = true;
+            order.extend(&chains[current]);
+
+            // Continue with the chain the current one jumps to, or else with the first
+            // chain that hasn't been placed yet.
+            let target = self.blocks[*chains[current].last().unwrap()].jump_target();
+            next = chains
+                .iter()
+                .enumerate()
+                .position(|(i, chain)| {
+                    !placed[i] && Some(self.blocks[chain[0]].name.as_str()) == target
+                })
+                .or_else(|| placed.iter().position(|placed| !placed));
+        }
+        self.permute_blocks(order);
+    }
+
+    /// Reorder the blocks in reverse postorder of the control-flow graph starting at the
+    /// first block, which stays first. This makes the layout independent of the order in
+    /// which blocks were appended. Blocks that fall through into their successor are kept
+    /// together and unreachable blocks are moved to the end in their original order.
+    pub fn reorder_rpo(&mut self) {
+        let chains = self.fall_through_chains();
+        if chains.is_empty() {
+            return;
+        }
+
+        fn visit(
+            current: usize,
+            blocks: &[Block],
+            chains: &[Vec<usize>],
+            visited: &mut [bool],
+            postorder: &mut Vec<usize>,
+        ) {
+            visited[current] = true;
+            for &block in &chains[current] {
+                for target in blocks[block].jump_targets() {
+                    let successor = chains
+                        .iter()
+                        .position(|chain| blocks[chain[0]].name == target);
+                    if let Some(successor) = successor {
+                        if !visited[successor] {
+                            visit(successor, blocks, chains, visited, postorder);
+                        }
+                    }
+                }
+            }
+            postorder.push(current);
+        }
+
+        let mut visited = vec![false; chains.len()];
+        let mut order = vec![];
+        visit(0, &self.blocks, &chains, &mut visited, &mut order);
+        order.reverse();
+        order.extend((0..chains.len()).filter(|&i| !visited[i]));
+
+        let order: Vec<usize> = order
+            .into_iter()
+            .flat_map(|i| chains[i].iter().copied())
+            .collect();
+        self.permute_blocks(order);
+    }
+
+    /// Split the blocks into chains of their indices which have to stay in order because
+    /// each block falls through into the next one.
+    fn fall_through_chains(&self) -> Vec<Vec<usize>> {
+        let mut chains: Vec<Vec<usize>> = vec![];
+        for i in 0..self.blocks.len() {
+            match chains.last_mut() {
+                Some(chain) if self.blocks[*chain.last().unwrap()].falls_through() => chain.push(i),
+                _ => chains.push(vec![i]),
+            }
+        }
+        chains
+    }
+
+    /// Rearrange the blocks so that the block at `order[i]` becomes the `i`th one.
+    fn permute_blocks(&mut self, order: Vec<usize>) {
+        let mut blocks: Vec<Option<Block>> = self.blocks.drain(..).map(Some).collect();
+        self.blocks = order
+            .into_iter()
+            .map(|i| blocks[i].take().expect("block placed twice"))
+            .collect();
+    }
+
+    /// Generate native code for this function.
+    ///
+    /// The callee-saved registers RBX and R12-R15 the function uses are pushed in its
+    /// prologue and popped again before it returns, unless it never returns:
+    ///
+    /// ```
+    /// use fig::ir::{Function, Register};
+    ///
+    /// let mut func = Function::new("answer".into());
+    /// let mut entry = func.new_block(".entry".into());
+    /// let value = entry.build_constant(42);
+    /// let value = entry.build_move_to_register(value, Register::Rbx);
+    /// entry.build_return(Some(value));
+    /// func.append_block(entry);
+    ///
+    /// let code = func.generate_string().unwrap();
+    /// let push = code.find("push rbx").unwrap();
+    /// let body = code.find("mov rbx, ").unwrap();
+    /// let pop = code.find("pop rbx").unwrap();
+    /// assert!(push < body && body < pop);
+    /// ```
+    pub fn generate_code(&self, w: &mut impl Write) -> std::io::Result<()> {
+        self.generate_code_with(w, &CodegenOptions::default())
+    }
+
+    /// Generate native code like `generate_code` and return it as a string.
+    pub fn generate_string(&self) -> std::io::Result<String> {
+        generate_string(|w| self.generate_code(w))
+    }
+
+    /// Generate native code for this function using the given module settings.
+    fn generate_code_with(
+        &self,
+        w: &mut impl Write,
+        options: &CodegenOptions,
+    ) -> std::io::Result<()> {
+        // A jump to an unknown block would only fail much later when linking, and a freed
+        // register would silently hold whatever was put in it since.
+        self.check_jump_targets()
+            .and_then(|()| self.check_register_uses())
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        if options.target == Target::Aarch64 {
+            return self.generate_aarch64(w, options);
+        }
+        // Leaf functions that never touch the stack don't need a frame at all.
+        let kind = if !self.blocks.iter().any(|block| block.needs_frame()) {
+            FrameKind::None
+        } else if self.stack_protector && self.blocks.iter().any(|block| block.allocates()) {
+            FrameKind::Protected {
+                fail_label: format!("..@{}.stack_chk_fail", self.name),
+            }
+        } else {
+            FrameKind::Standard
+        };
+        let frame = Frame {
+            kind,
+            saved: self.saved_registers(),
+        };
+        // Any block might be the target of an indirect jump within this function.
+        let indirect_targets =
+            options.cet && self.blocks.iter().any(|block| block.has_indirect_jump());
+
+        if options.symbol_info {
+            writeln!(
+                w,
+                "global {0}:function ({1} - {0})",
+                self.name,
+                self.end_label()
+            )?;
+        } else {
+            writeln!(w, "global {}", self.name)?;
+        }
+        writeln!(w, "{}:", self.name)?;
+        if options.cet {
+            writeln!(w, "\tendbr64")?;
+        }
+        // Saved above the frame so they don't move the stack memory relative to RBP.
+        for reg in &frame.saved {
+            writeln!(w, "\tpush {}", reg.name())?;
+        }
+        match frame.kind {
+            FrameKind::None => {}
+            FrameKind::Standard => {
+                writeln!(w, "\tpush rbp")?;
+                writeln!(w, "\tmov rbp, rsp")?;
+            }
+            FrameKind::Protected { .. } => {
+                // The canary sits between the saved RBP and the stack memory of the
+                // blocks, RBP points to it. It is padded to 16 bytes to keep the stack
+                // aligned. R11 is neither an argument register nor callee-saved so it is
+                // free to use here.
+                writeln!(w, "\tpush rbp")?;
+                writeln!(w, "\tsub rsp, 16")?;
+                writeln!(w, "\tmov rbp, rsp")?;
+                writeln!(w, "\tmov r11, [fs:0x28]")?;
+                writeln!(w, "\tmov [rbp], r11")?;
+            }
+        }
+        let mut frame_size = self.frame_size();
+        // The entry point is jumped to with an aligned stack instead of being called, so
+        // without a return address on the stack it is misaligned by 8 bytes here. So is
+        // any other function that saved an odd number of registers.
+        let misaligned = self.name == "_start" || frame.saved.len() % 2 == 1;
+        if misaligned && !matches!(frame.kind, FrameKind::None) {
+            frame_size += 8;
+        }
+        if frame_size > 0 {
+            writeln!(w, "\tsub rsp, {}", frame_size)?;
+        }
+        for (i, block) in self.blocks.iter().enumerate() {
+            let next = self.blocks.get(i + 1).map(|block| block.name.as_str());
+            block.generate_code(w, &self.name, options, next, indirect_targets, &frame)?;
+        }
+        // The epilogue is only reachable if the last block falls through.
+        let falls_off_end = self.blocks.last().is_none_or(|block| block.falls_through());
+        if !self.noreturn && falls_off_end {
+            frame.write_teardown(w)?;
+            writeln!(w, "\tret")?;
+        }
+        if let FrameKind::Protected { ref fail_label } = frame.kind {
+            writeln!(w, "{}:", fail_label)?;
+            if options.pic {
+                writeln!(w, "\tcall {} wrt ..plt", STACK_CHK_FAIL)?;
+            } else {
+                writeln!(w, "\tcall {}", STACK_CHK_FAIL)?;
+            }
+        }
+        if options.symbol_info {
+            writeln!(w, "{}:", self.end_label())?;
+        }
+        Ok(())
+    }
+
+    /// Label marking the end of this function's code. The `..@` prefix keeps it from
+    /// clashing with block labels and from starting a new scope for local labels.
+    fn end_label(&self) -> String {
+        format!("..@{}.end", self.name)
+    }
+}
+
+/// A block is a set of named set of instructions.
+#[derive(Debug)]
+pub struct Block {
+    /// The name will be used as a label in the resulting native code.
+    pub name: String,
+    /// List of instructions belonging to this block.
+    pub(crate) instructions: Vec<Instruction>,
+    /// Comments attached to the instruction at the given index. A comment attaches to the
+    /// instruction following it and moves with that instruction. Comments at the end of
+    /// the block have no instruction and are never emitted.
+    comments: Vec<(usize, String)>,
+    /// Register operands which weren't allocated when the instruction at the given index
+    /// was built, reported by `Function::validate`.
+    stale_operands: Vec<(usize, ValueRef)>,
+    /// Register allocator for code generation.
+    registers: RegisterAlloc,
+    /// Stack memory allocator for code generation.
+    stack: StackAlloc,
+}
+
+impl Block {
+    /// Create a new empty block with the given name. It has registers and stack memory of
+    /// its own, use `Function::new_block` for blocks sharing values in registers or stack
+    /// memory.
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            instructions: vec![],
+            comments: vec![],
+            stale_operands: vec![],
+            registers: RegisterAlloc::new(),
+            stack: StackAlloc::default(),
+        }
+    }
+
+    /// Fold binary operations whose operands are both set by `Constant` instructions into
+    /// a single `Constant` with the result, so it is computed at compile time. Constants
+    /// only used by a folded operation are removed. Operations faulting at runtime, like a
+    /// division by zero, are left untouched.
+    ///
+    /// A `Load` from stack memory a constant was stored in earlier in the block becomes a
+    /// `Constant` too, unless something in between may have changed the memory: an
+    /// instruction using overlapping memory, a fence, or one writing memory it doesn't
+    /// name as operand like `SaveContext`. Nothing else can write the stack slots of a
+    /// function since their address is never taken. Acquire and volatile loads are never
+    /// replaced.
+    ///
+    /// ```
+    /// use fig::ir::Block;
+    ///
+    /// let mut block = Block::new(".entry".into());
+    /// let slot = block.build_alloc(8);
+    /// let five = block.build_constant(5);
+    /// block.build_store(five, slot);
+    /// let value = block.build_load(slot);
+    /// let three = block.build_constant(3);
+    /// let sum = block.build_add(value, three);
+    /// block.build_exit(sum);
+    ///
+    /// block.optimize_constants();
+    /// assert_eq!(
+    ///     block.to_string(),
+    ///     ".entry:
+    ///     [rbp-16] = alloc 16
+    ///     %r15 = const 5
+    ///     store %r15, [rbp-16]
+    ///     %r15 = const 8
+    ///     exit %r15
+    /// "
+    /// );
+    /// ```
+    pub fn optimize_constants(&mut self) {
+        // Values set by a `Constant` and not used since, with the index of the `Constant`.
+        let mut known: HashMap<ValueRef, (Value, usize)> = HashMap::new();
+        // Constants stored in stack memory by the offset of the memory.
+        let mut stored: HashMap<usize, Value> = HashMap::new();
+        let mut removed = vec![];
+        for i in 0..self.instructions.len() {
+            let instruction = &self.instructions[i];
+            if let Instruction::Constant { storage, value, .. } = *instruction {
+                known.insert(storage, (value, i));
+                continue;
+            }
+            match *instruction {
+                Instruction::Store {
+                    value,
+                    storage: ValueRef::Memory(offset),
+                    volatile: false,
+                    width: Width::Qword,
+                } => {
+                    stored.retain(|&other, _| other.abs_diff(offset) >= 8);
+                    let value = match value {
+                        ValueRef::Immediate(value) => Some(Value::from(value)),
+                        _ => known.get(&value).map(|&(value, _)| value),
+                    };
+                    if let Some(value) = value {
+                        stored.insert(offset, value);
+                    }
+                }
+                Instruction::Load {
+                    storage,
+                    source: ValueRef::Memory(offset),
+                    volatile: false,
+                } => {
+                    // A load followed by a fence is an acquire load, which has to read
+                    // the memory in case another thread wrote it.
+                    let acquire = matches!(
+                        self.instructions.get(i + 1),
+                        Some(Instruction::Fence { .. })
+                    );
+                    if let (Some(&value), false) = (stored.get(&offset), acquire) {
+                        self.instructions[i] = Instruction::Constant {
+                            storage,
+                            value,
+                            width: Width::Qword,
+                        };
+                        known.insert(storage, (value, i));
+                        continue;
+                    }
+                }
+                Instruction::Fence { .. }
+                | Instruction::SaveContext { .. }
+                | Instruction::RestoreContext { .. }
+                | Instruction::SelectIndex { .. } => stored.clear(),
+                _ => {
+                    for operand in instruction.operands() {
+                        if let ValueRef::Memory(offset) = operand {
+                            stored.retain(|&other, _| other.abs_diff(offset) >= 8);
+                        }
+                    }
+                }
+            }
+            let folded = match instruction.operands()[..] {
+                [left, right] if left != right => match (known.get(&left), known.get(&right)) {
+                    (Some(&(l, left_index)), Some(&(r, right_index))) => instruction
+                        .fold(l, r)
+                        .map(|value| (left, right, value, left_index, right_index)),
+                    _ => None,
+                },
+                _ => None,
+            };
+            match folded {
+                Some((left, right, value, left_index, right_index)) => {
+                    removed.push(left_index);
+                    known.remove(&right);
+                    // Memory isn't freed by consuming it, so it may still be read later.
+                    if let ValueRef::Register(_) = right {
+                        removed.push(right_index);
+                    }
+                    self.instructions[i] = Instruction::Constant {
+                        storage: left,
+                        value,
+                        width: self.instructions[i].width(),
+                    };
+                    known.insert(left, (value, i));
+                }
+                None => {
+                    // Any other instruction might modify its operands, and calls and resume
+                    // points of contexts may change registers behind our back.
+                    if let Instruction::Call { .. } | Instruction::SaveContext { .. } = instruction
+                    {
+                        known.clear();
+                    }
+                    for operand in instruction.operands() {
+                        known.remove(&operand);
+                    }
+                }
+            }
+        }
+        self.remove_instructions(&removed);
+    }
+
+    /// Remove instructions that only compute a value in a register which is never used.
+    /// If the block shares its registers with other blocks, like those created by the same
+    /// function, registers still allocated at the end of the block count as used because
+    /// those blocks may use them. All stack memory counts as used. Calls, stores, volatile
+    /// loads and other instructions with side effects are always kept.
+    pub fn eliminate_dead_code(&mut self) {
+        let mut live = if self.registers.is_shared() {
+            self.registers.allocated()
+        } else {
+            vec![]
+        };
+        let mut removed = vec![];
+        for (i, instruction) in self.instructions.iter().enumerate().rev() {
+            let pure = instruction.pure_output();
+            if let Some((ValueRef::Register(reg), _)) = pure {
+                if !live.contains(&reg) {
+                    removed.push(i);
+                    continue;
+                }
+            }
+            // A value written without being read is dead before the instruction.
+            let written = match (pure, instruction) {
+                (Some((output, false)), _) => Some(output),
+                (None, Instruction::Call { result, .. }) => *result,
+                _ => None,
+            };
+            for operand in instruction.operands() {
+                if let ValueRef::Register(reg) = operand {
+                    if written == Some(operand) {
+                        live.retain(|&other| other != reg);
+                    } else if !live.contains(&reg) {
+                        live.push(reg);
+                    }
+                }
+            }
+        }
+        removed.reverse();
+        self.remove_instructions(&removed);
+    }
+
+    /// Remove redundant moves and fold constants into the instructions using them:
+    /// - A `Store`, `Move` or `Load` whose source and destination are the same is removed.
+    /// - A copy from `a` to `b` right after a copy from `b` to `a` is removed, since `b`
+    ///   already holds the value. One of them has to be a register.
+    /// - A `Constant` in a register that is next used as the right operand of an `Add`,
+    ///   `Subtract`, `And`, `Or` or `Xor`, or as the value of a `Store`, is removed and
+    ///   becomes an immediate of that instruction if it fits into 32 bits. These consume
+    ///   the register, so it is the only use of the constant.
+    ///
+    /// Volatile loads and stores and 32-bit stores are never removed.
+    ///
+    /// ```
+    /// use fig::ir::Block;
+    ///
+    /// let mut block = Block::new(".entry".into());
+    /// let counter = block.build_alloc(8);
+    /// let ten = block.build_constant(10);
+    /// block.build_store(ten, counter);
+    /// let value = block.build_load(counter);
+    /// block.build_store(value, counter);
+    /// block.build_move(counter, counter);
+    /// let one = block.build_constant(1);
+    /// block.build_subtract(counter, one);
+    /// let exit_code = block.build_load(counter);
+    /// block.build_exit(exit_code);
+    ///
+    /// block.optimize_peephole();
+    /// assert_eq!(
+    ///     block.to_string(),
+    ///     ".entry:
+    ///     [rbp-16] = alloc 16
+    ///     store 10, [rbp-16]
+    ///     %r15 = load [rbp-16]
+    ///     sub [rbp-16], 1
+    ///     %r15 = load [rbp-16]
+    ///     exit %r15
+    /// "
+    /// );
+    /// ```
+    pub fn optimize_peephole(&mut self) {
+        let mut removed = vec![];
+        for i in 0..self.instructions.len() {
+            if let Instruction::Constant {
+                storage: ValueRef::Register(reg),
+                value,
+                width,
+            } = self.instructions[i]
+            {
+                let immediate = match i32::try_from(width.truncate(value)) {
+                    Ok(immediate) => ValueRef::Immediate(immediate),
+                    Err(_) => continue,
+                };
+                let storage = ValueRef::Register(reg);
+                let user = (i + 1..self.instructions.len())
+                    .find(|&j| self.instructions[j].operands().contains(&storage));
+                let user = match user {
+                    Some(user) => user,
+                    None => continue,
+                };
+                use Instruction::*;
+                let operand = match &mut self.instructions[user] {
+                    Add { left, right, .. }
+                    | Subtract { left, right, .. }
+                    | And { left, right }
+                    | Or { left, right }
+                    | Xor { left, right }
+                        if *left != storage =>
+                    {
+                        right
+                    }
+                    Store {
+                        value,
+                        storage:
+                            ValueRef::Register(_)
+                            | ValueRef::Memory(_)
+                            | ValueRef::Global(_)
+                            | ValueRef::ThreadLocal(_),
+                        ..
+                    } => value,
+                    _ => continue,
+                };
+                if *operand == storage {
+                    *operand = immediate;
+                    removed.push(i);
+                }
+            }
+        }
+
+        // The source and destination of the last instruction kept if it copies a value.
+        let mut previous = None;
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            if removed.contains(&i) {
+                continue;
+            }
+            let copy = instruction.copy();
+            if let Some((storage, source)) = copy {
+                let register = !storage.in_memory() || !source.in_memory();
+                if storage == source || (register && previous == Some((source, storage))) {
+                    removed.push(i);
+                    continue;
+                }
+            }
+            previous = copy;
+        }
+        removed.sort_unstable();
+        self.remove_instructions(&removed);
+    }
+
+    /// Remove the instructions at the given indices together with the comments attached to
+    /// them, which would describe the wrong instruction otherwise.
+    fn remove_instructions(&mut self, indices: &[usize]) {
+        if indices.is_empty() {
+            return;
+        }
+        let mut index = 0;
+        self.instructions.retain(|_| {
+            let keep = !indices.contains(&index);
+            index += 1;
+            keep
+        });
+        self.comments.retain(|(at, _)| !indices.contains(at));
+        for (at, _) in &mut self.comments {
+            *at -= indices.iter().filter(|&&removed| removed < *at).count();
+        }
+        for (at, _) in &mut self.stale_operands {
+            *at -= indices.iter().filter(|&&removed| removed < *at).count();
+        }
+    }
+
+    /// Split this block in two at the given instruction index.
+    /// The instructions from `at` on are moved into a new block with the given name, which
+    /// is returned, and this block jumps to it. The new block continues with the register
+    /// and stack allocation state of this one, so values can still be used in it.
+    /// The caller is responsible for adding the new block to the function right after this
+    /// one if it falls through, `Function::split_block` does both.
+    pub fn split_off(&mut self, at: usize, name: String) -> Block {
+        let instructions = self.instructions.split_off(at);
+        let (comments, tail_comments): (Vec<_>, Vec<_>) =
+            self.comments.drain(..).partition(|(i, _)| *i < at);
+        self.comments = comments;
+        let (stale_operands, tail_stale_operands): (Vec<_>, Vec<_>) =
+            self.stale_operands.drain(..).partition(|(i, _)| *i < at);
+        self.stale_operands = stale_operands;
+
+        let tail = Block {
+            name,
+            instructions,
+            comments: tail_comments
+                .into_iter()
+                .map(|(i, comment)| (i - at, comment))
+                .collect(),
+            stale_operands: tail_stale_operands
+                .into_iter()
+                .map(|(i, value)| (i - at, value))
+                .collect(),
+            registers: self.registers.clone(),
+            stack: self.stack.clone(),
+        };
+        self.build_jump(tail.name.clone());
+        tail
+    }
+
+    /// Move the stack memory allocated by this block below the memory allocated by `stack`
+    /// so far and allocate from `stack` from now on, see `Function::append_block`.
+    fn rebase_stack(&mut self, stack: &StackAlloc) {
+        let size = self.stack.size();
+        self.stack = stack.clone();
+        if size == 0 {
+            return;
+        }
+        let base = self.stack.alloc(size) - size;
+        let rebase = |operand| match operand {
+            ValueRef::Memory(offset) if offset <= size => ValueRef::Memory(base + offset),
+            operand => operand,
+        };
+        for instruction in &mut self.instructions {
+            if let Instruction::Alloc { offset, .. } = instruction {
+                *offset += base;
+            }
+            instruction.map_operands(rebase);
+        }
+        for (_, operand) in &mut self.stale_operands {
+            *operand = rebase(*operand);
+        }
+    }
+
+    /// Make all jumps of this block to the block named `from` jump to `to` instead.
+    fn retarget(&mut self, from: &str, to: &str) {
+        for instruction in &mut self.instructions {
+            let dests = match instruction {
+                Instruction::Jump { dest }
+                | Instruction::JumpIfZero { dest, .. }
+                | Instruction::JumpIfNotZero { dest, .. } => vec![dest],
+                Instruction::Switch { cases, default, .. } => cases
+                    .iter_mut()
+                    .map(|(_, dest)| dest)
+                    .chain(Some(default))
+                    .collect(),
+                _ => continue,
+            };
+            for dest in dests {
+                if dest == from {
+                    *dest = to.to_string();
+                }
+            }
+        }
+    }
+
+    /// Whether this block allocates stack memory or calls other functions and thus
+    /// requires the enclosing function to set up a frame.
+    fn needs_frame(&self) -> bool {
+        self.instructions.iter().any(|instruction| {
+            matches!(
+                instruction,
+                Instruction::Alloc { .. } | Instruction::Call { .. }
+            )
+        })
+    }
+
+    /// Whether this block allocates stack memory.
+    fn allocates(&self) -> bool {
+        self.instructions
+            .iter()
+            .any(|instruction| matches!(instruction, Instruction::Alloc { .. }))
+    }
+
+    /// Whether this block contains an indirect jump, including one through a jump table.
+    fn has_indirect_jump(&self) -> bool {
+        self.instructions.iter().any(|instruction| {
+            matches!(
+                instruction,
+                Instruction::JumpIndirect { .. } | Instruction::Switch { table: Some(_), .. }
+            )
+        })
+    }
+
+    /// Whether execution continues with the following block after this block's last
+    /// instruction.
+    fn falls_through(&self) -> bool {
+        !matches!(
+            self.instructions.last(),
+            Some(Instruction::Jump { .. })
+                | Some(Instruction::JumpIndirect { .. })
+                | Some(Instruction::Switch { .. })
+                | Some(Instruction::TailCallIndirect { .. })
+                | Some(Instruction::RestoreContext { .. })
+                | Some(Instruction::Return { .. })
+                | Some(Instruction::Exit { .. })
+        )
+    }
+
+    /// The names of all functions called by this block, in order.
+    fn callees(&self) -> impl Iterator<Item = &str> {
+        self.instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Call { func, .. } => Some(func.as_str()),
+                _ => None,
+            })
+    }
+
+    /// The destinations of all jumps to other blocks in this block, in order.
+    fn jump_targets(&self) -> Vec<&str> {
+        self.instructions
+            .iter()
+            .flat_map(|instruction| match instruction {
+                Instruction::Jump { dest }
+                | Instruction::JumpIfZero { dest, .. }
+                | Instruction::JumpIfNotZero { dest, .. } => vec![dest.as_str()],
+                Instruction::Switch { cases, default, .. } => cases
+                    .iter()
+                    .map(|(_, dest)| dest.as_str())
+                    .chain(Some(default.as_str()))
+                    .collect(),
+                _ => vec![],
+            })
+            .collect()
+    }
+
+    /// Whether this block ends with an instruction transferring control elsewhere. A final
+    /// conditional jump counts, the block deliberately falls through if it isn't taken, and
+    /// so does a call to one of the functions named in `noreturn`, which never come back.
+    fn is_terminated(&self, noreturn: &[&str]) -> bool {
+        match self.instructions.last() {
+            Some(Instruction::JumpIfZero { .. }) | Some(Instruction::JumpIfNotZero { .. }) => true,
+            Some(Instruction::Call { func, .. }) => noreturn.contains(&func.as_str()),
+            _ => !self.falls_through(),
+        }
+    }
+
+    /// The destination of this block's final unconditional `Jump`, if any.
+    fn jump_target(&self) -> Option<&str> {
+        match self.instructions.last() {
+            Some(Instruction::Jump { dest }) => Some(dest),
+            _ => None,
+        }
+    }
+
+    /// Generate the native code for this block and write it to the given Writer.
+    /// `func` is the name of the enclosing function, which labels are prefixed with.
+    /// `next` is the name of the block emitted right after this one, a final jump to it is
+    /// omitted. `indirect_target` marks the block as a possible target of an indirect jump.
+    /// `frame` is the kind of frame the enclosing function has set up.
+    fn generate_code(
+        &self,
+        w: &mut impl Write,
+        func: &str,
+        options: &CodegenOptions,
+        next: Option<&str>,
+        indirect_target: bool,
+        frame: &Frame,
+    ) -> std::io::Result<()> {
+        use Instruction::*;
+
+        writeln!(w, "{}:", block_label(func, &self.name))?;
+        if indirect_target {
+            writeln!(w, "\tendbr64")?;
+        }
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            let is_last = i + 1 == self.instructions.len();
+            for (_, comment) in self.comments.iter().filter(|(at, _)| *at == i) {
+                for line in comment.lines() {
+                    writeln!(w, "\t; {}", line)?;
+                }
+            }
+            match *instruction {
+                Constant {
+                    storage,
+                    value,
+                    width,
+                } => {
+                    write_mov_immediate(w, storage, value, width)?;
+                }
+                // The memory is reserved in the prologue of the function.
+                Alloc { .. } => {}
+                Store {
+                    value,
+                    storage,
+                    width,
+                    ..
+                } => {
+                    write_sized_binary_op(w, "mov", storage, value, width)?;
+                }
+                Load {
+                    storage, source, ..
                 }
-                Add { left, right } => {
-                    writeln!(w, "\tadd {}, {}", left.code(), right.code())?;
+                | Move { storage, source } => {
+                    write_binary_op(w, "mov", storage, source)?;
                 }
-                Subtract { left, right } => {
-                    writeln!(w, "\tsub {}, {}", left.code(), right.code())?;
+                Fence {
+                    ordering: MemoryOrdering::SeqCst,
+                } => writeln!(w, "\tmfence")?,
+                Fence { .. } => {}
+                Add { left, right, width } => {
+                    write_sized_binary_op(w, "add", left, right, width)?;
                 }
-                Multiply { left, right } => {
-                    writeln!(w, "\timul {}, {}", left.code(), right.code())?;
+                Subtract { left, right, width } => {
+                    write_sized_binary_op(w, "sub", left, right, width)?;
                 }
+                FloatConstant { storage, value } => {
+                    let label = format!("..@{}.float{}", block_label(func, &self.name), i);
+                    match storage {
+                        ValueRef::Xmm(_) => {
+                            writeln!(w, "\tmovsd {}, [rel {}]", storage.code(), label)?;
+                        }
+                        _ => {
+                            writeln!(w, "\tmovsd xmm15, [rel {}]", label)?;
+                            writeln!(w, "\tmovq {}, xmm15", storage.code())?;
+                        }
+                    }
+                    writeln!(w, "section .rodata")?;
+                    writeln!(w, "align 8")?;
+                    writeln!(w, "{}:", label)?;
+                    writeln!(w, "\tdq 0x{:x}", value.to_bits())?;
+                    writeln!(w, "section .text")?;
+                }
+                FloatAdd { left, right } => write_float_op(w, "addsd", left, right)?,
+                FloatSubtract { left, right } => write_float_op(w, "subsd", left, right)?,
+                FloatMultiply { left, right } => write_float_op(w, "mulsd", left, right)?,
+                FloatDivide { left, right } => write_float_op(w, "divsd", left, right)?,
+                Multiply { left, right } => match left {
+                    ValueRef::Register(_) => {
+                        writeln!(w, "\timul {}, {}", left.code(), right.code())?;
+                    }
+                    _ => {
+                        // `imul` can only multiply into a register, so go through a
+                        // scratch register and write the result back.
+                        let scratch = scratch_register(&[right])?.name();
+                        writeln!(w, "\tpush {}", scratch)?;
+                        writeln!(w, "\tmov {}, {}", scratch, left.code())?;
+                        writeln!(w, "\timul {}, {}", scratch, right.code())?;
+                        writeln!(w, "\tmov {}, {}", left.code(), scratch)?;
+                        writeln!(w, "\tpop {}", scratch)?;
+                    }
+                },
                 Divide { left, right } => {
-                    writeln!(w, "\tpush rdx")?;
-                    writeln!(w, "\tmov rdx, 0")?;
-                    if left != ValueRef::Register(Register::Rax) {
-                        writeln!(w, "\tpush rax")?;
-                        writeln!(w, "\tmov rax, {}", left.code())?;
+                    write_division(w, left, right, Register::Rax, true)?;
+                }
+                Modulo { left, right } => {
+                    write_division(w, left, right, Register::Rdx, true)?;
+                }
+                UnsignedDivide { left, right } => {
+                    write_division(w, left, right, Register::Rax, false)?;
+                }
+                UnsignedModulo { left, right } => {
+                    write_division(w, left, right, Register::Rdx, false)?;
+                }
+                And { left, right } => {
+                    write_binary_op(w, "and", left, right)?;
+                }
+                Or { left, right } => {
+                    write_binary_op(w, "or", left, right)?;
+                }
+                Xor { left, right } => {
+                    write_binary_op(w, "xor", left, right)?;
+                }
+                TableLoad {
+                    storage,
+                    ref table,
+                    index,
+                    elem_size,
+                } => write_to_register(w, storage, &[index], |w, storage| {
+                    // The index has to live in a register to be usable as scaled index.
+                    let index = match index {
+                        ValueRef::Register(_) => index,
+                        _ => {
+                            writeln!(w, "\tmov {}, {}", storage.code(), index.code())?;
+                            storage
+                        }
+                    };
+                    let source = format!("[{} + {}*{}]", table, index.code(), elem_size);
+                    match elem_size {
+                        8 => writeln!(w, "\tmov {}, QWORD {}", storage.code(), source),
+                        4 => writeln!(w, "\tmovsxd {}, DWORD {}", storage.code(), source),
+                        2 => writeln!(w, "\tmovsx {}, WORD {}", storage.code(), source),
+                        _ => writeln!(w, "\tmovsx {}, BYTE {}", storage.code(), source),
+                    }
+                })?,
+                SelectIndex {
+                    storage,
+                    index,
+                    ref values,
+                    table: None,
+                } => {
+                    let operands: Vec<_> = values.iter().copied().chain(Some(index)).collect();
+                    write_to_register(w, storage, &operands, |w, storage| {
+                        writeln!(w, "\tmov {}, {}", storage.code(), values[0].code())?;
+                        for (i, value) in values.iter().enumerate().skip(1) {
+                            writeln!(w, "\tcmp {}, {}", index.sized_code(), i)?;
+                            writeln!(w, "\tcmove {}, {}", storage.code(), value.code())?;
+                        }
+                        Ok(())
+                    })?;
+                }
+                SelectIndex {
+                    storage,
+                    index,
+                    ref values,
+                    table: Some(ValueRef::Memory(table)),
+                } => {
+                    let operands: Vec<_> = values.iter().copied().chain(Some(index)).collect();
+                    write_to_register(w, storage, &operands, |w, storage| {
+                        for (i, value) in values.iter().enumerate() {
+                            let slot = format!("[rbp-{}]", table - i * 8);
+                            if value.in_memory() {
+                                writeln!(w, "\tmov {}, {}", storage.code(), value.code())?;
+                                writeln!(w, "\tmov {}, {}", slot, storage.code())?;
+                            } else {
+                                writeln!(w, "\tmov {}, {}", slot, value.code())?;
+                            }
+                        }
+                        let index = match index {
+                            ValueRef::Register(_) => index,
+                            _ => {
+                                writeln!(w, "\tmov {}, {}", storage.code(), index.code())?;
+                                storage
+                            }
+                        };
+                        writeln!(
+                            w,
+                            "\tmov {}, [rbp-{}+{}*8]",
+                            storage.code(),
+                            table,
+                            index.code()
+                        )
+                    })?;
+                }
+                SelectIndex {
+                    table: Some(table), ..
+                } => {
+                    return Err(CodegenError::InvalidOperand {
+                        instruction: "select",
+                        operand: table,
                     }
-                    writeln!(w, "\tidiv {}", right.code())?;
-                    if left != ValueRef::Register(Register::Rax) {
-                        writeln!(w, "\tmov {}, rax", left.code())?;
-                        writeln!(w, "\tpop rax ")?;
+                    .into())
+                }
+                Select {
+                    storage,
+                    cond,
+                    if_true,
+                    if_false,
+                } => {
+                    write_to_register(w, storage, &[cond, if_true, if_false], |w, storage| {
+                        writeln!(w, "\tmov {}, {}", storage.code(), if_false.code())?;
+                        match cond {
+                            ValueRef::Register(_) => {
+                                writeln!(w, "\ttest {0}, {0}", cond.code())?;
+                            }
+                            _ => writeln!(w, "\tcmp {}, 0", cond.sized_code())?,
+                        }
+                        writeln!(w, "\tcmovne {}, {}", storage.code(), if_true.code())
+                    })?;
+                }
+                Equal { left, right } => {
+                    write_comparison(w, "e", left, right)?;
+                }
+                NotEqual { left, right } => {
+                    write_comparison(w, "ne", left, right)?;
+                }
+                LessThan { left, right } => {
+                    write_comparison(w, "l", left, right)?;
+                }
+                GreaterThan { left, right } => {
+                    write_comparison(w, "g", left, right)?;
+                }
+                LessEqual { left, right } => {
+                    write_comparison(w, "le", left, right)?;
+                }
+                GreaterEqual { left, right } => {
+                    write_comparison(w, "ge", left, right)?;
+                }
+                Minimum { left, right } => {
+                    write_conditional_move(w, "g", left, right)?;
+                }
+                Maximum { left, right } => {
+                    write_conditional_move(w, "l", left, right)?;
+                }
+                Negate { value } => {
+                    writeln!(w, "\tneg {}", value.sized_code())?;
+                }
+                ShiftLeft { left, count } => {
+                    write_shift(w, "shl", left, count)?;
+                }
+                ShiftRight { left, count } => {
+                    write_shift(w, "shr", left, count)?;
+                }
+                Popcount { storage, value } => {
+                    if !options.features.popcnt {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "popcount requires the popcnt target feature",
+                        ));
                     }
-                    writeln!(w, "\tpop rdx")?;
+                    write_to_register(w, storage, &[value], |w, storage| {
+                        writeln!(w, "\tpopcnt {}, {}", storage.code(), value.code())
+                    })?;
                 }
                 Jump { ref dest } => {
-                    writeln!(w, "\tjmp {}", dest)?;
+                    if !is_last || next != Some(dest) {
+                        writeln!(w, "\tjmp {}", block_label(func, dest))?;
+                    }
+                }
+                JumpIndirect { target } => match target {
+                    ValueRef::Register(_) => writeln!(w, "\tjmp {}", target.code())?,
+                    _ => writeln!(w, "\tjmp QWORD {}", target.code())?,
+                },
+                TailCallIndirect { target, arg } => {
+                    // R11 is neither an argument register nor callee-saved, so it can hold
+                    // the target while the frame is torn down. An argument already in R11
+                    // is moved out of the way first.
+                    let r11 = ValueRef::Register(Register::R11);
+                    let rdi = ValueRef::Register(Register::Rdi);
+                    match arg {
+                        Some(arg) if arg == r11 && target == rdi => {
+                            writeln!(w, "\txchg r11, rdi")?;
+                        }
+                        Some(arg) if arg == r11 => {
+                            writeln!(w, "\tmov rdi, r11")?;
+                            writeln!(w, "\tmov r11, {}", target.code())?;
+                        }
+                        _ => {
+                            if target != r11 {
+                                writeln!(w, "\tmov r11, {}", target.code())?;
+                            }
+                            if let Some(arg) = arg.filter(|&arg| arg != rdi) {
+                                writeln!(w, "\tmov rdi, {}", arg.code())?;
+                            }
+                        }
+                    }
+                    frame.write_teardown(w)?;
+                    writeln!(w, "\tjmp r11")?;
                 }
                 JumpIfZero { value, ref dest } => {
                     writeln!(w, "\tcmp QWORD {}, 0", value.code())?;
-                    writeln!(w, "\tje {}", dest)?;
+                    writeln!(w, "\tje {}", block_label(func, dest))?;
+                }
+                JumpIfNotZero { value, ref dest } => {
+                    writeln!(w, "\tcmp QWORD {}, 0", value.code())?;
+                    writeln!(w, "\tjne {}", block_label(func, dest))?;
+                }
+                Switch {
+                    value,
+                    ref cases,
+                    ref default,
+                    table: Some(index),
+                } => {
+                    let (min, len) = switch_table_range(cases).expect("switch is not dense");
+                    let table = format!("..@{}.switch{}", block_label(func, &self.name), i);
+                    let index_code = ValueRef::Register(index).code();
+                    if value != ValueRef::Register(index) {
+                        writeln!(w, "\tmov {}, {}", index_code, value.code())?;
+                    }
+                    if min != 0 {
+                        writeln!(w, "\tsub {}, {}", index_code, min)?;
+                    }
+                    writeln!(w, "\tcmp {}, {}", index_code, len - 1)?;
+                    writeln!(w, "\tja {}", block_label(func, default))?;
+                    // The entries are 32-bit offsets from the start of the table, so it needs
+                    // no relocations even in position-independent code.
+                    let base = scratch_register(&[ValueRef::Register(index)])?.name();
+                    writeln!(w, "\tpush {}", base)?;
+                    writeln!(w, "\tlea {}, [rel {}]", base, table)?;
+                    writeln!(w, "\tmovsxd {0}, DWORD [{1}+{0}*4]", index_code, base)?;
+                    writeln!(w, "\tadd {}, {}", index_code, base)?;
+                    writeln!(w, "\tpop {}", base)?;
+                    writeln!(w, "\tjmp {}", index_code)?;
+                    writeln!(w, "\talign 4")?;
+                    writeln!(w, "{}:", table)?;
+                    for entry in min..min + len as Value {
+                        let dest = cases
+                            .iter()
+                            .find(|&&(case, _)| case == entry)
+                            .map_or(default, |(_, dest)| dest);
+                        writeln!(w, "\tdd {} - {}", block_label(func, dest), table)?;
+                    }
+                }
+                Switch {
+                    value,
+                    ref cases,
+                    ref default,
+                    table: None,
+                } => {
+                    for (case, dest) in cases {
+                        if i32::try_from(*case).is_ok() {
+                            writeln!(w, "\tcmp {}, {}", value.sized_code(), case)?;
+                        } else {
+                            // Comparisons only take 32-bit immediates, popping the scratch
+                            // register leaves the flags alone.
+                            let scratch = scratch_register(&[value])?.name();
+                            writeln!(w, "\tpush {}", scratch)?;
+                            writeln!(w, "\tmov {}, {}", scratch, case)?;
+                            writeln!(w, "\tcmp {}, {}", value.code(), scratch)?;
+                            writeln!(w, "\tpop {}", scratch)?;
+                        }
+                        writeln!(w, "\tje {}", block_label(func, dest))?;
+                    }
+                    if !is_last || next != Some(default) {
+                        writeln!(w, "\tjmp {}", block_label(func, default))?;
+                    }
+                }
+                Call {
+                    ref func,
+                    ref args,
+                    ref preserve,
+                    ref preserve_xmm,
+                    result,
+                    float_result,
+                } => {
+                    let returned = result.filter(|_| !float_result).map(|_| Register::Rax);
+                    let preserve = &options.call_saved(func, args, preserve, returned);
+                    for reg in preserve {
+                        writeln!(w, "\tpush {}", reg.name())?;
+                    }
+                    // There is no push for SSE registers, they get 8 bytes each below the
+                    // pushed registers.
+                    if !preserve_xmm.is_empty() {
+                        writeln!(w, "\tsub rsp, {}", preserve_xmm.len() * 8)?;
+                    }
+                    for (i, reg) in preserve_xmm.iter().enumerate() {
+                        writeln!(w, "\tmovsd [rsp+{}], {}", i * 8, reg.name())?;
+                    }
+                    write_call_args(w, args)?;
+                    // The frame keeps the stack aligned, only the saved registers can
+                    // misalign it.
+                    let padding = (preserve.len() + preserve_xmm.len()) % 2 == 1;
+                    if padding {
+                        writeln!(w, "\tsub rsp, 8")?;
+                    }
+                    if options.pic {
+                        writeln!(w, "\tcall {} wrt ..plt", func)?;
+                    } else {
+                        writeln!(w, "\tcall {}", func)?;
+                    }
+                    // Nothing after a call to a function that never returns is reachable.
+                    if options.noreturn.contains(&func.as_str()) {
+                        break;
+                    }
+                    if padding {
+                        writeln!(w, "\tadd rsp, 8")?;
+                    }
+                    match result {
+                        Some(ValueRef::Xmm(XmmRegister::Xmm0)) if float_result => {}
+                        Some(result) if float_result => {
+                            writeln!(w, "\tmovsd {}, xmm0", result.sized_code())?
+                        }
+                        Some(result) => writeln!(w, "\tmov {}, rax", result.code())?,
+                        None => {}
+                    }
+                    for (i, reg) in preserve_xmm.iter().enumerate() {
+                        writeln!(w, "\tmovsd {}, [rsp+{}]", reg.name(), i * 8)?;
+                    }
+                    if !preserve_xmm.is_empty() {
+                        writeln!(w, "\tadd rsp, {}", preserve_xmm.len() * 8)?;
+                    }
+                    for reg in preserve.iter().rev() {
+                        writeln!(w, "\tpop {}", reg.name())?;
+                    }
+                }
+                SaveContext { context } => {
+                    let resume = self.resume_label(func, context)?;
+                    let context = context_offset("save_context", context)?;
+                    for (i, reg) in CONTEXT_REGS.iter().enumerate() {
+                        writeln!(w, "\tmov {}, {}", context_slot(context, i), reg)?;
+                    }
+                    writeln!(
+                        w,
+                        "\tmov {}, rsp",
+                        context_slot(context, CONTEXT_REGS.len())
+                    )?;
+                    writeln!(w, "\tpush rax")?;
+                    writeln!(w, "\tlea rax, [rel {}]", resume)?;
+                    writeln!(
+                        w,
+                        "\tmov {}, rax",
+                        context_slot(context, CONTEXT_REGS.len() + 1)
+                    )?;
+                    writeln!(w, "\tpop rax")?;
+                    writeln!(w, "{}:", resume)?;
+                    if options.cet {
+                        writeln!(w, "\tendbr64")?;
+                    }
+                }
+                RestoreContext { context } => {
+                    let context = context_offset("restore_context", context)?;
+                    // Fetch the resume address first, the context is addressed relative to
+                    // RBP which is restored last.
+                    writeln!(
+                        w,
+                        "\tmov rax, {}",
+                        context_slot(context, CONTEXT_REGS.len() + 1)
+                    )?;
+                    for (i, reg) in CONTEXT_REGS.iter().enumerate() {
+                        if *reg != "rbp" {
+                            writeln!(w, "\tmov {}, {}", reg, context_slot(context, i))?;
+                        }
+                    }
+                    writeln!(
+                        w,
+                        "\tmov rsp, {}",
+                        context_slot(context, CONTEXT_REGS.len())
+                    )?;
+                    writeln!(w, "\tmov rbp, {}", context_slot(context, 1))?;
+                    writeln!(w, "\tjmp rax")?;
                 }
-                Call { ref func, arg } => {
-                    if let Some(arg) = arg {
-                        if arg != ValueRef::Register(Register::Rdi) {
-                            writeln!(w, "\tmov rdi, {}", arg.code())?;
+                PrintString {
+                    string,
+                    ref preserve,
+                } => {
+                    let label = match string {
+                        ValueRef::Global(label) => label,
+                        _ => {
+                            return Err(CodegenError::InvalidOperand {
+                                instruction: "print_string",
+                                operand: string,
+                            }
+                            .into())
                         }
+                    };
+                    for reg in preserve {
+                        writeln!(w, "\tpush {}", reg.name())?;
+                    }
+                    writeln!(w, "\tmov rax, 1")?;
+                    writeln!(w, "\tmov rdi, 1")?;
+                    writeln!(w, "\tlea rsi, [rel {}+8]", label)?;
+                    writeln!(w, "\tmov rdx, [rel {}]", label)?;
+                    writeln!(w, "\tsyscall")?;
+                    for reg in preserve.iter().rev() {
+                        writeln!(w, "\tpop {}", reg.name())?;
                     }
-                    writeln!(w, "\tcall {}", func)?;
                 }
                 Exit { exit_code } => {
-                    // We can savely overwrite RAX here because the process is about to be
-                    // terminated anyway.
-                    writeln!(w, "\tmov rax, 60")?;
                     // If the exit code is not already stored in RDI move it there.
                     if exit_code != ValueRef::Register(Register::Rdi) {
-                        writeln!(w, "\tmov rdi, {}", exit_code.code())?;
+                        write_binary_op(w, "mov", ValueRef::Register(Register::Rdi), exit_code)?;
                     }
+                    // We can savely overwrite RAX here because the process is about to be
+                    // terminated anyway, the exit code is out of it by now.
+                    writeln!(w, "\tmov rax, 60")?;
                     writeln!(w, "\tsyscall")?;
                 }
+                Return { value } => {
+                    match value {
+                        Some(ValueRef::Xmm(XmmRegister::Xmm0)) => {}
+                        Some(ValueRef::Xmm(reg)) => writeln!(w, "\tmovsd xmm0, {}", reg.name())?,
+                        Some(value) if value != ValueRef::Register(Register::Rax) => {
+                            write_binary_op(w, "mov", ValueRef::Register(Register::Rax), value)?
+                        }
+                        _ => {}
+                    }
+                    frame.write_teardown(w)?;
+                    writeln!(w, "\tret")?;
+                }
             }
         }
         Ok(())
     }
 
+    /// Label marking where execution resumes when the given context is restored.
+    fn resume_label(&self, func: &str, context: ValueRef) -> Result<String, CodegenError> {
+        let off = context_offset("save_context", context)?;
+        Ok(format!("{}.resume{}", block_label(func, &self.name), off))
+    }
+
+    /// Generate native code for this block on its own with the default module settings
+    /// and return it as a string.
+    pub fn generate_string(&self) -> std::io::Result<String> {
+        let kind = if self.needs_frame() {
+            FrameKind::Standard
+        } else {
+            FrameKind::None
+        };
+        let frame = Frame {
+            kind,
+            saved: vec![],
+        };
+        generate_string(|w| {
+            self.generate_code(w, "", &CodegenOptions::default(), None, false, &frame)
+        })
+    }
+
+    /// Allocate storage for a new value. This is a register unless all of them are in use,
+    /// then the value is spilled to stack memory, which is reused by later spilled values
+    /// once this one is consumed.
+    fn alloc_value(&mut self) -> ValueRef {
+        match self.registers.alloc() {
+            Ok(reg) => ValueRef::Register(reg),
+            Err(_) => self.alloc_spill(),
+        }
+    }
+
+    /// Allocate an SSE register for a new floating-point value, or stack memory if all of
+    /// them are in use.
+    fn alloc_xmm_value(&mut self) -> ValueRef {
+        match self.registers.alloc_xmm() {
+            Ok(reg) => ValueRef::Xmm(reg),
+            Err(_) => self.alloc_spill(),
+        }
+    }
+
+    /// Append an `Alloc` instruction for stack memory holding a spilled value.
+    fn alloc_spill(&mut self) -> ValueRef {
+        let offset = self.stack.alloc_spill(STACK_ALIGN);
+        self.instructions.push(Instruction::Alloc {
+            size: STACK_ALIGN,
+            offset,
+        });
+        ValueRef::Memory(offset)
+    }
+
+    /// Free the storage of a value consumed by an instruction: its register, or its stack
+    /// memory if it was spilled. Other memory stays allocated.
+    fn free_value(&mut self, value: ValueRef) {
+        match value {
+            ValueRef::Register(reg) if self.registers.is_allocated(reg) => self.registers.free(reg),
+            ValueRef::Xmm(reg) if self.registers.is_xmm_allocated(reg) => {
+                self.registers.free_xmm(reg)
+            }
+            ValueRef::Memory(offset) => self.stack.free_spill(offset, STACK_ALIGN),
+            _ => {}
+        }
+    }
+
+    /// Record a register operand which isn't currently allocated by this block, to be
+    /// reported by `Function::validate` and when generating code.
+    ///
+    /// Blocks not created by the same function allocate registers independently, so a
+    /// register value created by such a block, or one that has already been consumed,
+    /// refers to whatever this block put in that register and would silently produce wrong
+    /// code. A consumed value whose register was allocated again is only caught once the
+    /// new value is used after the stale use consumed it.
+    fn check_operand(&mut self, value: ValueRef) {
+        let allocated = match value {
+            ValueRef::Register(reg) => self.registers.is_allocated(reg),
+            ValueRef::Xmm(reg) => self.registers.is_xmm_allocated(reg),
+            _ => true,
+        };
+        if !allocated {
+            self.stale_operands.push((self.instructions.len(), value));
+        }
+    }
+
+    /// Check the operands of an integer instruction like `check_operand`. Panics if one of
+    /// them is a floating-point value in an SSE register, which integer instructions can't
+    /// operate on; convert it or use the floating-point instructions instead.
+    fn check_int_operands(&mut self, operands: &[ValueRef]) {
+        for &operand in operands {
+            assert!(
+                !matches!(operand, ValueRef::Xmm(_)),
+                "integer instructions can't take the floating-point value {}",
+                operand
+            );
+            self.check_operand(operand);
+        }
+    }
+
+    /// Attach a comment to the next instruction appended to this block.
+    /// The comment is emitted right before that instruction in the generated code.
+    pub fn build_comment(&mut self, comment: &str) {
+        self.comments
+            .push((self.instructions.len(), comment.into()));
+    }
+
     /// Append a `Constant` instruction to the end of this block.
     /// Returns a reference to the value to be used in other instructions.
     pub fn build_constant(&mut self, value: Value) -> ValueRef {
-        let storage = ValueRef::Register(self.registers.alloc());
+        self.build_constant_sized(value, Width::Qword)
+    }
+
+    /// Append a `Constant` instruction of the given width to the end of this block. The
+    /// value is truncated to the width.
+    /// Returns a reference to the value to be used in other instructions.
+    pub fn build_constant_sized(&mut self, value: Value, width: Width) -> ValueRef {
+        let storage = self.alloc_value();
+        self.instructions.push(Instruction::Constant {
+            storage,
+            value: width.truncate(value),
+            width,
+        });
+        storage
+    }
+
+    /// Append a `Constant` instruction like `build_constant` and annotate it in the
+    /// generated code with the name the value has in the source, e.g. `; x = const 10`.
+    pub fn build_constant_named(&mut self, value: Value, name: &str) -> ValueRef {
+        self.build_comment(&format!("{} = const {}", name, value));
+        self.build_constant(value)
+    }
+
+    /// An operand with the given value for the places immediates are allowed in, see
+    /// `ValueRef::Immediate`. Values fitting in 32 bits are immediates, larger ones are
+    /// materialized in a register with a `Constant` instruction, whose register the
+    /// instruction using the operand frees like any other.
+    ///
+    /// ```
+    /// use fig::ir::{Block, ValueRef};
+    ///
+    /// let mut block = Block::new(".entry".into());
+    /// assert_eq!(block.build_immediate(7), ValueRef::Immediate(7));
+    /// assert!(matches!(block.build_immediate(1 << 40), ValueRef::Register(_)));
+    /// ```
+    pub fn build_immediate(&mut self, value: Value) -> ValueRef {
+        ValueRef::immediate(value).unwrap_or_else(|_| self.build_constant(value))
+    }
+
+    /// Append an `Alloc` instruction to the end of this block.
+    /// The size is rounded up to a multiple of 16 bytes so the stack stays aligned for calls
+    /// as the System V ABI requires.
+    /// Returns a reference to the memory allocated to be used in other instructions.
+    pub fn build_alloc(&mut self, size: usize) -> ValueRef {
+        let size = size.next_multiple_of(STACK_ALIGN);
+        let offset = self.stack.alloc(size);
+        self.instructions.push(Instruction::Alloc { size, offset });
+        ValueRef::Memory(offset)
+    }
+
+    /// Append an `Alloc` instruction like `build_alloc` and annotate it in the generated
+    /// code with the name of the source variable living in the allocated memory.
+    pub fn build_alloc_named(&mut self, size: usize, name: &str) -> ValueRef {
+        self.build_comment(&format!("{} = alloc {}", name, size));
+        self.build_alloc(size)
+    }
+
+    /// Free stack memory returned by `build_alloc` with the same size, so a later
+    /// `build_alloc` of that size in any block sharing this block's stack memory reuses it
+    /// instead of growing the frame. The memory must not be used afterwards.
+    ///
+    /// ```
+    /// use fig::ir::Block;
+    ///
+    /// let mut block = Block::new(".entry".into());
+    /// let first = block.build_alloc(8);
+    /// block.free_alloc(first, 8)?;
+    /// let second = block.build_alloc(8);
+    /// assert_eq!(first, second);
+    /// # Ok::<(), fig::ir::CodegenError>(())
+    /// ```
+    pub fn free_alloc(&mut self, memory: ValueRef, size: usize) -> Result<(), CodegenError> {
+        match memory {
+            ValueRef::Memory(offset) => {
+                self.stack.free(offset, size.next_multiple_of(STACK_ALIGN));
+                Ok(())
+            }
+            _ => Err(CodegenError::InvalidOperand {
+                instruction: "free_alloc",
+                operand: memory,
+            }),
+        }
+    }
+
+    /// Append a `Load` instruction to the end of this block, reading the value at `source`.
+    /// Returns a reference to the loaded value to be used in other instructions.
+    pub fn build_load(&mut self, source: ValueRef) -> ValueRef {
+        self.push_load(source, false)
+    }
+
+    /// Append a volatile `Load` instruction to the end of this block.
+    /// Volatile loads are kept as is by optimizations even if the loaded value is never
+    /// used or the memory holds a known constant, which is needed for memory-mapped I/O.
+    /// Returns a reference to the loaded value to be used in other instructions.
+    pub fn build_load_volatile(&mut self, source: ValueRef) -> ValueRef {
+        self.push_load(source, true)
+    }
+
+    fn push_load(&mut self, source: ValueRef, volatile: bool) -> ValueRef {
+        self.check_operand(source);
+        let storage = self.alloc_value();
+        self.instructions.push(Instruction::Load {
+            storage,
+            source,
+            volatile,
+        });
+        storage
+    }
+
+    /// Append a `Move` instruction to the end of this block, copying the value at `source`
+    /// into `storage`. Unlike `build_store` this doesn't free `source`, both values stay
+    /// usable afterwards.
+    pub fn build_move(&mut self, source: ValueRef, storage: ValueRef) {
+        self.check_operand(source);
+        self.check_operand(storage);
+        self.instructions
+            .push(Instruction::Move { storage, source });
+    }
+
+    /// Append a `Store` instruction to the end of this block.
+    pub fn build_store(&mut self, value: ValueRef, storage: ValueRef) {
+        self.push_store(value, storage, false, Width::Qword);
+    }
+
+    /// Append a `Store` instruction of the given width to the end of this block. A 32-bit
+    /// store to memory only writes the lower 4 bytes of it.
+    pub fn build_store_sized(&mut self, value: ValueRef, storage: ValueRef, width: Width) {
+        self.push_store(value, storage, false, width);
+    }
+
+    /// Append a volatile `Store` instruction to the end of this block.
+    /// Volatile stores are kept as is by optimizations even if the memory is never read,
+    /// which is needed for memory-mapped I/O or observation points.
+    pub fn build_store_volatile(&mut self, value: ValueRef, storage: ValueRef) {
+        self.push_store(value, storage, true, Width::Qword);
+    }
+
+    /// Append a volatile `Store` with release semantics to the end of this block: memory
+    /// accesses before it are neither moved after it nor observed after it by other threads.
+    /// It is preceded by a release `Fence`, which emits no code on x86.
+    pub fn build_store_release(&mut self, value: ValueRef, storage: ValueRef) {
+        self.build_fence(MemoryOrdering::Release);
+        self.build_store_volatile(value, storage);
+    }
+
+    /// Append a `Load` with acquire semantics to the end of this block: memory accesses after
+    /// it are neither moved before it nor observed before it by other threads. It is followed
+    /// by an acquire `Fence`, which emits no code on x86.
+    /// Returns a reference to the loaded value to be used in other instructions.
+    pub fn build_load_acquire(&mut self, source: ValueRef) -> ValueRef {
+        let value = self.build_load(source);
+        self.build_fence(MemoryOrdering::Acquire);
+        value
+    }
+
+    /// Append a `Fence` instruction to the end of this block. Optimizations never move
+    /// loads or stores across a fence, and a `SeqCst` one also keeps the processor from
+    /// doing so with an `mfence`.
+    ///
+    /// ```
+    /// use fig::ir::{Block, MemoryOrdering};
+    ///
+    /// let mut block = Block::new(".entry".into());
+    /// block.build_fence(MemoryOrdering::Release);
+    /// assert!(!block.generate_string().unwrap().contains("mfence"));
+    /// block.build_fence(MemoryOrdering::SeqCst);
+    /// assert!(block.generate_string().unwrap().contains("mfence"));
+    /// ```
+    pub fn build_fence(&mut self, ordering: MemoryOrdering) {
+        self.instructions.push(Instruction::Fence { ordering });
+    }
+
+    fn push_store(&mut self, value: ValueRef, storage: ValueRef, volatile: bool, width: Width) {
+        self.check_operand(value);
+        self.check_operand(storage);
+        self.instructions.push(Instruction::Store {
+            value,
+            storage,
+            volatile,
+            width,
+        });
+        self.free_value(value);
+    }
+
+    /// Move a value into the given register, e.g. to satisfy a calling convention, and
+    /// reserve the register for it. If the register holds another live value, that value
+    /// is moved out of the way with `build_evict` first; call it yourself beforehand to
+    /// learn where the value went and keep using it.
+    /// Returns a reference to the register to be used in other instructions.
+    ///
+    /// ```
+    /// use fig::ir::{Block, Register};
+    ///
+    /// let mut block = Block::new(".entry".into());
+    /// let first = block.build_constant(1);
+    /// let first = block.build_move_to_register(first, Register::Rdi);
+    /// let first = block.build_evict(Register::Rdi).unwrap();
+    /// let second = block.build_constant(2);
+    /// let second = block.build_move_to_register(second, Register::Rdi);
+    /// let sum = block.build_add(first, second);
+    /// block.build_exit(sum);
+    /// assert_eq!(block.to_string().matches("move %rdi").count(), 1);
+    /// ```
+    pub fn build_move_to_register(&mut self, value: ValueRef, reg: Register) -> ValueRef {
+        self.check_operand(value);
+        let storage = ValueRef::Register(reg);
+        if value == storage {
+            return storage;
+        }
+        self.build_evict(reg);
+        self.registers
+            .alloc_specific(reg)
+            .expect("evicted register is free");
+        self.push_store(value, storage, false, Width::Qword);
+        storage
+    }
+
+    /// Move the value held in the given register to a newly allocated register, or stack
+    /// memory if all of them are in use, and free the register. The value has to be used
+    /// through the returned reference from then on.
+    /// Returns `None` and does nothing if the register holds no live value.
+    pub fn build_evict(&mut self, reg: Register) -> Option<ValueRef> {
+        if !self.registers.is_allocated(reg) {
+            return None;
+        }
+        let storage = self.alloc_value();
+        self.instructions.push(Instruction::Move {
+            storage,
+            source: ValueRef::Register(reg),
+        });
+        self.registers.free(reg);
+        Some(storage)
+    }
+
+    /// Append a `Add` instruction to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_add(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.build_add_sized(left, right, Width::Qword)
+    }
+
+    /// Append a `Add` instruction of the given width to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_add_sized(&mut self, left: ValueRef, right: ValueRef, width: Width) -> ValueRef {
+        self.check_int_operands(&[left, right]);
+        self.instructions
+            .push(Instruction::Add { left, right, width });
+        self.free_value(right);
+        left
+    }
+
+    /// Append a `FloatConstant` instruction to the end of this block.
+    /// Returns a reference to the value to be used in other instructions.
+    pub fn build_float_constant(&mut self, value: f64) -> ValueRef {
+        let storage = self.alloc_xmm_value();
         self.instructions
-            .push(Instruction::Constant { storage, value });
+            .push(Instruction::FloatConstant { storage, value });
         storage
     }
 
-    /// Append an `Alloc` instruction to the end of this block.
-    /// Returns a reference to the memory allocated to be used in other instructions.
-    pub fn build_alloc(&mut self, size: usize) -> ValueRef {
-        self.instructions.push(Instruction::Alloc { size });
-        ValueRef::Memory(self.stack.alloc(size))
+    /// Append a `FloatAdd` instruction to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_fadd(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.push_float_op(left, right, |left, right| Instruction::FloatAdd {
+            left,
+            right,
+        })
+    }
+
+    /// Append a `FloatSubtract` instruction to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_fsub(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.push_float_op(left, right, |left, right| Instruction::FloatSubtract {
+            left,
+            right,
+        })
     }
 
-    /// Append a `Store` instruction to the end of this block.
-    pub fn build_store(&mut self, value: ValueRef, storage: ValueRef) {
-        self.instructions
-            .push(Instruction::Store { value, storage });
-        if let ValueRef::Register(reg) = value {
-            self.registers.free(reg);
-        }
+    /// Append a `FloatMultiply` instruction to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_fmul(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.push_float_op(left, right, |left, right| Instruction::FloatMultiply {
+            left,
+            right,
+        })
     }
 
-    /// Append a `Add` instruction to the end of this block.
+    /// Append a `FloatDivide` instruction to the end of this block.
     /// Returns a reference to the result to be used in other instructions.
-    pub fn build_add(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
-        self.instructions.push(Instruction::Add { left, right });
-        if let ValueRef::Register(reg) = right {
-            self.registers.free(reg);
+    pub fn build_fdiv(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.push_float_op(left, right, |left, right| Instruction::FloatDivide {
+            left,
+            right,
+        })
+    }
+
+    fn push_float_op(
+        &mut self,
+        left: ValueRef,
+        right: ValueRef,
+        instruction: impl FnOnce(ValueRef, ValueRef) -> Instruction,
+    ) -> ValueRef {
+        self.check_operand(left);
+        self.check_operand(right);
+        for operand in [left, right] {
+            assert!(
+                matches!(
+                    operand,
+                    ValueRef::Xmm(_)
+                        | ValueRef::Memory(_)
+                        | ValueRef::Global(_)
+                        | ValueRef::ThreadLocal(_)
+                ),
+                "floating-point instructions can't take the integer value {}",
+                operand
+            );
         }
+        self.instructions.push(instruction(left, right));
+        self.free_value(right);
         left
     }
 
     /// Append a `Subtract` instruction to the end of this block.
     /// Returns a reference to the result to be used in other instructions.
     pub fn build_subtract(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.build_subtract_sized(left, right, Width::Qword)
+    }
+
+    /// Append a `Subtract` instruction of the given width to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_subtract_sized(
+        &mut self,
+        left: ValueRef,
+        right: ValueRef,
+        width: Width,
+    ) -> ValueRef {
+        self.check_int_operands(&[left, right]);
         self.instructions
-            .push(Instruction::Subtract { left, right });
-        if let ValueRef::Register(reg) = right {
-            self.registers.free(reg);
-        }
+            .push(Instruction::Subtract { left, right, width });
+        self.free_value(right);
         left
     }
 
     /// Append a `Multiply` instruction to the end of this block.
     /// Returns a reference to the result to be used in other instructions.
     pub fn build_multiply(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.check_int_operands(&[left, right]);
         self.instructions
             .push(Instruction::Multiply { left, right });
-        if let ValueRef::Register(reg) = right {
-            self.registers.free(reg);
-        }
+        self.free_value(right);
         left
     }
 
     /// Append a `Divide` instruction to the end of this block.
     /// Returns a reference to the result to be used in other instructions.
     pub fn build_divide(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.check_int_operands(&[left, right]);
         self.instructions.push(Instruction::Divide { left, right });
-        if let ValueRef::Register(reg) = right {
-            self.registers.free(reg);
+        self.free_value(right);
+        left
+    }
+
+    /// Append a `Modulo` instruction to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_modulo(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.check_int_operands(&[left, right]);
+        self.instructions.push(Instruction::Modulo { left, right });
+        self.free_value(right);
+        left
+    }
+
+    /// Append an `UnsignedDivide` instruction to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_unsigned_divide(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.check_int_operands(&[left, right]);
+        self.instructions
+            .push(Instruction::UnsignedDivide { left, right });
+        self.free_value(right);
+        left
+    }
+
+    /// Append an `UnsignedModulo` instruction to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_unsigned_modulo(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.check_int_operands(&[left, right]);
+        self.instructions
+            .push(Instruction::UnsignedModulo { left, right });
+        self.free_value(right);
+        left
+    }
+
+    /// Append an `And` instruction to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_and(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.check_int_operands(&[left, right]);
+        self.instructions.push(Instruction::And { left, right });
+        self.free_value(right);
+        left
+    }
+
+    /// Append an `Or` instruction to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_or(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.check_int_operands(&[left, right]);
+        self.instructions.push(Instruction::Or { left, right });
+        self.free_value(right);
+        left
+    }
+
+    /// Append a `Xor` instruction to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_xor(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.check_int_operands(&[left, right]);
+        self.instructions.push(Instruction::Xor { left, right });
+        self.free_value(right);
+        left
+    }
+
+    /// Append a `TableLoad` instruction to the end of this block.
+    /// Loads the entry at `index` of the table labeled `table` whose entries are `elem_size`
+    /// bytes wide. Entries smaller than 8 bytes are sign-extended. The table is addressed
+    /// absolutely, so this is not usable in position-independent code.
+    /// Returns a reference to the loaded value to be used in other instructions.
+    pub fn build_table_load(&mut self, table: &str, index: ValueRef, elem_size: usize) -> ValueRef {
+        self.check_operand(index);
+        assert!(
+            matches!(elem_size, 1 | 2 | 4 | 8),
+            "table entries must be 1, 2, 4 or 8 bytes wide"
+        );
+        // The index is consumed by the load so its register can hold the result.
+        self.free_value(index);
+        let storage = self.alloc_value();
+        self.instructions.push(Instruction::TableLoad {
+            storage,
+            table: table.into(),
+            index,
+            elem_size,
+        });
+        storage
+    }
+
+    /// Append a `Select` instruction to the end of this block, selecting `if_true` if `cond`
+    /// is non-zero and `if_false` otherwise without branching, using `cmovne`.
+    /// Returns a reference to the selected value to be used in other instructions.
+    pub fn build_select(
+        &mut self,
+        cond: ValueRef,
+        if_true: ValueRef,
+        if_false: ValueRef,
+    ) -> ValueRef {
+        for operand in [cond, if_true, if_false] {
+            self.check_operand(operand);
+        }
+        let storage = self.alloc_value();
+        self.instructions.push(Instruction::Select {
+            storage,
+            cond,
+            if_true,
+            if_false,
+        });
+        for operand in [cond, if_true, if_false] {
+            self.free_value(operand);
+        }
+        storage
+    }
+
+    /// Append a `SelectIndex` instruction to the end of this block, selecting the value at
+    /// `index` in `values` without branching. The index must be within bounds.
+    /// Up to four values are selected with a sequence of `cmove`, larger lists are copied
+    /// into a table on the stack which is then indexed.
+    /// Returns a reference to the selected value to be used in other instructions.
+    ///
+    /// Fails with `CodegenError::NothingToSelect` if `values` is empty, and with
+    /// `CodegenError::OutOfRegisters` if a table is needed while the values occupy every
+    /// register, since copying them into it takes a register none of them is in.
+    pub fn build_select_index(
+        &mut self,
+        index: ValueRef,
+        values: &[ValueRef],
+    ) -> Result<ValueRef, CodegenError> {
+        if values.is_empty() {
+            return Err(CodegenError::NothingToSelect);
         }
+        let operands: Vec<ValueRef> = values.iter().copied().chain(Some(index)).collect();
+        let all_in_operands = Register::ALL
+            .iter()
+            .all(|&reg| operands.contains(&ValueRef::Register(reg)));
+        if values.len() > MAX_CMOV_SELECT && all_in_operands {
+            return Err(CodegenError::OutOfRegisters);
+        }
+        let table = if values.len() > MAX_CMOV_SELECT {
+            Some(self.build_alloc(values.len() * 8))
+        } else {
+            None
+        };
+        Ok(self.build_select_index_in(index, values, table))
+    }
+
+    /// Append a `SelectIndex` instruction like `build_select_index`, copying the values
+    /// into `table` if given, memory of at least 8 bytes per value.
+    pub(crate) fn build_select_index_in(
+        &mut self,
+        index: ValueRef,
+        values: &[ValueRef],
+        table: Option<ValueRef>,
+    ) -> ValueRef {
+        self.check_operand(index);
+        for &value in values {
+            self.check_operand(value);
+        }
+        let storage = self.alloc_value();
+        self.instructions.push(Instruction::SelectIndex {
+            storage,
+            index,
+            values: values.to_vec(),
+            table,
+        });
+        for &operand in [index].iter().chain(values) {
+            self.free_value(operand);
+        }
+        storage
+    }
+
+    /// Append an `Equal` instruction to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_equal(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.check_int_operands(&[left, right]);
+        self.instructions.push(Instruction::Equal { left, right });
+        self.free_value(right);
+        left
+    }
+
+    /// Append a `NotEqual` instruction to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_not_equal(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.check_int_operands(&[left, right]);
+        self.instructions
+            .push(Instruction::NotEqual { left, right });
+        self.free_value(right);
+        left
+    }
+
+    /// Append a `LessThan` instruction to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_less_than(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.check_int_operands(&[left, right]);
+        self.instructions
+            .push(Instruction::LessThan { left, right });
+        self.free_value(right);
+        left
+    }
+
+    /// Append a `GreaterThan` instruction to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_greater_than(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.check_int_operands(&[left, right]);
+        self.instructions
+            .push(Instruction::GreaterThan { left, right });
+        self.free_value(right);
+        left
+    }
+
+    /// Append a `LessEqual` instruction to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_less_equal(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.check_int_operands(&[left, right]);
+        self.instructions
+            .push(Instruction::LessEqual { left, right });
+        self.free_value(right);
+        left
+    }
+
+    /// Append a `GreaterEqual` instruction to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_greater_equal(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.check_int_operands(&[left, right]);
+        self.instructions
+            .push(Instruction::GreaterEqual { left, right });
+        self.free_value(right);
+        left
+    }
+
+    /// Append a `Minimum` instruction to the end of this block, computing the smaller of
+    /// two signed values without branching, using `cmovg`.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_min(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.check_int_operands(&[left, right]);
+        self.instructions.push(Instruction::Minimum { left, right });
+        self.free_value(right);
+        left
+    }
+
+    /// Append a `Maximum` instruction to the end of this block, computing the larger of
+    /// two signed values without branching, using `cmovl`.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_max(&mut self, left: ValueRef, right: ValueRef) -> ValueRef {
+        self.check_int_operands(&[left, right]);
+        self.instructions.push(Instruction::Maximum { left, right });
+        self.free_value(right);
+        left
+    }
+
+    /// Append a `Negate` instruction to the end of this block.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_negate(&mut self, value: ValueRef) -> ValueRef {
+        self.check_int_operands(&[value]);
+        self.instructions.push(Instruction::Negate { value });
+        value
+    }
+
+    /// Append a `ShiftLeft` instruction to the end of this block.
+    /// If `count` was just set by a constant the shift uses an immediate count, otherwise
+    /// the count is moved into RCX, which is preserved if it holds a live value.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_shift_left(&mut self, left: ValueRef, count: ValueRef) -> ValueRef {
+        let count = self.shift_count(left, count);
+        self.instructions
+            .push(Instruction::ShiftLeft { left, count });
+        left
+    }
+
+    /// Append a `ShiftRight` instruction to the end of this block. This is a logical shift,
+    /// the vacated bits are filled with zeros. `count` is handled like in `build_shift_left`.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_shift_right(&mut self, left: ValueRef, count: ValueRef) -> ValueRef {
+        let count = self.shift_count(left, count);
+        self.instructions
+            .push(Instruction::ShiftRight { left, count });
         left
     }
 
+    /// Turn the operand `count` of a shift into a `ShiftCount`, freeing its register.
+    fn shift_count(&mut self, left: ValueRef, count: ValueRef) -> ShiftCount {
+        self.check_int_operands(&[left, count]);
+        let shift_count = match self.constant_value(count) {
+            Some(value) if (0..64).contains(&value) => ShiftCount::Immediate(value as u8),
+            _ => ShiftCount::Value {
+                count,
+                save_rcx: self.registers.is_allocated(Register::Rcx)
+                    && count != ValueRef::Register(Register::Rcx),
+            },
+        };
+        self.free_value(count);
+        shift_count
+    }
+
+    /// The value of `value` if it is an immediate or the last instruction using it set it
+    /// to a constant.
+    fn constant_value(&self, value: ValueRef) -> Option<i64> {
+        if let ValueRef::Immediate(value) = value {
+            return Some(value.into());
+        }
+        let last_use = self
+            .instructions
+            .iter()
+            .rev()
+            .find(|instruction| instruction.operands().contains(&value))?;
+        match *last_use {
+            Instruction::Constant {
+                storage,
+                value: constant,
+                ..
+            } if storage == value => Some(constant),
+            _ => None,
+        }
+    }
+
+    /// The register the last instruction of this block set to the constant `value`, if it
+    /// did.
+    pub(crate) fn last_constant(&self, value: Value) -> Option<ValueRef> {
+        match *self.instructions.last()? {
+            Instruction::Constant {
+                storage: storage @ ValueRef::Register(_),
+                value: constant,
+                ..
+            } if constant == value => Some(storage),
+            _ => None,
+        }
+    }
+
+    /// Append a `Popcount` instruction to the end of this block.
+    /// Generating code for it fails unless the module enables the `popcnt` target feature.
+    /// Returns a reference to the result to be used in other instructions.
+    pub fn build_popcount(&mut self, value: ValueRef) -> ValueRef {
+        self.check_int_operands(&[value]);
+        let storage = self.alloc_value();
+        self.instructions
+            .push(Instruction::Popcount { storage, value });
+        self.free_value(value);
+        storage
+    }
+
     /// Append a `Jump` instruction to the end of this block.
     pub fn build_jump(&mut self, dest: String) {
         self.instructions.push(Instruction::Jump { dest });
     }
 
+    /// Append a `JumpIndirect` instruction to the end of this block.
+    pub fn build_jump_indirect(&mut self, target: ValueRef) {
+        self.check_operand(target);
+        self.instructions.push(Instruction::JumpIndirect { target });
+        self.free_value(target);
+    }
+
+    /// Append a `TailCallIndirect` instruction to the end of this block, calling the
+    /// function at the address held by `target` in place of the current function. The
+    /// called function returns directly to the current function's caller, so the stack
+    /// doesn't grow with chains of such calls, e.g. in threaded interpreters.
+    pub fn build_tail_call_indirect(&mut self, target: ValueRef, arg: Option<ValueRef>) {
+        self.check_operand(target);
+        if let Some(arg) = arg {
+            self.check_operand(arg);
+        }
+        self.instructions
+            .push(Instruction::TailCallIndirect { target, arg });
+    }
+
     /// Append a `JumpIfZero` instruction to the end of this block.
     pub fn build_jump_if_zero(&mut self, value: ValueRef, dest: String) {
+        self.check_operand(value);
         self.instructions
             .push(Instruction::JumpIfZero { value, dest });
-        if let ValueRef::Register(reg) = value {
-            self.registers.free(reg);
+        self.free_value(value);
+    }
+
+    /// Append a `JumpIfNotZero` instruction to the end of this block.
+    pub fn build_jump_if_not_zero(&mut self, value: ValueRef, dest: String) {
+        self.check_operand(value);
+        self.instructions
+            .push(Instruction::JumpIfNotZero { value, dest });
+        self.free_value(value);
+    }
+
+    /// Append a `Switch` instruction to the end of this block, jumping to the block of the
+    /// case equal to `value` or to `default` if there is none.
+    /// At least three cases spanning a range at most twice as large as their number are
+    /// looked up in a jump table, others are compared one by one.
+    /// Fails with `BuildError::DuplicateCase` if a case value appears more than once.
+    pub fn build_switch(
+        &mut self,
+        value: ValueRef,
+        cases: &[(Value, String)],
+        default: String,
+    ) -> Result<(), BuildError> {
+        for (i, &(case, _)) in cases.iter().enumerate() {
+            if cases[..i].iter().any(|&(other, _)| other == case) {
+                return Err(BuildError::DuplicateCase { value: case });
+            }
+        }
+        self.check_operand(value);
+        // The index into the table is computed in the value's register, which the switch
+        // consumes, or in a free register if it is in memory.
+        let table = switch_table_range(cases).and_then(|_| match value {
+            ValueRef::Register(reg) => Some(reg),
+            _ => self.registers.alloc().ok(),
+        });
+        self.instructions.push(Instruction::Switch {
+            value,
+            cases: cases.to_vec(),
+            default,
+            table,
+        });
+        self.free_value(value);
+        match (value, table) {
+            (ValueRef::Register(_), _) | (_, None) => {}
+            (_, Some(reg)) => self.registers.free(reg),
+        }
+        Ok(())
+    }
+
+    /// Append a `Call` instruction to the end of this block. Allocated registers and SSE
+    /// registers the callee may overwrite are saved around the call.
+    /// Arguments in SSE registers are passed as floating-point values, all others as
+    /// integers, including floating-point values spilled to memory. At most eight of the
+    /// former and six of the latter are supported, passing more fails with
+    /// `BuildError::TooManyArguments`.
+    pub fn build_call(&mut self, func: String, args: &[ValueRef]) -> Result<(), BuildError> {
+        let preserve = self.registers.live_caller_saved();
+        self.build_call_preserving(func, args, &preserve)
+    }
+
+    /// Append a `Call` instruction to the end of this block which saves exactly the given
+    /// registers on the stack before the call and restores them afterwards, so values held
+    /// in them survive the call. Allocated SSE registers are saved as well, since every
+    /// callee may overwrite them.
+    pub fn build_call_preserving(
+        &mut self,
+        func: String,
+        args: &[ValueRef],
+        preserve: &[Register],
+    ) -> Result<(), BuildError> {
+        check_arg_count(args)?;
+        let preserve_xmm = self.registers.allocated_xmm();
+        self.push_call(func, args, preserve, &preserve_xmm, None, false);
+        Ok(())
+    }
+
+    /// Append a `Call` instruction to the end of this block for a function returning a
+    /// value. Registers are saved around the call like in `build_call`.
+    /// Returns a reference to the returned value to be used in other instructions.
+    pub fn build_call_with_result(
+        &mut self,
+        func: String,
+        args: &[ValueRef],
+    ) -> Result<ValueRef, BuildError> {
+        check_arg_count(args)?;
+        let preserve = self.registers.live_caller_saved();
+        let preserve_xmm = self.registers.allocated_xmm();
+        let result = self.alloc_value();
+        self.push_call(func, args, &preserve, &preserve_xmm, Some(result), false);
+        Ok(result)
+    }
+
+    /// Append a `Call` instruction to the end of this block for a function returning a
+    /// floating-point value, like C functions returning `double`. Registers are saved
+    /// around the call like in `build_call`.
+    /// Returns a reference to the returned value to be used in other instructions.
+    pub fn build_call_with_float_result(
+        &mut self,
+        func: String,
+        args: &[ValueRef],
+    ) -> Result<ValueRef, BuildError> {
+        check_arg_count(args)?;
+        let preserve = self.registers.live_caller_saved();
+        let preserve_xmm = self.registers.allocated_xmm();
+        let result = self.alloc_xmm_value();
+        self.push_call(func, args, &preserve, &preserve_xmm, Some(result), true);
+        Ok(result)
+    }
+
+    /// Append a `Call` to a runtime function taking the value as its only argument, which
+    /// always fits in a register.
+    fn push_runtime_call(&mut self, func: &str, value: ValueRef) {
+        let preserve = self.registers.live_caller_saved();
+        let preserve_xmm = self.registers.allocated_xmm();
+        self.push_call(
+            func.to_string(),
+            &[value],
+            &preserve,
+            &preserve_xmm,
+            None,
+            false,
+        );
+    }
+
+    fn push_call(
+        &mut self,
+        func: String,
+        args: &[ValueRef],
+        preserve: &[Register],
+        preserve_xmm: &[XmmRegister],
+        result: Option<ValueRef>,
+        float_result: bool,
+    ) {
+        for &arg in args {
+            self.check_operand(arg);
+        }
+        self.instructions.push(Instruction::Call {
+            func,
+            args: args.to_vec(),
+            preserve: preserve.to_vec(),
+            preserve_xmm: preserve_xmm.to_vec(),
+            result,
+            float_result,
+        });
+    }
+
+    /// Append a `Call` to the runtime's `put_int`, printing the value in decimal.
+    pub fn build_put_int(&mut self, value: ValueRef) {
+        self.push_runtime_call("put_int", value);
+    }
+
+    /// Append a `Call` to the runtime's `put_hex`, printing the value in hexadecimal.
+    pub fn build_put_hex(&mut self, value: ValueRef) {
+        self.push_runtime_call("put_hex", value);
+    }
+
+    /// Append a `Call` to the runtime's `put_bin`, printing the value in binary.
+    pub fn build_put_bin(&mut self, value: ValueRef) {
+        self.push_runtime_call("put_bin", value);
+    }
+
+    /// Append a `Call` to the runtime's `put_char`, writing the low byte of the value to
+    /// stdout as is.
+    pub fn build_put_char(&mut self, value: ValueRef) {
+        self.push_runtime_call("put_char", value);
+    }
+
+    /// Allocate a context buffer on the stack and append a `SaveContext` instruction saving
+    /// the current execution context into it.
+    /// Returns a reference to the context buffer to be passed to `build_restore_context`.
+    ///
+    /// This is a constrained, `setjmp`-like primitive and not a full unwinder:
+    /// - Only RBX, RBP, R12-R15 and RSP are restored to their values at the time of the
+    ///   save. All other registers are clobbered when execution resumes, so values that
+    ///   have to survive must be kept in stack memory.
+    /// - The buffer is addressed relative to the current stack frame, so the context can
+    ///   only be restored from within the same invocation of the function that saved it.
+    /// - Nothing tells the resumed code whether it got there by falling through or by a
+    ///   restore; front-ends have to track that in memory themselves.
+    pub fn build_save_context(&mut self) -> ValueRef {
+        let context = self.build_alloc(CONTEXT_SIZE);
+        self.build_save_context_in(context);
+        context
+    }
+
+    /// Append a `SaveContext` instruction saving the current execution context into
+    /// `context`, stack memory of `CONTEXT_SIZE` bytes.
+    pub(crate) fn build_save_context_in(&mut self, context: ValueRef) {
+        self.instructions.push(Instruction::SaveContext { context });
+    }
+
+    /// Append a `RestoreContext` instruction to the end of this block, transferring control
+    /// back to the point where `context` was saved. See `build_save_context` for caveats.
+    /// Fails if `context` is not stack memory, like the buffers `build_save_context` returns.
+    pub fn build_restore_context(&mut self, context: ValueRef) -> Result<(), CodegenError> {
+        context_offset("restore_context", context)?;
+        self.check_operand(context);
+        self.instructions
+            .push(Instruction::RestoreContext { context });
+        Ok(())
+    }
+
+    /// Append a `Return` instruction to the end of this block, returning `value` to the
+    /// caller of the function if given.
+    pub fn build_return(&mut self, value: Option<ValueRef>) {
+        if let Some(value) = value {
+            self.check_operand(value);
         }
+        self.instructions.push(Instruction::Return { value });
     }
 
-    /// Append a `Call` instruction to the end of this block.
-    pub fn build_call(&mut self, func: String, arg: Option<ValueRef>) {
-        self.instructions.push(Instruction::Call { func, arg });
+    /// Append a `PrintString` instruction to the end of this block, writing `string` to
+    /// standard output. The string must have been returned by `Module::add_string`.
+    /// Allocated registers the system call overwrites are saved around it.
+    pub fn build_print_string(&mut self, string: ValueRef) -> Result<(), CodegenError> {
+        use Register::*;
+        if !matches!(string, ValueRef::Global(_)) {
+            return Err(CodegenError::InvalidOperand {
+                instruction: "print_string",
+                operand: string,
+            });
+        }
+        let preserve = self
+            .registers
+            .live_caller_saved()
+            .into_iter()
+            .filter(|reg| matches!(reg, Rax | Rcx | Rdx | Rsi | Rdi | R11))
+            .collect();
+        self.instructions
+            .push(Instruction::PrintString { string, preserve });
+        Ok(())
     }
 
     /// Append an `Exit` instruction to the end of this block.
     pub fn build_exit(&mut self, exit_code: ValueRef) {
+        self.check_int_operands(&[exit_code]);
         self.instructions.push(Instruction::Exit { exit_code });
     }
+
+    /// Start appending instructions to the end of this block with chained calls.
+    /// See `InstructionBuilder`.
+    pub fn builder(&mut self) -> InstructionBuilder<'_> {
+        InstructionBuilder { block: self }
+    }
+}
+
+/// Appends instructions to a block with chained calls, so sequences of stores and control
+/// flow read top to bottom. Each method behaves like the `build_` method of `Block` with
+/// the same name and returns the builder. The builder dereferences to the block, so
+/// instructions yielding a value are built with the `build_` methods in between:
+///
+/// ```
+/// use fig::ir::{Block, Function, Module};
+///
+/// let mut func = Function::new("_start".into());
+/// let mut entry = func.new_block(".entry".into());
+/// let mut r#loop = func.new_block(".loop".into());
+/// let mut end = func.new_block(".end".into());
+///
+/// let mut b = entry.builder();
+/// let var = b.build_alloc(8);
+/// let val = b.build_constant(3);
+/// b.store(val, var).jump(".loop".into());
+///
+/// let mut b = r#loop.builder();
+/// b.put_int(var);
+/// let one = b.build_constant(1);
+/// b.build_subtract(var, one);
+/// b.jump_if_zero(var, ".end".into()).jump(".loop".into());
+///
+/// let mut b = end.builder();
+/// let exit_code = b.build_constant(0);
+/// b.exit(exit_code);
+///
+/// func.append_block(entry);
+/// func.append_block(r#loop);
+/// func.append_block(end);
+/// let mut module = Module::default();
+/// module.append_func(func);
+///
+/// let mut output = vec![];
+/// assert_eq!(module.interpret_to(&mut output).unwrap(), 0);
+/// assert_eq!(output, b"3\n2\n1\n");
+/// ```
+pub struct InstructionBuilder<'b> {
+    block: &'b mut Block,
+}
+
+impl InstructionBuilder<'_> {
+    /// See `Block::build_comment`.
+    pub fn comment(&mut self, comment: &str) -> &mut Self {
+        self.block.build_comment(comment);
+        self
+    }
+
+    /// See `Block::build_move`.
+    pub fn move_value(&mut self, source: ValueRef, storage: ValueRef) -> &mut Self {
+        self.block.build_move(source, storage);
+        self
+    }
+
+    /// See `Block::build_store`.
+    pub fn store(&mut self, value: ValueRef, storage: ValueRef) -> &mut Self {
+        self.block.build_store(value, storage);
+        self
+    }
+
+    /// See `Block::build_store_sized`.
+    pub fn store_sized(&mut self, value: ValueRef, storage: ValueRef, width: Width) -> &mut Self {
+        self.block.build_store_sized(value, storage, width);
+        self
+    }
+
+    /// See `Block::build_store_volatile`.
+    pub fn store_volatile(&mut self, value: ValueRef, storage: ValueRef) -> &mut Self {
+        self.block.build_store_volatile(value, storage);
+        self
+    }
+
+    /// See `Block::build_store_release`.
+    pub fn store_release(&mut self, value: ValueRef, storage: ValueRef) -> &mut Self {
+        self.block.build_store_release(value, storage);
+        self
+    }
+
+    /// See `Block::build_fence`.
+    pub fn fence(&mut self, ordering: MemoryOrdering) -> &mut Self {
+        self.block.build_fence(ordering);
+        self
+    }
+
+    /// See `Block::build_jump`.
+    pub fn jump(&mut self, dest: String) -> &mut Self {
+        self.block.build_jump(dest);
+        self
+    }
+
+    /// See `Block::build_jump_indirect`.
+    pub fn jump_indirect(&mut self, target: ValueRef) -> &mut Self {
+        self.block.build_jump_indirect(target);
+        self
+    }
+
+    /// See `Block::build_tail_call_indirect`.
+    pub fn tail_call_indirect(&mut self, target: ValueRef, arg: Option<ValueRef>) -> &mut Self {
+        self.block.build_tail_call_indirect(target, arg);
+        self
+    }
+
+    /// See `Block::build_jump_if_zero`.
+    pub fn jump_if_zero(&mut self, value: ValueRef, dest: String) -> &mut Self {
+        self.block.build_jump_if_zero(value, dest);
+        self
+    }
+
+    /// See `Block::build_jump_if_not_zero`.
+    pub fn jump_if_not_zero(&mut self, value: ValueRef, dest: String) -> &mut Self {
+        self.block.build_jump_if_not_zero(value, dest);
+        self
+    }
+
+    /// See `Block::build_switch`.
+    pub fn switch(
+        &mut self,
+        value: ValueRef,
+        cases: &[(Value, String)],
+        default: String,
+    ) -> Result<&mut Self, BuildError> {
+        self.block.build_switch(value, cases, default)?;
+        Ok(self)
+    }
+
+    /// See `Block::build_call`.
+    pub fn call(&mut self, func: String, args: &[ValueRef]) -> Result<&mut Self, BuildError> {
+        self.block.build_call(func, args)?;
+        Ok(self)
+    }
+
+    /// See `Block::build_call_preserving`.
+    pub fn call_preserving(
+        &mut self,
+        func: String,
+        args: &[ValueRef],
+        preserve: &[Register],
+    ) -> Result<&mut Self, BuildError> {
+        self.block.build_call_preserving(func, args, preserve)?;
+        Ok(self)
+    }
+
+    /// See `Block::build_put_int`.
+    pub fn put_int(&mut self, value: ValueRef) -> &mut Self {
+        self.block.build_put_int(value);
+        self
+    }
+
+    /// See `Block::build_put_hex`.
+    pub fn put_hex(&mut self, value: ValueRef) -> &mut Self {
+        self.block.build_put_hex(value);
+        self
+    }
+
+    /// See `Block::build_put_bin`.
+    pub fn put_bin(&mut self, value: ValueRef) -> &mut Self {
+        self.block.build_put_bin(value);
+        self
+    }
+
+    /// See `Block::build_put_char`.
+    pub fn put_char(&mut self, value: ValueRef) -> &mut Self {
+        self.block.build_put_char(value);
+        self
+    }
+
+    /// See `Block::build_print_string`.
+    pub fn print_string(&mut self, string: ValueRef) -> Result<&mut Self, CodegenError> {
+        self.block.build_print_string(string)?;
+        Ok(self)
+    }
+
+    /// See `Block::build_restore_context`.
+    pub fn restore_context(&mut self, context: ValueRef) -> Result<&mut Self, CodegenError> {
+        self.block.build_restore_context(context)?;
+        Ok(self)
+    }
+
+    /// See `Block::build_return`.
+    pub fn ret(&mut self, value: Option<ValueRef>) -> &mut Self {
+        self.block.build_return(value);
+        self
+    }
+
+    /// See `Block::build_exit`.
+    pub fn exit(&mut self, exit_code: ValueRef) -> &mut Self {
+        self.block.build_exit(exit_code);
+        self
+    }
+}
+
+impl std::ops::Deref for InstructionBuilder<'_> {
+    type Target = Block;
+
+    fn deref(&self) -> &Block {
+        self.block
+    }
+}
+
+impl std::ops::DerefMut for InstructionBuilder<'_> {
+    fn deref_mut(&mut self) -> &mut Block {
+        self.block
+    }
+}
+
+/// Formats the block in the textual form of the IR, its label followed by one instruction
+/// per line. Comments are shown before the instruction they belong to.
+impl std::fmt::Display for Block {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{}:", self.name)?;
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            for (_, comment) in self.comments.iter().filter(|(at, _)| *at == i) {
+                writeln!(f, "    ; {}", comment)?;
+            }
+            writeln!(f, "    {}", instruction)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats the function in the textual form of the IR, a `func` line followed by its
+/// blocks.
+impl std::fmt::Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "func {}", self.name)?;
+        if self.noreturn {
+            write!(f, " noreturn")?;
+        }
+        writeln!(f)?;
+        for block in &self.blocks {
+            write!(f, "{}", block)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats the module in the textual form of the IR, the declarations of its global and
+/// thread-local variables and strings followed by its functions one after another.
+impl std::fmt::Display for Module {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (name, init) in &self.globals {
+            writeln!(f, "global @{} {}", name, init)?;
+        }
+        for (name, init) in &self.thread_locals {
+            writeln!(f, "tls @{} {}", name, init)?;
+        }
+        for (label, string) in &self.strings {
+            writeln!(f, "string @{} {:?}", label, string)?;
+        }
+        for func in &self.funcs {
+            write!(f, "{}", func)?;
+        }
+        Ok(())
+    }
 }
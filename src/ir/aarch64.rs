@@ -0,0 +1,1760 @@
+//! Code generation for AArch64 (ARM64) Linux, written as GNU assembler source.
+//!
+//! The IR registers are named after their x86-64 counterparts, so each of them stands for
+//! an AArch64 register with the same role in the calling convention: the argument
+//! registers map to X0-X5 in order, the other caller-saved ones to X6, X9 and X10, and the
+//! callee-saved ones to X19-X23. X14-X17 are never allocated and hold operands loaded
+//! from memory, addresses and intermediate values while lowering a single instruction.
+//! The SSE registers XMM0-XMM15 map to the SIMD registers V16-V31, which are caller-saved
+//! just the same, and V0 and V1 hold floating-point operands loaded from memory.
+//! Stack memory is addressed relative to the frame pointer X29 like RBP on x86-64.
+
+use std::io::{self, Write};
+
+use super::{
+    block_label, context_offset, float_args, int_args, string_bytes, switch_table_range, Block,
+    CodegenError, CodegenOptions, Frame, FrameKind, Function, Instruction, MemoryOrdering,
+    Register, ShiftCount, Symbol, ValueRef, Width, XmmRegister, ARG_REGS, STACK_CHK_FAIL,
+};
+
+/// Holds the storage of an instruction's result while it is computed.
+const RESULT: u8 = 14;
+/// Holds addresses of stack memory out of reach of an immediate offset.
+const ADDRESS: u8 = 15;
+/// Holds the left operand of an instruction if it is in memory.
+const LEFT: u8 = 16;
+/// Holds the right operand of an instruction if it is in memory.
+const RIGHT: u8 = 17;
+/// Holds the left floating-point operand of an instruction if it isn't in a SIMD register.
+const FLOAT_LEFT: u8 = 0;
+/// Holds the right floating-point operand of an instruction if it isn't in a SIMD
+/// register.
+const FLOAT_RIGHT: u8 = 1;
+
+/// Registers saved by `SaveContext` in the order they are laid out in the context buffer,
+/// matching the x86-64 layout. The saved stack pointer and the resume address follow them.
+const CONTEXT_REGS: [u8; 6] = [19, 29, 20, 21, 22, 23];
+
+/// The number of the AArch64 register standing in for the given IR register.
+fn number(reg: Register) -> u8 {
+    use Register::*;
+    match reg {
+        Rdi => 0,
+        Rsi => 1,
+        Rdx => 2,
+        Rcx => 3,
+        R8 => 4,
+        R9 => 5,
+        Rax => 6,
+        R10 => 9,
+        R11 => 10,
+        Rbx => 19,
+        R12 => 20,
+        R13 => 21,
+        R14 => 22,
+        R15 => 23,
+    }
+}
+
+/// The name of register `n` when accessed with the given width, e.g. `x0` or `w0`.
+fn name(n: u8, width: Width) -> String {
+    match (n, width) {
+        (29, _) => "x29".to_string(),
+        (_, Width::Dword) => format!("w{}", n),
+        (_, Width::Qword) => format!("x{}", n),
+    }
+}
+
+/// Write code computing the address of the stack memory at `off` below the frame pointer
+/// and return it as a memory operand.
+fn address(w: &mut impl Write, off: usize) -> io::Result<String> {
+    if off <= 256 {
+        Ok(format!("[x29, #-{}]", off))
+    } else if off < 4096 {
+        writeln!(w, "\tsub x{}, x29, #{}", ADDRESS, off)?;
+        Ok(format!("[x{}]", ADDRESS))
+    } else {
+        write_mov_immediate(w, ADDRESS, off as i64, Width::Qword)?;
+        writeln!(w, "\tsub x{0}, x29, x{0}", ADDRESS)?;
+        Ok(format!("[x{}]", ADDRESS))
+    }
+}
+
+/// Write code computing the address of the global or thread-local variable `value` and
+/// return it as a memory operand. Thread-local variables are at a fixed offset from the
+/// thread pointer in TPIDR_EL0, like they are from FS on x86-64.
+fn global_address(w: &mut impl Write, value: ValueRef) -> io::Result<String> {
+    match value {
+        ValueRef::ThreadLocal(name) => {
+            writeln!(w, "\tmrs x{}, tpidr_el0", ADDRESS)?;
+            writeln!(
+                w,
+                "\tadd x{0}, x{0}, #:tprel_hi12:{1}, lsl #12",
+                ADDRESS, name
+            )?;
+            writeln!(w, "\tadd x{0}, x{0}, #:tprel_lo12_nc:{1}", ADDRESS, name)?;
+            Ok(format!("[x{}]", ADDRESS))
+        }
+        ValueRef::Global(name) => {
+            writeln!(w, "\tadrp x{}, {}", ADDRESS, name)?;
+            Ok(format!("[x{}, :lo12:{}]", ADDRESS, name))
+        }
+        _ => unreachable!("only variables are addressed by their symbol"),
+    }
+}
+
+/// The number of the SIMD register standing in for the given SSE register.
+fn float_number(reg: XmmRegister) -> u8 {
+    16 + reg.index()
+}
+
+/// The name of SIMD register `n` when accessed as a floating-point value of the given
+/// width, e.g. `d16` or `s16`.
+fn float_name(n: u8, width: Width) -> String {
+    match width {
+        Width::Dword => format!("s{}", n),
+        Width::Qword => format!("d{}", n),
+    }
+}
+
+/// Get `value` into a register, loading it into `scratch` if it is in memory, a SIMD
+/// register or an immediate. Returns the number of the register holding it.
+fn load(w: &mut impl Write, value: ValueRef, scratch: u8, width: Width) -> io::Result<u8> {
+    match value {
+        ValueRef::Register(reg) => Ok(number(reg)),
+        ValueRef::Xmm(reg) => {
+            let float = float_name(float_number(reg), width);
+            writeln!(w, "\tfmov {}, {}", name(scratch, width), float)?;
+            Ok(scratch)
+        }
+        ValueRef::Memory(off) => {
+            let address = address(w, off)?;
+            writeln!(w, "\tldr {}, {}", name(scratch, width), address)?;
+            Ok(scratch)
+        }
+        ValueRef::Global(_) | ValueRef::ThreadLocal(_) => {
+            let address = global_address(w, value)?;
+            writeln!(w, "\tldr {}, {}", name(scratch, width), address)?;
+            Ok(scratch)
+        }
+        ValueRef::Immediate(value) => {
+            write_mov_immediate(w, scratch, i64::from(value), width)?;
+            Ok(scratch)
+        }
+    }
+}
+
+/// Write the value of register `n` to `storage`.
+fn store(w: &mut impl Write, storage: ValueRef, n: u8, width: Width) -> io::Result<()> {
+    match storage {
+        ValueRef::Register(reg) if number(reg) == n => Ok(()),
+        ValueRef::Register(reg) => {
+            writeln!(w, "\tmov {}, {}", name(number(reg), width), name(n, width))
+        }
+        ValueRef::Xmm(reg) => {
+            let float = float_name(float_number(reg), width);
+            writeln!(w, "\tfmov {}, {}", float, name(n, width))
+        }
+        ValueRef::Memory(off) => {
+            let address = address(w, off)?;
+            writeln!(w, "\tstr {}, {}", name(n, width), address)
+        }
+        ValueRef::Global(_) | ValueRef::ThreadLocal(_) => {
+            let address = global_address(w, storage)?;
+            writeln!(w, "\tstr {}, {}", name(n, width), address)
+        }
+        ValueRef::Immediate(_) => Err(CodegenError::InvalidOperand {
+            instruction: "an instruction writing to it",
+            operand: storage,
+        }
+        .into()),
+    }
+}
+
+/// Get floating-point `value` into a SIMD register, loading it into `scratch` if it isn't
+/// in one. Returns the number of the register holding it.
+fn load_float(w: &mut impl Write, value: ValueRef, scratch: u8) -> io::Result<u8> {
+    match value {
+        ValueRef::Xmm(reg) => Ok(float_number(reg)),
+        ValueRef::Register(reg) => {
+            writeln!(w, "\tfmov d{}, x{}", scratch, number(reg))?;
+            Ok(scratch)
+        }
+        ValueRef::Memory(off) => {
+            let address = address(w, off)?;
+            writeln!(w, "\tldr d{}, {}", scratch, address)?;
+            Ok(scratch)
+        }
+        ValueRef::Global(_) | ValueRef::ThreadLocal(_) => {
+            let address = global_address(w, value)?;
+            writeln!(w, "\tldr d{}, {}", scratch, address)?;
+            Ok(scratch)
+        }
+        ValueRef::Immediate(_) => Err(CodegenError::InvalidOperand {
+            instruction: "a floating-point instruction",
+            operand: value,
+        }
+        .into()),
+    }
+}
+
+/// Write the floating-point value of SIMD register `n` to `storage`.
+fn store_float(w: &mut impl Write, storage: ValueRef, n: u8) -> io::Result<()> {
+    match storage {
+        ValueRef::Xmm(reg) if float_number(reg) == n => Ok(()),
+        ValueRef::Xmm(reg) => writeln!(w, "\tfmov d{}, d{}", float_number(reg), n),
+        ValueRef::Register(reg) => writeln!(w, "\tfmov x{}, d{}", number(reg), n),
+        ValueRef::Memory(off) => {
+            let address = address(w, off)?;
+            writeln!(w, "\tstr d{}, {}", n, address)
+        }
+        ValueRef::Global(_) | ValueRef::ThreadLocal(_) => {
+            let address = global_address(w, storage)?;
+            writeln!(w, "\tstr d{}, {}", n, address)
+        }
+        ValueRef::Immediate(_) => Err(CodegenError::InvalidOperand {
+            instruction: "an instruction writing to it",
+            operand: storage,
+        }
+        .into()),
+    }
+}
+
+/// Write a floating-point instruction computing `left op right` into `left`.
+fn write_float_op(w: &mut impl Write, op: &str, left: ValueRef, right: ValueRef) -> io::Result<()> {
+    let l = load_float(w, left, FLOAT_LEFT)?;
+    let r = load_float(w, right, FLOAT_RIGHT)?;
+    writeln!(w, "\t{0} d{1}, d{1}, d{2}", op, l, r)?;
+    store_float(w, left, l)
+}
+
+/// The register to compute a value for `storage` in: its own register, or `scratch` if it
+/// is in memory.
+fn target(storage: ValueRef, scratch: u8) -> u8 {
+    match storage {
+        ValueRef::Register(reg) => number(reg),
+        _ => scratch,
+    }
+}
+
+/// Write a move of an immediate value into register `n`. Only small immediates can be
+/// encoded in a single instruction, so the value is assembled 16 bits at a time.
+fn write_mov_immediate(w: &mut impl Write, n: u8, value: i64, width: Width) -> io::Result<()> {
+    let reg = name(n, width);
+    let chunks = match width {
+        Width::Dword => 2,
+        Width::Qword => 4,
+    };
+    writeln!(w, "\tmovz {}, #{}", reg, value & 0xffff)?;
+    for i in 1..chunks {
+        let chunk = (value >> (16 * i)) & 0xffff;
+        if chunk != 0 {
+            writeln!(w, "\tmovk {}, #{}, lsl #{}", reg, chunk, 16 * i)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write a three-address instruction like `add left, left, right`, storing the result in
+/// `left`.
+fn write_binary_op(
+    w: &mut impl Write,
+    op: &str,
+    left: ValueRef,
+    right: ValueRef,
+    width: Width,
+) -> io::Result<()> {
+    let l = load(w, left, LEFT, width)?;
+    let r = load(w, right, RIGHT, width)?;
+    writeln!(
+        w,
+        "\t{} {}, {}, {}",
+        op,
+        name(l, width),
+        name(l, width),
+        name(r, width)
+    )?;
+    store(w, left, l, width)
+}
+
+/// Write a division of `left` by `right` using `op`, which is `sdiv` or `udiv`, storing
+/// the quotient or, if `remainder` is set, the remainder in `left`.
+fn write_division(
+    w: &mut impl Write,
+    op: &str,
+    left: ValueRef,
+    right: ValueRef,
+    remainder: bool,
+) -> io::Result<()> {
+    let l = load(w, left, LEFT, Width::Qword)?;
+    let r = load(w, right, RIGHT, Width::Qword)?;
+    if remainder {
+        writeln!(w, "\t{} x{}, x{}, x{}", op, RESULT, l, r)?;
+        writeln!(w, "\tmsub x{0}, x{1}, x{2}, x{0}", l, RESULT, r)?;
+    } else {
+        writeln!(w, "\t{0} x{1}, x{1}, x{2}", op, l, r)?;
+    }
+    store(w, left, l, Width::Qword)
+}
+
+/// Write a comparison of `left` and `right` storing 1 in `left` if the condition `cc`
+/// holds and 0 otherwise.
+fn write_comparison(
+    w: &mut impl Write,
+    cc: &str,
+    left: ValueRef,
+    right: ValueRef,
+) -> io::Result<()> {
+    let l = load(w, left, LEFT, Width::Qword)?;
+    let r = load(w, right, RIGHT, Width::Qword)?;
+    writeln!(w, "\tcmp x{}, x{}", l, r)?;
+    writeln!(w, "\tcset x{}, {}", l, cc)?;
+    store(w, left, l, Width::Qword)
+}
+
+/// Write a comparison of `left` and `right` selecting `left` into it if the condition `cc`
+/// holds and `right` otherwise.
+fn write_select(w: &mut impl Write, cc: &str, left: ValueRef, right: ValueRef) -> io::Result<()> {
+    let l = load(w, left, LEFT, Width::Qword)?;
+    let r = load(w, right, RIGHT, Width::Qword)?;
+    writeln!(w, "\tcmp x{}, x{}", l, r)?;
+    writeln!(w, "\tcsel x{0}, x{0}, x{1}, {2}", l, r, cc)?;
+    store(w, left, l, Width::Qword)
+}
+
+/// Write a shift like `lsl left, left, count`. Shifts by a register count use the count
+/// modulo 64 like x86 does.
+fn write_shift(w: &mut impl Write, op: &str, left: ValueRef, count: ShiftCount) -> io::Result<()> {
+    let l = load(w, left, LEFT, Width::Qword)?;
+    match count {
+        ShiftCount::Immediate(count) => writeln!(w, "\t{0} x{1}, x{1}, #{2}", op, l, count & 63)?,
+        ShiftCount::Value { count, .. } => {
+            let r = load(w, count, RIGHT, Width::Qword)?;
+            writeln!(w, "\t{0} x{1}, x{1}, x{2}", op, l, r)?;
+        }
+    }
+    store(w, left, l, Width::Qword)
+}
+
+/// Move the arguments of a call into the argument registers, shuffling them through the
+/// stack if moving them one after another would overwrite a later one. Floating-point
+/// arguments go to D0 to D7, which never hold values.
+fn write_call_args(w: &mut impl Write, args: &[ValueRef]) -> io::Result<()> {
+    for (i, reg) in float_args(args).enumerate() {
+        writeln!(w, "\tfmov d{}, d{}", i, float_number(reg))?;
+    }
+    let moves: Vec<(Register, ValueRef)> = ARG_REGS
+        .iter()
+        .copied()
+        .zip(int_args(args))
+        .filter(|&(reg, arg)| arg != ValueRef::Register(reg))
+        .collect();
+    let clobbers = moves.iter().enumerate().any(|(i, &(_, arg))| {
+        moves[..i]
+            .iter()
+            .any(|&(reg, _)| arg == ValueRef::Register(reg))
+    });
+    if clobbers {
+        for &(_, arg) in &moves {
+            let n = load(w, arg, LEFT, Width::Qword)?;
+            writeln!(w, "\tstr x{}, [sp, #-16]!", n)?;
+        }
+        for (reg, _) in moves.iter().rev() {
+            writeln!(w, "\tldr x{}, [sp], #16", number(*reg))?;
+        }
+    } else {
+        for (reg, arg) in moves {
+            let n = load(w, arg, number(reg), Width::Qword)?;
+            store(w, ValueRef::Register(reg), n, Width::Qword)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write code loading the stack protector canary into register `n`.
+fn write_load_canary(w: &mut impl Write, n: u8) -> io::Result<()> {
+    writeln!(w, "\tadrp x{}, __stack_chk_guard", n)?;
+    writeln!(w, "\tldr x{0}, [x{0}, :lo12:__stack_chk_guard]", n)
+}
+
+/// Write the code tearing down the frame and restoring the saved registers, leaving SP
+/// where it was on entry and the return address in X30.
+fn write_teardown(w: &mut impl Write, frame: &Frame) -> io::Result<()> {
+    match &frame.kind {
+        FrameKind::None => {}
+        FrameKind::Standard => {
+            writeln!(w, "\tmov sp, x29")?;
+            writeln!(w, "\tldp x29, x30, [sp], #16")?;
+        }
+        FrameKind::Protected { fail_label } => {
+            writeln!(w, "\tldr x{}, [x29]", LEFT)?;
+            write_load_canary(w, RIGHT)?;
+            writeln!(w, "\tcmp x{}, x{}", LEFT, RIGHT)?;
+            writeln!(w, "\tb.ne {}", fail_label)?;
+            writeln!(w, "\tmov sp, x29")?;
+            writeln!(w, "\tldp x29, x30, [sp, #16]")?;
+            writeln!(w, "\tadd sp, sp, #32")?;
+        }
+    }
+    for pair in frame.saved.chunks(2).rev() {
+        match *pair {
+            [first, second] => writeln!(
+                w,
+                "\tldp x{}, x{}, [sp], #16",
+                number(first),
+                number(second)
+            )?,
+            [reg] => writeln!(w, "\tldr x{}, [sp], #16", number(reg))?,
+            _ => unreachable!(),
+        }
+    }
+    Ok(())
+}
+
+/// Address of the `index`-th 8 byte slot of the context buffer at offset `context`.
+fn context_slot(w: &mut impl Write, context: usize, index: usize) -> io::Result<String> {
+    address(w, context - index * 8)
+}
+
+/// Write the directives preceding the code of the functions.
+pub(super) fn write_header(
+    w: &mut impl Write,
+    globals: &[(Symbol, i64)],
+    thread_locals: &[(Symbol, i64)],
+    strings: &[(Symbol, String)],
+) -> io::Result<()> {
+    if !globals.is_empty() {
+        writeln!(w, "\t.data")?;
+        writeln!(w, "\t.balign 8")?;
+        for (name, init) in globals {
+            writeln!(w, "{}:", name)?;
+            writeln!(w, "\t.quad {}", init)?;
+        }
+    }
+    let (tbss, tdata): (Vec<_>, Vec<_>) = thread_locals.iter().partition(|&&(_, init)| init == 0);
+    if !tdata.is_empty() {
+        writeln!(w, "\t.section .tdata,\"awT\",@progbits")?;
+        writeln!(w, "\t.balign 8")?;
+        for (name, init) in tdata {
+            writeln!(w, "{}:", name)?;
+            writeln!(w, "\t.quad {}", init)?;
+        }
+    }
+    if !tbss.is_empty() {
+        writeln!(w, "\t.section .tbss,\"awT\",@nobits")?;
+        writeln!(w, "\t.balign 8")?;
+        for (name, _) in tbss {
+            writeln!(w, "{}:", name)?;
+            writeln!(w, "\t.zero 8")?;
+        }
+    }
+    if !strings.is_empty() {
+        writeln!(w, "\t.section .rodata")?;
+        for (label, string) in strings {
+            writeln!(w, "\t.balign 8")?;
+            writeln!(w, "{}:", label)?;
+            writeln!(w, "\t.quad {}", string.len())?;
+            if !string.is_empty() {
+                writeln!(w, "\t.byte {}", string_bytes(string))?;
+            }
+        }
+    }
+    writeln!(w, "\t.text")
+}
+
+/// Write a minimal `_start` entry point which calls the function `main_name` and exits
+/// the process with the value it returns.
+pub(super) fn write_crt0(w: &mut impl Write, main_name: &str) -> io::Result<()> {
+    writeln!(w, "\t.global _start")?;
+    writeln!(w, "_start:")?;
+    writeln!(w, "\tbl {}", main_name)?;
+    writeln!(w, "\tmov x8, #93")?;
+    writeln!(w, "\tsvc #0")
+}
+
+impl Function {
+    /// Generate AArch64 code for this function using the given module settings.
+    pub(super) fn generate_aarch64(
+        &self,
+        w: &mut impl Write,
+        options: &CodegenOptions,
+    ) -> io::Result<()> {
+        // Calls overwrite the link register, so they need a frame to save it just like
+        // stack memory does.
+        let kind = if !self.blocks.iter().any(|block| block.needs_frame()) {
+            FrameKind::None
+        } else if self.stack_protector && self.blocks.iter().any(|block| block.allocates()) {
+            FrameKind::Protected {
+                fail_label: format!(".L{}.stack_chk_fail", self.name),
+            }
+        } else {
+            FrameKind::Standard
+        };
+        let frame = Frame {
+            kind,
+            saved: self.saved_registers(),
+        };
+
+        writeln!(w, "\t.global {}", self.name)?;
+        if options.symbol_info {
+            writeln!(w, "\t.type {}, %function", self.name)?;
+        }
+        writeln!(w, "{}:", self.name)?;
+        // Saved in pairs above the frame so they don't move the stack memory relative to
+        // X29, a single one padded to keep SP aligned to 16 bytes.
+        for pair in frame.saved.chunks(2) {
+            match *pair {
+                [first, second] => writeln!(
+                    w,
+                    "\tstp x{}, x{}, [sp, #-16]!",
+                    number(first),
+                    number(second)
+                )?,
+                [reg] => writeln!(w, "\tstr x{}, [sp, #-16]!", number(reg))?,
+                _ => unreachable!(),
+            }
+        }
+        match frame.kind {
+            FrameKind::None => {}
+            FrameKind::Standard => {
+                writeln!(w, "\tstp x29, x30, [sp, #-16]!")?;
+                writeln!(w, "\tmov x29, sp")?;
+            }
+            FrameKind::Protected { .. } => {
+                // The canary sits between the saved frame pointer and link register and
+                // the stack memory of the blocks, X29 points to it. It is padded to 16
+                // bytes to keep the stack aligned.
+                writeln!(w, "\tsub sp, sp, #32")?;
+                writeln!(w, "\tstp x29, x30, [sp, #16]")?;
+                writeln!(w, "\tmov x29, sp")?;
+                write_load_canary(w, LEFT)?;
+                writeln!(w, "\tstr x{}, [x29]", LEFT)?;
+            }
+        }
+        let frame_size = self.frame_size();
+        if frame_size >= 4096 {
+            write_mov_immediate(w, LEFT, frame_size as i64, Width::Qword)?;
+            writeln!(w, "\tsub sp, sp, x{}", LEFT)?;
+        } else if frame_size > 0 {
+            writeln!(w, "\tsub sp, sp, #{}", frame_size)?;
+        }
+        for (i, block) in self.blocks.iter().enumerate() {
+            let next = self.blocks.get(i + 1).map(|block| block.name.as_str());
+            block.generate_aarch64(w, &self.name, options, next, &frame)?;
+        }
+        // The epilogue is only reachable if the last block falls through.
+        let falls_off_end = self.blocks.last().is_none_or(|block| block.falls_through());
+        if !self.noreturn && falls_off_end {
+            write_teardown(w, &frame)?;
+            writeln!(w, "\tret")?;
+        }
+        if let FrameKind::Protected { ref fail_label } = frame.kind {
+            writeln!(w, "{}:", fail_label)?;
+            writeln!(w, "\tbl {}", STACK_CHK_FAIL)?;
+        }
+        if options.symbol_info {
+            writeln!(w, "\t.size {0}, .-{0}", self.name)?;
+        }
+        Ok(())
+    }
+}
+
+impl Block {
+    /// Generate the AArch64 code for this block. The parameters are the same as for
+    /// `generate_code`, indirect jump targets need no marking.
+    fn generate_aarch64(
+        &self,
+        w: &mut impl Write,
+        func: &str,
+        options: &CodegenOptions,
+        next: Option<&str>,
+        frame: &Frame,
+    ) -> io::Result<()> {
+        use Instruction::*;
+
+        writeln!(w, "{}:", block_label(func, &self.name))?;
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            let is_last = i + 1 == self.instructions.len();
+            for (_, comment) in self.comments.iter().filter(|(at, _)| *at == i) {
+                for line in comment.lines() {
+                    writeln!(w, "\t// {}", line)?;
+                }
+            }
+            match *instruction {
+                Constant {
+                    storage,
+                    value,
+                    width,
+                } => {
+                    let n = target(storage, RESULT);
+                    write_mov_immediate(w, n, value, width)?;
+                    store(w, storage, n, width)?;
+                }
+                // The memory is reserved in the prologue of the function.
+                Alloc { .. } => {}
+                Store {
+                    value,
+                    storage,
+                    width,
+                    ..
+                } => {
+                    let n = load(w, value, RIGHT, width)?;
+                    store(w, storage, n, width)?;
+                }
+                Load {
+                    storage, source, ..
+                }
+                | Move { storage, source } => {
+                    let n = load(w, source, RIGHT, Width::Qword)?;
+                    store(w, storage, n, Width::Qword)?;
+                }
+                // Unlike x86, AArch64 reorders plain loads and stores, so every fence needs a
+                // barrier. One waiting for earlier loads suffices for acquire semantics.
+                Fence {
+                    ordering: MemoryOrdering::Acquire,
+                } => writeln!(w, "\tdmb ishld")?,
+                Fence { .. } => writeln!(w, "\tdmb ish")?,
+                Add { left, right, width } => write_binary_op(w, "add", left, right, width)?,
+                FloatConstant { storage, value } => {
+                    write_mov_immediate(w, RESULT, value.to_bits() as i64, Width::Qword)?;
+                    let n = match storage {
+                        ValueRef::Xmm(reg) => float_number(reg),
+                        _ => FLOAT_LEFT,
+                    };
+                    writeln!(w, "\tfmov d{}, x{}", n, RESULT)?;
+                    store_float(w, storage, n)?;
+                }
+                FloatAdd { left, right } => write_float_op(w, "fadd", left, right)?,
+                FloatSubtract { left, right } => write_float_op(w, "fsub", left, right)?,
+                FloatMultiply { left, right } => write_float_op(w, "fmul", left, right)?,
+                FloatDivide { left, right } => write_float_op(w, "fdiv", left, right)?,
+                Subtract { left, right, width } => write_binary_op(w, "sub", left, right, width)?,
+                Multiply { left, right } => write_binary_op(w, "mul", left, right, Width::Qword)?,
+                Divide { left, right } => write_division(w, "sdiv", left, right, false)?,
+                Modulo { left, right } => write_division(w, "sdiv", left, right, true)?,
+                UnsignedDivide { left, right } => write_division(w, "udiv", left, right, false)?,
+                UnsignedModulo { left, right } => write_division(w, "udiv", left, right, true)?,
+                And { left, right } => write_binary_op(w, "and", left, right, Width::Qword)?,
+                Or { left, right } => write_binary_op(w, "orr", left, right, Width::Qword)?,
+                Xor { left, right } => write_binary_op(w, "eor", left, right, Width::Qword)?,
+                TableLoad {
+                    storage,
+                    ref table,
+                    index,
+                    elem_size,
+                } => {
+                    let n = target(storage, RESULT);
+                    let index = load(w, index, LEFT, Width::Qword)?;
+                    writeln!(w, "\tadrp x{}, {}", RIGHT, table)?;
+                    writeln!(w, "\tadd x{0}, x{0}, :lo12:{1}", RIGHT, table)?;
+                    let (op, shift) = match elem_size {
+                        8 => ("ldr", ", lsl #3"),
+                        4 => ("ldrsw", ", lsl #2"),
+                        2 => ("ldrsh", ", lsl #1"),
+                        _ => ("ldrsb", ""),
+                    };
+                    writeln!(w, "\t{} x{}, [x{}, x{}{}]", op, n, RIGHT, index, shift)?;
+                    store(w, storage, n, Width::Qword)?;
+                }
+                // Unlike x86 there is no need for a table in memory, a chain of `csel`
+                // handles any number of values.
+                SelectIndex {
+                    storage,
+                    index,
+                    ref values,
+                    ..
+                } => {
+                    let n = target(storage, RESULT);
+                    let index = load(w, index, LEFT, Width::Qword)?;
+                    let first = load(w, values[0], RIGHT, Width::Qword)?;
+                    writeln!(w, "\tmov x{}, x{}", n, first)?;
+                    for (i, &value) in values.iter().enumerate().skip(1) {
+                        let value = load(w, value, RIGHT, Width::Qword)?;
+                        writeln!(w, "\tcmp x{}, #{}", index, i)?;
+                        writeln!(w, "\tcsel x{0}, x{1}, x{0}, eq", n, value)?;
+                    }
+                    store(w, storage, n, Width::Qword)?;
+                }
+                Select {
+                    storage,
+                    cond,
+                    if_true,
+                    if_false,
+                } => {
+                    let n = target(storage, RESULT);
+                    // Loading the other values leaves the flags alone.
+                    let cond = load(w, cond, RIGHT, Width::Qword)?;
+                    writeln!(w, "\tcmp x{}, #0", cond)?;
+                    let if_true = load(w, if_true, LEFT, Width::Qword)?;
+                    let if_false = load(w, if_false, RIGHT, Width::Qword)?;
+                    writeln!(w, "\tcsel x{}, x{}, x{}, ne", n, if_true, if_false)?;
+                    store(w, storage, n, Width::Qword)?;
+                }
+                Equal { left, right } => write_comparison(w, "eq", left, right)?,
+                NotEqual { left, right } => write_comparison(w, "ne", left, right)?,
+                LessThan { left, right } => write_comparison(w, "lt", left, right)?,
+                GreaterThan { left, right } => write_comparison(w, "gt", left, right)?,
+                LessEqual { left, right } => write_comparison(w, "le", left, right)?,
+                GreaterEqual { left, right } => write_comparison(w, "ge", left, right)?,
+                Minimum { left, right } => write_select(w, "lt", left, right)?,
+                Maximum { left, right } => write_select(w, "gt", left, right)?,
+                Negate { value } => {
+                    let n = load(w, value, LEFT, Width::Qword)?;
+                    writeln!(w, "\tneg x{0}, x{0}", n)?;
+                    store(w, value, n, Width::Qword)?;
+                }
+                ShiftLeft { left, count } => write_shift(w, "lsl", left, count)?,
+                ShiftRight { left, count } => write_shift(w, "lsr", left, count)?,
+                // The base instruction set has no population count, it is done with the
+                // SIMD registers, which are always available.
+                Popcount { storage, value } => {
+                    let n = target(storage, RESULT);
+                    let value = load(w, value, LEFT, Width::Qword)?;
+                    writeln!(w, "\tfmov d0, x{}", value)?;
+                    writeln!(w, "\tcnt v0.8b, v0.8b")?;
+                    writeln!(w, "\taddv b0, v0.8b")?;
+                    writeln!(w, "\tfmov x{}, d0", n)?;
+                    store(w, storage, n, Width::Qword)?;
+                }
+                Jump { ref dest } => {
+                    if !is_last || next != Some(dest) {
+                        writeln!(w, "\tb {}", block_label(func, dest))?;
+                    }
+                }
+                JumpIndirect { target } => {
+                    let n = load(w, target, LEFT, Width::Qword)?;
+                    writeln!(w, "\tbr x{}", n)?;
+                }
+                TailCallIndirect { target, arg } => {
+                    // The target is kept out of the registers the frame teardown uses.
+                    let n = load(w, target, RESULT, Width::Qword)?;
+                    if n != RESULT {
+                        writeln!(w, "\tmov x{}, x{}", RESULT, n)?;
+                    }
+                    if let Some(arg) = arg {
+                        let n = load(w, arg, 0, Width::Qword)?;
+                        store(w, ValueRef::Register(Register::Rdi), n, Width::Qword)?;
+                    }
+                    write_teardown(w, frame)?;
+                    writeln!(w, "\tbr x{}", RESULT)?;
+                }
+                JumpIfZero { value, ref dest } => {
+                    let n = load(w, value, LEFT, Width::Qword)?;
+                    writeln!(w, "\tcbz x{}, {}", n, block_label(func, dest))?;
+                }
+                JumpIfNotZero { value, ref dest } => {
+                    let n = load(w, value, LEFT, Width::Qword)?;
+                    writeln!(w, "\tcbnz x{}, {}", n, block_label(func, dest))?;
+                }
+                // The register reserved for the index on x86-64 isn't needed here.
+                Switch {
+                    value,
+                    ref cases,
+                    ref default,
+                    table: Some(_),
+                } => {
+                    let (min, len) = switch_table_range(cases).expect("switch is not dense");
+                    let table = format!(".L{}.switch{}", block_label(func, &self.name), i);
+                    let n = load(w, value, LEFT, Width::Qword)?;
+                    write_mov_immediate(w, RIGHT, min, Width::Qword)?;
+                    writeln!(w, "\tsub x{}, x{}, x{}", RESULT, n, RIGHT)?;
+                    write_mov_immediate(w, RIGHT, len as i64 - 1, Width::Qword)?;
+                    writeln!(w, "\tcmp x{}, x{}", RESULT, RIGHT)?;
+                    writeln!(w, "\tb.hi {}", block_label(func, default))?;
+                    writeln!(w, "\tadr x{}, {}", ADDRESS, table)?;
+                    writeln!(w, "\tldr x{}, [x{}, x{}, lsl #3]", RIGHT, ADDRESS, RESULT)?;
+                    writeln!(w, "\tadd x{0}, x{0}, x{1}", ADDRESS, RIGHT)?;
+                    writeln!(w, "\tbr x{}", ADDRESS)?;
+                    writeln!(w, "\t.balign 8")?;
+                    writeln!(w, "{}:", table)?;
+                    for entry in min..min + len as i64 {
+                        let dest = cases
+                            .iter()
+                            .find(|&&(case, _)| case == entry)
+                            .map_or(default, |(_, dest)| dest);
+                        writeln!(w, "\t.quad {} - {}", block_label(func, dest), table)?;
+                    }
+                }
+                Switch {
+                    value,
+                    ref cases,
+                    ref default,
+                    table: None,
+                } => {
+                    let n = load(w, value, LEFT, Width::Qword)?;
+                    for (case, dest) in cases {
+                        write_mov_immediate(w, RIGHT, *case, Width::Qword)?;
+                        writeln!(w, "\tcmp x{}, x{}", n, RIGHT)?;
+                        writeln!(w, "\tb.eq {}", block_label(func, dest))?;
+                    }
+                    if !is_last || next != Some(default) {
+                        writeln!(w, "\tb {}", block_label(func, default))?;
+                    }
+                }
+                Call {
+                    ref func,
+                    ref args,
+                    ref preserve,
+                    ref preserve_xmm,
+                    result,
+                    float_result,
+                } => {
+                    // Values are returned in X0, which RDI maps to, or D0.
+                    let returned = result.filter(|_| !float_result).map(|_| Register::Rdi);
+                    let preserve = &options.call_saved(func, args, preserve, returned);
+                    // Every register takes a whole 16 bytes to keep the stack aligned.
+                    for &reg in preserve {
+                        writeln!(w, "\tstr x{}, [sp, #-16]!", number(reg))?;
+                    }
+                    // The SIMD registers standing in for SSE registers are caller-saved too.
+                    for &reg in preserve_xmm {
+                        writeln!(w, "\tstr d{}, [sp, #-16]!", float_number(reg))?;
+                    }
+                    write_call_args(w, args)?;
+                    writeln!(w, "\tbl {}", func)?;
+                    // Nothing after a call to a function that never returns is reachable.
+                    if options.noreturn.contains(&func.as_str()) {
+                        break;
+                    }
+                    match result {
+                        Some(result) if float_result => store_float(w, result, 0)?,
+                        Some(result) => store(w, result, 0, Width::Qword)?,
+                        None => {}
+                    }
+                    for &reg in preserve_xmm.iter().rev() {
+                        writeln!(w, "\tldr d{}, [sp], #16", float_number(reg))?;
+                    }
+                    for &reg in preserve.iter().rev() {
+                        writeln!(w, "\tldr x{}, [sp], #16", number(reg))?;
+                    }
+                }
+                SaveContext { context } => {
+                    let resume = self.resume_label(func, context)?;
+                    let context = context_offset("save_context", context)?;
+                    for (i, &n) in CONTEXT_REGS.iter().enumerate() {
+                        let slot = context_slot(w, context, i)?;
+                        writeln!(w, "\tstr x{}, {}", n, slot)?;
+                    }
+                    writeln!(w, "\tmov x{}, sp", LEFT)?;
+                    let slot = context_slot(w, context, CONTEXT_REGS.len())?;
+                    writeln!(w, "\tstr x{}, {}", LEFT, slot)?;
+                    writeln!(w, "\tadr x{}, {}", LEFT, resume)?;
+                    let slot = context_slot(w, context, CONTEXT_REGS.len() + 1)?;
+                    writeln!(w, "\tstr x{}, {}", LEFT, slot)?;
+                    writeln!(w, "{}:", resume)?;
+                }
+                RestoreContext { context } => {
+                    let context = context_offset("restore_context", context)?;
+                    // The context is addressed relative to X29, so it is restored last.
+                    let slot = context_slot(w, context, CONTEXT_REGS.len() + 1)?;
+                    writeln!(w, "\tldr x{}, {}", LEFT, slot)?;
+                    for (i, &n) in CONTEXT_REGS.iter().enumerate() {
+                        if n != 29 {
+                            let slot = context_slot(w, context, i)?;
+                            writeln!(w, "\tldr x{}, {}", n, slot)?;
+                        }
+                    }
+                    let slot = context_slot(w, context, CONTEXT_REGS.len())?;
+                    writeln!(w, "\tldr x{}, {}", RIGHT, slot)?;
+                    writeln!(w, "\tmov sp, x{}", RIGHT)?;
+                    let slot = context_slot(w, context, 1)?;
+                    writeln!(w, "\tldr x29, {}", slot)?;
+                    writeln!(w, "\tbr x{}", LEFT)?;
+                }
+                // Only the argument registers are overwritten by the system call.
+                PrintString {
+                    string,
+                    ref preserve,
+                } => {
+                    let label = match string {
+                        ValueRef::Global(label) => label,
+                        _ => {
+                            return Err(CodegenError::InvalidOperand {
+                                instruction: "print_string",
+                                operand: string,
+                            }
+                            .into())
+                        }
+                    };
+                    for &reg in preserve {
+                        writeln!(w, "\tstr x{}, [sp, #-16]!", number(reg))?;
+                    }
+                    writeln!(w, "\tmov x0, #1")?;
+                    writeln!(w, "\tadrp x1, {}", label)?;
+                    writeln!(w, "\tadd x1, x1, :lo12:{}", label)?;
+                    writeln!(w, "\tldr x2, [x1], #8")?;
+                    writeln!(w, "\tmov x8, #64")?;
+                    writeln!(w, "\tsvc #0")?;
+                    for &reg in preserve.iter().rev() {
+                        writeln!(w, "\tldr x{}, [sp], #16", number(reg))?;
+                    }
+                }
+                Exit { exit_code } => {
+                    let n = load(w, exit_code, 0, Width::Qword)?;
+                    store(w, ValueRef::Register(Register::Rdi), n, Width::Qword)?;
+                    writeln!(w, "\tmov x8, #93")?;
+                    writeln!(w, "\tsvc #0")?;
+                }
+                Return { value } => {
+                    match value {
+                        Some(ValueRef::Xmm(reg)) => {
+                            writeln!(w, "\tfmov d0, d{}", float_number(reg))?
+                        }
+                        Some(value) => {
+                            let n = load(w, value, 0, Width::Qword)?;
+                            store(w, ValueRef::Register(Register::Rdi), n, Width::Qword)?;
+                        }
+                        None => {}
+                    }
+                    write_teardown(w, frame)?;
+                    writeln!(w, "\tret")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ir::{Module, Target, Value},
+        testing::{has_tool, report_skipped},
+    };
+    use std::process::{Command, Stdio};
+
+    /// The AArch64 code of `func`, generated with the settings of a default module.
+    fn code(func: &Function) -> String {
+        let mut module = Module::default();
+        module.set_target(Target::Aarch64);
+        let mut code = vec![];
+        func.generate_aarch64(&mut code, &module.codegen_options())
+            .unwrap();
+        String::from_utf8(code).unwrap()
+    }
+
+    /// A function `f` that never returns, so it has neither saved registers nor an
+    /// epilogue, with a single block built by `build`.
+    fn noreturn(build: impl FnOnce(&mut Block)) -> Function {
+        let mut func = Function::new("f".into());
+        func.set_noreturn(true);
+        let mut block = func.new_block(".entry".into());
+        build(&mut block);
+        func.append_block(block);
+        func
+    }
+
+    /// Assemble `code` into an object file with `llvm-mc` to check that it is valid.
+    fn assemble(test: &str, code: &str) {
+        if !has_tool("llvm-mc") {
+            return report_skipped(test, "llvm-mc");
+        }
+        let mut child = Command::new("llvm-mc")
+            .args([
+                "-triple=aarch64-linux-gnu",
+                "-filetype=obj",
+                "-o",
+                "/dev/null",
+            ])
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(code.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(
+            output.status.success(),
+            "{}\n{}",
+            String::from_utf8_lossy(&output.stderr),
+            code
+        );
+    }
+
+    /// Check the code of each of `cases`, a description, a function and its code, and
+    /// that the code assembles. Setting `FIG_PRINT_GOLDEN` prints the code of every case
+    /// instead, to update the expected code after a deliberate change.
+    fn check(test: &str, cases: Vec<(&str, Function, &str)>) {
+        for (description, func, expected) in cases {
+            let code = code(&func);
+            if std::env::var_os("FIG_PRINT_GOLDEN").is_some() {
+                println!("=== {}\n{}", description, code);
+                continue;
+            }
+            assert_eq!(code, expected, "{}", description);
+            assemble(test, &code);
+        }
+    }
+
+    #[test]
+    fn data_movement() {
+        check(
+            "data_movement",
+            vec![
+                (
+                    "constant",
+                    noreturn(|block| {
+                        block.build_constant(0x1234_5678_9abc);
+                        block.build_constant(-2);
+                        block.build_constant_sized(7, Width::Dword);
+                    }),
+                    "\t.global f\n\
+                     f:\n\
+                     f.entry:\n\
+                     \tmovz x23, #39612\n\
+                     \tmovk x23, #22136, lsl #16\n\
+                     \tmovk x23, #4660, lsl #32\n\
+                     \tmovz x22, #65534\n\
+                     \tmovk x22, #65535, lsl #16\n\
+                     \tmovk x22, #65535, lsl #32\n\
+                     \tmovk x22, #65535, lsl #48\n\
+                     \tmovz w21, #7\n",
+                ),
+                (
+                    "alloc, store, load and move",
+                    noreturn(|block| {
+                        let memory = block.build_alloc(16);
+                        let value = block.build_constant(5);
+                        block.build_store(value, memory);
+                        let value = block.build_constant(6);
+                        block.build_store_sized(value, memory, Width::Dword);
+                        let value = block.build_load(memory);
+                        block.build_move(value, memory);
+                    }),
+                    "\t.global f\n\
+                     f:\n\
+                     \tstp x29, x30, [sp, #-16]!\n\
+                     \tmov x29, sp\n\
+                     \tsub sp, sp, #16\n\
+                     f.entry:\n\
+                     \tmovz x23, #5\n\
+                     \tstr x23, [x29, #-16]\n\
+                     \tmovz x23, #6\n\
+                     \tstr w23, [x29, #-16]\n\
+                     \tldr x17, [x29, #-16]\n\
+                     \tmov x23, x17\n\
+                     \tstr x23, [x29, #-16]\n",
+                ),
+                (
+                    "fence",
+                    noreturn(|block| {
+                        block.build_fence(MemoryOrdering::Acquire);
+                        block.build_fence(MemoryOrdering::Release);
+                        block.build_fence(MemoryOrdering::SeqCst);
+                    }),
+                    "\t.global f\n\
+                     f:\n\
+                     f.entry:\n\
+                     \tdmb ishld\n\
+                     \tdmb ish\n\
+                     \tdmb ish\n",
+                ),
+                (
+                    "table load",
+                    noreturn(|block| {
+                        for elem_size in [1, 2, 4, 8] {
+                            let index = block.build_constant(3);
+                            block.build_table_load("table", index, elem_size);
+                        }
+                    }),
+                    "\t.global f\n\
+                     f:\n\
+                     f.entry:\n\
+                     \tmovz x23, #3\n\
+                     \tadrp x17, table\n\
+                     \tadd x17, x17, :lo12:table\n\
+                     \tldrsb x23, [x17, x23]\n\
+                     \tmovz x22, #3\n\
+                     \tadrp x17, table\n\
+                     \tadd x17, x17, :lo12:table\n\
+                     \tldrsh x22, [x17, x22, lsl #1]\n\
+                     \tmovz x21, #3\n\
+                     \tadrp x17, table\n\
+                     \tadd x17, x17, :lo12:table\n\
+                     \tldrsw x21, [x17, x21, lsl #2]\n\
+                     \tmovz x20, #3\n\
+                     \tadrp x17, table\n\
+                     \tadd x17, x17, :lo12:table\n\
+                     \tldr x20, [x17, x20, lsl #3]\n",
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn arithmetic() {
+        type BuildBinary = fn(&mut Block, ValueRef, ValueRef) -> ValueRef;
+        let binary = |build: BuildBinary| {
+            noreturn(move |block| {
+                let memory = block.build_alloc(8);
+                let left = block.build_constant(12);
+                let right = block.build_constant(5);
+                let result = build(block, left, right);
+                build(block, result, memory);
+            })
+        };
+        check(
+            "arithmetic",
+            vec![
+                (
+                    "add",
+                    noreturn(|block| {
+                        let left = block.build_constant(1);
+                        let right = block.build_constant(2);
+                        let sum = block.build_add(left, right);
+                        let sum = block.build_add(sum, ValueRef::Immediate(-3));
+                        block.build_add_sized(sum, ValueRef::Immediate(4), Width::Dword);
+                    }),
+                    "\t.global f\n\
+                     f:\n\
+                     f.entry:\n\
+                     \tmovz x23, #1\n\
+                     \tmovz x22, #2\n\
+                     \tadd x23, x23, x22\n\
+                     \tmovz x17, #65533\n\
+                     \tmovk x17, #65535, lsl #16\n\
+                     \tmovk x17, #65535, lsl #32\n\
+                     \tmovk x17, #65535, lsl #48\n\
+                     \tadd x23, x23, x17\n\
+                     \tmovz w17, #4\n\
+                     \tadd w23, w23, w17\n",
+                ),
+                (
+                    "subtract",
+                    noreturn(|block| {
+                        let left = block.build_constant(1);
+                        let right = block.build_constant(2);
+                        let difference = block.build_subtract(left, right);
+                        block.build_subtract_sized(
+                            difference,
+                            ValueRef::Immediate(3),
+                            Width::Dword,
+                        );
+                    }),
+                    "\t.global f\n\
+                     f:\n\
+                     f.entry:\n\
+                     \tmovz x23, #1\n\
+                     \tmovz x22, #2\n\
+                     \tsub x23, x23, x22\n\
+                     \tmovz w17, #3\n\
+                     \tsub w23, w23, w17\n",
+                ),
+                ("multiply", binary(Block::build_multiply), "\t.global f\n\
+                                                             f:\n\
+                                                             \tstp x29, x30, [sp, #-16]!\n\
+                                                             \tmov x29, sp\n\
+                                                             \tsub sp, sp, #16\n\
+                                                             f.entry:\n\
+                                                             \tmovz x23, #12\n\
+                                                             \tmovz x22, #5\n\
+                                                             \tmul x23, x23, x22\n\
+                                                             \tldr x17, [x29, #-16]\n\
+                                                             \tmul x23, x23, x17\n"),
+                ("divide", binary(Block::build_divide), "\t.global f\n\
+                                                         f:\n\
+                                                         \tstp x29, x30, [sp, #-16]!\n\
+                                                         \tmov x29, sp\n\
+                                                         \tsub sp, sp, #16\n\
+                                                         f.entry:\n\
+                                                         \tmovz x23, #12\n\
+                                                         \tmovz x22, #5\n\
+                                                         \tsdiv x23, x23, x22\n\
+                                                         \tldr x17, [x29, #-16]\n\
+                                                         \tsdiv x23, x23, x17\n"),
+                ("modulo", binary(Block::build_modulo), "\t.global f\n\
+                                                         f:\n\
+                                                         \tstp x29, x30, [sp, #-16]!\n\
+                                                         \tmov x29, sp\n\
+                                                         \tsub sp, sp, #16\n\
+                                                         f.entry:\n\
+                                                         \tmovz x23, #12\n\
+                                                         \tmovz x22, #5\n\
+                                                         \tsdiv x14, x23, x22\n\
+                                                         \tmsub x23, x14, x22, x23\n\
+                                                         \tldr x17, [x29, #-16]\n\
+                                                         \tsdiv x14, x23, x17\n\
+                                                         \tmsub x23, x14, x17, x23\n"),
+                ("unsigned divide", binary(Block::build_unsigned_divide), "\t.global f\n\
+                                                                           f:\n\
+                                                                           \tstp x29, x30, [sp, #-16]!\n\
+                                                                           \tmov x29, sp\n\
+                                                                           \tsub sp, sp, #16\n\
+                                                                           f.entry:\n\
+                                                                           \tmovz x23, #12\n\
+                                                                           \tmovz x22, #5\n\
+                                                                           \tudiv x23, x23, x22\n\
+                                                                           \tldr x17, [x29, #-16]\n\
+                                                                           \tudiv x23, x23, x17\n"),
+                ("unsigned modulo", binary(Block::build_unsigned_modulo), "\t.global f\n\
+                                                                           f:\n\
+                                                                           \tstp x29, x30, [sp, #-16]!\n\
+                                                                           \tmov x29, sp\n\
+                                                                           \tsub sp, sp, #16\n\
+                                                                           f.entry:\n\
+                                                                           \tmovz x23, #12\n\
+                                                                           \tmovz x22, #5\n\
+                                                                           \tudiv x14, x23, x22\n\
+                                                                           \tmsub x23, x14, x22, x23\n\
+                                                                           \tldr x17, [x29, #-16]\n\
+                                                                           \tudiv x14, x23, x17\n\
+                                                                           \tmsub x23, x14, x17, x23\n"),
+                ("and", binary(Block::build_and), "\t.global f\n\
+                                                   f:\n\
+                                                   \tstp x29, x30, [sp, #-16]!\n\
+                                                   \tmov x29, sp\n\
+                                                   \tsub sp, sp, #16\n\
+                                                   f.entry:\n\
+                                                   \tmovz x23, #12\n\
+                                                   \tmovz x22, #5\n\
+                                                   \tand x23, x23, x22\n\
+                                                   \tldr x17, [x29, #-16]\n\
+                                                   \tand x23, x23, x17\n"),
+                ("or", binary(Block::build_or), "\t.global f\n\
+                                                 f:\n\
+                                                 \tstp x29, x30, [sp, #-16]!\n\
+                                                 \tmov x29, sp\n\
+                                                 \tsub sp, sp, #16\n\
+                                                 f.entry:\n\
+                                                 \tmovz x23, #12\n\
+                                                 \tmovz x22, #5\n\
+                                                 \torr x23, x23, x22\n\
+                                                 \tldr x17, [x29, #-16]\n\
+                                                 \torr x23, x23, x17\n"),
+                ("xor", binary(Block::build_xor), "\t.global f\n\
+                                                   f:\n\
+                                                   \tstp x29, x30, [sp, #-16]!\n\
+                                                   \tmov x29, sp\n\
+                                                   \tsub sp, sp, #16\n\
+                                                   f.entry:\n\
+                                                   \tmovz x23, #12\n\
+                                                   \tmovz x22, #5\n\
+                                                   \teor x23, x23, x22\n\
+                                                   \tldr x17, [x29, #-16]\n\
+                                                   \teor x23, x23, x17\n"),
+                (
+                    "negate, shifts and popcount",
+                    noreturn(|block| {
+                        let value = block.build_constant(-8);
+                        let value = block.build_negate(value);
+                        let count = block.build_constant(3);
+                        let value = block.build_shift_left(value, count);
+                        let count = block.build_load(ValueRef::global("count").unwrap());
+                        let value = block.build_shift_right(value, count);
+                        block.build_popcount(value);
+                    }),
+                    "\t.global f\n\
+                     f:\n\
+                     f.entry:\n\
+                     \tmovz x23, #65528\n\
+                     \tmovk x23, #65535, lsl #16\n\
+                     \tmovk x23, #65535, lsl #32\n\
+                     \tmovk x23, #65535, lsl #48\n\
+                     \tneg x23, x23\n\
+                     \tmovz x22, #3\n\
+                     \tlsl x23, x23, #3\n\
+                     \tadrp x15, count\n\
+                     \tldr x17, [x15, :lo12:count]\n\
+                     \tmov x22, x17\n\
+                     \tlsr x23, x23, x22\n\
+                     \tfmov d0, x23\n\
+                     \tcnt v0.8b, v0.8b\n\
+                     \taddv b0, v0.8b\n\
+                     \tfmov x22, d0\n",
+                ),
+                (
+                    "floating point",
+                    noreturn(|block| {
+                        let left = block.build_float_constant(1.5);
+                        let right = block.build_float_constant(-0.25);
+                        let result = block.build_fadd(left, right);
+                        let right = block.build_float_constant(2.0);
+                        let result = block.build_fsub(result, right);
+                        let right = block.build_float_constant(3.0);
+                        let result = block.build_fmul(result, right);
+                        let right = block.build_float_constant(4.0);
+                        block.build_fdiv(result, right);
+                    }),
+                    "\t.global f\n\
+                     f:\n\
+                     f.entry:\n\
+                     \tmovz x14, #0\n\
+                     \tmovk x14, #16376, lsl #48\n\
+                     \tfmov d16, x14\n\
+                     \tmovz x14, #0\n\
+                     \tmovk x14, #49104, lsl #48\n\
+                     \tfmov d17, x14\n\
+                     \tfadd d16, d16, d17\n\
+                     \tmovz x14, #0\n\
+                     \tmovk x14, #16384, lsl #48\n\
+                     \tfmov d17, x14\n\
+                     \tfsub d16, d16, d17\n\
+                     \tmovz x14, #0\n\
+                     \tmovk x14, #16392, lsl #48\n\
+                     \tfmov d17, x14\n\
+                     \tfmul d16, d16, d17\n\
+                     \tmovz x14, #0\n\
+                     \tmovk x14, #16400, lsl #48\n\
+                     \tfmov d17, x14\n\
+                     \tfdiv d16, d16, d17\n",
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn comparisons_and_selection() {
+        type BuildBinary = fn(&mut Block, ValueRef, ValueRef) -> ValueRef;
+        let compare = |build: BuildBinary| {
+            noreturn(move |block| {
+                let left = block.build_constant(1);
+                let right = block.build_constant(2);
+                build(block, left, right);
+            })
+        };
+        check(
+            "comparisons_and_selection",
+            vec![
+                (
+                    "equal",
+                    compare(Block::build_equal),
+                    "\t.global f\n\
+                                                        f:\n\
+                                                        f.entry:\n\
+                                                        \tmovz x23, #1\n\
+                                                        \tmovz x22, #2\n\
+                                                        \tcmp x23, x22\n\
+                                                        \tcset x23, eq\n",
+                ),
+                (
+                    "not equal",
+                    compare(Block::build_not_equal),
+                    "\t.global f\n\
+                                                                f:\n\
+                                                                f.entry:\n\
+                                                                \tmovz x23, #1\n\
+                                                                \tmovz x22, #2\n\
+                                                                \tcmp x23, x22\n\
+                                                                \tcset x23, ne\n",
+                ),
+                (
+                    "less than",
+                    compare(Block::build_less_than),
+                    "\t.global f\n\
+                                                                f:\n\
+                                                                f.entry:\n\
+                                                                \tmovz x23, #1\n\
+                                                                \tmovz x22, #2\n\
+                                                                \tcmp x23, x22\n\
+                                                                \tcset x23, lt\n",
+                ),
+                (
+                    "greater than",
+                    compare(Block::build_greater_than),
+                    "\t.global f\n\
+                                                                      f:\n\
+                                                                      f.entry:\n\
+                                                                      \tmovz x23, #1\n\
+                                                                      \tmovz x22, #2\n\
+                                                                      \tcmp x23, x22\n\
+                                                                      \tcset x23, gt\n",
+                ),
+                (
+                    "less or equal",
+                    compare(Block::build_less_equal),
+                    "\t.global f\n\
+                                                                     f:\n\
+                                                                     f.entry:\n\
+                                                                     \tmovz x23, #1\n\
+                                                                     \tmovz x22, #2\n\
+                                                                     \tcmp x23, x22\n\
+                                                                     \tcset x23, le\n",
+                ),
+                (
+                    "greater or equal",
+                    compare(Block::build_greater_equal),
+                    "\t.global f\n\
+                                                                           f:\n\
+                                                                           f.entry:\n\
+                                                                           \tmovz x23, #1\n\
+                                                                           \tmovz x22, #2\n\
+                                                                           \tcmp x23, x22\n\
+                                                                           \tcset x23, ge\n",
+                ),
+                (
+                    "minimum",
+                    compare(Block::build_min),
+                    "\t.global f\n\
+                                                        f:\n\
+                                                        f.entry:\n\
+                                                        \tmovz x23, #1\n\
+                                                        \tmovz x22, #2\n\
+                                                        \tcmp x23, x22\n\
+                                                        \tcsel x23, x23, x22, lt\n",
+                ),
+                (
+                    "maximum",
+                    compare(Block::build_max),
+                    "\t.global f\n\
+                                                        f:\n\
+                                                        f.entry:\n\
+                                                        \tmovz x23, #1\n\
+                                                        \tmovz x22, #2\n\
+                                                        \tcmp x23, x22\n\
+                                                        \tcsel x23, x23, x22, gt\n",
+                ),
+                (
+                    "select",
+                    noreturn(|block| {
+                        let memory = block.build_alloc(8);
+                        let cond = block.build_constant(1);
+                        let if_true = block.build_constant(2);
+                        block.build_select(cond, if_true, memory);
+                    }),
+                    "\t.global f\n\
+                     f:\n\
+                     \tstp x29, x30, [sp, #-16]!\n\
+                     \tmov x29, sp\n\
+                     \tsub sp, sp, #16\n\
+                     f.entry:\n\
+                     \tmovz x23, #1\n\
+                     \tmovz x22, #2\n\
+                     \tcmp x23, #0\n\
+                     \tldr x17, [x29, #-16]\n\
+                     \tcsel x21, x22, x17, ne\n",
+                ),
+                (
+                    "select index",
+                    noreturn(|block| {
+                        let index = block.build_constant(2);
+                        let values: Vec<ValueRef> =
+                            (0..3).map(|value| block.build_constant(value)).collect();
+                        block.build_select_index(index, &values).unwrap();
+                    }),
+                    "\t.global f\n\
+                     f:\n\
+                     f.entry:\n\
+                     \tmovz x23, #2\n\
+                     \tmovz x22, #0\n\
+                     \tmovz x21, #1\n\
+                     \tmovz x20, #2\n\
+                     \tmov x10, x22\n\
+                     \tcmp x23, #1\n\
+                     \tcsel x10, x21, x10, eq\n\
+                     \tcmp x23, #2\n\
+                     \tcsel x10, x20, x10, eq\n",
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn control_flow() {
+        let mut branches = Function::new("f".into());
+        branches.set_noreturn(true);
+        let mut entry = branches.new_block(".entry".into());
+        let value = entry.build_constant(1);
+        entry.build_jump_if_zero(value, ".zero".into());
+        let mut nonzero = branches.new_block(".nonzero".into());
+        let value = nonzero.build_constant(2);
+        nonzero.build_jump_if_not_zero(value, ".entry".into());
+        nonzero.build_jump(".zero".into());
+        let mut zero = branches.new_block(".zero".into());
+        let target = zero.build_load(ValueRef::global("target").unwrap());
+        zero.build_jump_indirect(target);
+        for block in [entry, nonzero, zero] {
+            branches.append_block(block);
+        }
+
+        let switch = |cases: &[Value]| {
+            let mut func = Function::new("f".into());
+            func.set_noreturn(true);
+            let mut entry = func.new_block(".entry".into());
+            let value = entry.build_load(ValueRef::global("value").unwrap());
+            let cases: Vec<(Value, String)> =
+                cases.iter().map(|&case| (case, ".case".into())).collect();
+            entry
+                .build_switch(value, &cases, ".default".into())
+                .unwrap();
+            func.append_block(entry);
+            for name in [".case", ".default"] {
+                let mut block = func.new_block(name.into());
+                block.build_jump(".entry".into());
+                func.append_block(block);
+            }
+            func
+        };
+        check(
+            "control_flow",
+            vec![
+                ("jumps", branches, "\t.global f\n\
+                                     f:\n\
+                                     f.entry:\n\
+                                     \tmovz x23, #1\n\
+                                     \tcbz x23, f.zero\n\
+                                     f.nonzero:\n\
+                                     \tmovz x23, #2\n\
+                                     \tcbnz x23, f.entry\n\
+                                     f.zero:\n\
+                                     \tadrp x15, target\n\
+                                     \tldr x17, [x15, :lo12:target]\n\
+                                     \tmov x23, x17\n\
+                                     \tbr x23\n"),
+                ("switch with a jump table", switch(&[1, 2, 4]), "\t.global f\n\
+                                                                  f:\n\
+                                                                  f.entry:\n\
+                                                                  \tadrp x15, value\n\
+                                                                  \tldr x17, [x15, :lo12:value]\n\
+                                                                  \tmov x23, x17\n\
+                                                                  \tmovz x17, #1\n\
+                                                                  \tsub x14, x23, x17\n\
+                                                                  \tmovz x17, #3\n\
+                                                                  \tcmp x14, x17\n\
+                                                                  \tb.hi f.default\n\
+                                                                  \tadr x15, .Lf.entry.switch1\n\
+                                                                  \tldr x17, [x15, x14, lsl #3]\n\
+                                                                  \tadd x15, x15, x17\n\
+                                                                  \tbr x15\n\
+                                                                  \t.balign 8\n\
+                                                                  .Lf.entry.switch1:\n\
+                                                                  \t.quad f.case - .Lf.entry.switch1\n\
+                                                                  \t.quad f.case - .Lf.entry.switch1\n\
+                                                                  \t.quad f.default - .Lf.entry.switch1\n\
+                                                                  \t.quad f.case - .Lf.entry.switch1\n\
+                                                                  f.case:\n\
+                                                                  \tb f.entry\n\
+                                                                  f.default:\n\
+                                                                  \tb f.entry\n"),
+                ("switch comparing cases", switch(&[1, 100]), "\t.global f\n\
+                                                               f:\n\
+                                                               f.entry:\n\
+                                                               \tadrp x15, value\n\
+                                                               \tldr x17, [x15, :lo12:value]\n\
+                                                               \tmov x23, x17\n\
+                                                               \tmovz x17, #1\n\
+                                                               \tcmp x23, x17\n\
+                                                               \tb.eq f.case\n\
+                                                               \tmovz x17, #100\n\
+                                                               \tcmp x23, x17\n\
+                                                               \tb.eq f.case\n\
+                                                               \tb f.default\n\
+                                                               f.case:\n\
+                                                               \tb f.entry\n\
+                                                               f.default:\n\
+                                                               \tb f.entry\n"),
+            ],
+        );
+    }
+
+    #[test]
+    fn calls_contexts_and_system_calls() {
+        check(
+            "calls_contexts_and_system_calls",
+            vec![
+                (
+                    "call",
+                    noreturn(|block| {
+                        let live = block.build_constant(1);
+                        let arg = block.build_constant(2);
+                        let result = block
+                            .build_call_with_result("g".into(), &[arg, live])
+                            .unwrap();
+                        block.build_call("h".into(), &[result]).unwrap();
+                    }),
+                    "\t.global f\n\
+                     f:\n\
+                     \tstp x29, x30, [sp, #-16]!\n\
+                     \tmov x29, sp\n\
+                     f.entry:\n\
+                     \tmovz x23, #1\n\
+                     \tmovz x22, #2\n\
+                     \tmov x0, x22\n\
+                     \tmov x1, x23\n\
+                     \tbl g\n\
+                     \tmov x21, x0\n\
+                     \tmov x0, x21\n\
+                     \tbl h\n",
+                ),
+                (
+                    "context",
+                    noreturn(|block| {
+                        let context = block.build_save_context();
+                        block.build_restore_context(context).unwrap();
+                    }),
+                    "\t.global f\n\
+                     f:\n\
+                     \tstp x29, x30, [sp, #-16]!\n\
+                     \tmov x29, sp\n\
+                     \tsub sp, sp, #64\n\
+                     f.entry:\n\
+                     \tstr x19, [x29, #-64]\n\
+                     \tstr x29, [x29, #-56]\n\
+                     \tstr x20, [x29, #-48]\n\
+                     \tstr x21, [x29, #-40]\n\
+                     \tstr x22, [x29, #-32]\n\
+                     \tstr x23, [x29, #-24]\n\
+                     \tmov x16, sp\n\
+                     \tstr x16, [x29, #-16]\n\
+                     \tadr x16, f.entry.resume64\n\
+                     \tstr x16, [x29, #-8]\n\
+                     f.entry.resume64:\n\
+                     \tldr x16, [x29, #-8]\n\
+                     \tldr x19, [x29, #-64]\n\
+                     \tldr x20, [x29, #-48]\n\
+                     \tldr x21, [x29, #-40]\n\
+                     \tldr x22, [x29, #-32]\n\
+                     \tldr x23, [x29, #-24]\n\
+                     \tldr x17, [x29, #-16]\n\
+                     \tmov sp, x17\n\
+                     \tldr x29, [x29, #-56]\n\
+                     \tbr x16\n",
+                ),
+                (
+                    "print string and exit",
+                    noreturn(|block| {
+                        block
+                            .build_print_string(ValueRef::global("greeting").unwrap())
+                            .unwrap();
+                        let exit_code = block.build_constant(3);
+                        block.build_exit(exit_code);
+                    }),
+                    "\t.global f\n\
+                     f:\n\
+                     f.entry:\n\
+                     \tmov x0, #1\n\
+                     \tadrp x1, greeting\n\
+                     \tadd x1, x1, :lo12:greeting\n\
+                     \tldr x2, [x1], #8\n\
+                     \tmov x8, #64\n\
+                     \tsvc #0\n\
+                     \tmovz x23, #3\n\
+                     \tmov x0, x23\n\
+                     \tmov x8, #93\n\
+                     \tsvc #0\n",
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn frames_returns_and_tail_calls() {
+        let function = |protected: bool, tail_call: bool| {
+            let mut func = Function::new("f".into()).with_stack_protector(protected);
+            let mut block = func.new_block(".entry".into());
+            let memory = block.build_alloc(8);
+            let value = block.build_constant(7);
+            block.build_store(value, memory);
+            if tail_call {
+                let target = block.build_load(ValueRef::global("target").unwrap());
+                block.build_tail_call_indirect(target, Some(memory));
+            } else {
+                let value = block.build_load(memory);
+                block.build_return(Some(value));
+            }
+            func.append_block(block);
+            func
+        };
+        check(
+            "frames_returns_and_tail_calls",
+            vec![
+                ("standard frame", function(false, false), "\t.global f\n\
+                                                            f:\n\
+                                                            \tstr x23, [sp, #-16]!\n\
+                                                            \tstp x29, x30, [sp, #-16]!\n\
+                                                            \tmov x29, sp\n\
+                                                            \tsub sp, sp, #16\n\
+                                                            f.entry:\n\
+                                                            \tmovz x23, #7\n\
+                                                            \tstr x23, [x29, #-16]\n\
+                                                            \tldr x17, [x29, #-16]\n\
+                                                            \tmov x23, x17\n\
+                                                            \tmov x0, x23\n\
+                                                            \tmov sp, x29\n\
+                                                            \tldp x29, x30, [sp], #16\n\
+                                                            \tldr x23, [sp], #16\n\
+                                                            \tret\n"),
+                ("protected frame", function(true, false), "\t.global f\n\
+                                                            f:\n\
+                                                            \tstr x23, [sp, #-16]!\n\
+                                                            \tsub sp, sp, #32\n\
+                                                            \tstp x29, x30, [sp, #16]\n\
+                                                            \tmov x29, sp\n\
+                                                            \tadrp x16, __stack_chk_guard\n\
+                                                            \tldr x16, [x16, :lo12:__stack_chk_guard]\n\
+                                                            \tstr x16, [x29]\n\
+                                                            \tsub sp, sp, #16\n\
+                                                            f.entry:\n\
+                                                            \tmovz x23, #7\n\
+                                                            \tstr x23, [x29, #-16]\n\
+                                                            \tldr x17, [x29, #-16]\n\
+                                                            \tmov x23, x17\n\
+                                                            \tmov x0, x23\n\
+                                                            \tldr x16, [x29]\n\
+                                                            \tadrp x17, __stack_chk_guard\n\
+                                                            \tldr x17, [x17, :lo12:__stack_chk_guard]\n\
+                                                            \tcmp x16, x17\n\
+                                                            \tb.ne .Lf.stack_chk_fail\n\
+                                                            \tmov sp, x29\n\
+                                                            \tldp x29, x30, [sp, #16]\n\
+                                                            \tadd sp, sp, #32\n\
+                                                            \tldr x23, [sp], #16\n\
+                                                            \tret\n\
+                                                            .Lf.stack_chk_fail:\n\
+                                                            \tbl __stack_chk_fail\n"),
+                ("tail call from standard frame", function(false, true), "\t.global f\n\
+                                                                          f:\n\
+                                                                          \tstr x23, [sp, #-16]!\n\
+                                                                          \tstp x29, x30, [sp, #-16]!\n\
+                                                                          \tmov x29, sp\n\
+                                                                          \tsub sp, sp, #16\n\
+                                                                          f.entry:\n\
+                                                                          \tmovz x23, #7\n\
+                                                                          \tstr x23, [x29, #-16]\n\
+                                                                          \tadrp x15, target\n\
+                                                                          \tldr x17, [x15, :lo12:target]\n\
+                                                                          \tmov x23, x17\n\
+                                                                          \tmov x14, x23\n\
+                                                                          \tldr x0, [x29, #-16]\n\
+                                                                          \tmov sp, x29\n\
+                                                                          \tldp x29, x30, [sp], #16\n\
+                                                                          \tldr x23, [sp], #16\n\
+                                                                          \tbr x14\n"),
+                ("tail call from protected frame", function(true, true), "\t.global f\n\
+                                                                          f:\n\
+                                                                          \tstr x23, [sp, #-16]!\n\
+                                                                          \tsub sp, sp, #32\n\
+                                                                          \tstp x29, x30, [sp, #16]\n\
+                                                                          \tmov x29, sp\n\
+                                                                          \tadrp x16, __stack_chk_guard\n\
+                                                                          \tldr x16, [x16, :lo12:__stack_chk_guard]\n\
+                                                                          \tstr x16, [x29]\n\
+                                                                          \tsub sp, sp, #16\n\
+                                                                          f.entry:\n\
+                                                                          \tmovz x23, #7\n\
+                                                                          \tstr x23, [x29, #-16]\n\
+                                                                          \tadrp x15, target\n\
+                                                                          \tldr x17, [x15, :lo12:target]\n\
+                                                                          \tmov x23, x17\n\
+                                                                          \tmov x14, x23\n\
+                                                                          \tldr x0, [x29, #-16]\n\
+                                                                          \tldr x16, [x29]\n\
+                                                                          \tadrp x17, __stack_chk_guard\n\
+                                                                          \tldr x17, [x17, :lo12:__stack_chk_guard]\n\
+                                                                          \tcmp x16, x17\n\
+                                                                          \tb.ne .Lf.stack_chk_fail\n\
+                                                                          \tmov sp, x29\n\
+                                                                          \tldp x29, x30, [sp, #16]\n\
+                                                                          \tadd sp, sp, #32\n\
+                                                                          \tldr x23, [sp], #16\n\
+                                                                          \tbr x14\n\
+                                                                          .Lf.stack_chk_fail:\n\
+                                                                          \tbl __stack_chk_fail\n"),
+            ],
+        );
+    }
+}
@@ -0,0 +1,422 @@
+//! Translation of x86-64 code in NASM syntax into the AT&T syntax of the GNU assembler.
+//!
+//! Only the subset of NASM written by the code generator and the runtime library is
+//! understood: one instruction, label, directive or comment per line, and memory operands
+//! made of an optional size, a base, a scaled index and a displacement. Local labels
+//! starting with a dot are prefixed with the preceding non-local label like NASM does, and
+//! labels starting with `..@` become assembler-local `.L` labels.
+
+use std::io::{self, Write};
+
+/// Size of an operand, giving the suffix of AT&T mnemonics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Size {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+}
+
+impl Size {
+    fn suffix(self) -> char {
+        match self {
+            Size::Byte => 'b',
+            Size::Word => 'w',
+            Size::Dword => 'l',
+            Size::Qword => 'q',
+        }
+    }
+
+    /// The size given by a NASM size keyword.
+    fn from_keyword(keyword: &str) -> Option<Self> {
+        match keyword {
+            "BYTE" => Some(Size::Byte),
+            "WORD" => Some(Size::Word),
+            "DWORD" => Some(Size::Dword),
+            "QWORD" => Some(Size::Qword),
+            _ => None,
+        }
+    }
+
+    /// The size of the general-purpose register with the given name, if it is one.
+    fn of_register(name: &str) -> Option<Self> {
+        const LEGACY: [&str; 8] = ["ax", "bx", "cx", "dx", "si", "di", "sp", "bp"];
+        const BYTES: [&str; 8] = ["al", "bl", "cl", "dl", "sil", "dil", "spl", "bpl"];
+        if BYTES.contains(&name) {
+            return Some(Size::Byte);
+        }
+        for legacy in LEGACY {
+            if name == legacy {
+                return Some(Size::Word);
+            } else if name.strip_prefix('e') == Some(legacy) {
+                return Some(Size::Dword);
+            } else if name.strip_prefix('r') == Some(legacy) {
+                return Some(Size::Qword);
+            }
+        }
+        let numbered = name.strip_prefix('r')?;
+        let digits = numbered.trim_end_matches(['b', 'w', 'd']);
+        match digits.parse::<u8>() {
+            Ok(8..=15) => match &numbered[digits.len()..] {
+                "" => Some(Size::Qword),
+                "d" => Some(Size::Dword),
+                "w" => Some(Size::Word),
+                "b" => Some(Size::Byte),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// An operand translated into AT&T syntax.
+struct Operand {
+    text: String,
+    /// The size of the operand if it is a register or has an explicit size.
+    size: Option<Size>,
+    /// Whether the operand is a register or memory, which branches take with a `*`.
+    indirect: bool,
+}
+
+/// Write the NASM code `nasm` to `w` in AT&T syntax.
+pub(crate) fn translate(nasm: &str, w: &mut impl Write) -> io::Result<()> {
+    // The last non-local label, which local labels belong to.
+    let mut scope = String::new();
+    for line in nasm.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            writeln!(w)?;
+        } else if let Some(comment) = line.strip_prefix(';') {
+            writeln!(w, "\t#{}", comment)?;
+        } else if let Some(label) = line.strip_suffix(':') {
+            if !label.starts_with('.') {
+                scope = label.to_string();
+            }
+            writeln!(w, "{}:", symbol(label, &scope))?;
+        } else {
+            translate_statement(line, &scope, w)?;
+        }
+    }
+    Ok(())
+}
+
+/// The AT&T name of a NASM label.
+fn symbol(label: &str, scope: &str) -> String {
+    if let Some(name) = label.strip_prefix("..@") {
+        format!(".L{}", name)
+    } else if label.starts_with('.') {
+        format!("{}{}", scope, label)
+    } else {
+        label.to_string()
+    }
+}
+
+/// Translate a directive or an instruction.
+fn translate_statement(line: &str, scope: &str, w: &mut impl Write) -> io::Result<()> {
+    let (op, rest) = match line.split_once(char::is_whitespace) {
+        Some((op, rest)) => (op, rest.trim()),
+        None => (line, ""),
+    };
+    match op {
+        "section" | "segment" => writeln!(w, "\t.section {}", rest),
+        // RIP-relative addressing is spelled out in each operand instead.
+        "default" => Ok(()),
+        "extern" => writeln!(w, "\t.extern {}", rest),
+        "global" => match rest.split_once(":function") {
+            // `global name:function (end - name)` gives the symbol a type and size.
+            Some((name, size)) => {
+                let end = size
+                    .trim()
+                    .trim_start_matches('(')
+                    .split(" - ")
+                    .next()
+                    .unwrap_or_default();
+                writeln!(w, "\t.globl {}", name)?;
+                writeln!(w, "\t.type {}, @function", name)?;
+                writeln!(w, "\t.size {}, {} - {}", name, symbol(end, scope), name)
+            }
+            None => writeln!(w, "\t.globl {}", rest),
+        },
+        "align" => writeln!(w, "\t.balign {}", rest),
+        "db" => writeln!(w, "\t.byte {}", rest),
+        "resq" => writeln!(w, "\t.zero 8*{}", rest),
+        // Differences of labels in jump tables.
+        "dq" | "dd" => {
+            let terms: Vec<String> = rest
+                .split(" - ")
+                .map(|term| symbol(term.trim(), scope))
+                .collect();
+            let directive = if op == "dq" { ".quad" } else { ".long" };
+            writeln!(w, "\t{} {}", directive, terms.join(" - "))
+        }
+        "cqo" => writeln!(w, "\tcqto"),
+        _ if rest.is_empty() => writeln!(w, "\t{}", op),
+        _ if op.starts_with('j') || op == "call" => {
+            let target = match rest.strip_suffix(" wrt ..plt") {
+                Some(func) => format!("{}@PLT", func),
+                None => {
+                    let target = operand(rest, scope);
+                    if target.indirect {
+                        format!("*{}", target.text)
+                    } else {
+                        target.text
+                    }
+                }
+            };
+            writeln!(w, "\t{} {}", op, target)
+        }
+        _ => {
+            let operands: Vec<Operand> = rest.split(',').map(|op| operand(op, scope)).collect();
+            let mnemonic = match op {
+                "movzx" | "movsx" | "movsxd" => {
+                    let from = operands
+                        .get(1)
+                        .and_then(|op| op.size)
+                        .unwrap_or(Size::Dword);
+                    let to = operands[0].size.unwrap_or(Size::Qword);
+                    let extend = if op == "movzx" { "movz" } else { "movs" };
+                    format!("{}{}{}", extend, from.suffix(), to.suffix())
+                }
+                _ if op.starts_with("set") => op.to_string(),
+                // SSE instructions are named after their operand size already.
+                _ if operands.iter().any(|op| op.text.starts_with("%xmm")) => op.to_string(),
+                _ => match operands.iter().find_map(|op| op.size) {
+                    Some(size) => format!("{}{}", op, size.suffix()),
+                    None => op.to_string(),
+                },
+            };
+            let operands: Vec<&str> = operands.iter().rev().map(|op| op.text.as_str()).collect();
+            writeln!(w, "\t{} {}", mnemonic, operands.join(", "))
+        }
+    }
+}
+
+/// Translate a single operand.
+fn operand(text: &str, scope: &str) -> Operand {
+    let text = text.trim();
+    let (size, text) = match text.split_once(' ') {
+        Some((keyword, rest)) if Size::from_keyword(keyword).is_some() => {
+            (Size::from_keyword(keyword), rest.trim())
+        }
+        _ => (None, text),
+    };
+    if let Some(address) = text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+        Operand {
+            text: memory(address, scope),
+            size,
+            indirect: true,
+        }
+    } else if text
+        .strip_prefix("xmm")
+        .is_some_and(|n| n.parse::<u8>().is_ok())
+    {
+        Operand {
+            text: format!("%{}", text),
+            size: None,
+            indirect: true,
+        }
+    } else if let Some(size) = Size::of_register(text) {
+        Operand {
+            text: format!("%{}", text),
+            size: Some(size),
+            indirect: true,
+        }
+    } else if text.parse::<i64>().is_ok() || text.starts_with("0x") {
+        Operand {
+            text: format!("${}", text),
+            size,
+            indirect: false,
+        }
+    } else {
+        Operand {
+            text: symbol(text, scope),
+            size,
+            indirect: false,
+        }
+    }
+}
+
+/// Translate the address inside the brackets of a memory operand.
+fn memory(address: &str, scope: &str) -> String {
+    if let Some(label) = address.strip_prefix("rel ") {
+        return format!("{}(%rip)", symbol(label.trim(), scope));
+    }
+    if let Some((segment, offset)) = address.split_once(':') {
+        let offset = match offset.trim().strip_suffix(" wrt ..tpoff") {
+            Some(label) => format!("{}@tpoff", label),
+            None => offset.trim().to_string(),
+        };
+        return format!("%{}:{}", segment.trim(), offset);
+    }
+
+    let mut displacement = String::new();
+    let mut base = None;
+    let mut index = None;
+    let mut term = String::new();
+    // Split into terms keeping each term's sign, e.g. `rbp-32+r14*8` into `rbp`, `-32`
+    // and `+r14*8`.
+    for c in address.chars().chain(Some('+')) {
+        let complete = !term.trim().trim_start_matches(['+', '-']).trim().is_empty();
+        if (c == '+' || c == '-') && complete {
+            let (sign, value) = match term.trim().strip_prefix(['+', '-']) {
+                Some(value) => (&term.trim()[..1], value.trim()),
+                None => ("+", term.trim()),
+            };
+            if let Some((reg, scale)) = value.split_once('*') {
+                index = Some((reg.trim().to_string(), scale.trim().to_string()));
+            } else if Size::of_register(value).is_some() {
+                if base.is_none() {
+                    base = Some(value.to_string());
+                } else {
+                    index = Some((value.to_string(), "1".to_string()));
+                }
+            } else {
+                if sign == "-" || !displacement.is_empty() {
+                    displacement.push_str(sign);
+                }
+                displacement.push_str(&symbol(value, scope));
+            }
+            term.clear();
+        }
+        term.push(c);
+    }
+
+    let registers = match (base, index) {
+        (None, None) => return displacement,
+        (Some(base), None) => format!("%{}", base),
+        (base, Some((index, scale))) => {
+            let base = base.map(|base| format!("%{}", base)).unwrap_or_default();
+            if scale == "1" {
+                format!("{},%{}", base, index)
+            } else {
+                format!("{},%{},{}", base, index, scale)
+            }
+        }
+    };
+    format!("{}({})", displacement, registers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{has_tool, report_skipped};
+
+    /// Assemble `att` with the GNU assembler, panicking with its errors if it fails.
+    fn assemble(test: &str, att: &str) {
+        if !has_tool("as") {
+            return report_skipped(test, "as");
+        }
+        let dir = crate::build::unique_temp_dir("fig-att-test");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("code.s"), att).unwrap();
+        let output = std::process::Command::new("as")
+            .args(["--64", "-o", "code.o", "code.s"])
+            .current_dir(&dir)
+            .output()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    fn att(nasm: &str) -> String {
+        let mut att = vec![];
+        translate(nasm, &mut att).unwrap();
+        String::from_utf8(att).unwrap()
+    }
+
+    #[test]
+    fn translates_every_kind_of_statement() {
+        let nasm = "\
+section .text
+default rel
+extern put_int
+global f:function (..@f.end - f)
+f:
+; the body
+.loop:
+\tmov rax, 60
+\tmov QWORD [rbp-16], 5
+\tadd [rel counter], r15
+\tmov eax, [rbp-8]
+\tmovsxd rax, DWORD [rbx+rcx*4]
+\tmovzx eax, al
+\tlea rax, [rel ..@f.switch]
+\tmov rdx, [rax+rdx-8]
+\tshl r15, cl
+\tcqo
+\tsete al
+\taddsd xmm1, [rbp-32]
+\tmovq rax, xmm1
+\txor r11, [fs:0x28]
+\tmov r15, [fs:errno wrt ..tpoff]
+\tjne .loop
+\tjmp rax
+\tjmp [rbp-24]
+\tcall put_int wrt ..plt
+\tcall put_int
+\tret
+..@f.switch:
+\tdd f.loop - ..@f.switch
+\tdq -5
+\tdb 104, 105
+\tresq 1
+align 8
+..@f.end:
+";
+        assert_eq!(
+            att(nasm),
+            "\
+\t.section .text
+\t.extern put_int
+\t.globl f
+\t.type f, @function
+\t.size f, .Lf.end - f
+f:
+\t# the body
+f.loop:
+\tmovq $60, %rax
+\tmovq $5, -16(%rbp)
+\taddq %r15, counter(%rip)
+\tmovl -8(%rbp), %eax
+\tmovslq (%rbx,%rcx,4), %rax
+\tmovzbl %al, %eax
+\tleaq .Lf.switch(%rip), %rax
+\tmovq -8(%rax,%rdx), %rdx
+\tshlq %cl, %r15
+\tcqto
+\tsete %al
+\taddsd -32(%rbp), %xmm1
+\tmovq %xmm1, %rax
+\txorq %fs:0x28, %r11
+\tmovq %fs:errno@tpoff, %r15
+\tjne f.loop
+\tjmp *%rax
+\tjmp *-24(%rbp)
+\tcall put_int@PLT
+\tcall put_int
+\tret
+.Lf.switch:
+\t.long f.loop - .Lf.switch
+\t.quad -5
+\t.byte 104, 105
+\t.zero 8*1
+\t.balign 8
+.Lf.end:
+"
+        );
+        assemble("translates_every_kind_of_statement", &att(nasm));
+    }
+
+    #[test]
+    fn runtime_library_translates_into_valid_code() {
+        let code = att(crate::build::EMBEDDED_RUNTIME);
+        for function in ["put_int", "put_hex", "put_bin", "put_char"] {
+            assert!(code.contains(&format!("\n{}:\n", function)), "{}", code);
+        }
+        assemble("runtime_library_translates_into_valid_code", &code);
+    }
+}
@@ -0,0 +1,888 @@
+//! A compact binary encoding of the IR, e.g. to cache modules or pass them between
+//! processes. Unlike the textual form it keeps everything code generation depends on, so a
+//! decoded module generates exactly the same code as the encoded one.
+//!
+//! The encoding starts with `MAGIC` and `VERSION`. Unsigned integers are written as
+//! LEB128, signed ones zigzag encoded first, and strings and lists are prefixed with their
+//! length. Enums are a tag byte followed by their fields in declaration order.
+
+use std::convert::TryInto;
+
+use super::*;
+
+const MAGIC: &[u8] = b"FIG\0";
+const VERSION: u8 = 5;
+
+/// The registers in the order of their tags.
+const REGISTERS: [Register; 14] = {
+    use Register::*;
+    [
+        Rax, Rbx, Rcx, Rdx, Rsi, Rdi, R8, R9, R10, R11, R12, R13, R14, R15,
+    ]
+};
+
+/// The SSE registers in the order of their tags.
+const XMM_REGISTERS: [XmmRegister; 16] = {
+    use XmmRegister::*;
+    [
+        Xmm0, Xmm1, Xmm2, Xmm3, Xmm4, Xmm5, Xmm6, Xmm7, Xmm8, Xmm9, Xmm10, Xmm11, Xmm12, Xmm13,
+        Xmm14, Xmm15,
+    ]
+};
+
+/// The memory orderings of fences in the order of their tags.
+const ORDERINGS: [MemoryOrdering; 3] = [
+    MemoryOrdering::Acquire,
+    MemoryOrdering::Release,
+    MemoryOrdering::SeqCst,
+];
+
+/// An error found while decoding a module, with the offset of the offending byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "byte {}: {}", self.offset, self.message)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Module {
+    /// Encode this module in the binary form of the IR described in `fig::ir::binary`.
+    /// Which registers and stack memory are still allocated is not encoded, so blocks of a
+    /// decoded module are only meant for generating code and running passes, not for
+    /// building more instructions.
+    ///
+    /// ```
+    /// use fig::ir::{Block, Function, Module};
+    ///
+    /// let mut block = Block::new(".entry".into());
+    /// let exit_code = block.build_constant(3);
+    /// block.build_exit(exit_code);
+    /// let mut func = Function::new("_start".into());
+    /// func.append_block(block);
+    /// let mut module = Module::default();
+    /// module.append_func(func);
+    ///
+    /// let decoded = Module::from_bytes(&module.to_bytes()).unwrap();
+    /// assert_eq!(decoded.generate_string().unwrap(), module.generate_string().unwrap());
+    /// ```
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder {
+            bytes: MAGIC.to_vec(),
+        };
+        encoder.bytes.push(VERSION);
+        encoder.module(self);
+        encoder.bytes
+    }
+
+    /// Decode a module encoded with `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Module, DecodeError> {
+        let mut decoder = Decoder { bytes, offset: 0 };
+        if !bytes.starts_with(MAGIC) {
+            return Err(decoder.error("not a binary fig module"));
+        }
+        decoder.offset = MAGIC.len();
+        let version = decoder.byte()?;
+        if version != VERSION {
+            return Err(decoder.error(&format!("unsupported version {}", version)));
+        }
+        let module = decoder.module()?;
+        if decoder.offset != bytes.len() {
+            return Err(decoder.error("unexpected bytes after the module"));
+        }
+        Ok(module)
+    }
+}
+
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn uint(&mut self, mut value: u64) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.bytes.push(byte);
+                return;
+            }
+            self.bytes.push(byte | 0x80);
+        }
+    }
+
+    fn int(&mut self, value: i64) {
+        self.uint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn size(&mut self, value: usize) {
+        self.uint(value as u64);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.bytes.push(value as u8);
+    }
+
+    fn string(&mut self, string: &str) {
+        self.size(string.len());
+        self.bytes.extend_from_slice(string.as_bytes());
+    }
+
+    fn register(&mut self, reg: Register) {
+        let tag = REGISTERS.iter().position(|&other| other == reg).unwrap();
+        self.bytes.push(tag as u8);
+    }
+
+    fn xmm_register(&mut self, reg: XmmRegister) {
+        let tag = XMM_REGISTERS
+            .iter()
+            .position(|&other| other == reg)
+            .unwrap();
+        self.bytes.push(tag as u8);
+    }
+
+    fn width(&mut self, width: Width) {
+        self.bool(width == Width::Dword);
+    }
+
+    fn value(&mut self, value: ValueRef) {
+        match value {
+            ValueRef::Register(reg) => {
+                self.bytes.push(0);
+                self.register(reg);
+            }
+            ValueRef::Memory(offset) => {
+                self.bytes.push(1);
+                self.size(offset);
+            }
+            ValueRef::Global(name) => {
+                self.bytes.push(2);
+                self.string(&name);
+            }
+            ValueRef::Xmm(reg) => {
+                self.bytes.push(3);
+                self.xmm_register(reg);
+            }
+            ValueRef::Immediate(value) => {
+                self.bytes.push(4);
+                self.int(value.into());
+            }
+            ValueRef::ThreadLocal(name) => {
+                self.bytes.push(5);
+                self.string(&name);
+            }
+        }
+    }
+
+    fn values(&mut self, values: &[ValueRef]) {
+        self.size(values.len());
+        for &value in values {
+            self.value(value);
+        }
+    }
+
+    fn optional_value(&mut self, value: Option<ValueRef>) {
+        self.bool(value.is_some());
+        if let Some(value) = value {
+            self.value(value);
+        }
+    }
+
+    fn registers(&mut self, registers: &[Register]) {
+        self.size(registers.len());
+        for &reg in registers {
+            self.register(reg);
+        }
+    }
+
+    fn xmm_registers(&mut self, registers: &[XmmRegister]) {
+        self.size(registers.len());
+        for &reg in registers {
+            self.xmm_register(reg);
+        }
+    }
+
+    fn module(&mut self, module: &Module) {
+        self.bytes.push(match module.output_kind {
+            OutputKind::Executable => 0,
+            OutputKind::SharedLibrary => 1,
+            OutputKind::Object => 2,
+        });
+        self.bytes.push(match module.target {
+            Target::X86_64 => 0,
+            Target::Aarch64 => 1,
+        });
+        self.bytes.push(match module.syntax {
+            AsmSyntax::Nasm => 0,
+            AsmSyntax::Att => 1,
+        });
+        self.bool(module.features.popcnt);
+        self.bool(module.cet);
+        self.bool(module.symbol_info);
+        self.bool(module.check_externs);
+        self.size(module.globals.len());
+        for &(name, init) in &module.globals {
+            self.string(&name);
+            self.int(init);
+        }
+        self.size(module.thread_locals.len());
+        for &(name, init) in &module.thread_locals {
+            self.string(&name);
+            self.int(init);
+        }
+        self.size(module.strings.len());
+        for (label, string) in &module.strings {
+            self.string(label);
+            self.string(string);
+        }
+        self.size(module.externs.len());
+        for name in &module.externs {
+            self.string(name);
+        }
+        self.size(module.clobbers.len());
+        for (name, clobbered) in &module.clobbers {
+            self.string(name);
+            self.registers(clobbered);
+        }
+        self.size(module.funcs.len());
+        for func in &module.funcs {
+            self.function(func);
+        }
+    }
+
+    fn function(&mut self, func: &Function) {
+        self.string(&func.name);
+        self.bool(func.noreturn);
+        self.bool(func.stack_protector);
+        self.size(func.blocks.len());
+        for block in &func.blocks {
+            self.block(block);
+        }
+    }
+
+    fn block(&mut self, block: &Block) {
+        self.string(&block.name);
+        self.size(block.instructions.len());
+        for instruction in &block.instructions {
+            self.instruction(instruction);
+        }
+        self.size(block.comments.len());
+        for (at, comment) in &block.comments {
+            self.size(*at);
+            self.string(comment);
+        }
+        self.size(block.stale_operands.len());
+        for &(at, value) in &block.stale_operands {
+            self.size(at);
+            self.value(value);
+        }
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) {
+        use Instruction::*;
+        let tag = instruction_tag(instruction);
+        self.bytes.push(tag);
+        match instruction {
+            Constant {
+                storage,
+                value,
+                width,
+            } => {
+                self.value(*storage);
+                self.int(*value);
+                self.width(*width);
+            }
+            Alloc { size, offset } => {
+                self.size(*size);
+                self.size(*offset);
+            }
+            Store {
+                value,
+                storage,
+                volatile,
+                width,
+            } => {
+                self.value(*value);
+                self.value(*storage);
+                self.bool(*volatile);
+                self.width(*width);
+            }
+            Load {
+                storage,
+                source,
+                volatile,
+            } => {
+                self.value(*storage);
+                self.value(*source);
+                self.bool(*volatile);
+            }
+            Move { storage, source } => {
+                self.value(*storage);
+                self.value(*source);
+            }
+            Fence { ordering } => {
+                let tag = ORDERINGS
+                    .iter()
+                    .position(|other| other == ordering)
+                    .unwrap();
+                self.bytes.push(tag as u8);
+            }
+            Add { left, right, width } | Subtract { left, right, width } => {
+                self.value(*left);
+                self.value(*right);
+                self.width(*width);
+            }
+            FloatConstant { storage, value } => {
+                self.value(*storage);
+                self.bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            Multiply { left, right }
+            | FloatAdd { left, right }
+            | FloatSubtract { left, right }
+            | FloatMultiply { left, right }
+            | FloatDivide { left, right }
+            | Divide { left, right }
+            | Modulo { left, right }
+            | UnsignedDivide { left, right }
+            | UnsignedModulo { left, right }
+            | And { left, right }
+            | Or { left, right }
+            | Xor { left, right }
+            | Equal { left, right }
+            | NotEqual { left, right }
+            | LessThan { left, right }
+            | GreaterThan { left, right }
+            | LessEqual { left, right }
+            | GreaterEqual { left, right }
+            | Minimum { left, right }
+            | Maximum { left, right } => {
+                self.value(*left);
+                self.value(*right);
+            }
+            Negate { value } => self.value(*value),
+            ShiftLeft { left, count } | ShiftRight { left, count } => {
+                self.value(*left);
+                match *count {
+                    ShiftCount::Immediate(count) => {
+                        self.bytes.push(0);
+                        self.bytes.push(count);
+                    }
+                    ShiftCount::Value { count, save_rcx } => {
+                        self.bytes.push(1);
+                        self.value(count);
+                        self.bool(save_rcx);
+                    }
+                }
+            }
+            TableLoad {
+                storage,
+                table,
+                index,
+                elem_size,
+            } => {
+                self.value(*storage);
+                self.string(table);
+                self.value(*index);
+                self.size(*elem_size);
+            }
+            SelectIndex {
+                storage,
+                index,
+                values,
+                table,
+            } => {
+                self.value(*storage);
+                self.value(*index);
+                self.values(values);
+                self.optional_value(*table);
+            }
+            Select {
+                storage,
+                cond,
+                if_true,
+                if_false,
+            } => {
+                self.value(*storage);
+                self.value(*cond);
+                self.value(*if_true);
+                self.value(*if_false);
+            }
+            Popcount { storage, value } => {
+                self.value(*storage);
+                self.value(*value);
+            }
+            Jump { dest } => self.string(dest),
+            JumpIndirect { target } => self.value(*target),
+            TailCallIndirect { target, arg } => {
+                self.value(*target);
+                self.optional_value(*arg);
+            }
+            JumpIfZero { value, dest } | JumpIfNotZero { value, dest } => {
+                self.value(*value);
+                self.string(dest);
+            }
+            Switch {
+                value,
+                cases,
+                default,
+                table,
+            } => {
+                self.value(*value);
+                self.size(cases.len());
+                for (case, dest) in cases {
+                    self.int(*case);
+                    self.string(dest);
+                }
+                self.string(default);
+                self.bool(table.is_some());
+                if let Some(table) = table {
+                    self.register(*table);
+                }
+            }
+            Call {
+                func,
+                args,
+                preserve,
+                preserve_xmm,
+                result,
+                float_result,
+            } => {
+                self.string(func);
+                self.values(args);
+                self.registers(preserve);
+                self.xmm_registers(preserve_xmm);
+                self.optional_value(*result);
+                self.bool(*float_result);
+            }
+            SaveContext { context } | RestoreContext { context } => self.value(*context),
+            Return { value } => self.optional_value(*value),
+            Exit { exit_code } => self.value(*exit_code),
+            PrintString { string, preserve } => {
+                self.value(*string);
+                self.registers(preserve);
+            }
+        }
+    }
+}
+
+/// The tag of an instruction in the encoding. Tags are never changed so encoded modules
+/// stay readable, new instructions get the next free one.
+fn instruction_tag(instruction: &Instruction) -> u8 {
+    use Instruction::*;
+    match instruction {
+        Constant { .. } => 0,
+        Alloc { .. } => 1,
+        Store { .. } => 2,
+        Load { .. } => 3,
+        Move { .. } => 4,
+        Add { .. } => 5,
+        Subtract { .. } => 6,
+        Multiply { .. } => 7,
+        FloatConstant { .. } => 8,
+        FloatAdd { .. } => 9,
+        FloatSubtract { .. } => 10,
+        FloatMultiply { .. } => 11,
+        FloatDivide { .. } => 12,
+        Divide { .. } => 13,
+        Modulo { .. } => 14,
+        UnsignedDivide { .. } => 15,
+        UnsignedModulo { .. } => 16,
+        And { .. } => 17,
+        Or { .. } => 18,
+        Xor { .. } => 19,
+        Equal { .. } => 20,
+        NotEqual { .. } => 21,
+        LessThan { .. } => 22,
+        GreaterThan { .. } => 23,
+        LessEqual { .. } => 24,
+        GreaterEqual { .. } => 25,
+        Negate { .. } => 26,
+        ShiftLeft { .. } => 27,
+        ShiftRight { .. } => 28,
+        TableLoad { .. } => 29,
+        SelectIndex { .. } => 30,
+        Select { .. } => 31,
+        Popcount { .. } => 32,
+        Jump { .. } => 33,
+        JumpIndirect { .. } => 34,
+        TailCallIndirect { .. } => 35,
+        JumpIfZero { .. } => 36,
+        JumpIfNotZero { .. } => 37,
+        Switch { .. } => 38,
+        Call { .. } => 39,
+        SaveContext { .. } => 40,
+        RestoreContext { .. } => 41,
+        Return { .. } => 42,
+        Exit { .. } => 43,
+        PrintString { .. } => 44,
+        Minimum { .. } => 45,
+        Maximum { .. } => 46,
+        Fence { .. } => 47,
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Decoder<'_> {
+    fn error(&self, message: &str) -> DecodeError {
+        DecodeError {
+            offset: self.offset,
+            message: message.to_string(),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let byte = *self
+            .bytes
+            .get(self.offset)
+            .ok_or_else(|| self.error("unexpected end of input"))?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&[u8], DecodeError> {
+        if self.bytes.len() - self.offset < len {
+            return Err(self.error("unexpected end of input"));
+        }
+        self.offset += len;
+        Ok(&self.bytes[self.offset - len..self.offset])
+    }
+
+    fn uint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(self.error("integer too large"))
+    }
+
+    fn int(&mut self) -> Result<i64, DecodeError> {
+        let value = self.uint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    fn size(&mut self) -> Result<usize, DecodeError> {
+        let value = self.uint()?;
+        usize::try_from(value).map_err(|_| self.error("size too large"))
+    }
+
+    fn bool(&mut self) -> Result<bool, DecodeError> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte => Err(self.error(&format!("invalid boolean {}", byte))),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let len = self.size()?;
+        let start = self.offset;
+        let bytes = self.take(len)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| DecodeError {
+            offset: start,
+            message: "invalid UTF-8 in string".to_string(),
+        })
+    }
+
+    fn symbol(&mut self) -> Result<Symbol, DecodeError> {
+        let start = self.offset;
+        Symbol::new(&self.string()?).map_err(|err| DecodeError {
+            offset: start,
+            message: err.to_string(),
+        })
+    }
+
+    /// A list of `len` elements, each decoded by `element`.
+    fn list<T>(
+        &mut self,
+        mut element: impl FnMut(&mut Self) -> Result<T, DecodeError>,
+    ) -> Result<Vec<T>, DecodeError> {
+        let len = self.size()?;
+        let mut list = vec![];
+        for _ in 0..len {
+            list.push(element(self)?);
+        }
+        Ok(list)
+    }
+
+    /// A byte selecting one of `choices`, describing them as `what` in an error.
+    fn choice<T: Copy>(&mut self, choices: &[T], what: &str) -> Result<T, DecodeError> {
+        let tag = self.byte()?;
+        choices
+            .get(usize::from(tag))
+            .copied()
+            .ok_or_else(|| DecodeError {
+                offset: self.offset - 1,
+                message: format!("invalid {} {}", what, tag),
+            })
+    }
+
+    fn register(&mut self) -> Result<Register, DecodeError> {
+        self.choice(&REGISTERS, "register")
+    }
+
+    fn xmm_register(&mut self) -> Result<XmmRegister, DecodeError> {
+        self.choice(&XMM_REGISTERS, "SSE register")
+    }
+
+    fn width(&mut self) -> Result<Width, DecodeError> {
+        self.choice(&[Width::Qword, Width::Dword], "width")
+    }
+
+    fn value(&mut self) -> Result<ValueRef, DecodeError> {
+        match self.byte()? {
+            0 => Ok(ValueRef::Register(self.register()?)),
+            1 => Ok(ValueRef::Memory(self.size()?)),
+            2 => Ok(ValueRef::Global(self.symbol()?)),
+            3 => Ok(ValueRef::Xmm(self.xmm_register()?)),
+            4 => {
+                let value = self.int()?;
+                i32::try_from(value)
+                    .map(ValueRef::Immediate)
+                    .map_err(|_| self.error("immediate out of range"))
+            }
+            5 => Ok(ValueRef::ThreadLocal(self.symbol()?)),
+            tag => Err(DecodeError {
+                offset: self.offset - 1,
+                message: format!("invalid value {}", tag),
+            }),
+        }
+    }
+
+    fn optional_value(&mut self) -> Result<Option<ValueRef>, DecodeError> {
+        Ok(if self.bool()? {
+            Some(self.value()?)
+        } else {
+            None
+        })
+    }
+
+    fn module(&mut self) -> Result<Module, DecodeError> {
+        use OutputKind::*;
+        let mut module = Module {
+            output_kind: self.choice(&[Executable, SharedLibrary, Object], "output kind")?,
+            target: self.choice(&[Target::X86_64, Target::Aarch64], "target")?,
+            syntax: self.choice(&[AsmSyntax::Nasm, AsmSyntax::Att], "syntax")?,
+            features: TargetFeatures {
+                popcnt: self.bool()?,
+            },
+            cet: self.bool()?,
+            symbol_info: self.bool()?,
+            check_externs: self.bool()?,
+            ..Module::default()
+        };
+        module.globals = self.list(|decoder| Ok((decoder.symbol()?, decoder.int()?)))?;
+        module.thread_locals = self.list(|decoder| Ok((decoder.symbol()?, decoder.int()?)))?;
+        module.strings = self.list(|decoder| Ok((decoder.symbol()?, decoder.string()?)))?;
+        module.externs = self.list(Self::string)?;
+        module.clobbers =
+            self.list(|decoder| Ok((decoder.string()?, decoder.list(Self::register)?)))?;
+        module.funcs = self.list(Self::function)?;
+        Ok(module)
+    }
+
+    fn function(&mut self) -> Result<Function, DecodeError> {
+        let mut func = Function::new(self.string()?);
+        func.noreturn = self.bool()?;
+        func.stack_protector = self.bool()?;
+        func.blocks = self.list(|decoder| {
+            let mut block = func.new_block(decoder.string()?);
+            block.instructions = decoder.list(Self::instruction)?;
+            block.comments = decoder.list(|decoder| Ok((decoder.size()?, decoder.string()?)))?;
+            block.stale_operands =
+                decoder.list(|decoder| Ok((decoder.size()?, decoder.value()?)))?;
+            Ok(block)
+        })?;
+        Ok(func)
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, DecodeError> {
+        use Instruction::*;
+        let tag = self.byte()?;
+        Ok(match tag {
+            0 => Constant {
+                storage: self.value()?,
+                value: self.int()?,
+                width: self.width()?,
+            },
+            1 => Alloc {
+                size: self.size()?,
+                offset: self.size()?,
+            },
+            2 => Store {
+                value: self.value()?,
+                storage: self.value()?,
+                volatile: self.bool()?,
+                width: self.width()?,
+            },
+            3 => Load {
+                storage: self.value()?,
+                source: self.value()?,
+                volatile: self.bool()?,
+            },
+            4 => Move {
+                storage: self.value()?,
+                source: self.value()?,
+            },
+            5 => Add {
+                left: self.value()?,
+                right: self.value()?,
+                width: self.width()?,
+            },
+            6 => Subtract {
+                left: self.value()?,
+                right: self.value()?,
+                width: self.width()?,
+            },
+            8 => FloatConstant {
+                storage: self.value()?,
+                value: f64::from_le_bytes(self.take(8)?.try_into().unwrap()),
+            },
+            7 | 9..=25 | 45 | 46 => {
+                let (left, right) = (self.value()?, self.value()?);
+                match tag {
+                    7 => Multiply { left, right },
+                    9 => FloatAdd { left, right },
+                    10 => FloatSubtract { left, right },
+                    11 => FloatMultiply { left, right },
+                    12 => FloatDivide { left, right },
+                    13 => Divide { left, right },
+                    14 => Modulo { left, right },
+                    15 => UnsignedDivide { left, right },
+                    16 => UnsignedModulo { left, right },
+                    17 => And { left, right },
+                    18 => Or { left, right },
+                    19 => Xor { left, right },
+                    20 => Equal { left, right },
+                    21 => NotEqual { left, right },
+                    22 => LessThan { left, right },
+                    23 => GreaterThan { left, right },
+                    24 => LessEqual { left, right },
+                    25 => GreaterEqual { left, right },
+                    45 => Minimum { left, right },
+                    _ => Maximum { left, right },
+                }
+            }
+            26 => Negate {
+                value: self.value()?,
+            },
+            27 | 28 => {
+                let left = self.value()?;
+                let count = match self.byte()? {
+                    0 => ShiftCount::Immediate(self.byte()?),
+                    1 => ShiftCount::Value {
+                        count: self.value()?,
+                        save_rcx: self.bool()?,
+                    },
+                    tag => {
+                        return Err(DecodeError {
+                            offset: self.offset - 1,
+                            message: format!("invalid shift count {}", tag),
+                        })
+                    }
+                };
+                if tag == 27 {
+                    ShiftLeft { left, count }
+                } else {
+                    ShiftRight { left, count }
+                }
+            }
+            29 => TableLoad {
+                storage: self.value()?,
+                table: self.string()?,
+                index: self.value()?,
+                elem_size: self.size()?,
+            },
+            30 => SelectIndex {
+                storage: self.value()?,
+                index: self.value()?,
+                values: self.list(Self::value)?,
+                table: self.optional_value()?,
+            },
+            31 => Select {
+                storage: self.value()?,
+                cond: self.value()?,
+                if_true: self.value()?,
+                if_false: self.value()?,
+            },
+            32 => Popcount {
+                storage: self.value()?,
+                value: self.value()?,
+            },
+            33 => Jump {
+                dest: self.string()?,
+            },
+            34 => JumpIndirect {
+                target: self.value()?,
+            },
+            35 => TailCallIndirect {
+                target: self.value()?,
+                arg: self.optional_value()?,
+            },
+            36 => JumpIfZero {
+                value: self.value()?,
+                dest: self.string()?,
+            },
+            37 => JumpIfNotZero {
+                value: self.value()?,
+                dest: self.string()?,
+            },
+            38 => Switch {
+                value: self.value()?,
+                cases: self.list(|decoder| Ok((decoder.int()?, decoder.string()?)))?,
+                default: self.string()?,
+                table: if self.bool()? {
+                    Some(self.register()?)
+                } else {
+                    None
+                },
+            },
+            39 => Call {
+                func: self.string()?,
+                args: self.list(Self::value)?,
+                preserve: self.list(Self::register)?,
+                preserve_xmm: self.list(Self::xmm_register)?,
+                result: self.optional_value()?,
+                float_result: self.bool()?,
+            },
+            40 => SaveContext {
+                context: self.value()?,
+            },
+            41 => RestoreContext {
+                context: self.value()?,
+            },
+            42 => Return {
+                value: self.optional_value()?,
+            },
+            43 => Exit {
+                exit_code: self.value()?,
+            },
+            44 => PrintString {
+                string: self.value()?,
+                preserve: self.list(Self::register)?,
+            },
+            47 => Fence {
+                ordering: self.choice(&ORDERINGS, "memory ordering")?,
+            },
+            _ => {
+                return Err(DecodeError {
+                    offset: self.offset - 1,
+                    message: format!("invalid instruction {}", tag),
+                })
+            }
+        })
+    }
+}
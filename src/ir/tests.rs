@@ -0,0 +1,4236 @@
+use super::*;
+use crate::testing::{assert_program_exit_code, run_with_libc, run_with_libc_linked};
+
+/// A `build_` method of a binary operation.
+type BuildBinary = fn(&mut Block, ValueRef, ValueRef) -> ValueRef;
+
+/// Builds a computation at the end of a block and returns its result.
+type Compute = fn(&mut Block) -> ValueRef;
+
+/// Builds instructions at the end of a block.
+type Build = fn(&mut Block);
+
+/// Computes the expected result of a binary operation.
+type Evaluate = fn(Value, Value) -> Value;
+
+/// Generate the code of `module` in AT&T syntax for the GNU assembler.
+fn att_code(mut module: Module) -> String {
+    module.set_syntax(AsmSyntax::Att);
+    module.generate_string().unwrap()
+}
+
+/// A module with every kind of instruction and all settings differing from the defaults.
+/// It is only meant for generating code, not for running it.
+fn every_instruction() -> Module {
+    let mut module = Module::default().with_cet(true).with_symbol_info(true);
+    module.set_target_features(TargetFeatures { popcnt: true });
+    module.set_check_externs(true);
+    module.declare_extern("external".into());
+    module.declare_function_clobbers("external".into(), &[Register::Rax, Register::Rcx]);
+    let counter = module.add_global("counter".into(), -5).unwrap();
+    let thread_local = module.add_tls("thread_local".into(), 9).unwrap();
+    module.add_tls("zeroed".into(), 0).unwrap();
+    let greeting = module.add_string("hello\n");
+
+    let mut func = Function::new("every_instruction".into()).with_stack_protector(true);
+    let mut entry = func.new_block(".entry".into());
+    entry.build_comment("every kind of instruction");
+    let memory = entry.build_alloc(8);
+    let value = entry.build_constant(-1_234_567_890_123);
+    entry.build_store(value, memory);
+    let narrow = entry.build_constant_sized(7, Width::Dword);
+    entry.build_store_sized(narrow, memory, Width::Dword);
+    let value = entry.build_constant(3);
+    entry.build_store_volatile(value, counter);
+    let value = entry.build_constant(4);
+    entry.build_store_release(value, counter);
+    entry.build_fence(MemoryOrdering::SeqCst);
+    let value = entry.build_load_acquire(counter);
+    entry.build_move(value, memory);
+    let value = entry.build_load_volatile(counter);
+    entry.build_store(value, memory);
+    let value = entry.build_load(thread_local);
+    entry.build_store(value, thread_local);
+    let operations: [BuildBinary; 18] = [
+        Block::build_add,
+        Block::build_subtract,
+        Block::build_multiply,
+        Block::build_divide,
+        Block::build_modulo,
+        Block::build_unsigned_divide,
+        Block::build_unsigned_modulo,
+        Block::build_and,
+        Block::build_or,
+        Block::build_xor,
+        Block::build_equal,
+        Block::build_not_equal,
+        Block::build_less_than,
+        Block::build_greater_than,
+        Block::build_less_equal,
+        Block::build_greater_equal,
+        Block::build_min,
+        Block::build_max,
+    ];
+    let mut value = entry.build_load(memory);
+    for operation in &operations {
+        let right = entry.build_constant(3);
+        value = operation(&mut entry, value, right);
+    }
+    let value = entry.build_add_sized(value, ValueRef::Immediate(-5), Width::Dword);
+    let right = entry.build_load(memory);
+    let value = entry.build_subtract_sized(value, right, Width::Dword);
+    let count = entry.build_constant(4);
+    let value = entry.build_shift_left(value, count);
+    let count = entry.build_load(memory);
+    let value = entry.build_shift_right(value, count);
+    let value = entry.build_negate(value);
+    let value = entry.build_popcount(value);
+    entry.build_store(value, memory);
+
+    let mut float = entry.build_float_constant(1.5);
+    let float_operations: [BuildBinary; 4] = [
+        Block::build_fadd,
+        Block::build_fsub,
+        Block::build_fmul,
+        Block::build_fdiv,
+    ];
+    for operation in &float_operations {
+        let right = entry.build_float_constant(-0.25);
+        float = operation(&mut entry, float, right);
+    }
+    let returned = entry
+        .build_call_with_float_result("external".into(), &[float, memory])
+        .unwrap();
+    entry.build_store(returned, memory);
+
+    let index = entry.build_constant(1);
+    let loaded = entry.build_table_load("table", index, 4);
+    let cond = entry.build_load(memory);
+    let selected = entry.build_select(cond, loaded, memory);
+    let index = entry.build_constant(1);
+    let small = entry
+        .build_select_index(index, &[selected, memory])
+        .unwrap();
+    let index = entry.build_constant(4);
+    let values: Vec<ValueRef> = (0..5).map(|value| entry.build_constant(value)).collect();
+    let large = entry.build_select_index(index, &values).unwrap();
+    let value = entry.build_add(small, large);
+    entry.build_put_int(value);
+    let result = entry
+        .build_call_with_result("external".into(), &[memory, counter])
+        .unwrap();
+    entry.build_print_string(greeting).unwrap();
+    let context = entry.build_save_context();
+    entry.build_jump_if_zero(result, ".switch".into());
+    let value = entry.build_load(memory);
+    entry.build_jump_if_not_zero(value, ".compare".into());
+    entry.build_restore_context(context).unwrap();
+
+    let mut switch = func.new_block(".switch".into());
+    let value = switch.build_load(memory);
+    let cases = [(0, ".return"), (1, ".indirect"), (2, ".tail")];
+    let cases: Vec<(Value, String)> = cases
+        .iter()
+        .map(|&(case, dest)| (case, dest.into()))
+        .collect();
+    switch
+        .build_switch(value, &cases, ".compare".into())
+        .unwrap();
+
+    let mut compare = func.new_block(".compare".into());
+    let cases = vec![(-100, ".return".into()), (100, ".tail".into())];
+    compare
+        .build_switch(memory, &cases, ".jump".into())
+        .unwrap();
+
+    let mut jump = func.new_block(".jump".into());
+    jump.build_jump(".return".into());
+
+    let mut indirect = func.new_block(".indirect".into());
+    let target = indirect.build_load(memory);
+    indirect.build_jump_indirect(target);
+
+    let mut tail = func.new_block(".tail".into());
+    let target = tail.build_load(counter);
+    let arg = tail.build_load(memory);
+    tail.build_tail_call_indirect(target, Some(arg));
+
+    let mut ret = func.new_block(".return".into());
+    let value = ret.build_load(memory);
+    ret.build_return(Some(value));
+
+    for block in [entry, switch, compare, jump, indirect, tail, ret] {
+        func.append_block(block);
+    }
+    module.append_func(func);
+
+    let mut stop = Function::new("stop".into());
+    stop.set_noreturn(true);
+    let mut block = stop.new_block(".entry".into());
+    let exit_code = block.build_constant(1);
+    block.build_exit(exit_code);
+    stop.append_block(block);
+    module.append_func(stop);
+    module
+}
+
+#[test]
+fn protected_tail_call_indirect_keeps_target() {
+    let mut func = Function::new("entry".into()).with_stack_protector(true);
+    let mut block = func.new_block(".entry".into());
+    let buffer = block.build_alloc(8);
+    let value = block.build_constant(20);
+    block.build_store(value, buffer);
+    let index = block.build_constant(0);
+    let target = block.build_table_load("targets", index, 8);
+    let arg = block.build_load(buffer);
+    block.build_tail_call_indirect(target, Some(arg));
+    func.append_block(block);
+
+    let code = func.generate_string().unwrap();
+    let jump = code.find("jmp r11").unwrap();
+    let target_set = code[..jump].rfind("mov r11, ").unwrap();
+    assert!(
+        !code[target_set + "mov r11".len()..jump].contains("r11"),
+        "{}",
+        code
+    );
+
+    let mut module = Module::default();
+    module.append_func(func);
+    let c = "long entry(void);
+             long twice(long x) { return 2 * x; }
+             long (*targets[])(long) = { twice };
+             int main(void) { return entry(); }";
+    if let Some(code) = run_with_libc("protected_tail_call_indirect", &att_code(module), c) {
+        assert_eq!(code, 40);
+    }
+}
+
+#[test]
+fn jump_table_in_position_independent_executable() {
+    let mut func = Function::new("dispatch".into());
+    let mut entry = func.new_block(".entry".into());
+    let cases: Vec<(Value, String)> = (0..4)
+        .map(|case| (case, format!(".case{}", case)))
+        .collect();
+    let selector = entry
+        .build_call_with_result("selector".into(), &[])
+        .unwrap();
+    entry
+        .build_switch(selector, &cases, ".default".into())
+        .unwrap();
+    func.append_block(entry);
+    for (case, name) in &cases {
+        let mut block = func.new_block(name.clone());
+        let result = block.build_constant(10 * case + 3);
+        block.build_return(Some(result));
+        func.append_block(block);
+    }
+    let mut default = func.new_block(".default".into());
+    let result = default.build_constant(99);
+    default.build_return(Some(result));
+    func.append_block(default);
+
+    let mut module = Module::default();
+    module.set_output_kind(OutputKind::SharedLibrary);
+    module.declare_extern("selector".into());
+    module.append_func(func);
+    let code = module.generate_string().unwrap();
+    assert!(code.contains("movsxd"), "{}", code);
+    assert!(
+        code.contains("\tdd dispatch.case2 - ..@dispatch.entry.switch"),
+        "{}",
+        code
+    );
+
+    let c = "long dispatch(void);
+             long selected;
+             long selector(void) { return selected; }
+             long run(long x) { selected = x; return dispatch(); }
+             int main(void) { return run(0) + run(2) + run(7); }";
+    let link_args = ["-pie", "-Wl,-z,text"];
+    if let Some(code) = run_with_libc_linked("jump_table_in_pie", &att_code(module), c, &link_args)
+    {
+        assert_eq!(code, 3 + 23 + 99);
+    }
+}
+
+#[test]
+fn tail_call_indirect_moves_argument_out_of_r11() {
+    let mut block = Block::new(".entry".into());
+    block.build_tail_call_indirect(
+        ValueRef::Register(Register::Rax),
+        Some(ValueRef::Register(Register::R11)),
+    );
+    let code = block.generate_string().unwrap();
+    assert!(
+        code.contains("\tmov rdi, r11\n\tmov r11, rax\n"),
+        "{}",
+        code
+    );
+
+    let mut block = Block::new(".entry".into());
+    block.build_tail_call_indirect(
+        ValueRef::Register(Register::Rdi),
+        Some(ValueRef::Register(Register::R11)),
+    );
+    let code = block.generate_string().unwrap();
+    assert!(code.contains("\txchg r11, rdi\n\tjmp r11\n"), "{}", code);
+}
+
+/// Where a test puts an operand.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Location {
+    Register(Register),
+    Memory,
+}
+
+/// Put `value` at `location`.
+fn place(block: &mut Block, value: Value, location: Location) -> ValueRef {
+    let constant = block.build_constant(value);
+    match location {
+        Location::Register(reg) => block.build_move_to_register(constant, reg),
+        Location::Memory => {
+            let memory = block.build_alloc(8);
+            block.build_store(constant, memory);
+            memory
+        }
+    }
+}
+
+/// Check a division built with `build` for operands in every combination of RAX, RDX,
+/// another register and memory, against the result computed by `expected`, both in the
+/// interpreter and natively. RAX and RDX hold values of their own if no operand is in
+/// them, which must survive the division.
+fn check_division(build: BuildBinary, expected: fn(Value, Value) -> Value) {
+    use Register::*;
+    let (left_value, right_value) = (-100, 7);
+    let locations = [
+        Location::Register(Rax),
+        Location::Register(Rdx),
+        Location::Register(Rcx),
+        Location::Memory,
+    ];
+    for &left in &locations {
+        for &right in &locations {
+            if left == right && left != Location::Memory {
+                continue;
+            }
+            let build_module = |module: &mut Module| {
+                let mut func = Function::new("_start".into());
+                let mut block = func.new_block(".entry".into());
+                let left_ref = place(&mut block, left_value, left);
+                let right_ref = place(&mut block, right_value, right);
+                let bystanders: Vec<ValueRef> = [(Rax, 10), (Rdx, 20)]
+                    .iter()
+                    .map(|&(reg, value)| (Location::Register(reg), value))
+                    .filter(|&(location, _)| location != left && location != right)
+                    .map(|(location, value)| place(&mut block, value, location))
+                    .collect();
+                let mut result = build(&mut block, left_ref, right_ref);
+                if result.in_memory() {
+                    result = block.build_load(result);
+                }
+                for bystander in bystanders {
+                    result = block.build_add(result, bystander);
+                }
+                block.build_exit(result);
+                func.append_block(block);
+                module.append_func(func);
+            };
+            let bystanders: Value = [(Rax, 10), (Rdx, 20)]
+                .iter()
+                .filter(|&&(reg, _)| {
+                    Location::Register(reg) != left && Location::Register(reg) != right
+                })
+                .map(|&(_, value)| value)
+                .sum();
+            let expected = expected(left_value, right_value).wrapping_add(bystanders);
+
+            let mut module = Module::default();
+            build_module(&mut module);
+            let result = module.interpret_to(&mut std::io::sink()).unwrap();
+            assert_eq!(result, expected, "{:?} / {:?}", left, right);
+            assert_program_exit_code(build_module, expected as u8 as i32);
+        }
+    }
+}
+
+#[test]
+fn division_faults_trap_like_sigfpe() {
+    use crate::{
+        build::{build, unique_temp_dir, BuildConfig},
+        interp::Trap,
+    };
+    use std::os::unix::process::ExitStatusExt;
+
+    let operations: [(BuildBinary, Value, Value); 6] = [
+        (Block::build_divide, 7, 0),
+        (Block::build_modulo, 7, 0),
+        (Block::build_unsigned_divide, 7, 0),
+        (Block::build_unsigned_modulo, 7, 0),
+        (Block::build_divide, Value::MIN, -1),
+        (Block::build_modulo, Value::MIN, -1),
+    ];
+    let native = ["as", "ld"]
+        .iter()
+        .all(|tool| crate::testing::has_tool(tool));
+    for (i, &(operation, left, right)) in operations.iter().enumerate() {
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        let left = block.build_constant(left);
+        let right = block.build_constant(right);
+        let result = operation(&mut block, left, right);
+        block.build_exit(result);
+        func.append_block(block);
+        let mut module = Module::default();
+        module.append_func(func);
+
+        let error = module.interpret_to(&mut std::io::sink()).unwrap_err();
+        let trap = error.get_ref().and_then(|error| error.downcast_ref());
+        assert_eq!(trap, Some(&Trap::DivideError), "operation {}", i);
+        if native {
+            module.set_syntax(AsmSyntax::Att);
+            let dir = unique_temp_dir("fig-test");
+            let config = BuildConfig {
+                build_dir: dir.clone(),
+                output: dir.join("output"),
+                ..BuildConfig::default()
+            };
+            build(&module, &config).unwrap();
+            let status = std::process::Command::new(&config.output).status().unwrap();
+            std::fs::remove_dir_all(&dir).ok();
+            assert_eq!(status.signal(), Some(8), "operation {}", i);
+        }
+    }
+    if !native {
+        crate::testing::report_skipped("division_faults_trap_like_sigfpe", "as or ld");
+    }
+}
+
+#[test]
+fn divide_with_operands_in_rax_and_rdx() {
+    check_division(Block::build_divide, |left, right| left / right);
+}
+
+#[test]
+fn modulo_with_operands_in_rax_and_rdx() {
+    check_division(Block::build_modulo, |left, right| left % right);
+}
+
+#[test]
+fn unsigned_divide_with_operands_in_rax_and_rdx() {
+    check_division(Block::build_unsigned_divide, |left, right| {
+        (left as u64 / right as u64) as Value
+    });
+}
+
+#[test]
+fn unsigned_modulo_with_operands_in_rax_and_rdx() {
+    check_division(Block::build_unsigned_modulo, |left, right| {
+        (left as u64 % right as u64) as Value
+    });
+}
+
+#[test]
+fn unsigned_division_clears_rdx_after_saving_divisor() {
+    let mut block = Block::new(".entry".into());
+    let left = block.build_constant(100);
+    let right = block.build_constant(7);
+    let right = block.build_move_to_register(right, Register::Rdx);
+    let result = block.build_unsigned_divide(left, right);
+    block.build_exit(result);
+    let code = block.generate_string().unwrap();
+    let saved = code.find("\tmov rcx, rdx\n").unwrap();
+    let cleared = code.find("\txor edx, edx\n").unwrap();
+    assert!(saved < cleared, "{}", code);
+    assert!(code.contains("\tdiv rcx\n"), "{}", code);
+}
+
+#[test]
+fn move_to_occupied_register_evicts_occupant() {
+    // With every register holding a value, the occupant of RDI has to go to memory.
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        let values: Vec<ValueRef> = (1..=14).map(|value| block.build_constant(value)).collect();
+        assert_eq!(values[13], ValueRef::Register(Register::Rdi));
+        let evicted = block.build_evict(Register::Rdi).unwrap();
+        assert!(evicted.in_memory());
+        assert_eq!(block.build_evict(Register::Rdi), None);
+        let pinned = block.build_constant(100);
+        let pinned = block.build_move_to_register(pinned, Register::Rdi);
+        let mut sum = block.build_add(pinned, evicted);
+        for &value in &values[..13] {
+            sum = block.build_add(sum, value);
+        }
+        block.build_exit(sum);
+        func.append_block(block);
+        module.append_func(func);
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    assert_eq!(module.interpret_to(&mut std::io::sink()).unwrap(), 205);
+    assert_program_exit_code(build_module, 205);
+}
+
+#[test]
+fn move_to_occupied_register_without_eviction_does_not_panic() {
+    let mut block = Block::new(".entry".into());
+    let first = block.build_constant(1);
+    block.build_move_to_register(first, Register::Rdi);
+    let second = block.build_constant(2);
+    let second = block.build_move_to_register(second, Register::Rdi);
+    block.build_exit(second);
+    let mut func = Function::new("_start".into());
+    func.append_block(block);
+    let mut module = Module::default();
+    module.append_func(func);
+    assert_eq!(module.interpret_to(&mut std::io::sink()).unwrap(), 2);
+}
+
+#[test]
+fn removing_unused_slots_shrinks_frame() {
+    let build_func = || {
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        let first = block.build_alloc(8);
+        block.build_alloc(32);
+        let second = block.build_alloc(8);
+        let value = block.build_constant(30);
+        block.build_store(value, first);
+        let value = block.build_constant(12);
+        block.build_store(value, second);
+        let left = block.build_load(first);
+        let right = block.build_load(second);
+        let sum = block.build_add(left, right);
+        block.build_exit(sum);
+        func.append_block(block);
+        func
+    };
+    let mut func = build_func();
+    assert_eq!(func.unused_slots(), vec![48]);
+    assert_eq!(func.frame_size(), 64);
+
+    func.remove_unused_slots();
+    assert!(func.unused_slots().is_empty());
+    assert_eq!(func.frame_size(), 32);
+    let code = func.generate_string().unwrap();
+    assert!(
+        code.contains("[rbp-32]") && !code.contains("[rbp-64]"),
+        "{}",
+        code
+    );
+
+    let mut module = Module::default();
+    module.append_func(func);
+    assert_eq!(module.interpret_to(&mut std::io::sink()).unwrap(), 42);
+    assert_program_exit_code(
+        |module| {
+            let mut func = build_func();
+            func.remove_unused_slots();
+            module.append_func(func);
+        },
+        42,
+    );
+}
+
+#[test]
+fn promoting_locals_keeps_them_in_registers() {
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut entry = func.new_block(".entry".into());
+        let mut exit = func.new_block(".exit".into());
+        let local = entry.build_alloc(8);
+        let operand = entry.build_alloc(8);
+        let shared = entry.build_alloc(8);
+        let value = entry.build_constant(30);
+        entry.build_store(value, local);
+        let value = entry.build_constant(0);
+        entry.build_store(value, operand);
+        let value = entry.build_constant(10);
+        entry.build_store(value, shared);
+        let two = entry.build_call_with_result("two".into(), &[]).unwrap();
+        let value = entry.build_load(local);
+        let sum = entry.build_add(value, two);
+        let sum = entry.build_add(sum, operand);
+        let value = exit.build_load(shared);
+        let sum = exit.build_add(sum, value);
+        exit.build_exit(sum);
+        func.append_block(entry);
+        func.append_block(exit);
+
+        assert_eq!(func.frame_size(), 48);
+        func.promote_locals();
+        assert_eq!(func.frame_size(), 32);
+        let code = func.to_string();
+        assert!(code.contains("move %r15, %rbx\n"), "{}", code);
+        assert!(code.contains("move %rbx, %r14\n"), "{}", code);
+        assert!(code.contains("add %r14, [rbp-16]\n"), "{}", code);
+        assert!(code.contains("%r15 = load [rbp-32]\n"), "{}", code);
+        module.append_func(func);
+
+        // Overwrites every register, including the one the local was promoted to.
+        let mut func = Function::new("two".into());
+        let mut block = func.new_block(".entry".into());
+        take_all_registers(&mut block);
+        let two = block.build_constant(2);
+        block.build_return(Some(two));
+        func.append_block(block);
+        module.append_func(func);
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    assert_eq!(module.interpret(), 42);
+    assert_program_exit_code(build_module, 42);
+}
+
+/// A diamond whose entry block either jumps straight to the join block or falls through
+/// into the block in between, which adds 10 to the exit code. The edge from the entry to
+/// the join block is critical. If `fall_through` is set, the entry falls through into the
+/// join block and jumps to the other one instead.
+fn diamond(cond: Value, fall_through: bool) -> Function {
+    let mut func = Function::new("_start".into());
+    let mut entry = func.new_block(".entry".into());
+    let mut then = func.new_block(".then".into());
+    let mut join = func.new_block(".join".into());
+    let result = entry.build_alloc(8);
+    let value = entry.build_constant(1);
+    entry.build_store(value, result);
+    let cond = entry.build_constant(cond);
+    if fall_through {
+        entry.build_jump_if_not_zero(cond, ".then".into());
+    } else {
+        entry.build_jump_if_zero(cond, ".join".into());
+    }
+    let ten = then.build_constant(10);
+    then.build_add(result, ten);
+    then.build_jump(".join".into());
+    let exit_code = join.build_load(result);
+    join.build_exit(exit_code);
+    func.append_block(entry);
+    if fall_through {
+        func.append_block(join);
+        func.append_block(then);
+    } else {
+        func.append_block(then);
+        func.append_block(join);
+    }
+    func
+}
+
+#[test]
+fn split_critical_edges_inserts_block() {
+    for &fall_through in &[false, true] {
+        let mut func = diamond(0, fall_through);
+        assert!(func.predecessors(".join").contains(&".entry"));
+        func.split_critical_edges();
+        assert_eq!(func.successors(".entry").len(), 2);
+        assert!(func.successors(".entry").contains(&".entry.join"));
+        assert_eq!(func.successors(".entry.join"), [".join"]);
+        assert_eq!(func.predecessors(".entry.join"), [".entry"]);
+        assert!(!func.predecessors(".join").contains(&".entry"));
+        assert_eq!(func.blocks.len(), 4);
+        func.validate().unwrap();
+
+        for &(cond, expected) in &[(0, 1), (1, 11)] {
+            let build_module = |module: &mut Module| {
+                let mut func = diamond(cond, fall_through);
+                func.split_critical_edges();
+                module.append_func(func);
+            };
+            let mut module = Module::default();
+            build_module(&mut module);
+            let result = module.interpret_to(&mut std::io::sink()).unwrap();
+            assert_eq!(result, expected, "cond {}", cond);
+            assert_program_exit_code(build_module, expected as i32);
+        }
+    }
+}
+
+#[test]
+fn binary_round_trip_generates_same_code() {
+    let module = every_instruction();
+    let bytes = module.to_bytes();
+    let decoded = Module::from_bytes(&bytes).unwrap();
+    assert_eq!(decoded.to_bytes(), bytes);
+    assert_eq!(decoded.to_string(), module.to_string());
+    assert_eq!(
+        decoded.generate_string().unwrap(),
+        module.generate_string().unwrap()
+    );
+    assert_eq!(att_code(decoded), att_code(every_instruction()));
+}
+
+#[test]
+fn binary_decoding_rejects_invalid_input() {
+    let bytes = every_instruction().to_bytes();
+    let error = Module::from_bytes(&bytes[..bytes.len() - 1]).unwrap_err();
+    assert_eq!(error.message, "unexpected end of input");
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    let error = Module::from_bytes(&trailing).unwrap_err();
+    assert_eq!(error.offset, bytes.len());
+    assert!(Module::from_bytes(b"ELF").is_err());
+    let mut version = bytes;
+    version[4] = 6;
+    assert_eq!(
+        Module::from_bytes(&version).unwrap_err().to_string(),
+        "byte 5: unsupported version 6"
+    );
+}
+
+#[test]
+fn text_round_trip_builds_same_instructions() {
+    let module = every_instruction();
+    let text = module.to_string();
+    for expected in &[
+        "global @counter -5\n",
+        "tls @thread_local 9\n",
+        "string @__fig_str0 \"hello\\n\"\n",
+        "    ; every kind of instruction\n",
+        "[rbp-16] = alloc 16",
+        ", table [rbp-",
+        "save_context [rbp-",
+        "shl %r14, 4",
+    ] {
+        assert!(text.contains(expected), "{} not in\n{}", expected, text);
+    }
+    let parsed = Module::parse(&text).unwrap();
+    assert_eq!(parsed.to_string(), text);
+    for (parsed, func) in parsed.funcs.iter().zip(&module.funcs) {
+        for (parsed, block) in parsed.blocks.iter().zip(&func.blocks) {
+            assert_eq!(
+                format!("{:?}", parsed.instructions),
+                format!("{:?}", block.instructions)
+            );
+            assert_eq!(parsed.comments, block.comments);
+        }
+    }
+}
+
+#[test]
+fn parallel_generation_matches_sequential_byte_for_byte() {
+    let configure: [fn(&mut Module); 4] = [
+        |_| {},
+        |module| module.set_syntax(AsmSyntax::Att),
+        |module| module.set_output_kind(OutputKind::SharedLibrary),
+        |module| module.set_target(Target::Aarch64),
+    ];
+    for configure in &configure {
+        let mut module = every_instruction();
+        for i in 0..37 {
+            let mut func = Function::new(format!("f{}", i));
+            let mut block = func.new_block(".entry".into());
+            let value = block.build_constant(i);
+            let value = block
+                .build_call_with_result("stop".into(), &[value])
+                .unwrap();
+            block.build_return(Some(value));
+            func.append_block(block);
+            module.append_func(func);
+        }
+        configure(&mut module);
+        let sequential = module.generate_string().unwrap();
+        for threads in [1, 2, 3, 8, 64] {
+            let parallel = module.generate_code_on(threads).unwrap();
+            assert!(
+                parallel.as_bytes() == sequential.as_bytes(),
+                "{} threads",
+                threads
+            );
+        }
+    }
+}
+
+#[test]
+fn builders_report_invalid_operands_as_errors() {
+    let mut block = Block::new(".entry".into());
+    let args: Vec<ValueRef> = (0..7).map(ValueRef::Immediate).collect();
+    let too_many = Err(BuildError::TooManyArguments { count: 7, max: 6 });
+    assert_eq!(block.build_call("f".into(), &args), too_many);
+    assert_eq!(
+        block.build_call_with_result("f".into(), &args).map(|_| ()),
+        too_many
+    );
+    assert_eq!(
+        block.build_call_preserving("f".into(), &args, &[]),
+        too_many
+    );
+    let value = block.build_constant(1);
+    let cases = [
+        (1, ".one".to_string()),
+        (2, ".two".into()),
+        (1, ".other".into()),
+    ];
+    assert_eq!(
+        block.build_switch(value, &cases, ".default".into()),
+        Err(BuildError::DuplicateCase { value: 1 })
+    );
+    assert_eq!(
+        block.build_select_index(value, &[]),
+        Err(CodegenError::NothingToSelect)
+    );
+    // Nothing was built and the value is still live.
+    assert_eq!(block.instructions.len(), 1);
+    assert!(block.registers.is_allocated(Register::R15));
+    assert!(!block.registers.is_allocated(Register::R14));
+}
+
+#[test]
+fn globals_are_declared_once_with_names_fitting_a_symbol() {
+    let mut module = Module::default();
+    let counter = module.add_global("counter".into(), 1).unwrap();
+    assert_eq!(counter, ValueRef::global("counter").unwrap());
+    assert_eq!(
+        module.add_global("counter".into(), 2),
+        Err(BuildError::DuplicateGlobal {
+            name: "counter".into()
+        })
+    );
+    let long = "c".repeat(Symbol::CAPACITY + 1);
+    assert_eq!(
+        module.add_global(long.clone(), 3),
+        Err(BuildError::SymbolTooLong { name: long })
+    );
+    let longest = "c".repeat(Symbol::CAPACITY);
+    let global = module.add_global(longest.clone(), 4).unwrap();
+    assert_eq!(global.to_string(), format!("@{}", longest));
+    assert_eq!(module.globals.len(), 2);
+}
+
+#[test]
+fn thread_locals_have_a_copy_per_thread() {
+    // Increments `count` and returns it plus `base`, one in `.tbss` and one in `.tdata`.
+    let bump = |module: &mut Module| {
+        let base = module.add_tls("base".into(), 40).unwrap();
+        let count = module.add_tls("count".into(), 0).unwrap();
+        let mut func = Function::new("bump".into());
+        let mut block = func.new_block(".entry".into());
+        let value = block.build_load(count);
+        let one = block.build_constant(1);
+        let value = block.build_add(value, one);
+        block.build_store(value, count);
+        let value = block.build_load(count);
+        let right = block.build_load(base);
+        let sum = block.build_add(value, right);
+        block.build_return(Some(sum));
+        func.append_block(block);
+        module.append_func(func);
+    };
+
+    let mut module = Module::default();
+    bump(&mut module);
+    let mut func = Function::new("_start".into());
+    let mut block = func.new_block(".entry".into());
+    block.build_call("bump".into(), &[]).unwrap();
+    let result = block.build_call_with_result("bump".into(), &[]).unwrap();
+    block.build_exit(result);
+    func.append_block(block);
+    module.append_func(func);
+    assert_eq!(module.interpret(), 42);
+    module.set_target(Target::Aarch64);
+    let code = module.generate_string().unwrap();
+    let address = "\tmrs x15, tpidr_el0
+\tadd x15, x15, #:tprel_hi12:count, lsl #12
+\tadd x15, x15, #:tprel_lo12_nc:count
+\tldr x17, [x15]
+";
+    assert!(code.contains(address), "{}", code);
+    assert!(
+        code.contains("\t.section .tbss,\"awT\",@nobits\n"),
+        "{}",
+        code
+    );
+
+    // Without libc nothing sets up the thread pointer, so this only runs natively with it.
+    let mut module = Module::default();
+    bump(&mut module);
+    let code = att_code(module);
+    assert!(code.contains("\tmovq %fs:count@tpoff, %r15\n"), "{}", code);
+    let c = r#"
+#include <pthread.h>
+
+long bump(void);
+
+static void *run(void *arg) {
+    (void)arg;
+    bump();
+    return (void *)bump();
+}
+
+int main(void) {
+    pthread_t thread;
+    void *result;
+    if (bump() != 41)
+        return 1;
+    pthread_create(&thread, 0, run, 0);
+    pthread_join(thread, &result);
+    if ((long)result != 42)
+        return 2;
+    return bump() == 42 ? 42 : 3;
+}
+"#;
+    let test = "thread_locals_have_a_copy_per_thread";
+    if let Some(exit_code) = run_with_libc_linked(test, &code, c, &["-no-pie", "-pthread"]) {
+        assert_eq!(exit_code, 42);
+    }
+}
+
+/// A module whose entry point holds values in R15 to R8 while calling `keep`, which only
+/// overwrites R11 and is declared to do so if `declare` is set, and exits with their sum.
+fn call_keeping_registers(declare: bool) -> Module {
+    let mut module = Module::default();
+    if declare {
+        module.declare_function_clobbers("keep".into(), &[Register::R11]);
+    }
+    let mut func = Function::new("_start".into());
+    let mut block = func.new_block(".entry".into());
+    let values: Vec<ValueRef> = (1..=8).map(|value| block.build_constant(value)).collect();
+    assert_eq!(values[7], ValueRef::Register(Register::R8));
+    block.build_call("keep".into(), &[]).unwrap();
+    let sum = values[1..]
+        .iter()
+        .fold(values[0], |sum, &value| block.build_add(sum, value));
+    block.build_exit(sum);
+    func.append_block(block);
+    module.append_func(func);
+
+    let mut keep = Function::new("keep".into());
+    let mut block = keep.new_block(".entry".into());
+    block.build_move_to_register(ValueRef::Immediate(99), Register::R11);
+    block.build_return(None);
+    keep.append_block(block);
+    module.append_func(keep);
+    module
+}
+
+#[test]
+fn call_saves_only_declared_clobbers() {
+    let code = call_keeping_registers(false).generate_string().unwrap();
+    for reg in &["r11", "r10", "r9", "r8"] {
+        assert!(code.contains(&format!("\tpush {}\n", reg)), "{}", code);
+    }
+    let code = call_keeping_registers(true).generate_string().unwrap();
+    let start = &code[..code.find("keep:").unwrap()];
+    assert!(start.contains("\tpush r11\n"), "{}", code);
+    for reg in &["r10", "r9", "r8"] {
+        assert!(!start.contains(&format!("\tpush {}\n", reg)), "{}", code);
+    }
+
+    let result = call_keeping_registers(true)
+        .interpret_to(&mut std::io::sink())
+        .unwrap();
+    assert_eq!(result, 36);
+    assert_program_exit_code(|module| *module = call_keeping_registers(true), 36);
+}
+
+#[test]
+fn call_to_function_without_clobbers_saves_nothing() {
+    let build_module = |declare: bool| {
+        let mut module = Module::default();
+        if declare {
+            module.declare_function_clobbers("put_int".into(), &[]);
+        }
+        let mut block = Block::new(".entry".into());
+        let values: Vec<ValueRef> = (1..=8).map(|value| block.build_constant(value)).collect();
+        block.build_call("put_int".into(), &[]).unwrap();
+        let pinned = block.build_constant(9);
+        let pinned = block.build_move_to_register(pinned, Register::Rdi);
+        block.build_call("put_int".into(), &[values[0]]).unwrap();
+        block.build_exit(pinned);
+        let mut func = Function::new("_start".into());
+        func.append_block(block);
+        module.append_func(func);
+        let code = module.generate_string().unwrap();
+        code[code.find(".entry:").unwrap()..].to_string()
+    };
+    let code = build_module(false);
+    assert_eq!(code.matches("\tpush ").count(), 9, "{}", code);
+    // Only RDI, which the argument of the second call is passed in, is still saved.
+    let code = build_module(true);
+    assert_eq!(code.matches("\tpush ").count(), 1, "{}", code);
+    assert!(code.contains("\tpush rdi\n"), "{}", code);
+}
+
+#[test]
+fn select_with_memory_operands_and_destination() {
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        let [cond, a, b, first, third] =
+            [0, 10, 20, 0, 2].map(|value| place(&mut block, value, Location::Memory));
+        // With every register taken, the results are spilled to memory.
+        let values: Vec<ValueRef> = (1..=14).map(|value| block.build_constant(value)).collect();
+        let selected = block.build_select(cond, a, b);
+        let small = block.build_select_index(first, &[a, b, selected]).unwrap();
+        let large = block
+            .build_select_index(third, &[a, b, selected, a, b])
+            .unwrap();
+        for result in [selected, small, large] {
+            assert!(result.in_memory(), "{:?}", result);
+        }
+        let mut sum = values[1..]
+            .iter()
+            .fold(values[0], |sum, &value| block.build_add(sum, value));
+        for result in [selected, small, large] {
+            let result = block.build_load(result);
+            sum = block.build_add(sum, result);
+        }
+        block.build_exit(sum);
+        func.append_block(block);
+        module.append_func(func);
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    let code = module.generate_string().unwrap();
+    assert!(
+        !code.contains("\tcmove [") && !code.contains("\tcmovne ["),
+        "{}",
+        code
+    );
+    assert_eq!(module.interpret_to(&mut std::io::sink()).unwrap(), 155);
+    assert_program_exit_code(build_module, 155);
+}
+
+#[test]
+fn min_and_max_of_stack_slots() {
+    use Location::Memory;
+    let rcx = Location::Register(Register::Rcx);
+    let cases: [(BuildBinary, Value, Value, Value); 4] = [
+        (Block::build_min, -7, 5, -7),
+        (Block::build_min, 9, 5, 5),
+        (Block::build_max, -7, 5, 5),
+        (Block::build_max, 9, -5, 9),
+    ];
+    for &(build, left, right, expected) in &cases {
+        for &(left_location, right_location) in &[(Memory, Memory), (Memory, rcx), (rcx, Memory)] {
+            let build_module = |module: &mut Module| {
+                let mut func = Function::new("_start".into());
+                let mut block = func.new_block(".entry".into());
+                let left = place(&mut block, left, left_location);
+                let right = place(&mut block, right, right_location);
+                let mut result = build(&mut block, left, right);
+                if result.in_memory() {
+                    result = block.build_load(result);
+                }
+                block.build_exit(result);
+                func.append_block(block);
+                module.append_func(func);
+            };
+            let mut module = Module::default();
+            build_module(&mut module);
+            let code = module.generate_string().unwrap();
+            assert!(
+                !code.contains("\tcmovg [") && !code.contains("\tcmovl ["),
+                "{}",
+                code
+            );
+            let result = module.interpret_to(&mut std::io::sink()).unwrap();
+            assert_eq!(result, expected);
+            assert_program_exit_code(build_module, expected as u8 as i32);
+        }
+    }
+}
+
+#[test]
+fn oversized_immediate_is_materialized_in_register() {
+    let build_module = |module: &mut Module| {
+        let mut block = Block::new(".entry".into());
+        let left = block.build_constant(3);
+        let right = block.build_immediate((1 << 40) + 4);
+        assert!(matches!(right, ValueRef::Register(_)));
+        let sum = block.build_add(left, right);
+        let mask = block.build_immediate(0xff);
+        assert_eq!(mask, ValueRef::Immediate(0xff));
+        let result = block.build_and(sum, mask);
+        block.build_exit(result);
+        let mut func = Function::new("_start".into());
+        func.append_block(block);
+        let code = func.generate_string().unwrap();
+        assert!(code.contains("mov r14, 1099511627780"), "{}", code);
+        assert!(code.contains("and r15, 255"), "{}", code);
+        module.append_func(func);
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    assert_eq!(module.interpret(), 7);
+    assert_program_exit_code(build_module, 7);
+}
+
+#[test]
+fn optimizations_keep_memory_accesses_on_their_side_of_fences() {
+    let optimize = |block: &mut Block| {
+        block.optimize_constants();
+        block.eliminate_dead_code();
+        block.optimize_peephole();
+    };
+
+    let mut block = Block::new(".entry".into());
+    let data = block.build_alloc(8);
+    let flag = block.build_alloc(8);
+    let value = block.build_constant(1);
+    block.build_store(value, data);
+    let value = block.build_constant(1);
+    block.build_store_release(value, flag);
+    let value = block.build_constant(2);
+    block.build_store(value, data);
+    let exit_code = block.build_load_acquire(data);
+    block.build_exit(exit_code);
+    optimize(&mut block);
+    assert_eq!(
+        block.to_string(),
+        ".entry:
+    [rbp-16] = alloc 16
+    [rbp-32] = alloc 16
+    store 1, [rbp-16]
+    fence release
+    store_volatile 1, [rbp-32]
+    store 2, [rbp-16]
+    %r15 = load [rbp-16]
+    fence acquire
+    exit %r15
+"
+    );
+    let code = block.generate_string().unwrap();
+    assert!(!code.contains("mfence"), "{}", code);
+
+    // Without the fence, storing the value just loaded would be removed as redundant.
+    for ordering in [None, Some(MemoryOrdering::Acquire)] {
+        let mut block = Block::new(".entry".into());
+        let shared = block.build_alloc(8);
+        let value = block.build_load(shared);
+        if let Some(ordering) = ordering {
+            block.build_fence(ordering);
+        }
+        block.build_store(value, shared);
+        optimize(&mut block);
+        let stores = block.to_string().matches("store").count();
+        assert_eq!(stores, ordering.is_some() as usize, "{}", block);
+    }
+}
+
+#[test]
+fn constants_propagate_through_stack_memory() {
+    // Instructions built between the store and the load of the slot, whether the load is
+    // replaced with the constant stored, and the exit code.
+    type Between = fn(&mut Block, ValueRef);
+    let cases: [(Between, bool, i32); 4] = [
+        (|_, _| {}, true, 8),
+        (
+            |block, slot| {
+                let one = block.build_constant(1);
+                block.build_add(slot, one);
+            },
+            false,
+            9,
+        ),
+        (
+            |block, _| block.build_fence(MemoryOrdering::SeqCst),
+            false,
+            8,
+        ),
+        (
+            |block, _| {
+                let other = block.build_alloc(8);
+                let seven = block.build_constant(7);
+                block.build_store(seven, other);
+            },
+            true,
+            8,
+        ),
+    ];
+    for &(between, propagated, expected) in &cases {
+        let build_module = |module: &mut Module| {
+            let mut block = Block::new(".entry".into());
+            let slot = block.build_alloc(8);
+            let five = block.build_constant(5);
+            block.build_store(five, slot);
+            between(&mut block, slot);
+            let value = block.build_load(slot);
+            let three = block.build_constant(3);
+            let sum = block.build_add(value, three);
+            block.build_exit(sum);
+            block.optimize_constants();
+            assert_eq!(!block.to_string().contains("load"), propagated, "{}", block);
+            let mut func = Function::new("_start".into());
+            func.append_block(block);
+            module.append_func(func);
+        };
+        let mut module = Module::default();
+        build_module(&mut module);
+        assert_eq!(module.interpret(), expected as i64);
+        assert_program_exit_code(build_module, expected);
+    }
+}
+
+#[test]
+fn allocation_and_invalid_operands_return_errors() {
+    let registers = RegisterAlloc::new();
+    for _ in 0..Register::ALL.len() {
+        registers.alloc().unwrap();
+    }
+    assert_eq!(registers.alloc(), Err(CodegenError::OutOfRegisters));
+    assert_eq!(
+        registers.alloc_specific(Register::Rax),
+        Err(CodegenError::RegisterInUse(Register::Rax))
+    );
+
+    let mut block = Block::new(".entry".into());
+    let values: Vec<ValueRef> = (0..Register::ALL.len())
+        .map(|value| block.build_constant(value as Value))
+        .collect();
+    assert!(values
+        .iter()
+        .all(|value| matches!(value, ValueRef::Register(_))));
+    let index = block.build_constant(3);
+    let built = block.instructions.len();
+    assert_eq!(
+        block.build_select_index(index, &values),
+        Err(CodegenError::OutOfRegisters)
+    );
+    assert_eq!(block.instructions.len(), built);
+    assert_eq!(
+        block.build_print_string(index),
+        Err(CodegenError::InvalidOperand {
+            instruction: "print_string",
+            operand: index,
+        })
+    );
+    assert!(block.free_alloc(values[0], 8).is_err());
+
+    // Code generation reports invalid instructions it is given instead of panicking.
+    let mut block = Block::new(".entry".into());
+    let context = ValueRef::Register(Register::Rax);
+    block
+        .instructions
+        .push(Instruction::SaveContext { context });
+    let err = block.generate_string().unwrap_err();
+    let err = err.get_ref().unwrap().downcast_ref::<CodegenError>();
+    assert_eq!(
+        err,
+        Some(&CodegenError::InvalidOperand {
+            instruction: "save_context",
+            operand: context,
+        })
+    );
+}
+
+/// Parse `source`, a program exiting with `exit_code`, and apply `pass` to every block of
+/// it. Check that the program reads `expected` afterwards and still exits with the same
+/// code, both interpreted and run natively.
+fn check_pass(source: &str, pass: fn(&mut Block), expected: &str, exit_code: i32) {
+    let original = Module::parse(source).unwrap();
+    assert_eq!(original.interpret(), exit_code as i64, "{}", original);
+    let mut optimized = Module::parse(source).unwrap();
+    for func in &mut optimized.funcs {
+        func.blocks.iter_mut().for_each(pass);
+    }
+    assert_eq!(optimized.to_string(), expected);
+    assert_eq!(optimized.interpret(), exit_code as i64);
+    assert_program_exit_code(|module| *module = optimized, exit_code);
+}
+
+#[test]
+fn peephole_removes_redundant_moves_and_folds_immediates() {
+    check_pass(
+        "func _start
+.entry:
+    %x = alloc 8
+    %y = alloc 8
+    %0 = const 10
+    store %0, %x
+    %1 = load %x
+    store %1, %x
+    move %x, %x
+    %2 = load %x
+    store %2, %y
+    %3 = load %y
+    %4 = const 5
+    add %3, %4
+    %5 = const 3
+    sub %3, %5
+    %6 = const 16
+    or %3, %6
+    %7 = const 1
+    xor %3, %7
+    %8 = const 255
+    and %3, %8
+    %big = const 4294967296
+    add %3, %big
+    %9 = const 4294967296
+    sub %3, %9
+    %10 = const 2
+    mul %3, %10
+    %11 = const 7
+    store_volatile %11, %y
+    %12 = load %y
+    store_volatile %12, %y
+    exit %3
+",
+        Block::optimize_peephole,
+        "func _start
+.entry:
+    [rbp-16] = alloc 16
+    [rbp-32] = alloc 16
+    store 10, [rbp-16]
+    %r15 = load [rbp-16]
+    %r15 = load [rbp-16]
+    store %r15, [rbp-32]
+    add %r15, 5
+    sub %r15, 3
+    or %r15, 16
+    xor %r15, 1
+    and %r15, 255
+    %r14 = const 4294967296
+    add %r15, %r14
+    %r14 = const 4294967296
+    sub %r15, %r14
+    %r14 = const 2
+    mul %r15, %r14
+    store_volatile 7, [rbp-32]
+    %r14 = load [rbp-32]
+    store_volatile %r14, [rbp-32]
+    exit %r15
+",
+        58,
+    );
+}
+
+/// A function `clobber` that overwrites `regs` and returns 7.
+fn clobber(regs: &[Register]) -> Function {
+    let mut func = Function::new("clobber".into());
+    let mut block = func.new_block(".entry".into());
+    let slot = block.build_alloc(8);
+    for (i, &reg) in regs.iter().enumerate() {
+        let value = block.build_constant(100 + i as Value);
+        let value = block.build_move_to_register(value, reg);
+        block.build_store(value, slot);
+    }
+    let result = block.build_constant(7);
+    block.build_return(Some(result));
+    func.append_block(block);
+    func
+}
+
+#[test]
+fn callee_saved_registers_survive_calls() {
+    use Register::*;
+    let saved = [Rbx, R12, R13, R14, R15];
+    let code = clobber(&saved).generate_string().unwrap();
+    let pushes: Vec<&str> = code.lines().filter(|line| line.contains("push")).collect();
+    assert_eq!(
+        pushes,
+        [
+            "\tpush rbx",
+            "\tpush r12",
+            "\tpush r13",
+            "\tpush r14",
+            "\tpush r15",
+            "\tpush rbp"
+        ]
+    );
+    let pops: Vec<&str> = code.lines().filter(|line| line.contains("pop")).collect();
+    assert_eq!(
+        pops,
+        [
+            "\tpop r15",
+            "\tpop r14",
+            "\tpop r13",
+            "\tpop r12",
+            "\tpop rbx"
+        ]
+    );
+    // Caller-saved registers are the caller's business.
+    let code = clobber(&[Rax, R11]).generate_string().unwrap();
+    assert!(!code.contains("\tpush r11"), "{}", code);
+
+    // The entry point keeps values in the callee-saved registers across the call.
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        let mut values: Vec<ValueRef> = (1..=5).map(|value| block.build_constant(value)).collect();
+        assert_eq!(values[3], ValueRef::Register(R12));
+        let value = block.build_constant(6);
+        values.push(block.build_move_to_register(value, Rbx));
+        let result = block.build_call_with_result("clobber".into(), &[]).unwrap();
+        let sum = values
+            .iter()
+            .fold(result, |sum, &value| block.build_add(sum, value));
+        block.build_exit(sum);
+        func.append_block(block);
+        module.append_func(func);
+        module.append_func(clobber(&saved));
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    let code = module.funcs[0].generate_string().unwrap();
+    for reg in &saved {
+        assert!(
+            !code.contains(&format!("push {}\n", reg.name())),
+            "{}",
+            code
+        );
+    }
+    assert_eq!(module.interpret(), 28);
+    assert_program_exit_code(build_module, 28);
+
+    // So does C code calling it.
+    let mut module = Module::default();
+    module.append_func(clobber(&saved));
+    let c = r#"
+long clobber(void);
+
+int main(void) {
+    long regs[5];
+    long result;
+    __asm__ volatile(
+        "mov $1, %%rbx\n\tmov $2, %%r12\n\tmov $3, %%r13\n\tmov $4, %%r14\n\tmov $5, %%r15\n\t"
+        "sub $128, %%rsp\n\tcall clobber\n\tadd $128, %%rsp\n\t"
+        "mov %%rbx, 0(%1)\n\tmov %%r12, 8(%1)\n\tmov %%r13, 16(%1)\n\t"
+        "mov %%r14, 24(%1)\n\tmov %%r15, 32(%1)"
+        : "=&a"(result)
+        : "D"(regs)
+        : "rbx", "r12", "r13", "r14", "r15", "rcx", "rdx", "rsi", "r8", "r9", "r10",
+          "r11", "memory");
+    for (int i = 0; i < 5; i++) {
+        if (regs[i] != i + 1) {
+            return 10 + i;
+        }
+    }
+    return result;
+}
+"#;
+    let code = att_code(module);
+    if let Some(exit_code) = run_with_libc("callee_saved_registers_survive_calls", &code, c) {
+        assert_eq!(exit_code, 7);
+    }
+}
+
+#[test]
+fn freed_stack_slots_are_reused() {
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut entry = func.new_block(".entry".into());
+        let first = entry.build_alloc(8);
+        let five = entry.build_constant(5);
+        entry.build_store(five, first);
+        let sum = entry.build_alloc(8);
+        entry.build_move(first, sum);
+        entry.free_alloc(first, 8).unwrap();
+        // A slot of another size is new memory.
+        let wide = entry.build_alloc(24);
+        assert!(wide != first && wide != sum);
+        entry.build_jump(".next".into());
+
+        // Other blocks of the function share the freed slots.
+        let mut next = func.new_block(".next".into());
+        let second = next.build_alloc(8);
+        assert_eq!(second, first);
+        let seven = next.build_constant(7);
+        next.build_store(seven, second);
+        let value = next.build_load(second);
+        next.build_add(sum, value);
+        next.free_alloc(second, 8).unwrap();
+        next.free_alloc(wide, 24).unwrap();
+        assert_eq!(next.build_alloc(24), wide);
+        let exit_code = next.build_load(sum);
+        next.build_exit(exit_code);
+        func.append_block(entry);
+        func.append_block(next);
+        // The frame is as large as the most memory allocated at once.
+        assert_eq!(func.frame_size(), 16 + 16 + 32);
+        module.append_func(func);
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    assert_eq!(module.interpret(), 12);
+    assert_program_exit_code(build_module, 12);
+}
+
+#[test]
+fn use_of_freed_register_fails_validation_and_codegen() {
+    let use_after_free = |register: &str, build: Build| {
+        let mut func = Function::new("_start".into());
+        let mut entry = func.new_block(".entry".into());
+        build(&mut entry);
+        let zero = entry.build_constant(0);
+        entry.build_exit(zero);
+        func.append_block(entry);
+        assert_eq!(
+            func.validate(),
+            Err(ValidationError::UseAfterFree {
+                block: ".entry".into(),
+                register: register.into(),
+            })
+        );
+        let err = func.generate_string().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "block .entry uses register {} after the value in it was freed",
+                register
+            )
+        );
+    };
+    // Consumed by an earlier instruction.
+    use_after_free("r14", |block| {
+        let one = block.build_constant(1);
+        let two = block.build_constant(2);
+        let sum = block.build_add(one, two);
+        block.build_add(sum, two);
+    });
+    use_after_free("xmm1", |block| {
+        let one = block.build_float_constant(1.0);
+        let two = block.build_float_constant(2.0);
+        let sum = block.build_fadd(one, two);
+        block.build_fmul(sum, two);
+    });
+    // Created by a block with registers of its own.
+    use_after_free("r15", |block| {
+        let value = Block::new(".other".into()).build_constant(1);
+        block.build_put_int(value);
+    });
+
+    // A freed register allocated again holds the new value, which is fine to use.
+    let mut func = Function::new("_start".into());
+    let mut entry = func.new_block(".entry".into());
+    let one = entry.build_constant(1);
+    let two = entry.build_constant(2);
+    let sum = entry.build_add(one, two);
+    let three = entry.build_constant(3);
+    assert_eq!(three, two);
+    let sum = entry.build_add(sum, three);
+    entry.build_exit(sum);
+    func.append_block(entry);
+    assert_eq!(func.validate(), Ok(()));
+
+    // A stale use moves with its instruction when the block is split.
+    let mut func = Function::new("_start".into());
+    let mut entry = func.new_block(".entry".into());
+    let one = entry.build_constant(1);
+    let two = entry.build_constant(2);
+    let sum = entry.build_add(one, two);
+    entry.build_put_int(two);
+    entry.build_exit(sum);
+    let tail = entry.split_off(entry.instructions.len() - 2, ".tail".into());
+    func.append_block(entry);
+    func.append_block(tail);
+    assert_eq!(
+        func.validate(),
+        Err(ValidationError::UseAfterFree {
+            block: ".tail".into(),
+            register: "r14".into(),
+        })
+    );
+}
+
+/// A loop counting down from 3 whose exit is reached through a `Switch`, exiting with 42.
+const COUNTDOWN: &str = "func _start
+.entry:
+    %n = alloc 8
+    %0 = const 3
+    store %0, %n
+    %1 = load %n
+    jz %1, .end
+.loop:
+    %2 = load %n
+    switch %2, .body, 0: .end, 1: .last
+.body:
+    sub %n, 1
+    jmp .loop
+.last:
+    sub %n, 1
+    jmp .loop
+.end:
+    %3 = const 42
+    exit %3
+.unreachable:
+    ret
+";
+
+#[test]
+fn successors_and_predecessors_follow_jumps_and_fall_through() {
+    let module = Module::parse(COUNTDOWN).unwrap();
+    let func = &module.funcs[0];
+    let cases: [(&str, &[&str], &[&str]); 6] = [
+        (".entry", &[".end", ".loop"], &[]),
+        (
+            ".loop",
+            &[".end", ".last", ".body"],
+            &[".entry", ".body", ".last"],
+        ),
+        (".body", &[".loop"], &[".loop"]),
+        (".last", &[".loop"], &[".loop"]),
+        (".end", &[], &[".entry", ".loop"]),
+        (".unreachable", &[], &[]),
+    ];
+    for &(block, successors, predecessors) in &cases {
+        assert_eq!(func.successors(block), successors, "{}", block);
+        assert_eq!(func.predecessors(block), predecessors, "{}", block);
+    }
+    assert_eq!(module.interpret(), 42);
+    assert_program_exit_code(|module| *module = Module::parse(COUNTDOWN).unwrap(), 42);
+}
+
+#[test]
+fn dot_output_has_node_per_block_and_edge_per_successor() {
+    let module = Module::parse(COUNTDOWN).unwrap();
+    assert_eq!(
+        module.funcs[0].to_dot(),
+        r#"digraph "_start" {
+    ".entry" [label=".entry"];
+    ".loop" [label=".loop"];
+    ".body" [label=".body"];
+    ".last" [label=".last"];
+    ".end" [label=".end"];
+    ".unreachable" [label=".unreachable"];
+    ".entry" -> ".end" [style=dashed];
+    ".entry" -> ".loop";
+    ".loop" -> ".end" [style=dashed];
+    ".loop" -> ".last" [style=dashed];
+    ".loop" -> ".body" [style=dashed];
+    ".body" -> ".loop";
+    ".last" -> ".loop";
+}
+"#
+    );
+
+    let mut func = Function::new("quote\"d".into());
+    let mut block = func.new_block(".back\\slash".into());
+    block.build_jump(".back\\slash".into());
+    func.append_block(block);
+    assert_eq!(
+        func.to_dot(),
+        r#"digraph "quote\"d" {
+    ".back\\slash" [label=".back\\slash"];
+    ".back\\slash" -> ".back\\slash";
+}
+"#
+    );
+}
+
+#[test]
+fn builder_appends_same_instructions_as_build_methods() {
+    let string = ValueRef::global("__fig_str0").unwrap();
+    let cases = vec![(1, ".one".to_string()), (2, ".two".to_string())];
+    let setup = |block: &mut Block| (block.build_alloc(8), block.build_save_context());
+
+    let mut built = Block::new(".entry".into());
+    let (memory, context) = setup(&mut built);
+    built.build_comment("every builder method");
+    built.build_move(memory, memory);
+    built.build_store(memory, memory);
+    built.build_store_sized(ValueRef::Immediate(1), memory, Width::Dword);
+    built.build_store_volatile(ValueRef::Immediate(2), memory);
+    built.build_store_release(ValueRef::Immediate(3), memory);
+    built.build_fence(MemoryOrdering::Acquire);
+    built.build_call("f".into(), &[memory]).unwrap();
+    built
+        .build_call_preserving("g".into(), &[memory, memory], &[Register::R11])
+        .unwrap();
+    built.build_put_int(memory);
+    built.build_put_hex(memory);
+    built.build_put_bin(memory);
+    built.build_put_char(memory);
+    built.build_print_string(string).unwrap();
+    built.build_jump_if_zero(memory, ".zero".into());
+    built.build_jump_if_not_zero(memory, ".nonzero".into());
+    built
+        .build_switch(memory, &cases, ".default".into())
+        .unwrap();
+    built.build_restore_context(context).unwrap();
+    built.build_jump_indirect(memory);
+    built.build_tail_call_indirect(memory, Some(memory));
+    built.build_jump(".next".into());
+    built.build_return(Some(memory));
+    built.build_exit(memory);
+
+    let mut chained = Block::new(".entry".into());
+    let (memory, context) = setup(&mut chained);
+    chained
+        .builder()
+        .comment("every builder method")
+        .move_value(memory, memory)
+        .store(memory, memory)
+        .store_sized(ValueRef::Immediate(1), memory, Width::Dword)
+        .store_volatile(ValueRef::Immediate(2), memory)
+        .store_release(ValueRef::Immediate(3), memory)
+        .fence(MemoryOrdering::Acquire)
+        .call("f".into(), &[memory])
+        .unwrap()
+        .call_preserving("g".into(), &[memory, memory], &[Register::R11])
+        .unwrap()
+        .put_int(memory)
+        .put_hex(memory)
+        .put_bin(memory)
+        .put_char(memory)
+        .print_string(string)
+        .unwrap()
+        .jump_if_zero(memory, ".zero".into())
+        .jump_if_not_zero(memory, ".nonzero".into())
+        .switch(memory, &cases, ".default".into())
+        .unwrap()
+        .restore_context(context)
+        .unwrap()
+        .jump_indirect(memory)
+        .tail_call_indirect(memory, Some(memory))
+        .jump(".next".into())
+        .ret(Some(memory))
+        .exit(memory);
+
+    assert_eq!(
+        format!("{:?}", chained.instructions),
+        format!("{:?}", built.instructions)
+    );
+    assert_eq!(chained.comments, built.comments);
+    assert_eq!(chained.instructions.len(), 26);
+}
+
+/// Exit with the bits of a floating-point value as the exit code.
+fn exit_with_bits(block: &mut Block, value: ValueRef) {
+    let memory = block.build_alloc(8);
+    block.build_store(value, memory);
+    let bits = block.build_load(memory);
+    block.build_exit(bits);
+}
+
+/// A function computing ((0.5 + 1.0 + ... + 10.0) * 1.5 / 0.25 + 0.5 - 2.0) = 628.5 with
+/// more values live at once than there are SSE registers, and one of the operands in stack
+/// memory. The result is returned or, in `_start`, its bits are the exit code.
+fn float_function(name: &str) -> Function {
+    let mut func = Function::new(name.into());
+    let mut block = func.new_block(".entry".into());
+    let half = block.build_float_constant(0.5);
+    let slot = block.build_alloc(8);
+    block.build_store(half, slot);
+    let values: Vec<ValueRef> = (1..=20)
+        .map(|i| block.build_float_constant(0.5 * i as f64))
+        .collect();
+    assert!(values.iter().any(|value| value.in_memory()));
+    let mut sum = values[0];
+    for &value in &values[1..] {
+        sum = block.build_fadd(sum, value);
+    }
+    let factor = block.build_float_constant(1.5);
+    let product = block.build_fmul(sum, factor);
+    let divisor = block.build_float_constant(0.25);
+    let quotient = block.build_fdiv(product, divisor);
+    let result = block.build_fadd(quotient, slot);
+    let two = block.build_float_constant(2.0);
+    let result = block.build_fsub(result, two);
+    if name == "_start" {
+        exit_with_bits(&mut block, result);
+    } else {
+        block.build_return(Some(result));
+    }
+    func.append_block(block);
+    func
+}
+
+#[test]
+fn float_arithmetic_spills_and_computes_exact_results() {
+    let mut module = Module::default();
+    module.append_func(float_function("_start"));
+    assert_eq!(module.interpret(), 628.5f64.to_bits() as i64);
+
+    let mut module = Module::default();
+    module.append_func(float_function("compute"));
+    let c = r#"
+double compute(void);
+
+int main(void) {
+    return compute() == 628.5 ? 42 : 1;
+}
+"#;
+    let code = att_code(module);
+    let test = "float_arithmetic_spills_and_computes_exact_results";
+    if let Some(exit_code) = run_with_libc(test, &code, c) {
+        assert_eq!(exit_code, 42);
+    }
+}
+
+/// A function computing 0.1 + 0.2 with 0.1 held in an SSE register across a call to
+/// `clobber`. The result is returned or, in `_start`, its bits are the exit code.
+fn float_across_call(name: &str) -> Function {
+    let mut func = Function::new(name.into());
+    let mut block = func.new_block(".entry".into());
+    let first = block.build_float_constant(0.1);
+    block.build_call("clobber".into(), &[]).unwrap();
+    let second = block.build_float_constant(0.2);
+    let sum = block.build_fadd(first, second);
+    if name == "_start" {
+        exit_with_bits(&mut block, sum);
+    } else {
+        block.build_return(Some(sum));
+    }
+    func.append_block(block);
+    func
+}
+
+#[test]
+fn live_sse_registers_survive_calls() {
+    let expected = (0.1f64 + 0.2).to_bits() as i64;
+    let build_module = |module: &mut Module| {
+        module.append_func(float_across_call("_start"));
+        let mut clobber = Function::new("clobber".into());
+        let mut block = clobber.new_block(".entry".into());
+        let value = block.build_float_constant(7.0);
+        let other = block.build_float_constant(9.0);
+        block.build_fmul(value, other);
+        block.build_return(None);
+        clobber.append_block(block);
+        module.append_func(clobber);
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    let code = module.generate_string().unwrap();
+    assert!(code.contains("\tmovsd [rsp+0], xmm0\n"), "{}", code);
+    assert_eq!(module.interpret(), expected);
+    assert_program_exit_code(build_module, expected as u8 as i32);
+
+    // So does a value held across a call to C code overwriting every SSE register.
+    let mut module = Module::default();
+    module.append_func(float_across_call("compute"));
+    let c = r#"
+double compute(void);
+
+void clobber(void) {
+    __asm__ volatile("pcmpeqd %%xmm0, %%xmm0\n\tpcmpeqd %%xmm1, %%xmm1\n\tpcmpeqd %%xmm7, %%xmm7"
+                     ::: "xmm0", "xmm1", "xmm7");
+}
+
+int main(void) {
+    return compute() == 0.1 + 0.2 ? 42 : 1;
+}
+"#;
+    let code = att_code(module);
+    if let Some(exit_code) = run_with_libc("live_sse_registers_survive_calls", &code, c) {
+        assert_eq!(exit_code, 42);
+    }
+}
+
+#[test]
+fn floating_point_values_are_passed_and_returned_in_sse_registers() {
+    let expected = (0.1f64 + 0.2).to_bits() as i64;
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        let first = block.build_float_constant(0.1);
+        let second = block
+            .build_call_with_float_result("second".into(), &[])
+            .unwrap();
+        let sum = block.build_fadd(first, second);
+        exit_with_bits(&mut block, sum);
+        func.append_block(block);
+        module.append_func(func);
+
+        let mut second = Function::new("second".into());
+        let mut block = second.new_block(".entry".into());
+        block.build_float_constant(1.0);
+        let value = block.build_float_constant(0.2);
+        block.build_return(Some(value));
+        second.append_block(block);
+        module.append_func(second);
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    let code = module.generate_string().unwrap();
+    assert!(code.contains("\tmovsd xmm0, xmm1\n\tret\n"), "{}", code);
+    assert_eq!(module.interpret(), expected);
+    assert_program_exit_code(build_module, expected as u8 as i32);
+
+    // The arguments are held in each other's argument registers, so they are shuffled.
+    let mut func = Function::new("compute".into());
+    let mut block = func.new_block(".entry".into());
+    let first = block.build_float_constant(1.5);
+    let second = block.build_float_constant(0.25);
+    let count = block.build_constant(3);
+    let result = block
+        .build_call_with_float_result("weigh".into(), &[second, count, first])
+        .unwrap();
+    block.build_return(Some(result));
+    func.append_block(block);
+    let mut module = Module::default();
+    module.append_func(func);
+    let c = r#"
+double compute(void);
+
+double weigh(double x, long n, double y) {
+    return x * n + y;
+}
+
+int main(void) {
+    return compute() == 2.25 ? 42 : 1;
+}
+"#;
+    let code = att_code(module);
+    let test = "floating_point_values_are_passed_and_returned_in_sse_registers";
+    if let Some(exit_code) = run_with_libc(test, &code, c) {
+        assert_eq!(exit_code, 42);
+    }
+}
+
+#[test]
+#[should_panic(expected = "integer instructions can't take the floating-point value %xmm0")]
+fn exit_codes_are_integers() {
+    let mut block = Block::new(".entry".into());
+    let value = block.build_float_constant(1.0);
+    block.build_exit(value);
+}
+
+#[test]
+fn externs_are_declared_and_checked() {
+    let build_module = |module: &mut Module| {
+        module.declare_extern("put_int".into());
+        module.declare_extern("unused".into());
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        let value = block.build_call_with_result("helper".into(), &[]).unwrap();
+        block.build_exit(value);
+        func.append_block(block);
+        module.append_func(func);
+
+        let mut helper = Function::new("helper".into());
+        let mut block = helper.new_block(".entry".into());
+        let value = block.build_constant(23);
+        block.build_return(Some(value));
+        helper.append_block(block);
+        module.append_func(helper);
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    let code = module.generate_string().unwrap();
+    let externs: Vec<&str> = code
+        .lines()
+        .filter(|line| line.starts_with("extern"))
+        .collect();
+    assert_eq!(externs, ["extern put_int", "extern unused"]);
+    module.set_check_externs(true);
+    assert_eq!(module.validate_all(), Ok(()));
+    assert_program_exit_code(build_module, 23);
+
+    // A misspelled callee is only reported when checking externs.
+    let mut func = Function::new("f".into());
+    let mut block = func.new_block(".body".into());
+    block.build_call("put_itn".into(), &[]).unwrap();
+    block.build_return(None);
+    func.append_block(block);
+    module.append_func(func);
+    assert_eq!(
+        module.validate_all(),
+        Err(vec![(
+            "f".to_string(),
+            ValidationError::UndeclaredFunction {
+                block: ".body".into(),
+                func: "put_itn".into(),
+            }
+        )])
+    );
+    module.set_check_externs(false);
+    assert_eq!(module.validate_all(), Ok(()));
+    assert!(module
+        .generate_string()
+        .unwrap()
+        .contains("extern put_itn\n"));
+}
+
+/// Build `module` in AT&T syntax into an executable, run it and return its exit code and
+/// what it wrote to stdout. Returns `None` if the GNU assembler or `ld` isn't installed.
+fn run_output(test: &str, mut module: Module) -> Option<(i32, Vec<u8>)> {
+    use crate::build::{build, unique_temp_dir, BuildConfig};
+    use crate::testing::{has_tool, report_skipped};
+    for tool in ["as", "ld"] {
+        if !has_tool(tool) {
+            report_skipped(test, tool);
+            return None;
+        }
+    }
+    module.set_syntax(AsmSyntax::Att);
+    let dir = unique_temp_dir("fig-output-test");
+    let config = BuildConfig {
+        build_dir: dir.clone(),
+        output: dir.join("output"),
+        ..BuildConfig::default()
+    };
+    let built = build(&module, &config);
+    let output = built.and_then(|()| std::process::Command::new(&config.output).output());
+    std::fs::remove_dir_all(&dir).ok();
+    let output = output.expect("failed to build and run program");
+    Some((output.status.code().unwrap(), output.stdout))
+}
+
+#[test]
+fn strings_are_printed_and_load_as_their_length() {
+    let strings = [
+        "hello, world\n",
+        "",
+        "tab\t\"quotes\" \\ snowman \u{2603}\n",
+    ];
+    let build_module = || {
+        let mut module = Module::default();
+        let labels: Vec<ValueRef> = strings.iter().map(|s| module.add_string(s)).collect();
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        for &label in labels.iter().chain(&labels[..1]) {
+            block.build_print_string(label).unwrap();
+        }
+        let length = block.build_load(labels[2]);
+        block.build_exit(length);
+        func.append_block(block);
+        module.append_func(func);
+        module
+    };
+    let expected = format!("{}{}{}{}", strings[0], strings[1], strings[2], strings[0]);
+    let length = strings[2].len() as i32;
+
+    let mut output = vec![];
+    let exit_code = build_module().interpret_to(&mut output).unwrap();
+    assert_eq!(
+        (exit_code, output),
+        (length as i64, expected.clone().into_bytes())
+    );
+    let test = "strings_are_printed_and_load_as_their_length";
+    if let Some(result) = run_output(test, build_module()) {
+        assert_eq!(result, (length, expected.into_bytes()));
+    }
+}
+
+#[test]
+fn global_variables_keep_values_across_calls() {
+    let source = "global @counter -5
+global @copy 0
+func bump
+.entry:
+    %0 = const 10
+    add @counter, %0
+    move @counter, @copy
+    ret
+func _start
+.entry:
+    call bump
+    call bump
+    %1 = load @counter
+    %2 = load @copy
+    add %1, %2
+    %3 = const 7
+    store %3, @copy
+    %4 = load @copy
+    add %1, %4
+    exit %1
+";
+    let module = Module::parse(source).unwrap();
+    let code = module.generate_string().unwrap();
+    assert!(code.contains("counter:\n\tdq -5\n"), "{}", code);
+    assert!(code.contains("\tadd [rel counter], r15\n"), "{}", code);
+    assert_eq!(module.interpret(), 37);
+    assert_program_exit_code(|module| *module = Module::parse(source).unwrap(), 37);
+}
+
+/// A program switching over `input`, held in a register or stack memory, with the given
+/// cases. Case `i` exits with `i + 10`, the default with 99.
+fn switch_program(input: Value, in_memory: bool, cases: &[Value]) -> Module {
+    let mut func = Function::new("_start".into());
+    let mut entry = func.new_block(".entry".into());
+    let mut value = entry.build_constant(input);
+    if in_memory {
+        let slot = entry.build_alloc(8);
+        entry.build_store(value, slot);
+        value = slot;
+    }
+    let cases: Vec<(Value, String)> = cases
+        .iter()
+        .enumerate()
+        .map(|(i, &case)| (case, format!(".case{}", i)))
+        .collect();
+    entry
+        .build_switch(value, &cases, ".default".into())
+        .unwrap();
+    func.append_block(entry);
+    for (i, (_, name)) in cases.iter().enumerate() {
+        let mut block = func.new_block(name.clone());
+        let exit_code = block.build_constant(i as Value + 10);
+        block.build_exit(exit_code);
+        func.append_block(block);
+    }
+    let mut default = func.new_block(".default".into());
+    let exit_code = default.build_constant(99);
+    default.build_exit(exit_code);
+    func.append_block(default);
+    let mut module = Module::default();
+    module.append_func(func);
+    module
+}
+
+#[test]
+fn switch_jumps_to_matching_case_or_default() {
+    // A dense switch with a hole becomes a jump table, a sparse one a chain of comparisons.
+    let dense = [3, 4, 6, 7];
+    let sparse = [-7, 100, 1 << 40];
+    let code = switch_program(0, false, &dense).generate_string().unwrap();
+    assert!(code.contains("\tdd _start.default - "), "{}", code);
+    let code = switch_program(0, false, &sparse).generate_string().unwrap();
+    assert!(!code.contains("\tdd "), "{}", code);
+
+    let inputs = [
+        (&dense[..], 2, 99),
+        (&dense, 3, 10),
+        (&dense, 5, 99),
+        (&dense, 7, 13),
+        (&dense, 8, 99),
+        (&dense, -1, 99),
+        (&sparse, -7, 10),
+        (&sparse, 100, 11),
+        (&sparse, 1 << 40, 12),
+        (&sparse, 0, 99),
+    ];
+    for &(cases, input, expected) in &inputs {
+        for &in_memory in &[false, true] {
+            let module = switch_program(input, in_memory, cases);
+            assert_eq!(module.interpret(), expected as i64, "{:?} {}", cases, input);
+            assert_program_exit_code(
+                |module| *module = switch_program(input, in_memory, cases),
+                expected,
+            );
+        }
+    }
+}
+
+#[test]
+fn select_picks_value_by_condition_without_branching() {
+    for &cond in &[0, 1, -3] {
+        let build_module = |module: &mut Module| {
+            let mut func = Function::new("_start".into());
+            let mut block = func.new_block(".entry".into());
+            let cond = block.build_constant(cond);
+            let if_true = block.build_constant(42);
+            let if_false = block.build_constant(7);
+            let selected = block.build_select(cond, if_true, if_false);
+            assert!(!selected.in_memory());
+            block.build_exit(selected);
+            func.append_block(block);
+            module.append_func(func);
+        };
+        let mut module = Module::default();
+        build_module(&mut module);
+        let code = module.generate_string().unwrap();
+        assert!(code.contains("\tcmovne "), "{}", code);
+        assert!(!code.contains("\tj"), "{}", code);
+        let expected = if cond != 0 { 42 } else { 7 };
+        assert_eq!(module.interpret(), expected as i64);
+        assert_program_exit_code(build_module, expected);
+    }
+}
+
+#[test]
+fn move_copies_between_registers_and_memory() {
+    use Location::Memory;
+    let rcx = Location::Register(Register::Rcx);
+    let rsi = Location::Register(Register::Rsi);
+    for &(source_location, storage_location) in
+        &[(rcx, rsi), (rcx, Memory), (Memory, rsi), (Memory, Memory)]
+    {
+        let build_module = |module: &mut Module| {
+            let mut func = Function::new("_start".into());
+            let mut block = func.new_block(".entry".into());
+            let source = place(&mut block, 30, source_location);
+            let storage = place(&mut block, 0, storage_location);
+            block.build_move(source, storage);
+            // Both stay usable and independent of each other.
+            let one = block.build_constant(1);
+            block.build_add(storage, one);
+            let storage = block.build_load(storage);
+            let sum = block.build_add(storage, source);
+            block.build_exit(sum);
+            func.append_block(block);
+            module.append_func(func);
+        };
+        let mut module = Module::default();
+        build_module(&mut module);
+        assert_eq!(module.interpret(), 61);
+        assert_program_exit_code(build_module, 61);
+    }
+}
+
+/// Check that programs exiting with the values computed by the given builders exit with
+/// the expected values, in full in the interpreter and truncated natively.
+fn check_results(cases: &[(Compute, Value)]) {
+    for (i, &(compute, expected)) in cases.iter().enumerate() {
+        let build_module = |module: &mut Module| {
+            let mut func = Function::new("_start".into());
+            let mut block = func.new_block(".entry".into());
+            let mut result = compute(&mut block);
+            if result.in_memory() {
+                result = block.build_load(result);
+            }
+            block.build_exit(result);
+            func.append_block(block);
+            module.append_func(func);
+        };
+        let mut module = Module::default();
+        build_module(&mut module);
+        assert_eq!(module.interpret(), expected, "case {}", i);
+        assert_program_exit_code(build_module, expected as u8 as i32);
+    }
+}
+
+/// Shift `value` right by 32 bits, leaving its upper half.
+fn upper_half(block: &mut Block, value: ValueRef) -> ValueRef {
+    let value = block.build_load(value);
+    let count = block.build_constant(32);
+    block.build_shift_right(value, count)
+}
+
+#[test]
+fn dword_operations_clear_upper_half_of_registers_only() {
+    check_results(&[
+        // 0xffffffff + 3 wraps around.
+        (
+            |block| {
+                let value = block.build_constant_sized(-1, Width::Dword);
+                block.build_add_sized(value, ValueRef::Immediate(3), Width::Dword)
+            },
+            2,
+        ),
+        (
+            |block| {
+                let value = block.build_constant_sized(-1, Width::Dword);
+                upper_half(block, value)
+            },
+            0,
+        ),
+        // A 32-bit store leaves the upper half of the memory alone.
+        (
+            |block| {
+                let slot = place(block, (1 << 32) + 100, Location::Memory);
+                let seven = block.build_constant(7);
+                block.build_store_sized(seven, slot, Width::Dword);
+                slot
+            },
+            (1 << 32) + 7,
+        ),
+        (
+            |block| {
+                let value = place(block, (1 << 32) + 100, Location::Memory);
+                let value = block.build_load(value);
+                let eight = block.build_constant(8);
+                let value = block.build_subtract_sized(value, eight, Width::Dword);
+                upper_half(block, value)
+            },
+            0,
+        ),
+        // So does arithmetic on memory, even when the lower half overflows.
+        (
+            |block| {
+                let slot = place(block, (1 << 32) + 0xffff_ffff, Location::Memory);
+                block.build_add_sized(slot, ValueRef::Immediate(1), Width::Dword);
+                upper_half(block, slot)
+            },
+            1,
+        ),
+        (
+            |block| {
+                let slot = place(block, (2 << 32) + 5, Location::Memory);
+                let right = place(block, 7, Location::Memory);
+                block.build_subtract_sized(slot, right, Width::Dword)
+            },
+            (2 << 32) + 0xffff_fffe,
+        ),
+    ]);
+}
+
+/// Take every register with a value, so the next values are spilled to stack memory.
+fn take_all_registers(block: &mut Block) {
+    for _ in 0..Register::ALL.len() {
+        block.build_constant(0);
+    }
+    assert!(block.build_constant(0).in_memory());
+}
+
+#[test]
+fn constants_beyond_32_bits_keep_all_bits() {
+    check_results(&[
+        (|block| block.build_constant(0x8000_0000), 0x8000_0000),
+        (
+            |block| {
+                let value = block.build_constant((3 << 32) + 9);
+                upper_half(block, value)
+            },
+            3,
+        ),
+        (
+            |block| {
+                take_all_registers(block);
+                let value = block.build_constant((5 << 32) + 9);
+                assert!(value.in_memory());
+                value
+            },
+            (5 << 32) + 9,
+        ),
+        (
+            |block| {
+                take_all_registers(block);
+                let value = block.build_constant((5 << 32) + 9);
+                upper_half(block, value)
+            },
+            5,
+        ),
+        (
+            |block| {
+                take_all_registers(block);
+                block.build_constant(i32::MIN as Value - 1)
+            },
+            i32::MIN as Value - 1,
+        ),
+        (
+            |block| {
+                let slot = block.build_alloc(8);
+                block.build_store(ValueRef::Immediate(-2), slot);
+                let value = block.build_constant(Value::MIN);
+                block.build_store(value, slot);
+                upper_half(block, slot)
+            },
+            0x8000_0000,
+        ),
+    ]);
+}
+
+#[test]
+fn memory_to_memory_store_keeps_registers_intact() {
+    let build_module = |module: &mut Module| {
+        let global = module.add_global("total".into(), 0).unwrap();
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        let source = place(&mut block, 40, Location::Memory);
+        let storage = block.build_alloc(8);
+        let values: Vec<ValueRef> = (1..=Register::ALL.len() as Value)
+            .map(|value| block.build_constant(value))
+            .collect();
+        assert!(values.iter().all(|value| !value.in_memory()));
+        block.build_store(source, storage);
+        block.build_store(storage, global);
+        let sum = values[1..]
+            .iter()
+            .fold(values[0], |sum, &value| block.build_add(sum, value));
+        let stored = block.build_load(global);
+        let sum = block.build_add(sum, stored);
+        block.build_exit(sum);
+        func.append_block(block);
+        module.append_func(func);
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    let expected = (1..=Register::ALL.len() as i32).sum::<i32>() + 40;
+    assert_eq!(module.interpret(), expected as i64);
+    assert_program_exit_code(build_module, expected);
+}
+
+#[test]
+fn load_copies_value_leaving_source_intact() {
+    check_results(&[
+        (
+            |block| {
+                let slot = place(block, 21, Location::Memory);
+                let value = block.build_load(slot);
+                let one = block.build_constant(1);
+                let value = block.build_add(value, one);
+                let original = block.build_load(slot);
+                block.build_add(value, original)
+            },
+            43,
+        ),
+        (
+            |block| {
+                let value = block.build_constant(9);
+                let copy = block.build_load(value);
+                assert_ne!(copy, value);
+                let copy = block.build_multiply(copy, value);
+                let value = block.build_constant(9);
+                block.build_subtract(copy, value)
+            },
+            72,
+        ),
+        // Loaded into memory once every register is taken.
+        (
+            |block| {
+                let slot = place(block, 17, Location::Memory);
+                take_all_registers(block);
+                let value = block.build_load(slot);
+                assert!(value.in_memory() && value != slot);
+                block.build_store(ValueRef::Immediate(3), slot);
+                value
+            },
+            17,
+        ),
+    ]);
+}
+
+#[test]
+fn functions_may_use_the_same_block_names() {
+    let source = "func add_three
+.entry:
+    %n = alloc 8
+    store 3, %n
+    %acc = alloc 8
+    store 0, %acc
+.loop:
+    add %acc, 1
+    sub %n, 1
+    %0 = load %n
+    jz %0, .end
+    jmp .loop
+.end:
+    %1 = load %acc
+    ret %1
+func _start
+.entry:
+    %n = alloc 8
+    store 2, %n
+    %sum = alloc 8
+    store 0, %sum
+.loop:
+    %r = call add_three
+    add %sum, %r
+    sub %n, 1
+    %0 = load %n
+    jz %0, .end
+    jmp .loop
+.end:
+    %1 = load %sum
+    exit %1
+";
+    let module = Module::parse(source).unwrap();
+    let code = module.generate_string().unwrap();
+    for label in &[
+        "add_three.loop:",
+        "add_three.end:",
+        "_start.loop:",
+        "_start.end:",
+    ] {
+        assert!(code.contains(label), "{}", code);
+    }
+    assert_eq!(module.interpret(), 6);
+    assert_program_exit_code(|module| *module = Module::parse(source).unwrap(), 6);
+}
+
+#[test]
+fn jumps_to_unknown_blocks_are_rejected_before_emitting_code() {
+    let jumps: [Build; 5] = [
+        |block| block.build_jump(".missing".into()),
+        |block| {
+            let value = block.build_constant(0);
+            block.build_jump_if_zero(value, ".missing".into());
+        },
+        |block| {
+            let value = block.build_constant(0);
+            block.build_jump_if_not_zero(value, ".missing".into());
+        },
+        |block| {
+            let value = block.build_constant(0);
+            block
+                .build_switch(value, &[(1, ".missing".into())], ".entry".into())
+                .unwrap();
+        },
+        |block| {
+            let value = block.build_constant(0);
+            block
+                .build_switch(value, &[(1, ".entry".into())], ".missing".into())
+                .unwrap();
+        },
+    ];
+    for jump in &jumps {
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        jump(&mut block);
+        block.build_jump(".entry".into());
+        func.append_block(block);
+        let expected = ValidationError::UnknownBlock {
+            from: ".entry".into(),
+            dest: ".missing".into(),
+        };
+        assert_eq!(func.validate(), Err(expected.clone()));
+        let mut module = Module::default();
+        module.append_func(func);
+        // A block of that name in another function doesn't count.
+        let mut other = Function::new("other".into());
+        let mut block = other.new_block(".missing".into());
+        block.build_return(None);
+        other.append_block(block);
+        module.funcs.insert(0, other);
+        for result in [module.generate_string(), module.generate_code_parallel()] {
+            let err = result.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            assert_eq!(err.to_string(), expected.to_string());
+        }
+    }
+}
+
+#[test]
+fn blocks_have_to_end_with_a_terminator() {
+    let endings: [(Build, bool); 9] = [
+        (|block| block.build_jump(".entry".into()), true),
+        (|block| block.build_return(None), true),
+        (
+            |block| {
+                let value = block.build_constant(0);
+                block.build_exit(value);
+            },
+            true,
+        ),
+        (
+            |block| {
+                let value = block.build_constant(0);
+                block.build_switch(value, &[], ".entry".into()).unwrap();
+            },
+            true,
+        ),
+        // The block falls through deliberately if a final conditional jump isn't taken.
+        (
+            |block| {
+                let value = block.build_constant(0);
+                block.build_jump_if_zero(value, ".entry".into());
+            },
+            true,
+        ),
+        (|_| {}, false),
+        (
+            |block| {
+                let slot = block.build_alloc(8);
+                block.build_store(ValueRef::Immediate(1), slot);
+            },
+            false,
+        ),
+        (|block| block.build_call("f".into(), &[]).unwrap(), false),
+        (
+            |block| {
+                block.build_return(None);
+                block.build_comment("after the return");
+                block.build_call("f".into(), &[]).unwrap();
+            },
+            false,
+        ),
+    ];
+    let mut module = Module::default();
+    for (i, &(end, terminated)) in endings.iter().enumerate() {
+        let mut func = Function::new(format!("f{}", i));
+        let mut block = func.new_block(".entry".into());
+        end(&mut block);
+        func.append_block(block);
+        let expected = if terminated {
+            Ok(())
+        } else {
+            Err(ValidationError::MissingTerminator {
+                block: ".entry".into(),
+            })
+        };
+        assert_eq!(func.validate(), expected, "ending {}", i);
+        module.append_func(func);
+    }
+    let errors = module.validate_all().unwrap_err();
+    let functions: Vec<&str> = errors.iter().map(|(func, _)| func.as_str()).collect();
+    assert_eq!(functions, ["f5", "f6", "f7", "f8"]);
+}
+
+#[test]
+fn validating_a_module_reports_every_broken_function() {
+    let mut module = Module::default();
+    let mut unallocated = Function::new("unallocated".into());
+    let mut entry = unallocated.new_block(".entry".into());
+    entry.build_store(ValueRef::Immediate(1), ValueRef::Memory(16));
+    entry.build_return(None);
+    unallocated.append_block(entry);
+    module.append_func(unallocated);
+    let mut valid = Function::new("valid".into());
+    let mut entry = valid.new_block(".entry".into());
+    entry.build_return(None);
+    valid.append_block(entry);
+    module.append_func(valid);
+    let mut unterminated = Function::new("unterminated".into());
+    let entry = unterminated.new_block(".entry".into());
+    unterminated.append_block(entry);
+    module.append_func(unterminated);
+
+    assert_eq!(
+        module.validate_all(),
+        Err(vec![
+            (
+                "unallocated".into(),
+                ValidationError::UnallocatedSlot {
+                    block: ".entry".into(),
+                    offset: 16
+                }
+            ),
+            (
+                "unterminated".into(),
+                ValidationError::MissingTerminator {
+                    block: ".entry".into()
+                }
+            ),
+        ])
+    );
+}
+
+#[test]
+fn dead_code_elimination_keeps_used_values_and_side_effects() {
+    let build_module = |eliminate: bool| {
+        let mut block = Block::new(".entry".into());
+        let slot = block.build_alloc(8);
+        block.build_constant(99);
+        let dead = block.build_constant(3);
+        let seven = block.build_constant(7);
+        let dead = block.build_add(dead, seven);
+        block.build_negate(dead);
+        let five = block.build_constant(5);
+        block.build_store(five, slot);
+        let value = block.build_load(slot);
+        let two = block.build_constant(2);
+        let value = block.build_multiply(value, two);
+        // Divisions may fault, so they stay even if their result isn't used.
+        let ten = block.build_constant(10);
+        let one = block.build_constant(1);
+        block.build_divide(ten, one);
+        block.build_exit(value);
+        if eliminate {
+            block.eliminate_dead_code();
+        }
+        let mut func = Function::new("_start".into());
+        func.append_block(block);
+        let mut module = Module::default();
+        module.append_func(func);
+        module
+    };
+    let module = build_module(true);
+    assert_eq!(
+        module.to_string(),
+        "func _start
+.entry:
+    [rbp-16] = alloc 16
+    %r13 = const 5
+    store %r13, [rbp-16]
+    %r13 = load [rbp-16]
+    %r12 = const 2
+    mul %r13, %r12
+    %r12 = const 10
+    %r11 = const 1
+    div %r12, %r11
+    exit %r13
+"
+    );
+    for eliminate in [false, true] {
+        assert_eq!(build_module(eliminate).interpret(), 10);
+        assert_program_exit_code(|module| *module = build_module(eliminate), 10);
+    }
+
+    // Blocks created by a function may use values left in registers by each other.
+    let mut func = Function::new("_start".into());
+    let mut entry = func.new_block(".entry".into());
+    let value = entry.build_constant(42);
+    entry.build_constant(1);
+    entry.build_jump(".exit".into());
+    let mut exit = func.new_block(".exit".into());
+    exit.build_exit(value);
+    entry.eliminate_dead_code();
+    assert_eq!(entry.instructions.len(), 3);
+    func.append_block(entry);
+    func.append_block(exit);
+    let mut module = Module::default();
+    module.append_func(func);
+    assert_eq!(module.interpret(), 42);
+}
+
+#[test]
+fn volatile_loads_survive_every_pass() {
+    let build_module = |load: fn(&mut Block, ValueRef) -> ValueRef| {
+        let mut block = Block::new(".entry".into());
+        let slot = block.build_alloc(8);
+        let five = block.build_constant(5);
+        block.build_store(five, slot);
+        load(&mut block, slot);
+        let value = load(&mut block, slot);
+        block.build_store(value, slot);
+        let value = load(&mut block, slot);
+        block.build_exit(value);
+        block.optimize_constants();
+        block.eliminate_dead_code();
+        block.optimize_peephole();
+        let mut func = Function::new("_start".into());
+        func.append_block(block);
+        let mut module = Module::default();
+        module.append_func(func);
+        module
+    };
+    let module = build_module(Block::build_load);
+    assert!(!module.to_string().contains("load"), "{}", module);
+    assert_eq!(module.interpret(), 5);
+
+    let module = build_module(Block::build_load_volatile);
+    assert_eq!(
+        module.to_string(),
+        "func _start
+.entry:
+    [rbp-16] = alloc 16
+    store 5, [rbp-16]
+    %r15 = load_volatile [rbp-16]
+    %r14 = load_volatile [rbp-16]
+    store %r14, [rbp-16]
+    %r14 = load_volatile [rbp-16]
+    exit %r14
+"
+    );
+    assert_eq!(module.interpret(), 5);
+    assert_program_exit_code(|m| *m = build_module(Block::build_load_volatile), 5);
+    let reparsed = Module::parse(&module.to_string()).unwrap();
+    assert_eq!(reparsed.to_string(), module.to_string());
+}
+
+#[test]
+#[should_panic(expected = "integer instructions can't take the floating-point value %xmm0")]
+fn integer_instructions_reject_floating_point_values() {
+    let mut block = Block::new(".entry".into());
+    let int = block.build_constant(1);
+    let float = block.build_float_constant(1.5);
+    block.build_add(int, float);
+}
+
+#[test]
+#[should_panic(expected = "floating-point instructions can't take the integer value %r15")]
+fn floating_point_instructions_reject_integer_values() {
+    let mut block = Block::new(".entry".into());
+    let int = block.build_constant(1);
+    let float = block.build_float_constant(1.5);
+    block.build_fadd(float, int);
+}
+
+#[test]
+fn constant_folding_evaluates_operations_on_known_values() {
+    check_pass(
+        "func _start
+.entry:
+    %x = alloc 8
+    store_volatile 5, %x
+    %0 = const 6
+    %1 = const 7
+    mul %0, %1
+    %2 = const 2
+    sub %0, %2
+    %3 = const -3
+    div %0, %3
+    %4 = const 20
+    add %0, %4
+    %5 = const 7
+    eq %0, %5
+    %6 = const 41
+    add %0, %6
+    %7 = const32 -1
+    %8 = const 3
+    add32 %7, %8
+    add %0, %7
+    %9 = load %x
+    add %0, %9
+    exit %0
+.never:
+    %10 = const 1
+    %11 = const 0
+    div %10, %11
+    exit %10
+",
+        Block::optimize_constants,
+        "func _start
+.entry:
+    [rbp-16] = alloc 16
+    store_volatile 5, [rbp-16]
+    %r15 = const 44
+    %r14 = load [rbp-16]
+    add %r15, %r14
+    exit %r15
+.never:
+    %r14 = const 1
+    %r13 = const 0
+    div %r14, %r13
+    exit %r14
+",
+        49,
+    );
+}
+
+#[test]
+fn generated_strings_match_written_code() {
+    let source = "func _start
+.entry:
+    %0 = const 3
+    %1 = const 4
+    add %0, %1
+    exit %0
+";
+    let module = Module::parse(source).unwrap();
+    let mut written = vec![];
+    module.generate_code(&mut written).unwrap();
+    let code = module.generate_string().unwrap();
+    assert_eq!(code.as_bytes(), &written[..]);
+
+    let func = &module.funcs[0];
+    let mut written = vec![];
+    func.generate_code(&mut written).unwrap();
+    assert_eq!(func.generate_string().unwrap().as_bytes(), &written[..]);
+    assert!(code.contains(&func.generate_string().unwrap()), "{}", code);
+
+    let block = func.blocks[0].generate_string().unwrap();
+    assert!(block.contains("\tadd r15, r14\n"), "{}", block);
+    assert!(code.contains(&block), "{}\n{}", code, block);
+
+    assert_program_exit_code(|m| *m = Module::parse(source).unwrap(), 7);
+}
+
+#[test]
+fn values_beyond_the_registers_spill_to_distinct_stack_slots() {
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        let count = Register::ALL.len() as Value + 4;
+        let values: Vec<ValueRef> = (1..=count)
+            .map(|value| block.build_constant(value))
+            .collect();
+        let (in_registers, spilled) = values.split_at(Register::ALL.len());
+        assert!(in_registers.iter().all(|value| !value.in_memory()));
+        assert!(spilled.iter().all(|value| value.in_memory()));
+        for (i, value) in spilled.iter().enumerate() {
+            assert!(!spilled[i + 1..].contains(value), "{:?}", spilled);
+        }
+        // Operations on two spilled values go through a scratch register.
+        let product = block.build_multiply(spilled[0], spilled[1]);
+        assert_eq!(product, spilled[0]);
+        let sum = values[1..]
+            .iter()
+            .rev()
+            .fold(values[0], |sum, &value| block.build_add(value, sum));
+        block.build_exit(sum);
+        func.append_block(block);
+        module.append_func(func);
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    let count = Register::ALL.len() as i32 + 4;
+    let spilled = count - 3;
+    let expected = (1..=count).sum::<i32>() - spilled + spilled * (spilled + 1);
+    assert_eq!(module.interpret(), expected as i64);
+    assert_program_exit_code(build_module, expected as u8 as i32);
+}
+
+#[test]
+fn consumed_spilled_values_free_their_stack_slots() {
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        take_all_registers(&mut block);
+        let first = block.build_constant(1);
+        let second = block.build_constant(2);
+        assert!(first.in_memory() && second.in_memory());
+        let sum = block.build_add(first, second);
+        assert_eq!(sum, first);
+        // The slot of the consumed value is handed out again.
+        let third = block.build_constant(4);
+        assert_eq!(third, second);
+        let sum = block.build_add(sum, third);
+        // Memory allocated explicitly is not a spilled value and stays allocated.
+        let memory = block.build_alloc(8);
+        block.build_store(sum, memory);
+        let loaded = block.build_load(memory);
+        assert_ne!(block.build_constant(0), memory);
+        block.build_exit(loaded);
+        func.append_block(block);
+        module.append_func(func);
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    assert_eq!(module.interpret(), 7);
+    assert_program_exit_code(build_module, 7);
+}
+
+#[test]
+fn blocks_of_a_function_share_its_registers() {
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut entry = func.new_block(".entry".into());
+        let first = entry.build_constant(30);
+        entry.build_jump(".next".into());
+        // A value of a later block does not take the register of an earlier one.
+        let mut next = func.new_block(".next".into());
+        let second = next.build_constant(12);
+        assert_ne!(first, second);
+        let sum = next.build_add(first, second);
+        next.build_exit(sum);
+        func.append_block(entry);
+        func.append_block(next);
+        module.append_func(func);
+
+        // Every function has registers of its own.
+        let mut other = Function::new("other".into());
+        let mut block = other.new_block(".entry".into());
+        assert_eq!(block.build_constant(1), first);
+        block.build_return(None);
+        other.append_block(block);
+        module.append_func(other);
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    assert_eq!(module.interpret(), 42);
+    assert_program_exit_code(build_module, 42);
+}
+
+#[test]
+fn stack_frame_is_reserved_once_in_the_prologue() {
+    let source = "func _start
+.entry:
+    %x = alloc 8
+    store 5, %x
+    jmp .next
+.next:
+    %y = alloc 24
+    store 7, %y
+    %0 = load %x
+    %1 = load %y
+    add %0, %1
+    exit %0
+";
+    let module = Module::parse(source).unwrap();
+    assert_eq!(module.funcs[0].frame_size(), 16 + 32);
+    let code = module.generate_string().unwrap();
+    let reserved: Vec<&str> = code.lines().filter(|line| line.contains("rsp")).collect();
+    // The saved RBP and the frame keep RSP 16-byte aligned.
+    assert_eq!(reserved, ["\tmov rbp, rsp", "\tsub rsp, 56"]);
+    assert!(
+        code.find("sub rsp").unwrap() < code.find("_start.entry:").unwrap(),
+        "{}",
+        code
+    );
+    assert_eq!(module.interpret(), 12);
+    assert_program_exit_code(|m| *m = Module::parse(source).unwrap(), 12);
+}
+
+#[test]
+fn stack_is_16_byte_aligned_at_calls() {
+    // Functions with any number of live values and any frame size.
+    let mut module = Module::default();
+    let mut checks = String::new();
+    for live in 0..=Register::ALL.len() as Value + 1 {
+        let name = format!("aligned_{}", live);
+        let mut func = Function::new(name.clone());
+        let mut block = func.new_block(".entry".into());
+        block.build_alloc(8 * (live as usize % 3));
+        let values: Vec<ValueRef> = (1..=live)
+            .map(|value| block.build_constant(value))
+            .collect();
+        let result = block
+            .build_call_with_result("misalignment".into(), &[])
+            .unwrap();
+        let result = values
+            .iter()
+            .fold(result, |sum, &value| block.build_add(sum, value));
+        block.build_return(Some(result));
+        func.append_block(block);
+        module.append_func(func);
+        checks.push_str(&format!(
+            "    if ({}() != {}) return {};\n",
+            name,
+            live * (live + 1) / 2,
+            live + 1
+        ));
+    }
+    // Calling a function pushes the return address, so RBP, which the callee pushes next,
+    // is 16-byte aligned if RSP was at the call.
+    let c = format!(
+        r#"
+long misalignment(void) {{
+    return (long)__builtin_frame_address(0) % 16;
+}}
+{}
+int main(void) {{
+{}    return 0;
+}}
+"#,
+        (0..=Register::ALL.len() + 1)
+            .map(|live| format!("long aligned_{}(void);\n", live))
+            .collect::<String>(),
+        checks
+    );
+    let code = att_code(module);
+    if let Some(exit_code) = run_with_libc("stack_is_16_byte_aligned_at_calls", &code, &c) {
+        assert_eq!(exit_code, 0);
+    }
+}
+
+#[test]
+fn caller_saved_registers_survive_calls() {
+    let build_module = |module: &mut Module| {
+        let caller_saved: Vec<Register> = Register::ALL
+            .iter()
+            .copied()
+            .filter(|reg| reg.is_caller_saved())
+            .collect();
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        let values: Vec<ValueRef> = (1..=Register::ALL.len() as Value)
+            .map(|value| block.build_constant(value))
+            .collect();
+        assert!(values.iter().any(|value| match value {
+            ValueRef::Register(reg) => reg.is_caller_saved(),
+            _ => false,
+        }));
+        let result = block.build_call_with_result("clobber".into(), &[]).unwrap();
+        let sum = values
+            .iter()
+            .fold(result, |sum, &value| block.build_add(sum, value));
+        block.build_exit(sum);
+        func.append_block(block);
+        module.append_func(func);
+        module.append_func(clobber(&caller_saved));
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    let expected = (1..=Register::ALL.len() as i32).sum::<i32>() + 7;
+    assert_eq!(module.interpret(), expected as i64);
+    assert_program_exit_code(build_module, expected);
+}
+
+#[test]
+fn call_arguments_are_passed_in_system_v_registers() {
+    let mut module = Module::default();
+    let global = module.add_global("six".into(), 6).unwrap();
+    let mut func = Function::new("call_weigh".into());
+    let mut block = func.new_block(".entry".into());
+    // Arguments swapping their registers, in memory, immediate and global.
+    let value = block.build_constant(1);
+    let first = block.build_move_to_register(value, Register::Rsi);
+    let value = block.build_constant(2);
+    let second = block.build_move_to_register(value, Register::Rdi);
+    let third = block.build_constant(3);
+    let fourth = place(&mut block, 4, Location::Memory);
+    let args = [first, second, third, fourth, ValueRef::Immediate(5), global];
+    let result = block.build_call_with_result("weigh".into(), &args).unwrap();
+    block.build_return(Some(result));
+    func.append_block(block);
+    module.append_func(func);
+    let c = r#"
+long weigh(long a, long b, long c, long d, long e, long f) {
+    return a + 10 * b + 100 * c + 1000 * d + 10000 * e + 100000 * f;
+}
+
+long call_weigh(void);
+
+int main(void) {
+    return call_weigh() != 654321;
+}
+"#;
+    let code = att_code(module);
+    let test = "call_arguments_are_passed_in_system_v_registers";
+    if let Some(exit_code) = run_with_libc(test, &code, c) {
+        assert_eq!(exit_code, 0);
+    }
+}
+
+/// A function `name` returning `value` from `location`.
+fn returning(name: &str, value: Value, location: Location) -> Function {
+    let mut func = Function::new(name.into());
+    let mut block = func.new_block(".entry".into());
+    let result = place(&mut block, value, location);
+    block.build_return(Some(result));
+    func.append_block(block);
+    func
+}
+
+#[test]
+fn call_results_are_captured_in_registers_and_memory() {
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        let in_register = block.build_call_with_result("thirty".into(), &[]).unwrap();
+        assert!(!in_register.in_memory());
+        take_all_registers(&mut block);
+        let in_memory = block.build_call_with_result("twelve".into(), &[]).unwrap();
+        assert!(in_memory.in_memory());
+        let sum = block.build_add(in_memory, in_register);
+        block.build_exit(sum);
+        func.append_block(block);
+        module.append_func(func);
+        module.append_func(returning("thirty", 30, Location::Register(Register::Rbx)));
+        module.append_func(returning("twelve", 12, Location::Memory));
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    assert_eq!(module.interpret(), 42);
+    assert_program_exit_code(build_module, 42);
+}
+
+/// A function `clamp` returning its argument limited to between 0 and 40, returning from
+/// a different block for each case.
+fn clamp() -> Function {
+    let mut func = Function::new("clamp".into());
+    let mut entry = func.new_block(".entry".into());
+    entry.registers.alloc_specific(Register::Rdi).unwrap();
+    let arg = ValueRef::Register(Register::Rdi);
+    let limit = entry.build_constant(40);
+    let fits = entry.build_constant(40);
+    let copy = entry.build_load(arg);
+    let fits = entry.build_greater_equal(fits, copy);
+    entry.build_jump_if_zero(fits, ".too_big".into());
+    entry.build_jump(".check_low".into());
+    let mut check_low = func.new_block(".check_low".into());
+    let too_small = check_low.build_constant(0);
+    let copy = check_low.build_load(arg);
+    let too_small = check_low.build_greater_than(too_small, copy);
+    check_low.build_jump_if_not_zero(too_small, ".too_small".into());
+    check_low.build_return(Some(arg));
+    let mut too_small = func.new_block(".too_small".into());
+    too_small.build_return(Some(ValueRef::Immediate(0)));
+    let mut too_big = func.new_block(".too_big".into());
+    too_big.build_return(Some(limit));
+    for block in [entry, check_low, too_small, too_big] {
+        func.append_block(block);
+    }
+    func
+}
+
+#[test]
+fn functions_return_to_their_caller_with_a_value() {
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        let results: Vec<ValueRef> = [50, -3, 17]
+            .iter()
+            .map(|&arg| {
+                block
+                    .build_call_with_result("clamp".into(), &[ValueRef::Immediate(arg)])
+                    .unwrap()
+            })
+            .collect();
+        block.build_call("nothing".into(), &[]).unwrap();
+        let from_memory = block
+            .build_call_with_result("from_memory".into(), &[])
+            .unwrap();
+        let sum = results
+            .iter()
+            .fold(from_memory, |sum, &value| block.build_add(sum, value));
+        block.build_exit(sum);
+        func.append_block(block);
+        module.append_func(func);
+        module.append_func(clamp());
+
+        // Returning without a value tears down the frame all the same.
+        let mut func = Function::new("nothing".into());
+        let mut block = func.new_block(".entry".into());
+        let memory = place(&mut block, 9, Location::Memory);
+        block.build_move_to_register(memory, Register::Rax);
+        block.build_return(None);
+        func.append_block(block);
+        module.append_func(func);
+        module.append_func(returning("from_memory", 2, Location::Memory));
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    assert_eq!(module.interpret(), 59);
+    assert_program_exit_code(build_module, 59);
+}
+
+#[test]
+fn conditional_jumps_branch_on_zero_and_non_zero() {
+    let program = |module: &mut Module, cond: Value, location: Location, not_zero: bool| {
+        let mut func = Function::new("_start".into());
+        let mut entry = func.new_block(".entry".into());
+        let cond = place(&mut entry, cond, location);
+        if not_zero {
+            entry.build_jump_if_not_zero(cond, ".taken".into());
+        } else {
+            entry.build_jump_if_zero(cond, ".taken".into());
+        }
+        entry.build_jump(".not_taken".into());
+        let mut not_taken = func.new_block(".not_taken".into());
+        not_taken.build_exit(ValueRef::Immediate(1));
+        let mut taken = func.new_block(".taken".into());
+        taken.build_exit(ValueRef::Immediate(2));
+        for block in [entry, not_taken, taken] {
+            func.append_block(block);
+        }
+        module.append_func(func);
+    };
+    for &cond in &[0, 1, -1, 1 << 40] {
+        for &location in &[Location::Register(Register::Rbx), Location::Memory] {
+            for &not_zero in &[false, true] {
+                let expected = if (cond != 0) == not_zero { 2 } else { 1 };
+                let mut module = Module::default();
+                program(&mut module, cond, location, not_zero);
+                assert_eq!(module.interpret(), expected as i64);
+                assert_program_exit_code(
+                    |module| program(module, cond, location, not_zero),
+                    expected,
+                );
+            }
+        }
+    }
+}
+
+/// Check an operation built with `build` on each pair of operands in every combination of
+/// registers and memory, and with an immediate right operand, against the result
+/// computed by `expected`, in full in the interpreter and truncated natively.
+fn check_operation(
+    build: BuildBinary,
+    expected: impl Fn(Value, Value) -> Value,
+    pairs: &[(Value, Value)],
+) {
+    let locations = [
+        Some(Location::Register(Register::Rcx)),
+        Some(Location::Register(Register::Rsi)),
+        Some(Location::Memory),
+    ];
+    for &(left_value, right_value) in pairs {
+        for &left in &locations {
+            for &right in locations.iter().chain(&[None]) {
+                let immediate = i32::try_from(right_value);
+                if left == right && left != Some(Location::Memory)
+                    || right.is_none() && immediate.is_err()
+                {
+                    continue;
+                }
+                let build_module = |module: &mut Module| {
+                    let mut func = Function::new("_start".into());
+                    let mut block = func.new_block(".entry".into());
+                    let left_ref = place(&mut block, left_value, left.unwrap());
+                    let right_ref = match right {
+                        Some(right) => place(&mut block, right_value, right),
+                        None => ValueRef::Immediate(immediate.unwrap()),
+                    };
+                    let mut result = build(&mut block, left_ref, right_ref);
+                    if result.in_memory() {
+                        result = block.build_load(result);
+                    }
+                    block.build_exit(result);
+                    func.append_block(block);
+                    module.append_func(func);
+                };
+                let expected = expected(left_value, right_value);
+                let mut module = Module::default();
+                build_module(&mut module);
+                let case = format!(
+                    "{} at {:?}, {} at {:?}",
+                    left_value, left, right_value, right
+                );
+                assert_eq!(module.interpret(), expected, "{}", case);
+                assert_program_exit_code(build_module, expected as u8 as i32);
+            }
+        }
+    }
+}
+
+#[test]
+fn comparisons_yield_zero_or_one() {
+    let pairs = [(-5, 3), (3, -5), (7, 7), (Value::MIN, Value::MAX)];
+    let comparisons: [(BuildBinary, Evaluate); 6] = [
+        (Block::build_equal, |a, b| (a == b) as Value),
+        (Block::build_not_equal, |a, b| (a != b) as Value),
+        (Block::build_less_than, |a, b| (a < b) as Value),
+        (Block::build_greater_than, |a, b| (a > b) as Value),
+        (Block::build_less_equal, |a, b| (a <= b) as Value),
+        (Block::build_greater_equal, |a, b| (a >= b) as Value),
+    ];
+    for &(build, expected) in &comparisons {
+        check_operation(build, expected, &pairs);
+    }
+}
+
+#[test]
+fn negate_flips_the_sign_in_registers_and_memory() {
+    check_results(&[
+        (
+            |block| {
+                let value = block.build_constant(-42);
+                block.build_negate(value)
+            },
+            42,
+        ),
+        (
+            |block| {
+                let value = place(block, 42, Location::Memory);
+                let negated = block.build_negate(value);
+                assert_eq!(negated, value);
+                let offset = block.build_constant(300);
+                block.build_add(negated, offset)
+            },
+            258,
+        ),
+        (
+            |block| {
+                let value = block.build_constant(0);
+                block.build_negate(value)
+            },
+            0,
+        ),
+        (
+            |block| {
+                let value = block.build_constant(Value::MIN);
+                block.build_negate(value)
+            },
+            Value::MIN,
+        ),
+    ]);
+}
+
+#[test]
+fn shifts_take_their_count_modulo_64_from_anywhere() {
+    let pairs = [(1, 4), (-1, 60), (5, 65), (-8, 0), (3 << 40, 38)];
+    check_operation(
+        Block::build_shift_left,
+        |a, b| a.wrapping_shl(b as u32),
+        &pairs,
+    );
+    check_operation(
+        Block::build_shift_right,
+        |a, b| (a as u64).wrapping_shr(b as u32) as Value,
+        &pairs,
+    );
+    // A value in RCX survives shifting by a count elsewhere.
+    check_results(&[(
+        |block| {
+            let bystander = place(block, 100, Location::Register(Register::Rcx));
+            let value = block.build_constant(3);
+            let count = place(block, 2, Location::Memory);
+            let value = block.build_shift_left(value, count);
+            block.build_add(value, bystander)
+        },
+        112,
+    )]);
+}
+
+#[test]
+fn bitwise_operations_combine_operands_bit_by_bit() {
+    let pairs = [
+        (0b1100, 0b1010),
+        (-1, 0x0f0f),
+        (1 << 63, -1),
+        (0x1234 << 32, 0xff),
+    ];
+    let operations: [(BuildBinary, Evaluate); 3] = [
+        (Block::build_and, |a, b| a & b),
+        (Block::build_or, |a, b| a | b),
+        (Block::build_xor, |a, b| a ^ b),
+    ];
+    for &(build, expected) in &operations {
+        check_operation(build, expected, &pairs);
+    }
+}
+
+#[test]
+fn leaf_functions_without_stack_memory_have_no_frame() {
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        let first = block.build_call_with_result("leaf".into(), &[]).unwrap();
+        let second = block.build_call_with_result("twelve".into(), &[]).unwrap();
+        let sum = block.build_add(first, second);
+        block.build_exit(sum);
+        func.append_block(block);
+        module.append_func(func);
+        module.append_func(returning("leaf", 30, Location::Register(Register::Rcx)));
+        module.append_func(returning("twelve", 12, Location::Memory));
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    let frame = ["\tpush rbp", "\tmov rbp, rsp"];
+    let has_frame = |func: &Function| {
+        let code = func.generate_string().unwrap();
+        let lines: Vec<&str> = code.lines().collect();
+        let found: Vec<bool> = frame.iter().map(|line| lines.contains(line)).collect();
+        assert!(found.iter().all(|&line| line == found[0]), "{}", code);
+        found[0]
+    };
+    assert!(has_frame(&module.funcs[0]));
+    assert!(!has_frame(&module.funcs[1]));
+    assert!(has_frame(&module.funcs[2]));
+    assert_eq!(module.interpret(), 42);
+    assert_program_exit_code(build_module, 42);
+}
+
+#[test]
+fn table_loads_sign_extend_entries_at_index() {
+    let mut module = Module::default();
+    let mut checks = String::new();
+    for &elem_size in &[1, 2, 4, 8] {
+        for &location in &[Location::Register(Register::Rcx), Location::Memory] {
+            let name = format!("load_{}_{}", elem_size, location != Location::Memory);
+            let mut func = Function::new(name.clone());
+            let mut block = func.new_block(".entry".into());
+            let index = place(&mut block, 2, location);
+            let table = format!("table{}", elem_size);
+            let value = block.build_table_load(&table, index, elem_size);
+            block.build_return(Some(value));
+            func.append_block(block);
+            module.append_func(func);
+            checks.push_str(&format!(
+                "    long {}(void);\n    if ({}() != -{}) return {};\n",
+                name,
+                name,
+                elem_size * 10 + 2,
+                checks.matches("return").count() + 1
+            ));
+        }
+    }
+    let c = format!(
+        r#"
+signed char table1[] = {{-10, -11, -12, -13}};
+short table2[] = {{-20, -21, -22, -23}};
+int table4[] = {{-40, -41, -42, -43}};
+long table8[] = {{-80, -81, -82, -83}};
+
+int main(void) {{
+{}    return 0;
+}}
+"#,
+        checks
+    );
+    let code = att_code(module);
+    if let Some(exit_code) = run_with_libc("table_loads_sign_extend_entries_at_index", &code, &c) {
+        assert_eq!(exit_code, 0);
+    }
+}
+
+#[test]
+fn indirect_jump_ends_a_block_and_goes_to_the_address() {
+    let mut func = Function::new("dispatch".into());
+    let mut block = func.new_block(".entry".into());
+    block.registers.alloc_specific(Register::Rdi).unwrap();
+    block.build_jump_indirect(ValueRef::Register(Register::Rdi));
+    func.append_block(block);
+    assert_eq!(func.validate(), Ok(()));
+    let code = func.generate_string().unwrap();
+    assert!(code.ends_with("dispatch.entry:\n\tjmp rdi\n"), "{}", code);
+
+    // Without a frame the jump leaves the stack as the caller left it, like a tail call.
+    let mut module = Module::default();
+    module.append_func(func);
+    let c = r#"
+long seven(void) { return 7; }
+long dispatch(long (*target)(void));
+
+int main(void) {
+    return dispatch(seven);
+}
+"#;
+    let code = att_code(module);
+    let test = "indirect_jump_ends_a_block_and_goes_to_the_address";
+    if let Some(exit_code) = run_with_libc(test, &code, c) {
+        assert_eq!(exit_code, 7);
+    }
+}
+
+#[test]
+fn restoring_a_context_resumes_after_saving_it() {
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut entry = func.new_block(".entry".into());
+        let counter = place(&mut entry, 0, Location::Memory);
+        let context = entry.build_save_context();
+        // Execution resumes here with only the stack memory left as it was.
+        let count = entry.build_load(counter);
+        let count = entry.build_add(count, ValueRef::Immediate(1));
+        entry.build_store(count, counter);
+        let count = entry.build_load(counter);
+        let done = entry.build_greater_equal(count, ValueRef::Immediate(3));
+        entry.build_jump_if_not_zero(done, ".done".into());
+        entry.build_jump(".again".into());
+        let mut again = func.new_block(".again".into());
+        again.build_restore_context(context).unwrap();
+        let mut done = func.new_block(".done".into());
+        let count = done.build_load(counter);
+        done.build_exit(count);
+        for block in [entry, again, done] {
+            func.append_block(block);
+        }
+        module.append_func(func);
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    assert_eq!(module.funcs[0].validate(), Ok(()));
+    assert_program_exit_code(build_module, 3);
+}
+
+#[test]
+fn popcount_needs_the_popcnt_target_feature() {
+    let program = |module: &mut Module, value: Value, location: Location| {
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        let value = place(&mut block, value, location);
+        let count = block.build_popcount(value);
+        block.build_exit(count);
+        func.append_block(block);
+        module.append_func(func);
+    };
+    let mut module = Module::default();
+    program(&mut module, 7, Location::Memory);
+    let error = module.generate_string().unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(
+        error.to_string(),
+        "popcount requires the popcnt target feature"
+    );
+
+    for &(value, expected) in &[(0, 0), (0b1011_0110, 5), (-1, 64), (Value::MIN, 1)] {
+        for &location in &[Location::Register(Register::Rcx), Location::Memory] {
+            let mut module = Module::default();
+            program(&mut module, value, location);
+            assert_eq!(module.interpret(), expected);
+            assert_program_exit_code(
+                |module| {
+                    module.set_target_features(TargetFeatures { popcnt: true });
+                    program(module, value, location);
+                },
+                expected as i32,
+            );
+        }
+    }
+}
+
+#[test]
+fn layout_turns_jumps_into_fall_throughs() {
+    let source = "func _start
+.entry:
+    %x = alloc 8
+    store 5, %x
+    jmp .second
+.third:
+    %2 = load %x
+    exit %2
+.second:
+    %0 = load %x
+    jz %0, .other
+.fall:
+    %1 = const 40
+    store %1, %x
+    jmp .third
+.other:
+    %3 = const 1
+    exit %3
+";
+    let jumps = |module: &Module| {
+        let code = module.generate_string().unwrap();
+        code.matches("\tjmp ").count()
+    };
+    let names = |module: &Module| -> Vec<String> {
+        let blocks = &module.funcs[0].blocks;
+        blocks.iter().map(|block| block.name.clone()).collect()
+    };
+    let module = Module::parse(source).unwrap();
+    assert_eq!(jumps(&module), 2);
+
+    let layout = |module: &mut Module| {
+        *module = Module::parse(source).unwrap();
+        module.funcs[0].layout_blocks();
+    };
+    let mut module = Module::default();
+    layout(&mut module);
+    // `.fall` stays behind `.second`, which falls through into it.
+    assert_eq!(
+        names(&module),
+        [".entry", ".second", ".fall", ".third", ".other"]
+    );
+    assert_eq!(jumps(&module), 0);
+    assert_eq!(module.interpret(), 40);
+    assert_program_exit_code(layout, 40);
+}
+
+#[test]
+fn cet_marks_functions_and_indirect_jump_targets() {
+    let build_module = |cet: bool| {
+        // Enough cases for a jump table.
+        let module = switch_program(3, false, &[0, 1, 2, 3, 4]);
+        let mut module = module.with_cet(cet);
+        let mut func = Function::new("direct".into());
+        let mut entry = func.new_block(".entry".into());
+        entry.build_jump(".exit".into());
+        let mut exit = func.new_block(".exit".into());
+        exit.build_return(None);
+        func.append_block(entry);
+        func.append_block(exit);
+        module.append_func(func);
+        module
+    };
+    let marked_labels = |code: &str| -> Vec<String> {
+        let lines: Vec<&str> = code.lines().collect();
+        lines
+            .windows(2)
+            .filter(|pair| pair[1] == "\tendbr64")
+            .map(|pair| pair[0].to_string())
+            .collect()
+    };
+    let code = build_module(true).generate_string().unwrap();
+    let mut expected = vec!["_start:".to_string(), "_start.entry:".to_string()];
+    expected.extend((0..5).map(|i| format!("_start.case{}:", i)));
+    expected.push("_start.default:".into());
+    expected.push("direct:".into());
+    assert_eq!(marked_labels(&code), expected, "{}", code);
+    assert_eq!(code.matches("endbr64").count(), expected.len());
+
+    let code = build_module(false).generate_string().unwrap();
+    assert!(!code.contains("endbr64"), "{}", code);
+    // `endbr64` does nothing unless the CPU enforces CET.
+    assert_program_exit_code(|module| *module = build_module(true), 13);
+}
+
+#[test]
+fn timed_generation_reports_every_function_and_writes_the_same_code() {
+    let mut module = Module::parse(COUNTDOWN).unwrap();
+    module.append_func(returning("twelve", 12, Location::Memory));
+    for syntax in [AsmSyntax::Nasm, AsmSyntax::Att] {
+        module.set_syntax(syntax);
+        let mut timed = vec![];
+        let (result, timings) = module.generate_code_timed(&mut timed);
+        result.unwrap();
+        assert_eq!(timed, module.generate_string().unwrap().into_bytes());
+        let names: Vec<&str> = timings
+            .functions
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["_start", "twelve"]);
+        let functions: Duration = timings.functions.iter().map(|&(_, time)| time).sum();
+        assert!(timings.total >= functions, "{:?}", timings);
+    }
+}
+
+#[test]
+fn comments_stay_with_their_instruction_through_passes() {
+    let build_module = || {
+        let mut block = Block::new(".entry".into());
+        block.build_comment("never used");
+        block.build_constant(1);
+        block.build_comment("the answer");
+        let value = block.build_constant(42);
+        block.build_comment("done");
+        block.build_exit(value);
+        // The comment of a removed instruction goes with it.
+        block.eliminate_dead_code();
+        let mut func = Function::new("_start".into());
+        func.append_block(block);
+        let mut module = Module::default();
+        module.append_func(func);
+        module
+    };
+    let module = build_module();
+    assert_eq!(
+        module.to_string(),
+        "func _start
+.entry:
+    ; the answer
+    %r14 = const 42
+    ; done
+    exit %r14
+"
+    );
+    let code = module.generate_string().unwrap();
+    assert!(!code.contains("never used"), "{}", code);
+    assert!(code.contains("\t; the answer\n\tmov r14, 42\n"), "{}", code);
+    assert_program_exit_code(|module| *module = build_module(), 42);
+
+    let source = "func _start
+.entry:
+    ; first
+    %0 = const 2
+    ; second
+    %1 = const 40
+    add %0, %1
+    ; last
+    exit %0
+";
+    let mut module = Module::parse(source).unwrap();
+    module.funcs[0].split_block(".entry", 1, ".tail".into());
+    assert_eq!(
+        module.to_string(),
+        "func _start
+.entry:
+    ; first
+    %r15 = const 2
+    jmp .tail
+.tail:
+    ; second
+    %r14 = const 40
+    add %r15, %r14
+    ; last
+    exit %r15
+"
+    );
+    assert_eq!(module.interpret(), 42);
+}
+
+#[test]
+fn split_blocks_continue_with_the_same_registers_and_stack() {
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut entry = func.new_block(".entry".into());
+        let slot = place(&mut entry, 30, Location::Memory);
+        let value = entry.build_constant(10);
+        let at = entry.instructions.len();
+        let two = entry.build_constant(2);
+        let value = entry.build_add(value, two);
+        let mut tail = entry.split_off(at, ".tail".into());
+        assert_eq!(entry.instructions.len(), at + 1);
+        assert_eq!(tail.instructions.len(), 2);
+        // Values allocated later don't overwrite those of before the split.
+        let other = tail.build_alloc(8);
+        assert_ne!(other, slot);
+        tail.build_store(ValueRef::Immediate(100), other);
+        let loaded = tail.build_load(slot);
+        assert_ne!(loaded, value);
+        let sum = tail.build_add(loaded, value);
+        tail.build_exit(sum);
+        func.append_block(entry);
+        func.append_block(tail);
+        assert_eq!(func.validate(), Ok(()));
+        module.append_func(func);
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    assert_eq!(module.interpret(), 42);
+    assert_program_exit_code(build_module, 42);
+}
+
+#[test]
+fn split_blocks_keep_falling_through_into_the_same_block() {
+    let source = "func _start
+.entry:
+    %0 = const 5
+    %1 = const 1
+    jz %1, .other
+.next:
+    exit %0
+.other:
+    %2 = const 1
+    exit %2
+";
+    let build_module = |module: &mut Module| {
+        *module = Module::parse(source).unwrap();
+        module.funcs[0].split_block(".entry", 1, ".tail".into());
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    let func = &module.funcs[0];
+    let names: Vec<&str> = func
+        .blocks
+        .iter()
+        .map(|block| block.name.as_str())
+        .collect();
+    assert_eq!(names, [".entry", ".tail", ".next", ".other"]);
+    assert_eq!(func.successors(".entry"), [".tail"]);
+    assert_eq!(func.successors(".tail"), [".other", ".next"]);
+    assert_eq!(func.validate(), Ok(()));
+    assert_eq!(module.interpret(), 5);
+    assert_program_exit_code(build_module, 5);
+}
+
+#[test]
+fn blocks_not_created_by_the_function_get_memory_of_their_own() {
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut entry = func.new_block(".entry".into());
+        let slot = entry.build_alloc(8);
+        entry.build_store(ValueRef::Immediate(1), slot);
+        entry.build_jump(".other".into());
+        let mut other = Block::new(".other".into());
+        let other_slot = other.build_alloc(8);
+        assert_eq!(other_slot, slot);
+        other.build_store(ValueRef::Immediate(2), other_slot);
+        let value = other.build_load(other_slot);
+        other.build_store(value, other_slot);
+        other.build_jump(".exit".into());
+        let mut exit = func.new_block(".exit".into());
+        let first = exit.build_load(slot);
+        exit.build_exit(first);
+        func.append_block(entry);
+        func.append_block(other);
+        func.append_block(exit);
+        assert_eq!(func.validate(), Ok(()));
+        module.append_func(func);
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    assert_eq!(
+        module.to_string(),
+        "func _start
+.entry:
+    [rbp-16] = alloc 16
+    store 1, [rbp-16]
+    jmp .other
+.other:
+    [rbp-32] = alloc 16
+    store 2, [rbp-32]
+    %r15 = load [rbp-32]
+    store %r15, [rbp-32]
+    jmp .exit
+.exit:
+    %r15 = load [rbp-16]
+    exit %r15
+"
+    );
+    assert_eq!(module.interpret(), 1);
+    assert_program_exit_code(build_module, 1);
+}
+
+#[test]
+fn crt0_exits_with_the_value_main_returns() {
+    use crate::build::unique_temp_dir;
+    use crate::testing::{has_tool, report_skipped};
+    use std::process::Command;
+
+    let mut module = Module::default();
+    let mut func = Function::new("main".into());
+    let mut block = func.new_block(".entry".into());
+    let result = block.build_call_with_result("thirty".into(), &[]).unwrap();
+    let sum = block.build_add(result, ValueRef::Immediate(12));
+    block.build_return(Some(sum));
+    func.append_block(block);
+    module.append_func(func);
+    module.append_func(returning("thirty", 30, Location::Memory));
+    module.set_syntax(AsmSyntax::Att);
+    let mut code = vec![];
+    module.generate_with_crt0(&mut code, "main").unwrap();
+    let code = String::from_utf8(code).unwrap();
+    assert!(code.contains("_start:\n\tcall main\n"), "{}", code);
+
+    let test = "crt0_exits_with_the_value_main_returns";
+    for tool in ["as", "ld"] {
+        if !has_tool(tool) {
+            report_skipped(test, tool);
+            return;
+        }
+    }
+    let dir = unique_temp_dir("fig-crt0-test");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("code.s"), code).unwrap();
+    let steps = [
+        ("as", &["--64", "-o", "code.o", "code.s"][..]),
+        ("ld", &["-o", "program", "code.o"]),
+    ];
+    for (tool, args) in steps {
+        let status = Command::new(tool).args(args).current_dir(&dir).status();
+        assert!(status.unwrap().success());
+    }
+    let status = Command::new(dir.join("program")).status().unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+    assert_eq!(status.code(), Some(42));
+}
+
+#[test]
+fn recursive_calls_have_a_frame_each() {
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        let result = block
+            .build_call_with_result("factorial".into(), &[ValueRef::Immediate(5)])
+            .unwrap();
+        block.build_exit(result);
+        func.append_block(block);
+        module.append_func(func);
+
+        // The argument is kept in stack memory across the recursive call.
+        let mut func = Function::new("factorial".into());
+        let mut entry = func.new_block(".entry".into());
+        entry.registers.alloc_specific(Register::Rdi).unwrap();
+        let n = entry.build_alloc(8);
+        entry.build_store(ValueRef::Register(Register::Rdi), n);
+        let value = entry.build_load(n);
+        entry.build_jump_if_zero(value, ".base".into());
+        entry.build_jump(".recurse".into());
+        let mut recurse = func.new_block(".recurse".into());
+        let arg = recurse.build_load(n);
+        let arg = recurse.build_subtract(arg, ValueRef::Immediate(1));
+        let result = recurse
+            .build_call_with_result("factorial".into(), &[arg])
+            .unwrap();
+        let value = recurse.build_load(n);
+        let result = recurse.build_multiply(result, value);
+        recurse.build_return(Some(result));
+        let mut base = func.new_block(".base".into());
+        base.build_return(Some(ValueRef::Immediate(1)));
+        for block in [entry, recurse, base] {
+            func.append_block(block);
+        }
+        module.append_func(func);
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    assert_eq!(module.interpret(), 120);
+    assert_program_exit_code(build_module, 120);
+}
+
+#[test]
+fn named_values_are_annotated_in_the_generated_code() {
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        let x = block.build_alloc_named(8, "x");
+        let value = block.build_constant_named(42, "answer");
+        block.build_store(value, x);
+        let value = block.build_load(x);
+        block.build_exit(value);
+        func.append_block(block);
+        module.append_func(func);
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    assert_eq!(
+        module.to_string(),
+        "func _start
+.entry:
+    ; x = alloc 8
+    [rbp-16] = alloc 16
+    ; answer = const 42
+    %r15 = const 42
+    store %r15, [rbp-16]
+    %r15 = load [rbp-16]
+    exit %r15
+"
+    );
+    let code = module.generate_string().unwrap();
+    assert!(
+        code.contains("\t; answer = const 42\n\tmov r15, 42\n"),
+        "{}",
+        code
+    );
+    assert!(code.contains("\t; x = alloc 8\n"), "{}", code);
+    assert_program_exit_code(build_module, 42);
+}
+
+#[test]
+fn reverse_postorder_does_not_depend_on_the_order_of_blocks() {
+    let blocks = [
+        ".entry:
+    %n = alloc 8
+    store 3, %n
+    jmp .loop
+",
+        ".loop:
+    %0 = load %n
+    jz %0, .end
+.body:
+    sub %n, 1
+    jmp .loop
+",
+        ".end:
+    %1 = const 42
+    exit %1
+",
+        ".dead:
+    ret
+",
+    ];
+    let build_module = |order: &[usize]| {
+        let source: String = order.iter().map(|&i| blocks[i]).collect();
+        let mut module = Module::parse(&format!("func _start\n{}", source)).unwrap();
+        module.funcs[0].reorder_rpo();
+        module
+    };
+    let names = |module: &Module| -> Vec<String> {
+        let blocks = &module.funcs[0].blocks;
+        blocks.iter().map(|block| block.name.clone()).collect()
+    };
+    // `.body` stays behind `.loop`, which falls through into it.
+    let expected = [".entry", ".loop", ".body", ".end", ".dead"];
+    for order in [[0, 1, 2, 3], [0, 3, 2, 1], [0, 2, 1, 3]] {
+        assert_eq!(names(&build_module(&order)), expected);
+    }
+    let module = build_module(&[0, 3, 2, 1]);
+    assert_eq!(module.interpret(), 42);
+    assert_program_exit_code(|module| *module = build_module(&[0, 3, 2, 1]), 42);
+}
+
+#[test]
+fn multiply_into_registers_and_memory() {
+    let pairs = [(6, 7), (-3, 5), (1 << 33, 3), (-1, Value::MIN)];
+    check_operation(Block::build_multiply, Value::wrapping_mul, &pairs);
+}
+
+#[test]
+fn add_and_subtract_between_any_operands() {
+    let pairs = [(40, 2), (-7, 12), (1 << 40, -(1 << 40)), (Value::MAX, 1)];
+    check_operation(Block::build_add, Value::wrapping_add, &pairs);
+    check_operation(Block::build_subtract, Value::wrapping_sub, &pairs);
+}
+
+#[test]
+fn put_hex_and_put_bin_print_digits_without_prefix() {
+    let values = [0, 5, 255, 0x1234_abcd, -1];
+    let mut module = Module::default();
+    let mut func = Function::new("_start".into());
+    let mut block = func.new_block(".entry".into());
+    for &value in &values {
+        let hex = block.build_constant(value);
+        block.build_put_hex(hex);
+        let bin = place(&mut block, value, Location::Memory);
+        block.build_put_bin(bin);
+    }
+    let zero = block.build_constant(0);
+    block.build_exit(zero);
+    func.append_block(block);
+    module.append_func(func);
+
+    let expected: String = values
+        .iter()
+        .map(|value| format!("{:x}\n{:b}\n", value, value))
+        .collect();
+    assert!(expected.starts_with("0\n0\n5\n101\nff\n11111111\n1234abcd\n"));
+    let mut output = vec![];
+    assert_eq!(module.interpret_to(&mut output).unwrap(), 0);
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+    let test = "put_hex_and_put_bin_print_digits_without_prefix";
+    if let Some((exit_code, output)) = run_output(test, module) {
+        assert_eq!(exit_code, 0);
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+}
+
+#[test]
+fn put_char_writes_the_low_byte_as_is() {
+    let mut module = Module::default();
+    let mut func = Function::new("_start".into());
+    let mut block = func.new_block(".entry".into());
+    for &value in &[
+        b'h' as Value,
+        0x100 + b'i' as Value,
+        b'\n' as Value,
+        0xff,
+        0,
+    ] {
+        let value = place(&mut block, value, Location::Memory);
+        block.build_put_char(value);
+    }
+    let exit_code = block.build_constant(3);
+    block.build_exit(exit_code);
+    func.append_block(block);
+    module.append_func(func);
+
+    let expected = b"hi\n\xff\0";
+    let mut output = vec![];
+    assert_eq!(module.interpret_to(&mut output).unwrap(), 3);
+    assert_eq!(output, expected);
+    if let Some((exit_code, output)) = run_output("put_char_writes_the_low_byte_as_is", module) {
+        assert_eq!(exit_code, 3);
+        assert_eq!(output, expected);
+    }
+}
+
+#[test]
+fn code_after_calls_to_noreturn_functions_is_dropped() {
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        let kept = place(&mut block, 1, Location::Register(Register::Rcx));
+        block
+            .build_call("fail".into(), &[ValueRef::Immediate(5)])
+            .unwrap();
+        block.build_exit(kept);
+        func.append_block(block);
+        module.append_func(func);
+
+        let mut func = Function::new("fail".into());
+        func.set_noreturn(true);
+        let mut block = func.new_block(".entry".into());
+        block.registers.alloc_specific(Register::Rdi).unwrap();
+        block.build_exit(ValueRef::Register(Register::Rdi));
+        func.append_block(block);
+        module.append_func(func);
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    let code = module.generate_string().unwrap();
+    let (start, fail) = code.split_at(code.find("global fail").unwrap());
+    assert!(start.ends_with("\tcall fail\n"), "{}", code);
+    // A function never returning neither restores registers nor has an epilogue.
+    assert!(!fail.contains("push") && !fail.contains("ret"), "{}", code);
+    assert_eq!(module.interpret(), 5);
+    assert_program_exit_code(build_module, 5);
+
+    // Such a call ends a block like an exit does.
+    let build_module = |module: &mut Module| {
+        build_module(module);
+        let block = &mut module.funcs[0].blocks[0];
+        block.instructions.pop();
+        assert!(matches!(
+            block.instructions.last(),
+            Some(Instruction::Call { .. })
+        ));
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    assert_eq!(module.validate_all(), Ok(()));
+    assert_eq!(
+        module.funcs[0].validate(),
+        Err(ValidationError::MissingTerminator {
+            block: ".entry".into()
+        })
+    );
+    assert_eq!(module.interpret(), 5);
+    assert_program_exit_code(build_module, 5);
+}
+
+#[test]
+fn memory_has_to_be_allocated_before_it_is_used() {
+    let check = |build: fn(&mut Function), expected: Result<(), ValidationError>| {
+        let mut func = Function::new("f".into());
+        build(&mut func);
+        assert_eq!(func.validate(), expected, "{}", func);
+    };
+    let unallocated = |block: &str, offset| {
+        Err(ValidationError::UnallocatedSlot {
+            block: block.into(),
+            offset,
+        })
+    };
+    // Used before the allocation in the same block.
+    check(
+        |func| {
+            let mut entry = func.new_block(".entry".into());
+            entry.build_store(ValueRef::Immediate(1), ValueRef::Memory(16));
+            entry.build_alloc(8);
+            entry.build_return(None);
+            func.append_block(entry);
+        },
+        unallocated(".entry", 16),
+    );
+    // Allocated in a block emitted after the one using it.
+    check(
+        |func| {
+            let mut entry = func.new_block(".entry".into());
+            entry.build_jump(".second".into());
+            let mut first = func.new_block(".first".into());
+            first.build_store(ValueRef::Immediate(1), ValueRef::Memory(16));
+            first.build_return(None);
+            let mut second = func.new_block(".second".into());
+            assert_eq!(second.build_alloc(8), ValueRef::Memory(16));
+            second.build_jump(".first".into());
+            for block in [entry, first, second] {
+                func.append_block(block);
+            }
+        },
+        unallocated(".first", 16),
+    );
+    // Beyond the end of the memory allocated.
+    check(
+        |func| {
+            let mut entry = func.new_block(".entry".into());
+            entry.build_alloc(8);
+            entry.build_store(ValueRef::Immediate(1), ValueRef::Memory(32));
+            entry.build_return(None);
+            func.append_block(entry);
+        },
+        unallocated(".entry", 32),
+    );
+    // Anywhere within the memory allocated, also in later blocks.
+    check(
+        |func| {
+            let mut entry = func.new_block(".entry".into());
+            assert_eq!(entry.build_alloc(24), ValueRef::Memory(32));
+            entry.build_store(ValueRef::Immediate(1), ValueRef::Memory(32));
+            entry.build_jump(".next".into());
+            let mut next = func.new_block(".next".into());
+            next.build_store(ValueRef::Immediate(2), ValueRef::Memory(9));
+            next.build_return(None);
+            func.append_block(entry);
+            func.append_block(next);
+        },
+        Ok(()),
+    );
+}
+
+#[test]
+fn call_preserving_saves_exactly_the_given_registers() {
+    use Register::*;
+    let build_module = |module: &mut Module| {
+        let mut func = Function::new("_start".into());
+        let mut block = func.new_block(".entry".into());
+        let kept = place(&mut block, 5, Location::Register(R11));
+        let overwritten = place(&mut block, 50, Location::Register(R10));
+        block
+            .build_call_preserving("clobber".into(), &[], &[R11])
+            .unwrap();
+        let sum = block.build_add(kept, overwritten);
+        block.build_exit(sum);
+        func.append_block(block);
+        module.append_func(func);
+        // Leaves its result 7 in R10.
+        module.append_func(clobber(&[R11, R10]));
+    };
+    let mut module = Module::default();
+    build_module(&mut module);
+    let code = module.funcs[0].generate_string().unwrap();
+    let pushes: Vec<&str> = code
+        .lines()
+        .filter(|line| line.contains("push r"))
+        .collect();
+    assert_eq!(pushes, ["\tpush rbp", "\tpush r11"]);
+    assert_eq!(module.interpret(), 12);
+    assert_program_exit_code(build_module, 12);
+}
+
+#[test]
+fn select_index_picks_every_value_of_short_and_long_lists() {
+    // A single value is simply copied, up to four values are selected with `cmov` and more
+    // through a table on the stack.
+    for len in [1, 2, 4, 5, 7] {
+        for index in 0..len {
+            let build_module = |module: &mut Module| {
+                let mut func = Function::new("_start".into());
+                let mut block = func.new_block(".entry".into());
+                let index_ref = place(&mut block, index, Location::Memory);
+                let values: Vec<ValueRef> =
+                    (0..len).map(|i| block.build_constant(10 * i + 3)).collect();
+                let selected = block.build_select_index(index_ref, &values).unwrap();
+                block.build_exit(selected);
+                func.append_block(block);
+                module.append_func(func);
+            };
+            let mut module = Module::default();
+            build_module(&mut module);
+            let code = module.generate_string().unwrap();
+            assert_eq!(code.contains("cmov"), (2..=4).contains(&len), "{}", code);
+            let expected = 10 * index + 3;
+            assert_eq!(module.interpret(), expected, "{} of {}", index, len);
+            assert_program_exit_code(build_module, expected as i32);
+        }
+    }
+}
+
+/// A module whose entry point calls `count` functions built in a loop, each returning its
+/// number, and exits with their sum. The blocks only live in the module returned.
+fn counting_module(count: Value) -> Module {
+    let mut module = Module::default();
+    let mut func = Function::new("_start".into());
+    let mut block = func.new_block(".entry".into());
+    let results: Vec<ValueRef> = (1..=count)
+        .map(|i| {
+            block
+                .build_call_with_result(format!("number{}", i), &[])
+                .unwrap()
+        })
+        .collect();
+    let sum = results[1..]
+        .iter()
+        .fold(results[0], |sum, &value| block.build_add(sum, value));
+    block.build_exit(sum);
+    func.append_block(block);
+    module.append_func(func);
+    for i in 1..=count {
+        let mut func = Function::new(format!("number{}", i));
+        let mut block = func.new_block(".entry".into());
+        let value = block.build_constant(i);
+        block.build_return(Some(value));
+        func.append_block(block);
+        module.append_func(func);
+    }
+    module
+}
+
+#[test]
+fn modules_built_in_a_helper_are_returned_by_value() {
+    let module = counting_module(6);
+    assert_eq!(module.funcs.len(), 7);
+    assert_eq!(module.interpret(), 21);
+    assert_program_exit_code(|module| *module = counting_module(6), 21);
+}
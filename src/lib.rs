@@ -1 +1,5 @@
+pub mod build;
+pub mod interp;
 pub mod ir;
+pub mod parse;
+pub mod testing;
@@ -1,19 +1,35 @@
-use std::{fs::OpenOptions, io::Write, process::Command};
-
-use fig::ir::{Block, Function, Module};
-
-fn run_command(cmd: &str) -> std::io::Result<()> {
-    println!("+ {}", cmd);
-    let output = Command::new("sh").arg("-c").arg(cmd).output()?;
-    std::io::stdout().write_all(&output.stdout)?;
-    std::io::stderr().write_all(&output.stderr)?;
-    Ok(())
+use std::io;
+
+use fig::{
+    build::{build, BuildConfig},
+    ir::{Function, Module},
+};
+
+/// Build configuration from the command line: `--format <elf64|macho64|win64>` selects the
+/// object file format and `--linker <command>` the linker.
+fn config_from_args(args: impl IntoIterator<Item = String>) -> io::Result<BuildConfig> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let mut config = BuildConfig::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| invalid(format!("missing value for `{}`", arg)))?;
+        match arg.as_str() {
+            "--format" => config.object_format = value.parse().map_err(invalid)?,
+            "--linker" => config.linker = value,
+            _ => return Err(invalid(format!("unknown argument `{}`", arg))),
+        }
+    }
+    Ok(config)
 }
 
-fn main() -> std::io::Result<()> {
-    let mut entry = Block::new(".entry".into());
+/// The sample program: count down from 10, printing each value, then exit with 0.
+fn sample_module() -> Module {
+    let mut func = Function::new("_start".into());
+    let mut entry = func.new_block(".entry".into());
 
-    let mut end = Block::new(".end".into());
+    let mut end = func.new_block(".end".into());
     let exit_code = end.build_constant(0);
     end.build_exit(exit_code);
 
@@ -21,31 +37,25 @@ fn main() -> std::io::Result<()> {
     let val = entry.build_constant(10);
     entry.build_store(val, var);
 
-    let mut r#loop = Block::new(".loop".into());
-    r#loop.build_call("put_int".into(), Some(var));
+    let mut r#loop = func.new_block(".loop".into());
+    entry.build_jump(r#loop.name.clone());
+    r#loop.build_put_int(var);
     let one = r#loop.build_constant(1);
     r#loop.build_subtract(var, one);
     r#loop.build_jump_if_zero(var, end.name.clone());
     r#loop.build_jump(r#loop.name.clone());
 
-    let mut func = Function::new("_start".into());
-    func.append_block(&entry);
-    func.append_block(&r#loop);
-    func.append_block(&end);
+    func.append_block(entry);
+    func.append_block(r#loop);
+    func.append_block(end);
 
     let mut module = Module::default();
-    module.append_func(&func);
-
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open("output.s")?;
-    module.generate_code(&mut file)?;
-
-    run_command("nasm -f elf64 -o output.o output.s")?;
-    run_command("nasm -f elf64 -o lib/lib.o lib/lib.s")?;
-    run_command("ld -o output output.o lib/lib.o")?;
+    module.append_func(func);
+    module
+}
 
+fn main() -> io::Result<()> {
+    let config = config_from_args(std::env::args().skip(1))?;
+    build(&sample_module(), &config)?;
     Ok(())
 }
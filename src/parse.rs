@@ -0,0 +1,823 @@
+//! A parser for a textual form of the IR, to write programs without the builder API.
+//!
+//! The syntax is line based. `func <name>` starts a function, optionally followed by
+//! `noreturn`, and a label like `.entry:` starts a block of it. Every other line is an
+//! instruction of the current block, which may bind its result to a value name:
+//!
+//! ```text
+//! func _start
+//! .entry:
+//!     %counter = alloc 8
+//!     %0 = const 10
+//!     store %0, %counter
+//!     jmp .loop
+//! .loop:
+//!     call put_int %counter
+//!     %1 = const 1
+//!     sub %counter, %1
+//!     jz %counter, .end
+//!     jmp .loop
+//! .end:
+//!     %2 = const 0
+//!     exit %2
+//! ```
+//!
+//! A line like `global @counter 0` declares a global variable initialized to 0, which the
+//! instructions after it can use as `@counter`. Likewise `string @greeting "hi\n"` adds a
+//! string to be printed with `print_string @greeting`, written with the escapes of Rust.
+//! A thread-local variable is declared like a global one with `tls @errno 0`.
+//!
+//! The right operand of `add`, `sub`, `and`, `or` and `xor` and the value of a store may
+//! also be an immediate written as a number that fits into 32 bits, like `add %0, 1`. So
+//! may the count of `shl` and `shr`, which consumes a value that `const` set to that
+//! number right before the shift like a shift by that value would.
+//!
+//! Value names start with `%` or are enclosed in brackets, like the names `Display` gives
+//! values in registers and stack memory, `%rax` and `[rbp-16]`. The text `Display` writes
+//! for a module reads back into one with the same instructions, except that freeing stack
+//! memory isn't written and so not repeated.
+//!
+//! Everything after a `;` outside of a string is a comment, and comments on lines of their
+//! own are kept for the next instruction. Instructions taking operands consume them like
+//! the builder methods do, so a value freed by one instruction can't be used afterwards.
+
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+};
+
+use crate::ir::{Block, Function, MemoryOrdering, Module, ValueRef, Width};
+
+/// An error found while parsing, with the number of the offending line starting at 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl Module {
+    /// Parse a program written in the textual form of the IR described in `fig::parse`.
+    pub fn parse(source: &str) -> Result<Module, ParseError> {
+        let mut parser = Parser::default();
+        for (i, line) in source.lines().enumerate() {
+            let (line, comment) = split_comment(line);
+            let line = line.trim();
+            let result = match comment {
+                _ if !line.is_empty() => parser.line(line),
+                Some(comment) => {
+                    parser.comment(comment.trim());
+                    Ok(())
+                }
+                None => Ok(()),
+            };
+            result.map_err(|message| ParseError {
+                line: i + 1,
+                message,
+            })?;
+        }
+        parser.finish_function();
+        Ok(parser.module)
+    }
+}
+
+/// The state of the function being parsed.
+struct CurrentFunction {
+    /// Creates the blocks so that they can share values, and holds the finished ones.
+    func: Function,
+    block: Option<Block>,
+    values: HashMap<String, ValueRef>,
+}
+
+#[derive(Default)]
+struct Parser {
+    module: Module,
+    current: Option<CurrentFunction>,
+    /// The global and thread-local variables declared so far, by their names including the
+    /// `@`.
+    globals: HashMap<String, ValueRef>,
+}
+
+impl Parser {
+    fn line(&mut self, line: &str) -> Result<(), String> {
+        let variable = (line.strip_prefix("global ").map(|rest| (rest, false)))
+            .or_else(|| line.strip_prefix("tls ").map(|rest| (rest, true)));
+        if let Some((rest, thread_local)) = variable {
+            let (name, init) = match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [name, init] => (name, integer(init)?),
+                _ => return Err("expected a global name and its initial value".to_string()),
+            };
+            let symbol = match name.strip_prefix('@') {
+                Some(symbol) if !symbol.is_empty() => symbol,
+                _ => return Err(format!("invalid global name `{}`", name)),
+            };
+            if self.globals.contains_key(name) {
+                return Err(format!("global `{}` is declared twice", name));
+            }
+            let global = if thread_local {
+                self.module.add_tls(symbol.to_string(), init)
+            } else {
+                self.module.add_global(symbol.to_string(), init)
+            };
+            let global = global.map_err(|err| err.to_string())?;
+            self.globals.insert(name.to_string(), global);
+            if let Some(current) = &mut self.current {
+                current.values.insert(name.to_string(), global);
+            }
+            return Ok(());
+        }
+        if let Some(rest) = line.strip_prefix("string ") {
+            let (name, string) = rest
+                .trim()
+                .split_once(char::is_whitespace)
+                .ok_or("expected a string name and its contents")?;
+            let string = string_literal(string.trim())?;
+            if !name.starts_with('@') || name.len() == 1 {
+                return Err(format!("invalid string name `{}`", name));
+            }
+            if self.globals.contains_key(name) {
+                return Err(format!("global `{}` is declared twice", name));
+            }
+            let string = self.module.add_string(&string);
+            self.globals.insert(name.to_string(), string);
+            if let Some(current) = &mut self.current {
+                current.values.insert(name.to_string(), string);
+            }
+            return Ok(());
+        }
+        if let Some(rest) = line.strip_prefix("func ") {
+            self.finish_function();
+            let mut words = rest.split_whitespace();
+            let name = words.next().ok_or("missing function name")?;
+            let noreturn = match words.next() {
+                None => false,
+                Some("noreturn") => true,
+                Some(word) => return Err(format!("unexpected `{}` after function name", word)),
+            };
+            let mut func = Function::new(name.to_string());
+            func.set_noreturn(noreturn);
+            self.current = Some(CurrentFunction {
+                func,
+                block: None,
+                values: self.globals.clone(),
+            });
+            return Ok(());
+        }
+        let current = self
+            .current
+            .as_mut()
+            .ok_or("expected `func` before the first block")?;
+        if let Some(label) = line.strip_suffix(':') {
+            if label.contains(char::is_whitespace) {
+                return Err(format!("invalid block label `{}`", label));
+            }
+            if let Some(block) = current.block.take() {
+                current.func.append_block(block);
+            }
+            current.block = Some(current.func.new_block(label.to_string()));
+            return Ok(());
+        }
+        current.instruction(line)
+    }
+
+    /// Attach a comment on a line of its own to the next instruction of the current block.
+    /// Comments outside of blocks are dropped.
+    fn comment(&mut self, comment: &str) {
+        if let Some(block) = self
+            .current
+            .as_mut()
+            .and_then(|current| current.block.as_mut())
+        {
+            block.build_comment(comment);
+        }
+    }
+
+    fn finish_function(&mut self) {
+        if let Some(mut current) = self.current.take() {
+            if let Some(block) = current.block.take() {
+                current.func.append_block(block);
+            }
+            self.module.append_func(current.func);
+        }
+    }
+}
+
+impl CurrentFunction {
+    fn instruction(&mut self, line: &str) -> Result<(), String> {
+        let (dest, line) = match line.split_once('=') {
+            Some((dest, rest)) => (Some(value_name(dest.trim())?), rest.trim()),
+            None => (None, line),
+        };
+        let (op, rest) = match line.split_once(char::is_whitespace) {
+            Some((op, rest)) => (op, rest.trim()),
+            None => (line, ""),
+        };
+        let block = self
+            .block
+            .as_mut()
+            .ok_or("expected a block label before the first instruction")?;
+        let values = &mut self.values;
+        let operands: Vec<&str> = if rest.is_empty() {
+            vec![]
+        } else {
+            rest.split(',').map(str::trim).collect()
+        };
+
+        let result = match op {
+            "const" | "const32" => {
+                let [value] = operand_list(&operands)?;
+                Some(block.build_constant_sized(integer(value)?, width(op)))
+            }
+            "fconst" => {
+                let [value] = operand_list(&operands)?;
+                let value = value
+                    .parse()
+                    .map_err(|_| format!("invalid floating-point number `{}`", value))?;
+                Some(block.build_float_constant(value))
+            }
+            "alloc" => {
+                let [size] = operand_list(&operands)?;
+                Some(block.build_alloc(integer(size)? as usize))
+            }
+            "store" | "store32" | "store_volatile" => {
+                let [value, storage] = operand_list(&operands)?;
+                let value = take_or_immediate(values, value)?;
+                let storage = lookup(values, storage)?;
+                if op == "store_volatile" {
+                    block.build_store_volatile(value, storage);
+                } else {
+                    block.build_store_sized(value, storage, width(op));
+                }
+                None
+            }
+            "fence" => {
+                let [ordering] = operand_list(&operands)?;
+                let ordering = match ordering {
+                    "acquire" => MemoryOrdering::Acquire,
+                    "release" => MemoryOrdering::Release,
+                    "seq_cst" => MemoryOrdering::SeqCst,
+                    _ => return Err(format!("unknown memory ordering `{}`", ordering)),
+                };
+                block.build_fence(ordering);
+                None
+            }
+            "move" => {
+                let [source, storage] = operand_list(&operands)?;
+                let source = lookup(values, source)?;
+                let storage = lookup(values, storage)?;
+                block.build_move(source, storage);
+                None
+            }
+            "add32" | "sub32" => {
+                let [left, right] = operand_list(&operands)?;
+                let left = lookup(values, left)?;
+                let right = take_or_immediate(values, right)?;
+                if op == "add32" {
+                    Some(block.build_add_sized(left, right, Width::Dword))
+                } else {
+                    Some(block.build_subtract_sized(left, right, Width::Dword))
+                }
+            }
+            "shl" | "shr" => {
+                let [left, count] = operand_list(&operands)?;
+                let left = lookup(values, left)?;
+                let count = if count.starts_with(|c: char| c == '-' || c.is_ascii_digit()) {
+                    let value = integer(count)?;
+                    match block.last_constant(value) {
+                        Some(constant) => {
+                            values.retain(|_, other| *other != constant);
+                            constant
+                        }
+                        None => take_or_immediate(values, count)?,
+                    }
+                } else {
+                    take(values, count)?
+                };
+                if op == "shl" {
+                    Some(block.build_shift_left(left, count))
+                } else {
+                    Some(block.build_shift_right(left, count))
+                }
+            }
+            "add" | "sub" | "mul" | "div" | "mod" | "udiv" | "umod" | "and" | "or" | "xor"
+            | "eq" | "ne" | "lt" | "gt" | "le" | "ge" | "min" | "max" | "fadd" | "fsub"
+            | "fmul" | "fdiv" => {
+                let [left, right] = operand_list(&operands)?;
+                let left = lookup(values, left)?;
+                let right = match op {
+                    "add" | "sub" | "and" | "or" | "xor" => take_or_immediate(values, right)?,
+                    _ => take(values, right)?,
+                };
+                let build = match op {
+                    "add" => Block::build_add,
+                    "sub" => Block::build_subtract,
+                    "mul" => Block::build_multiply,
+                    "div" => Block::build_divide,
+                    "mod" => Block::build_modulo,
+                    "udiv" => Block::build_unsigned_divide,
+                    "umod" => Block::build_unsigned_modulo,
+                    "and" => Block::build_and,
+                    "or" => Block::build_or,
+                    "xor" => Block::build_xor,
+                    "eq" => Block::build_equal,
+                    "ne" => Block::build_not_equal,
+                    "lt" => Block::build_less_than,
+                    "gt" => Block::build_greater_than,
+                    "le" => Block::build_less_equal,
+                    "ge" => Block::build_greater_equal,
+                    "min" => Block::build_min,
+                    "max" => Block::build_max,
+                    "fadd" => Block::build_fadd,
+                    "fsub" => Block::build_fsub,
+                    "fmul" => Block::build_fmul,
+                    _ => Block::build_fdiv,
+                };
+                Some(build(block, left, right))
+            }
+            "load" | "load_volatile" => {
+                let [source] = operand_list(&operands)?;
+                let source = lookup(values, source)?;
+                if op == "load_volatile" {
+                    Some(block.build_load_volatile(source))
+                } else {
+                    Some(block.build_load(source))
+                }
+            }
+            "neg" => {
+                let [value] = operand_list(&operands)?;
+                let value = lookup(values, value)?;
+                Some(block.build_negate(value))
+            }
+            "select_if" => {
+                let [cond, if_true, if_false] = operand_list(&operands)?;
+                let cond = take(values, cond)?;
+                let if_true = take(values, if_true)?;
+                let if_false = take(values, if_false)?;
+                Some(block.build_select(cond, if_true, if_false))
+            }
+            "select" => {
+                let (table, operands) = match operands.split_last() {
+                    Some((last, rest)) if last.starts_with("table ") => {
+                        let table = lookup(values, last["table ".len()..].trim())?;
+                        if !matches!(table, ValueRef::Memory(_)) {
+                            return Err(format!("table `{}` is not stack memory", last));
+                        }
+                        (Some(table), rest)
+                    }
+                    _ => (None, &operands[..]),
+                };
+                let (index, selected) = match operands {
+                    [index, selected @ ..] if !selected.is_empty() => (index, selected),
+                    _ => return Err("expected an index and values to select from".to_string()),
+                };
+                // A value may be selected more than once, so they are freed after the lookup.
+                let index = lookup(values, index)?;
+                let selected = selected
+                    .iter()
+                    .map(|value| lookup(values, value))
+                    .collect::<Result<Vec<_>, _>>()?;
+                for &value in selected.iter().chain([&index]) {
+                    if let ValueRef::Register(_) | ValueRef::Xmm(_) = value {
+                        values.retain(|_, other| *other != value);
+                    }
+                }
+                match table {
+                    Some(_) => Some(block.build_select_index_in(index, &selected, table)),
+                    None => Some(
+                        block
+                            .build_select_index(index, &selected)
+                            .map_err(|error| error.to_string())?,
+                    ),
+                }
+            }
+            "table_load" => {
+                let [table, index, elem_size] = operand_list(&operands)?;
+                let index = take(values, index)?;
+                let elem_size = integer(elem_size)?;
+                if !matches!(elem_size, 1 | 2 | 4 | 8) {
+                    return Err("table entries must be 1, 2, 4 or 8 bytes wide".to_string());
+                }
+                Some(block.build_table_load(table, index, elem_size as usize))
+            }
+            "popcount" => {
+                let [value] = operand_list(&operands)?;
+                let value = take(values, value)?;
+                Some(block.build_popcount(value))
+            }
+            "jmp" => {
+                let [dest] = operand_list(&operands)?;
+                block.build_jump(dest.to_string());
+                None
+            }
+            "jmp_indirect" => {
+                let [target] = operand_list(&operands)?;
+                let target = take(values, target)?;
+                block.build_jump_indirect(target);
+                None
+            }
+            "tail_call_indirect" => {
+                let (target, arg) = match operands[..] {
+                    [target] => (target, None),
+                    [target, arg] => (target, Some(lookup(values, arg)?)),
+                    _ => return Err("expected a target and at most 1 argument".to_string()),
+                };
+                let target = lookup(values, target)?;
+                block.build_tail_call_indirect(target, arg);
+                None
+            }
+            "jz" | "jnz" => {
+                let [value, dest] = operand_list(&operands)?;
+                let value = take(values, value)?;
+                if op == "jz" {
+                    block.build_jump_if_zero(value, dest.to_string());
+                } else {
+                    block.build_jump_if_not_zero(value, dest.to_string());
+                }
+                None
+            }
+            "switch" => {
+                let (value, default) = match operands[..] {
+                    [value, default, ..] => (value, default),
+                    _ => return Err("expected a value and a default block".to_string()),
+                };
+                let value = take(values, value)?;
+                let cases = operands[2..]
+                    .iter()
+                    .map(|case| {
+                        let (case, dest) = case.split_once(':').ok_or_else(|| {
+                            format!("expected `<value>: <block>`, found `{}`", case)
+                        })?;
+                        Ok((integer(case.trim())?, dest.trim().to_string()))
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+                block
+                    .build_switch(value, &cases, default.to_string())
+                    .map_err(|error| error.to_string())?;
+                None
+            }
+            "call" | "call_float" => {
+                // The callee is separated from the arguments by whitespace, not a comma.
+                let (func, args) = match rest.split_once(char::is_whitespace) {
+                    Some((func, args)) => (func, args.split(',').map(str::trim).collect()),
+                    None => (rest, vec![]),
+                };
+                if func.is_empty() {
+                    return Err("missing function name".to_string());
+                }
+                let args = args
+                    .iter()
+                    .map(|arg| lookup(values, arg))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if op == "call_float" {
+                    Some(
+                        block
+                            .build_call_with_float_result(func.to_string(), &args)
+                            .map_err(|error| error.to_string())?,
+                    )
+                } else if dest.is_some() {
+                    Some(
+                        block
+                            .build_call_with_result(func.to_string(), &args)
+                            .map_err(|error| error.to_string())?,
+                    )
+                } else {
+                    block
+                        .build_call(func.to_string(), &args)
+                        .map_err(|error| error.to_string())?;
+                    None
+                }
+            }
+            "save_context" => {
+                let [context] = operand_list(&operands)?;
+                let context = lookup(values, context)?;
+                if !matches!(context, ValueRef::Memory(_)) {
+                    return Err(format!("context `{}` is not stack memory", operands[0]));
+                }
+                block.build_save_context_in(context);
+                None
+            }
+            "restore_context" => {
+                let [context] = operand_list(&operands)?;
+                let context = lookup(values, context)?;
+                block
+                    .build_restore_context(context)
+                    .map_err(|error| error.to_string())?;
+                None
+            }
+            "print_string" => {
+                let [string] = operand_list(&operands)?;
+                let string = lookup(values, string)?;
+                block
+                    .build_print_string(string)
+                    .map_err(|error| error.to_string())?;
+                None
+            }
+            "ret" => {
+                let value = match operands[..] {
+                    [] => None,
+                    [value] => Some(lookup(values, value)?),
+                    _ => return Err("expected at most 1 operand".to_string()),
+                };
+                block.build_return(value);
+                None
+            }
+            "exit" => {
+                let [exit_code] = operand_list(&operands)?;
+                let exit_code = lookup(values, exit_code)?;
+                block.build_exit(exit_code);
+                None
+            }
+            _ => return Err(format!("unknown instruction `{}`", op)),
+        };
+
+        match (dest, result) {
+            (Some(dest), Some(result)) => {
+                values.insert(dest.to_string(), result);
+                Ok(())
+            }
+            (Some(_), None) => Err(format!("`{}` has no result", op)),
+            (None, _) => Ok(()),
+        }
+    }
+}
+
+/// Check that exactly `N` operands are given.
+fn operand_list<'s, const N: usize>(operands: &[&'s str]) -> Result<[&'s str; N], String> {
+    operands.try_into().map_err(|_| {
+        format!(
+            "expected {} operand{}, found {}",
+            N,
+            if N == 1 { "" } else { "s" },
+            operands.len()
+        )
+    })
+}
+
+/// The width of the values an instruction operates on, given by a `32` suffix.
+fn width(op: &str) -> Width {
+    if op.ends_with("32") {
+        Width::Dword
+    } else {
+        Width::Qword
+    }
+}
+
+fn value_name(name: &str) -> Result<&str, String> {
+    let ident = match name.strip_prefix('%') {
+        Some(ident) => Some(ident),
+        None => name
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']')),
+    };
+    match ident {
+        Some(ident) if !ident.is_empty() && !ident.contains(char::is_whitespace) => Ok(name),
+        _ => Err(format!("invalid value name `{}`", name)),
+    }
+}
+
+/// Split a line into its code and the comment after a `;` outside of a string, if any.
+fn split_comment(line: &str) -> (&str, Option<&str>) {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return (&line[..i], Some(&line[i + 1..])),
+            _ => {}
+        }
+    }
+    (line, None)
+}
+
+/// The contents of a string literal in quotes, with the escapes `Debug` writes for strings.
+fn string_literal(text: &str) -> Result<String, String> {
+    let invalid = || format!("invalid string `{}`", text);
+    let inner = text
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .ok_or_else(invalid)?;
+    let mut string = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == '"' {
+            return Err(invalid());
+        }
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        string.push(match chars.next().ok_or_else(invalid)? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            '0' => '\0',
+            '\\' => '\\',
+            '\'' => '\'',
+            '"' => '"',
+            'u' => {
+                let rest = chars.as_str();
+                let (digits, after) = rest
+                    .strip_prefix('{')
+                    .and_then(|rest| rest.split_once('}'))
+                    .ok_or_else(invalid)?;
+                chars = after.chars();
+                u32::from_str_radix(digits, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(invalid)?
+            }
+            _ => return Err(invalid()),
+        });
+    }
+    Ok(string)
+}
+
+fn integer(text: &str) -> Result<i64, String> {
+    text.parse()
+        .map_err(|_| format!("invalid integer `{}`", text))
+}
+
+fn lookup(values: &HashMap<String, ValueRef>, name: &str) -> Result<ValueRef, String> {
+    let key = if name.starts_with('@') {
+        name
+    } else {
+        value_name(name)?
+    };
+    values
+        .get(key)
+        .copied()
+        .ok_or_else(|| format!("undefined value `{}`", name))
+}
+
+/// Look up a value which the instruction consumes. Values in registers are freed by it and
+/// can't be used anymore.
+fn take(values: &mut HashMap<String, ValueRef>, name: &str) -> Result<ValueRef, String> {
+    let value = lookup(values, name)?;
+    if let ValueRef::Register(_) | ValueRef::Xmm(_) = value {
+        values.retain(|_, other| *other != value);
+    }
+    Ok(value)
+}
+
+/// Like `take`, but a number is read as an immediate.
+fn take_or_immediate(
+    values: &mut HashMap<String, ValueRef>,
+    name: &str,
+) -> Result<ValueRef, String> {
+    if !name.starts_with(|c: char| c == '-' || c.is_ascii_digit()) {
+        return take(values, name);
+    }
+    let value = integer(name)?;
+    let value = i32::try_from(value)
+        .map_err(|_| format!("immediate `{}` doesn't fit into 32 bits", name))?;
+    Ok(ValueRef::Immediate(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_names_strings_and_comments_written_by_display() {
+        let source = r#"
+string @greeting "a; \"b\"\n\u{2603}"
+func _start
+.entry:
+    ; the buffer is named like stack memory
+    [buffer] = alloc 64
+    save_context [buffer]
+    %0 = const 3
+    shl %0, 2                   ; by an immediate, no constant to consume
+    %1 = const 0
+    %2 = const 2
+    %3 = select %1, %0, %2, %0, %2, %0, table [buffer]
+    print_string @greeting
+    exit %3
+"#;
+        let module = Module::parse(source).unwrap();
+        assert_eq!(module.strings[0].1, "a; \"b\"\n\u{2603}");
+        let text = module.to_string();
+        assert!(text.contains("string @__fig_str0 \"a; \\\"b\\\"\\n☃\"\n"));
+        assert!(text.contains("    ; the buffer is named like stack memory\n"));
+        assert!(text.contains("shl %r15, 2\n"));
+        assert!(text.contains(", table [rbp-64]\n"));
+        assert_eq!(Module::parse(&text).unwrap().to_string(), text);
+        module.generate_string().unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_new_syntax() {
+        let error = |source: &str| Module::parse(source).unwrap_err().to_string();
+        let program = |line: &str| format!("func f\n.entry:\n    %0 = const 1\n    {}\n", line);
+        assert_eq!(
+            error("string @s \"open\n"),
+            "line 1: invalid string `\"open`"
+        );
+        assert_eq!(
+            error("string s \"text\"\n"),
+            "line 1: invalid string name `s`"
+        );
+        assert_eq!(
+            error(&program("save_context %0")),
+            "line 4: context `%0` is not stack memory"
+        );
+        assert_eq!(
+            error(&program("%1 = select %0")),
+            "line 4: expected an index and values to select from"
+        );
+        assert_eq!(
+            error(&program("%1 = table_load table, %0, 3")),
+            "line 4: table entries must be 1, 2, 4 or 8 bytes wide"
+        );
+        assert_eq!(
+            error(&program("print_string %0")),
+            "line 4: %r15 can't be an operand of print_string"
+        );
+    }
+
+    #[test]
+    fn runs_the_documented_countdown() {
+        let source = "func _start
+.entry:
+    %counter = alloc 8
+    %0 = const 10
+    store %0, %counter
+    jmp .loop
+.loop:
+    call put_int %counter
+    %1 = const 1
+    sub %counter, %1
+    jz %counter, .end
+    jmp .loop
+.end:
+    %2 = const 0
+    exit %2
+";
+        let module = Module::parse(source).unwrap();
+        assert_eq!(module.funcs[0].blocks.len(), 3);
+        module.funcs[0].validate().unwrap();
+        let mut output = vec![];
+        assert_eq!(module.interpret_to(&mut output).unwrap(), 0);
+        assert_eq!(output, b"10\n9\n8\n7\n6\n5\n4\n3\n2\n1\n");
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        let error = |source: &str| Module::parse(source).unwrap_err().to_string();
+        let program = |line: &str| format!("func f\n.entry:\n    %0 = const 1\n    {}\n", line);
+        let cases = [
+            ("frobnicate %0", "line 4: unknown instruction `frobnicate`"),
+            ("%1 = add %0, %2", "line 4: undefined value `%2`"),
+            ("%1 = add %0", "line 4: expected 2 operands, found 1"),
+            ("%1 = store 1, %0", "line 4: `store` has no result"),
+            ("%1 = const 1x", "line 4: invalid integer `1x`"),
+            (
+                "%1 = add %0, 4294967296",
+                "line 4: immediate `4294967296` doesn't fit into 32 bits",
+            ),
+            ("%1 = call", "line 4: missing function name"),
+            ("fence relaxed", "line 4: unknown memory ordering `relaxed`"),
+        ];
+        for (line, expected) in &cases {
+            assert_eq!(error(&program(line)), *expected);
+        }
+        // A value in a register is gone once an instruction consumed it.
+        assert_eq!(
+            error(&program("%1 = const 2\n    add %0, %1\n    add %0, %1")),
+            "line 6: undefined value `%1`"
+        );
+        assert_eq!(
+            error(".entry:\n"),
+            "line 1: expected `func` before the first block"
+        );
+        assert_eq!(
+            error("func f\n    ret\n"),
+            "line 2: expected a block label before the first instruction"
+        );
+        assert_eq!(
+            error("func f extra\n"),
+            "line 1: unexpected `extra` after function name"
+        );
+        assert_eq!(
+            error("global @g 1\nglobal @g 2\n"),
+            "line 2: global `@g` is declared twice"
+        );
+        assert_eq!(
+            error("global @g 1\ntls @g 2\n"),
+            "line 2: global `@g` is declared twice"
+        );
+        assert_eq!(
+            error(&format!("global @{} 1\n", "g".repeat(32))),
+            format!("line 1: symbol {} is longer than 31 bytes", "g".repeat(32))
+        );
+    }
+}
@@ -0,0 +1,153 @@
+//! Helpers for testing front-ends that lower to this IR.
+
+use std::{fs, process::Command};
+
+use crate::{
+    build::{build, unique_temp_dir, BuildConfig},
+    ir::{AsmSyntax, Module},
+};
+
+/// Build a module with `build_module`, compile it into an executable, run it and assert
+/// that it exits with the expected exit code.
+///
+/// This needs `ld` and an assembler: NASM, or the GNU assembler if NASM is not installed,
+/// in which case the code is generated in AT&T syntax. Without a toolchain the check is
+/// skipped: a line saying so is written to stderr, which the test harness doesn't capture,
+/// and the function returns normally so test suites still pass on such machines. Setting
+/// the environment variable `FIG_REQUIRE_TOOLCHAIN` turns a skip into a failure, for CI
+/// machines that are supposed to have the toolchain.
+///
+/// ```
+/// use fig::ir::{Block, Function};
+/// use fig::testing::assert_program_exit_code;
+///
+/// assert_program_exit_code(
+///     |module| {
+///         let mut entry = Block::new(".entry".into());
+///         let three = entry.build_constant(3);
+///         let four = entry.build_constant(4);
+///         let product = entry.build_multiply(three, four);
+///         let two = entry.build_constant(2);
+///         let result = entry.build_subtract(product, two);
+///         entry.build_exit(result);
+///
+///         let mut func = Function::new("_start".into());
+///         func.append_block(entry);
+///         module.append_func(func);
+///     },
+///     10,
+/// );
+/// ```
+pub fn assert_program_exit_code(build_module: impl FnOnce(&mut Module), expected: i32) {
+    let mut module = Module::default();
+    build_module(&mut module);
+    if module.syntax() == AsmSyntax::Nasm && !has_tool("nasm") && has_tool("as") {
+        module.set_syntax(AsmSyntax::Att);
+    }
+    let assembler = match module.syntax() {
+        AsmSyntax::Nasm => "nasm",
+        AsmSyntax::Att => "as",
+    };
+    for tool in [assembler, "ld"] {
+        if !has_tool(tool) {
+            if std::env::var_os("FIG_REQUIRE_TOOLCHAIN").is_some() {
+                panic!("{} not found but FIG_REQUIRE_TOOLCHAIN is set", tool);
+            }
+            return report_skipped("assert_program_exit_code", tool);
+        }
+    }
+
+    let dir = unique_temp_dir("fig-test");
+    let output = dir.join("output");
+    let config = BuildConfig {
+        build_dir: dir.clone(),
+        output: output.clone(),
+        ..BuildConfig::default()
+    };
+    let built = build(&module, &config);
+    let status = built.and_then(|()| Command::new(output).status());
+    fs::remove_dir_all(&dir).ok();
+    let status = status.expect("failed to build and run program");
+    assert_eq!(status.code(), Some(expected), "unexpected exit code");
+}
+
+/// Whether the external tool `name` is installed.
+pub(crate) fn has_tool(name: &str) -> bool {
+    Command::new(name).arg("--version").output().is_ok()
+}
+
+/// Report that `test` skipped a check because `tool` isn't installed. This bypasses the
+/// output capturing of the test harness so the skip doesn't go unnoticed.
+pub(crate) fn report_skipped(test: &str, tool: &str) {
+    use std::io::Write;
+    let _ = writeln!(std::io::stderr(), "{}: skipped, {} not found", test, tool);
+}
+
+/// Assemble `asm`, code in AT&T syntax, link it together with the C source `c` against
+/// libc with `gcc` into a position-dependent executable, run the program and return its
+/// exit code. Returns `None` if the GNU assembler or `gcc` isn't installed.
+#[cfg(test)]
+pub(crate) fn run_with_libc(test: &str, asm: &str, c: &str) -> Option<i32> {
+    run_with_libc_linked(test, asm, c, &["-no-pie"])
+}
+
+/// Like `run_with_libc`, passing `link_args` to `gcc` instead of `-no-pie`.
+#[cfg(test)]
+pub(crate) fn run_with_libc_linked(
+    test: &str,
+    asm: &str,
+    c: &str,
+    link_args: &[&str],
+) -> Option<i32> {
+    for tool in ["as", "gcc"] {
+        if !has_tool(tool) {
+            report_skipped(test, tool);
+            return None;
+        }
+    }
+    let dir = unique_temp_dir("fig-libc-test");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("code.s"), asm).unwrap();
+    fs::write(dir.join("main.c"), c).unwrap();
+    let run = |cmd: &mut Command| {
+        let output = cmd.current_dir(&dir).output().unwrap();
+        assert!(
+            output.status.success(),
+            "{:?} failed: {}",
+            cmd,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    };
+    run(Command::new("as").args(["--64", "-o", "code.o", "code.s"]));
+    run(Command::new("gcc")
+        .args(link_args)
+        .args(["-o", "program", "main.c", "code.o"]));
+    let status = Command::new(dir.join("program")).status().unwrap();
+    fs::remove_dir_all(&dir).ok();
+    Some(status.code().expect("program was killed by a signal"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::Function;
+
+    #[test]
+    fn runs_program_built_by_closure() {
+        assert_program_exit_code(
+            |module| {
+                let mut func = Function::new("_start".into());
+                let mut entry = func.new_block(".entry".into());
+                let counter = entry.build_alloc(8);
+                let value = entry.build_constant(6);
+                entry.build_store(value, counter);
+                let seven = entry.build_constant(7);
+                let product = entry.build_multiply(seven, counter);
+                entry.build_exit(product);
+                func.append_block(entry);
+                module.append_func(func);
+            },
+            42,
+        );
+    }
+}